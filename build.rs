@@ -0,0 +1,12 @@
+fn main() {
+    // The gRPC API is generated from proto/rasterizer.proto, with a bundled protoc
+    // so building doesn't depend on one being installed
+    #[cfg(feature = "grpc")]
+    {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().expect("No bundled protoc for this platform"));
+        tonic_build::configure()
+            .build_client(false)
+            .compile(&["proto/rasterizer.proto"], &["proto"])
+            .expect("Failed to compile proto/rasterizer.proto");
+    }
+}
@@ -0,0 +1,1131 @@
+use roxmltree::{Document, Node, NodeId};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::error::{Error, Result};
+
+// Sampling of animated SVGs. SMIL animation elements (<animate>, <set>,
+// <animateTransform>) and CSS @keyframes animations are evaluated at a point in
+// time and written into a static SVG that renders as that frame. Motion paths,
+// event-based begins, and CSS selectors other than tag names, classes, ids and
+// descendants aren't supported; what they animate keeps its static value.
+
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+const XLINK_NS: &str = "http://www.w3.org/1999/xlink";
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+
+const ANIMATION_ELEMENTS: [&str; 5] = ["animate", "animateColor", "animateMotion", "animateTransform", "set"];
+
+// usvg reads these from style attributes too, so animated values go there to win
+// over stylesheet rules. Everything else is written as an attribute.
+const PRESENTATION_ATTRIBUTES: &[&str] = &[
+    "clip-path", "color", "display", "fill", "fill-opacity", "fill-rule", "filter", "flood-color",
+    "flood-opacity", "font-size", "font-weight", "letter-spacing", "mask", "opacity", "stop-color",
+    "stop-opacity", "stroke", "stroke-dasharray", "stroke-dashoffset", "stroke-linecap",
+    "stroke-linejoin", "stroke-miterlimit", "stroke-opacity", "stroke-width", "transform", "visibility",
+];
+
+// The CSS initial timing function
+const EASE: Easing = Easing::CubicBezier(0.25, 0.1, 0.25, 1.0);
+
+// An animated SVG, parsed once and sampled for every frame
+pub struct Animation<'a> {
+    document: Document<'a>,
+    tracks: Vec<Track>,
+}
+
+// One animated attribute of one element
+struct Track {
+    target: NodeId,
+    attribute: String,
+    timing: Timing,
+    keyframes: Vec<Keyframe>,
+    // animateTransform with additive="sum" applies on top of the element's own transform
+    additive: bool,
+    // CSS transforms rotate and scale around transform-origin
+    origin: Option<(f32, f32)>,
+}
+
+#[derive(Clone, Debug)]
+struct Timing {
+    begin: f32,
+    // Seconds per iteration, infinite for `indefinite`
+    duration: f32,
+    repeat: f32,
+    // Keep the last value once the animation is over
+    forwards: bool,
+    // Show the first value before the animation begins
+    backwards: bool,
+    alternate: bool,
+    reverse: bool,
+}
+
+struct Keyframe {
+    offset: f32,
+    value: String,
+    // From this keyframe to the next
+    easing: Easing,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Easing {
+    Linear,
+    Discrete,
+    CubicBezier(f32, f32, f32, f32),
+    // Number of steps, and whether the first one is taken right away
+    Steps(u32, bool),
+}
+
+// A CSS animation as declared on an element, before its keyframes are looked up
+struct CssAnimation {
+    name: String,
+    timing: Timing,
+    easing: Easing,
+}
+
+struct Rule {
+    selectors: Vec<String>,
+    declarations: Vec<(String, String)>,
+}
+
+type Keyframes = HashMap<String, Vec<(f32, Vec<(String, String)>)>>;
+
+impl<'a> Animation<'a> {
+    pub fn parse(svg_data: &'a str) -> Result<Self> {
+        let document = Document::parse(svg_data)
+            .map_err(|e| Error::Parse(format!("Failed to parse SVG: {}", e)))?;
+        let mut tracks = smil_tracks(&document);
+        tracks.extend(css_tracks(&document));
+        Ok(Self { document, tracks })
+    }
+
+    pub fn is_animated(&self) -> bool {
+        !self.tracks.is_empty()
+    }
+
+    // Seconds until every animation has run once, None when none has a finite duration.
+    // Looping animations are counted once, so loops of the same length repeat seamlessly.
+    pub fn duration(&self) -> Option<f32> {
+        self.tracks.iter()
+            .map(|track| &track.timing)
+            .filter(|timing| timing.duration.is_finite())
+            .map(|timing| {
+                let iterations = if timing.repeat.is_finite() { timing.repeat } else { 1.0 };
+                timing.begin.max(0.0) + timing.duration * iterations
+            })
+            .filter(|end| *end > 0.0)
+            .reduce(f32::max)
+    }
+
+    // The SVG as it looks `time` seconds in, without its animation elements
+    pub fn frame(&self, time: f32) -> String {
+        let mut overrides: HashMap<NodeId, Vec<(String, String)>> = HashMap::new();
+        for track in &self.tracks {
+            let Some(value) = track.value_at(time) else {
+                continue;
+            };
+            let values = overrides.entry(track.target).or_default();
+            let current = values.iter()
+                .position(|(name, _)| *name == track.attribute)
+                .map(|index| values.remove(index).1);
+            let value = match current.or_else(|| self.document.get_node(track.target)?.attribute(track.attribute.as_str()).map(str::to_string)) {
+                Some(base) if track.additive => format!("{} {}", base, value),
+                _ => value,
+            };
+            values.push((track.attribute.clone(), value));
+        }
+
+        let mut output = String::new();
+        write_element(self.document.root_element(), &overrides, &mut output, true);
+        output
+    }
+}
+
+impl Track {
+    fn value_at(&self, time: f32) -> Option<String> {
+        let progress = self.timing.progress(time)?;
+        let value = self.interpolate(progress);
+        Some(match self.origin {
+            Some((x, y)) => format!("translate({} {}) {} translate({} {})",
+                format_number(x), format_number(y), value, format_number(-x), format_number(-y)),
+            None => value,
+        })
+    }
+
+    fn interpolate(&self, progress: f32) -> String {
+        let next = self.keyframes.iter()
+            .position(|keyframe| keyframe.offset > progress)
+            .unwrap_or(self.keyframes.len());
+        if next == 0 {
+            return self.keyframes[0].value.clone();
+        }
+        if next == self.keyframes.len() {
+            return self.keyframes[next - 1].value.clone();
+        }
+
+        let (from, to) = (&self.keyframes[next - 1], &self.keyframes[next]);
+        let t = (progress - from.offset) / (to.offset - from.offset);
+        interpolate(&from.value, &to.value, from.easing.apply(t))
+    }
+}
+
+impl Timing {
+    // How far into its current iteration the animation is at `time`, from 0 to 1,
+    // or None when it doesn't apply at that time
+    fn progress(&self, time: f32) -> Option<f32> {
+        let local = time - self.begin;
+        if local < 0.0 {
+            return self.backwards.then(|| self.directed(0, 0.0));
+        }
+        if !self.duration.is_finite() {
+            return Some(0.0);
+        }
+
+        let elapsed = local / self.duration;
+        if elapsed >= self.repeat {
+            if !self.forwards {
+                return None;
+            }
+            // Stays wherever the last iteration ended, which may be partway through
+            let last = (self.repeat.ceil() - 1.0).max(0.0);
+            return Some(self.directed(last as u32, self.repeat - last));
+        }
+        let iteration = elapsed.floor();
+        Some(self.directed(iteration as u32, elapsed - iteration))
+    }
+
+    fn directed(&self, iteration: u32, progress: f32) -> f32 {
+        if self.reverse != (self.alternate && iteration % 2 == 1) {
+            1.0 - progress
+        } else {
+            progress
+        }
+    }
+}
+
+impl Default for Timing {
+    fn default() -> Self {
+        Self {
+            begin: 0.0,
+            duration: 0.0,
+            repeat: 1.0,
+            forwards: false,
+            backwards: false,
+            alternate: false,
+            reverse: false,
+        }
+    }
+}
+
+impl Easing {
+    fn apply(self, t: f32) -> f32 {
+        match self {
+            Easing::Linear => t,
+            Easing::Discrete => 0.0,
+            Easing::Steps(steps, start) => {
+                let steps = steps.max(1) as f32;
+                let step = (t * steps).floor() + if start { 1.0 } else { 0.0 };
+                (step / steps).min(1.0)
+            },
+            Easing::CubicBezier(x1, y1, x2, y2) => {
+                let bezier = |a: f32, b: f32, s: f32| 3.0 * a * s * (1.0 - s).powi(2) + 3.0 * b * s.powi(2) * (1.0 - s) + s.powi(3);
+                // The curve's x grows with its parameter, so bisect for the one at t
+                let (mut low, mut high) = (0.0, 1.0);
+                for _ in 0..24 {
+                    let middle = (low + high) / 2.0;
+                    if bezier(x1, x2, middle) < t {
+                        low = middle;
+                    } else {
+                        high = middle;
+                    }
+                }
+                bezier(y1, y2, (low + high) / 2.0)
+            },
+        }
+    }
+}
+
+fn smil_tracks(document: &Document) -> Vec<Track> {
+    let mut tracks = Vec::new();
+    for node in document.descendants().filter(|node| node.is_element()) {
+        let name = node.tag_name().name();
+        if !matches!(name, "animate" | "animateColor" | "animateTransform" | "set") {
+            continue;
+        }
+        match smil_track(document, node) {
+            Some(track) => tracks.push(track),
+            None => log::debug!("Skipping unsupported <{}> animation", name),
+        }
+    }
+    tracks
+}
+
+fn smil_track(document: &Document, node: Node) -> Option<Track> {
+    let target = match node.attribute((XLINK_NS, "href")).or_else(|| node.attribute("href")) {
+        Some(href) => {
+            let id = href.strip_prefix('#')?;
+            document.descendants().find(|element| element.attribute("id") == Some(id))?
+        },
+        None => node.parent_element()?,
+    };
+    let attribute = node.attribute("attributeName")?.to_string();
+
+    // Only the first of several begin times, and never event-based ones
+    let begin = match node.attribute("begin") {
+        Some(begin) => clock_value(begin.split(';').next()?)?,
+        None => 0.0,
+    };
+    let duration = match node.attribute("dur").map(str::trim) {
+        None | Some("indefinite") => f32::INFINITY,
+        Some(duration) => clock_value(duration).filter(|duration| *duration > 0.0)?,
+    };
+    let repeat = match (node.attribute("repeatCount").map(str::trim), node.attribute("repeatDur").map(str::trim)) {
+        (Some("indefinite"), _) | (_, Some("indefinite")) => f32::INFINITY,
+        (Some(count), _) => count.parse::<f32>().ok().filter(|count| *count > 0.0)?,
+        (None, Some(repeat_duration)) => clock_value(repeat_duration)? / duration,
+        (None, None) => 1.0,
+    };
+    let timing = Timing {
+        begin,
+        duration,
+        repeat,
+        forwards: node.attribute("fill") == Some("freeze"),
+        ..Timing::default()
+    };
+
+    let transform_type = (node.tag_name().name() == "animateTransform").then(|| node.attribute("type").unwrap_or("translate"));
+    let wrap = |value: &str| match transform_type {
+        Some(kind) => format!("{}({})", kind, value.trim()),
+        None => value.trim().to_string(),
+    };
+    let base = || target.attribute(attribute.as_str()).map(str::to_string);
+
+    let values = if node.tag_name().name() == "set" {
+        vec![wrap(node.attribute("to")?)]
+    } else if let Some(values) = node.attribute("values") {
+        values.split(';').filter(|value| !value.trim().is_empty()).map(wrap).collect()
+    } else {
+        // Without `from` the animation starts at the element's own value
+        let from = node.attribute("from").map(wrap).or_else(base)?;
+        let to = match (node.attribute("to"), node.attribute("by")) {
+            (Some(to), _) => wrap(to),
+            (None, Some(by)) => add(&from, &wrap(by))?,
+            (None, None) => return None,
+        };
+        vec![from, to]
+    };
+    if values.is_empty() {
+        return None;
+    }
+
+    let count = values.len();
+    let discrete = node.attribute("calcMode") == Some("discrete");
+    let offsets = match node.attribute("keyTimes") {
+        Some(key_times) => {
+            let offsets = key_times.split(';')
+                .filter(|time| !time.trim().is_empty())
+                .map(|time| time.trim().parse::<f32>().ok())
+                .collect::<Option<Vec<_>>>()?;
+            (offsets.len() == count).then_some(offsets)?
+        },
+        // Discrete values each get an equal share, interpolated ones meet at the end
+        None if discrete => (0..count).map(|i| i as f32 / count as f32).collect(),
+        None => (0..count).map(|i| if count == 1 { 0.0 } else { i as f32 / (count - 1) as f32 }).collect(),
+    };
+    let splines = match (node.attribute("calcMode"), node.attribute("keySplines")) {
+        (Some("spline"), Some(splines)) => splines.split(';')
+            .filter(|spline| !spline.trim().is_empty())
+            .map(|spline| match numbers(spline).as_slice() {
+                [x1, y1, x2, y2] => Easing::CubicBezier(*x1, *y1, *x2, *y2),
+                _ => Easing::Linear,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    let keyframes = values.into_iter()
+        .zip(offsets)
+        .enumerate()
+        .map(|(i, (value, offset))| Keyframe {
+            offset,
+            value,
+            easing: if discrete { Easing::Discrete } else { splines.get(i).copied().unwrap_or(Easing::Linear) },
+        })
+        .collect();
+
+    Some(Track {
+        target: target.id(),
+        attribute,
+        timing,
+        keyframes,
+        additive: transform_type.is_some() && node.attribute("additive") == Some("sum"),
+        origin: None,
+    })
+}
+
+fn css_tracks(document: &Document) -> Vec<Track> {
+    let stylesheet = document.descendants()
+        .filter(|node| node.is_element() && node.tag_name().name() == "style")
+        .flat_map(|node| node.children().filter_map(|child| child.text()))
+        .collect::<String>();
+    let (keyframes, rules) = parse_stylesheet(&strip_comments(&stylesheet));
+    if keyframes.is_empty() {
+        return Vec::new();
+    }
+    let view_box = view_box_size(document.root_element());
+
+    let mut tracks = Vec::new();
+    for node in document.descendants().filter(|node| node.is_element()) {
+        // Rules in source order, then the style attribute. Specificity isn't taken into account.
+        let mut declarations = rules.iter()
+            .filter(|rule| rule.selectors.iter().any(|selector| selector_matches(selector, node)))
+            .flat_map(|rule| rule.declarations.iter().cloned())
+            .collect::<Vec<_>>();
+        if let Some(style) = node.attribute("style") {
+            declarations.extend(parse_declarations(style));
+        }
+
+        let origin = declarations.iter().rev()
+            .find(|(name, _)| name == "transform-origin")
+            .and_then(|(_, value)| transform_origin(value, view_box));
+        for animation in css_animations(&declarations) {
+            if let Some(stops) = keyframes.get(&animation.name) {
+                tracks.extend(animation.tracks(node.id(), stops, origin));
+            }
+        }
+    }
+    tracks
+}
+
+// The animations declared with `animation` and its longhands, in declaration order
+fn css_animations(declarations: &[(String, String)]) -> Vec<CssAnimation> {
+    let mut animations: Vec<CssAnimation> = Vec::new();
+    for (name, value) in declarations {
+        if name == "animation" {
+            animations = split_top_level(value, |c| c == ',').into_iter().map(parse_animation_shorthand).collect();
+            continue;
+        }
+        if !name.starts_with("animation-") {
+            continue;
+        }
+        // Shorter lists repeat to cover every animation. An empty one is invalid and ignored.
+        let items = split_top_level(value, |c| c == ',');
+        if items.is_empty() {
+            continue;
+        }
+        for i in 0..animations.len().max(items.len()) {
+            if animations.len() <= i {
+                animations.push(CssAnimation::default());
+            }
+            set_longhand(&mut animations[i], name, items[i % items.len()]);
+        }
+    }
+    animations.retain(|animation| animation.timing.duration > 0.0 && animation.timing.repeat > 0.0 && animation.name != "none");
+    animations
+}
+
+impl Default for CssAnimation {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            timing: Timing::default(),
+            easing: EASE,
+        }
+    }
+}
+
+impl CssAnimation {
+    fn tracks(&self, target: NodeId, stops: &[(f32, Vec<(String, String)>)], origin: Option<(f32, f32)>) -> Vec<Track> {
+        let mut properties: Vec<&str> = Vec::new();
+        for (_, declarations) in stops {
+            for (name, _) in declarations {
+                if name != "animation-timing-function" && !properties.contains(&name.as_str()) {
+                    properties.push(name);
+                }
+            }
+        }
+
+        properties.into_iter()
+            .filter_map(|property| {
+                let keyframes = stops.iter()
+                    .filter_map(|(offset, declarations)| {
+                        let find = |name: &str| declarations.iter().rev().find(|(declared, _)| declared == name).map(|(_, value)| value.as_str());
+                        let value = find(property)?;
+                        let value = if property == "transform" { svg_transform(value)? } else { value.to_string() };
+                        let easing = find("animation-timing-function").and_then(timing_function).unwrap_or(self.easing);
+                        Some(Keyframe { offset: *offset, value, easing })
+                    })
+                    .collect::<Vec<_>>();
+                (!keyframes.is_empty()).then(|| Track {
+                    target,
+                    attribute: property.to_string(),
+                    timing: self.timing.clone(),
+                    keyframes,
+                    additive: false,
+                    origin: if property == "transform" { origin } else { None },
+                })
+            })
+            .collect()
+    }
+}
+
+// `spin 1s linear infinite`: the first time is the duration, the second the delay
+fn parse_animation_shorthand(value: &str) -> CssAnimation {
+    let mut animation = CssAnimation::default();
+    let mut times = 0;
+    for token in split_top_level(value, char::is_whitespace) {
+        match token {
+            "infinite" => animation.timing.repeat = f32::INFINITY,
+            "normal" | "reverse" | "alternate" | "alternate-reverse" => set_longhand(&mut animation, "animation-direction", token),
+            "none" | "forwards" | "backwards" | "both" => set_longhand(&mut animation, "animation-fill-mode", token),
+            "running" | "paused" => {},
+            token if token.ends_with('s') && clock_value(token).is_some() => {
+                let field = if times == 0 { "animation-duration" } else { "animation-delay" };
+                set_longhand(&mut animation, field, token);
+                times += 1;
+            },
+            token if token.parse::<f32>().is_ok() => set_longhand(&mut animation, "animation-iteration-count", token),
+            token => match timing_function(token) {
+                Some(easing) => animation.easing = easing,
+                None => animation.name = unquote(token).to_string(),
+            },
+        }
+    }
+    animation
+}
+
+fn set_longhand(animation: &mut CssAnimation, property: &str, value: &str) {
+    let value = value.trim();
+    let timing = &mut animation.timing;
+    match property {
+        "animation-name" => animation.name = unquote(value).to_string(),
+        "animation-duration" => timing.duration = clock_value(value).unwrap_or(0.0),
+        "animation-delay" => timing.begin = clock_value(value).unwrap_or(0.0),
+        "animation-iteration-count" => timing.repeat = match value {
+            "infinite" => f32::INFINITY,
+            count => count.parse().unwrap_or(1.0),
+        },
+        "animation-timing-function" => animation.easing = timing_function(value).unwrap_or(EASE),
+        "animation-direction" => {
+            timing.alternate = value.starts_with("alternate");
+            timing.reverse = value.ends_with("reverse");
+        },
+        "animation-fill-mode" => {
+            timing.forwards = matches!(value, "forwards" | "both");
+            timing.backwards = matches!(value, "backwards" | "both");
+        },
+        _ => {},
+    }
+}
+
+fn timing_function(value: &str) -> Option<Easing> {
+    let value = value.trim();
+    let easing = match value {
+        "linear" => Easing::Linear,
+        "ease" => EASE,
+        "ease-in" => Easing::CubicBezier(0.42, 0.0, 1.0, 1.0),
+        "ease-out" => Easing::CubicBezier(0.0, 0.0, 0.58, 1.0),
+        "ease-in-out" => Easing::CubicBezier(0.42, 0.0, 0.58, 1.0),
+        "step-start" => Easing::Steps(1, true),
+        "step-end" => Easing::Steps(1, false),
+        _ => {
+            let (name, arguments) = value.strip_suffix(')')?.split_once('(')?;
+            let arguments = arguments.split(',').map(str::trim).collect::<Vec<_>>();
+            match (name.trim(), arguments.as_slice()) {
+                ("cubic-bezier", [x1, y1, x2, y2]) => Easing::CubicBezier(x1.parse().ok()?, y1.parse().ok()?, x2.parse().ok()?, y2.parse().ok()?),
+                ("steps", [steps]) => Easing::Steps(steps.parse().ok()?, false),
+                ("steps", [steps, position]) => Easing::Steps(steps.parse().ok()?, matches!(*position, "start" | "jump-start")),
+                _ => return None,
+            }
+        },
+    };
+    Some(easing)
+}
+
+// CSS transform functions in SVG syntax, with angles in degrees and lengths in user units
+fn svg_transform(value: &str) -> Option<String> {
+    if value.trim() == "none" {
+        return Some("matrix(1 0 0 1 0 0)".to_string());
+    }
+
+    let mut functions = Vec::new();
+    let mut rest = value.trim();
+    while !rest.is_empty() {
+        let (name, after) = rest.split_once('(')?;
+        let (arguments, after) = after.split_once(')')?;
+        let arguments = arguments.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|argument| !argument.is_empty())
+            .map(css_number)
+            .collect::<Option<Vec<_>>>()?;
+        let arguments = arguments.iter().map(|argument| format_number(*argument)).collect::<Vec<_>>();
+        let function = match (name.trim(), arguments.as_slice()) {
+            ("translate", [x]) | ("translateX", [x]) => format!("translate({} 0)", x),
+            ("translate", [x, y]) => format!("translate({} {})", x, y),
+            ("translateY", [y]) => format!("translate(0 {})", y),
+            ("scale", [s]) => format!("scale({})", s),
+            ("scale", [x, y]) => format!("scale({} {})", x, y),
+            ("scaleX", [x]) => format!("scale({} 1)", x),
+            ("scaleY", [y]) => format!("scale(1 {})", y),
+            ("rotate", [angle]) | ("rotateZ", [angle]) => format!("rotate({})", angle),
+            ("skewX", [angle]) => format!("skewX({})", angle),
+            ("skewY", [angle]) => format!("skewY({})", angle),
+            ("matrix", [a, b, c, d, e, f]) => format!("matrix({} {} {} {} {} {})", a, b, c, d, e, f),
+            _ => return None,
+        };
+        functions.push(function);
+        rest = after.trim_start();
+    }
+    Some(functions.join(" "))
+}
+
+// Angles in degrees and lengths in pixels. Percentages need a reference box, which isn't known here.
+fn css_number(value: &str) -> Option<f32> {
+    let split = value.find(|c: char| c.is_ascii_alphabetic() || c == '%').unwrap_or(value.len());
+    let number = value[..split].parse::<f32>().ok()?;
+    match &value[split..] {
+        "" | "px" | "deg" => Some(number),
+        "rad" => Some(number.to_degrees()),
+        "grad" => Some(number * 0.9),
+        "turn" => Some(number * 360.0),
+        _ => None,
+    }
+}
+
+// transform-origin relative to the viewBox, like CSS's default `transform-box: view-box`
+fn transform_origin(value: &str, (width, height): (f32, f32)) -> Option<(f32, f32)> {
+    let mut parts = value.split_whitespace();
+    let first = parts.next()?;
+    let second = parts.next().unwrap_or("center");
+    // Keywords may come in either order, `top left`
+    let (x, y) = if matches!(first, "top" | "bottom") || matches!(second, "left" | "right") {
+        (second, first)
+    } else {
+        (first, second)
+    };
+    let resolve = |value: &str, size: f32| match value {
+        "left" | "top" => Some(0.0),
+        "center" => Some(size / 2.0),
+        "right" | "bottom" => Some(size),
+        value => match value.strip_suffix('%') {
+            Some(percentage) => percentage.parse::<f32>().ok().map(|percentage| size * percentage / 100.0),
+            None => css_number(value),
+        },
+    };
+    Some((resolve(x, width)?, resolve(y, height)?))
+}
+
+fn view_box_size(root: Node) -> (f32, f32) {
+    if let Some([_, _, width, height]) = root.attribute("viewBox").map(numbers).as_deref() {
+        return (*width, *height);
+    }
+    let length = |name: &str| root.attribute(name).and_then(css_number);
+    (length("width").unwrap_or(100.0), length("height").unwrap_or(100.0))
+}
+
+// Style rules and @keyframes blocks. Other at-rules are skipped along with their contents.
+fn parse_stylesheet(css: &str) -> (Keyframes, Vec<Rule>) {
+    let mut keyframes = Keyframes::new();
+    let mut rules = Vec::new();
+    for (prelude, block) in blocks(css) {
+        if let Some(name) = prelude.strip_prefix("@keyframes").or_else(|| prelude.strip_prefix("@-webkit-keyframes")) {
+            keyframes.insert(unquote(name.trim()).to_string(), parse_keyframes(block));
+        } else if !prelude.starts_with('@') {
+            rules.push(Rule {
+                selectors: prelude.split(',').map(|selector| selector.trim().to_string()).collect(),
+                declarations: parse_declarations(block),
+            });
+        }
+    }
+    (keyframes, rules)
+}
+
+fn parse_keyframes(block: &str) -> Vec<(f32, Vec<(String, String)>)> {
+    let mut stops = Vec::new();
+    for (selectors, declarations) in blocks(block) {
+        let declarations = parse_declarations(declarations);
+        for selector in selectors.split(',').map(str::trim) {
+            let offset = match selector {
+                "from" => Some(0.0),
+                "to" => Some(1.0),
+                selector => selector.strip_suffix('%').and_then(|percentage| percentage.trim().parse::<f32>().ok()).map(|percentage| percentage / 100.0),
+            };
+            if let Some(offset) = offset {
+                stops.push((offset, declarations.clone()));
+            }
+        }
+    }
+    stops.sort_by(|(a, _), (b, _)| a.total_cmp(b));
+    stops
+}
+
+// Every top-level `prelude { block }`, with the preludes trimmed
+fn blocks(css: &str) -> Vec<(&str, &str)> {
+    let mut blocks = Vec::new();
+    let mut rest = css;
+    while let Some(open) = rest.find('{') {
+        let mut depth = 0;
+        let Some(close) = rest[open..].find(|c| {
+            depth += match c { '{' => 1, '}' => -1, _ => 0 };
+            depth == 0
+        }) else {
+            break;
+        };
+        // Statements like @import end with a semicolon before the next prelude
+        let prelude = rest[..open].rsplit(';').next().unwrap_or_default().trim();
+        blocks.push((prelude, &rest[open + 1..open + close]));
+        rest = &rest[open + close + 1..];
+    }
+    blocks
+}
+
+fn parse_declarations(block: &str) -> Vec<(String, String)> {
+    block.split(';')
+        .filter_map(|declaration| declaration.split_once(':'))
+        .map(|(name, value)| {
+            let value = value.trim();
+            (name.trim().to_ascii_lowercase(), value.strip_suffix("!important").unwrap_or(value).trim().to_string())
+        })
+        .collect()
+}
+
+fn strip_comments(css: &str) -> String {
+    let mut output = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("/*") {
+        output.push_str(&rest[..start]);
+        rest = rest[start + 2..].split_once("*/").map_or("", |(_, after)| after);
+    }
+    output.push_str(rest);
+    output
+}
+
+// Tag names, classes and ids, like `circle.dot#first`, optionally combined with
+// descendant combinators. Selectors with anything else never match.
+fn selector_matches(selector: &str, node: Node) -> bool {
+    let mut compounds = selector.split_whitespace().rev();
+    let Some(last) = compounds.next() else {
+        return false;
+    };
+    if !compound_matches(last, node) {
+        return false;
+    }
+    let mut ancestors = node.ancestors().skip(1).filter(|ancestor| ancestor.is_element());
+    compounds.all(|compound| ancestors.any(|ancestor| compound_matches(compound, ancestor)))
+}
+
+fn compound_matches(compound: &str, node: Node) -> bool {
+    if compound.contains([':', '[', '>', '+', '~']) {
+        return false;
+    }
+    let tag_end = compound.find(['.', '#']).unwrap_or(compound.len());
+    let tag = &compound[..tag_end];
+    if !tag.is_empty() && tag != "*" && tag != node.tag_name().name() {
+        return false;
+    }
+
+    let mut rest = &compound[tag_end..];
+    while let Some(kind) = rest.chars().next() {
+        let end = rest[1..].find(['.', '#']).map_or(rest.len(), |end| end + 1);
+        let name = &rest[1..end];
+        let matched = match kind {
+            '.' => node.attribute("class").unwrap_or_default().split_whitespace().any(|class| class == name),
+            _ => node.attribute("id") == Some(name),
+        };
+        if name.is_empty() || !matched {
+            return false;
+        }
+        rest = &rest[end..];
+    }
+    true
+}
+
+// Splits where `separator` matches outside of parentheses, dropping empty parts
+fn split_top_level(value: &str, separator: impl Fn(char) -> bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut start) = (0, 0);
+    for (i, c) in value.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            c if depth == 0 && separator(c) => {
+                parts.push(value[start..i].trim());
+                start = i + c.len_utf8();
+            },
+            _ => {},
+        }
+    }
+    parts.push(value[start..].trim());
+    parts.retain(|part| !part.is_empty());
+    parts
+}
+
+fn unquote(value: &str) -> &str {
+    value.trim_matches(['"', '\''])
+}
+
+// SMIL and CSS times: `2s`, `150ms`, `1.5min`, a bare number of seconds, or `00:01.5`
+fn clock_value(value: &str) -> Option<f32> {
+    let value = value.trim();
+    let (number, scale) = if let Some(number) = value.strip_suffix("ms") {
+        (number, 0.001)
+    } else if let Some(number) = value.strip_suffix("min") {
+        (number, 60.0)
+    } else if let Some(number) = value.strip_suffix('h') {
+        (number, 3600.0)
+    } else if let Some(number) = value.strip_suffix('s') {
+        (number, 1.0)
+    } else if value.contains(':') {
+        return value.split(':').try_fold(0.0, |total: f32, part| Some(total * 60.0 + part.parse::<f32>().ok()?));
+    } else {
+        (value, 1.0)
+    };
+    number.trim().parse::<f32>().ok().filter(|number| number.is_finite()).map(|number| number * scale)
+}
+
+fn numbers(value: &str) -> Vec<f32> {
+    value.split(|c: char| c == ',' || c.is_whitespace())
+        .filter_map(|number| number.parse().ok())
+        .collect()
+}
+
+// Interpolates between colors, or between values with numbers in the same places,
+// like lengths, transforms and paths with the same commands. Anything else
+// switches halfway.
+fn interpolate(from: &str, to: &str, t: f32) -> String {
+    if let (Ok(from), Ok(to)) = (svgtypes::Color::from_str(from), svgtypes::Color::from_str(to)) {
+        let channel = |a: u8, b: u8| (a as f32 + (b as f32 - a as f32) * t).round() as u8;
+        let (red, green, blue) = (channel(from.red, to.red), channel(from.green, to.green), channel(from.blue, to.blue));
+        return match channel(from.alpha, to.alpha) {
+            255 => format!("#{:02x}{:02x}{:02x}", red, green, blue),
+            alpha => format!("rgba({}, {}, {}, {})", red, green, blue, format_number(alpha as f32 / 255.0)),
+        };
+    }
+
+    let (from_parts, from_numbers) = split_numbers(from);
+    let (to_parts, to_numbers) = split_numbers(to);
+    let separators = |c: char| c == ',' || c.is_whitespace();
+    let same_shape = from_numbers.len() == to_numbers.len()
+        && from_parts.iter().zip(&to_parts).all(|(a, b)| a.trim_matches(separators) == b.trim_matches(separators));
+    if !same_shape {
+        return if t < 0.5 { from } else { to }.to_string();
+    }
+
+    let mut output = String::with_capacity(from.len());
+    for (i, part) in from_parts.iter().enumerate() {
+        output.push_str(part);
+        if let (Some(a), Some(b)) = (from_numbers.get(i), to_numbers.get(i)) {
+            // Unchanged numbers keep their spelling, they may be part of a name
+            if a == b {
+                output.push_str(a);
+            } else {
+                let (a, b) = (a.parse::<f32>().unwrap_or_default(), b.parse::<f32>().unwrap_or_default());
+                output.push_str(&format_number(a + (b - a) * t));
+            }
+        }
+    }
+    output
+}
+
+// `from` with the numbers in `by` added to its own, for by-animations
+fn add(from: &str, by: &str) -> Option<String> {
+    let (parts, from_numbers) = split_numbers(from);
+    let (_, by_numbers) = split_numbers(by);
+    if from_numbers.len() != by_numbers.len() {
+        return None;
+    }
+    let mut output = String::new();
+    for (i, part) in parts.iter().enumerate() {
+        output.push_str(part);
+        if let (Some(a), Some(b)) = (from_numbers.get(i), by_numbers.get(i)) {
+            output.push_str(&format_number(a.parse::<f32>().ok()? + b.parse::<f32>().ok()?));
+        }
+    }
+    Some(output)
+}
+
+// The text around the numbers in a value, and the numbers, which always have text
+// (possibly empty) on both sides
+fn split_numbers(value: &str) -> (Vec<&str>, Vec<&str>) {
+    let bytes = value.as_bytes();
+    let digit = |i: usize| bytes.get(i).is_some_and(u8::is_ascii_digit);
+    let (mut parts, mut numbers) = (Vec::new(), Vec::new());
+    let (mut start, mut i) = (0, 0);
+    while i < bytes.len() {
+        let first = i + matches!(bytes[i], b'-' | b'+') as usize;
+        if !(digit(first) || (bytes.get(first) == Some(&b'.') && digit(first + 1))) {
+            i += 1;
+            continue;
+        }
+        let mut end = first;
+        while digit(end) {
+            end += 1;
+        }
+        if bytes.get(end) == Some(&b'.') && digit(end + 1) {
+            end += 1;
+            while digit(end) {
+                end += 1;
+            }
+        }
+        parts.push(&value[start..i]);
+        numbers.push(&value[i..end]);
+        (start, i) = (end, end);
+    }
+    parts.push(&value[start..]);
+    (parts, numbers)
+}
+
+fn format_number(value: f32) -> String {
+    let rounded = (value * 1000.0).round() / 1000.0;
+    if rounded == 0.0 { "0".to_string() } else { rounded.to_string() }
+}
+
+// Writes the element without animation elements and with the animated values in place
+fn write_element(node: Node, overrides: &HashMap<NodeId, Vec<(String, String)>>, output: &mut String, is_root: bool) {
+    let name = node.tag_name().name();
+    output.push('<');
+    output.push_str(name);
+    if is_root {
+        output.push_str(&format!(" xmlns=\"{}\" xmlns:xlink=\"{}\"", SVG_NS, XLINK_NS));
+    }
+
+    let values = overrides.get(&node.id()).map(Vec::as_slice).unwrap_or_default();
+    let (styled, attributes): (Vec<_>, Vec<_>) = values.iter()
+        .partition(|(name, _)| PRESENTATION_ATTRIBUTES.contains(&name.as_str()));
+
+    for attribute in node.attributes() {
+        let prefix = match attribute.namespace() {
+            None => "",
+            Some(XLINK_NS) => "xlink:",
+            Some(XML_NS) => "xml:",
+            Some(_) => continue,
+        };
+        let local = attribute.name();
+        let replaced = prefix.is_empty()
+            && ((local == "style" && !styled.is_empty()) || attributes.iter().any(|(name, _)| name == local));
+        if !replaced {
+            output.push_str(&format!(" {}{}=\"{}\"", prefix, local, escape(attribute.value())));
+        }
+    }
+    for (name, value) in &attributes {
+        output.push_str(&format!(" {}=\"{}\"", name, escape(value)));
+    }
+    if !styled.is_empty() {
+        let mut style = node.attribute("style")
+            .map(parse_declarations)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(name, _)| !styled.iter().any(|(styled, _)| styled == name))
+            .map(|(name, value)| format!("{}:{}", name, value))
+            .collect::<Vec<_>>();
+        style.extend(styled.iter().map(|(name, value)| format!("{}:{}", name, value)));
+        output.push_str(&format!(" style=\"{}\"", escape(&style.join(";"))));
+    }
+    output.push('>');
+
+    for child in node.children() {
+        if child.is_text() {
+            output.push_str(&escape(child.text().unwrap_or_default()));
+        } else if child.is_element()
+            && matches!(child.tag_name().namespace(), None | Some(SVG_NS))
+            && !ANIMATION_ELEMENTS.contains(&child.tag_name().name())
+        {
+            write_element(child, overrides, output, false);
+        }
+    }
+
+    output.push_str(&format!("</{}>", name));
+}
+
+fn escape(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: f32 = 1e-3;
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < EPSILON, "{} != {}", actual, expected);
+    }
+
+    fn svg(body: &str) -> String {
+        format!(r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 100 100">{}</svg>"#, body)
+    }
+
+    // The value of `attribute` on the element with id `id`, `time` seconds in
+    fn value_at(svg: &str, id: &str, attribute: &str, time: f32) -> Option<String> {
+        let frame = Animation::parse(svg).unwrap().frame(time);
+        let document = Document::parse(&frame).unwrap();
+        let node = document.descendants().find(|node| node.attribute("id") == Some(id))?;
+        node.attribute(attribute).map(str::to_string).or_else(|| {
+            parse_declarations(node.attribute("style")?).into_iter().find(|(name, _)| name == attribute).map(|(_, value)| value)
+        })
+    }
+
+    #[test]
+    fn times_iterations_and_delays() {
+        let timing = Timing { begin: 1.0, duration: 2.0, repeat: 2.0, ..Timing::default() };
+        assert_eq!(timing.progress(0.5), None);
+        assert_close(timing.progress(2.0).unwrap(), 0.5);
+        assert_close(timing.progress(4.5).unwrap(), 0.75);
+        assert_eq!(timing.progress(5.0), None);
+
+        let indefinite = Timing { duration: f32::INFINITY, ..Timing::default() };
+        assert_eq!(indefinite.progress(100.0), Some(0.0));
+    }
+
+    #[test]
+    fn fills_and_directions() {
+        let forwards = Timing { duration: 1.0, repeat: 1.5, forwards: true, ..Timing::default() };
+        assert_close(forwards.progress(10.0).unwrap(), 0.5);
+
+        let backwards = Timing { begin: 1.0, duration: 1.0, backwards: true, reverse: true, ..Timing::default() };
+        assert_eq!(backwards.progress(0.0), Some(1.0));
+
+        let alternate = Timing { duration: 1.0, repeat: f32::INFINITY, alternate: true, ..Timing::default() };
+        assert_close(alternate.progress(0.25).unwrap(), 0.25);
+        assert_close(alternate.progress(1.25).unwrap(), 0.75);
+
+        let alternate_reverse = Timing { reverse: true, ..alternate };
+        assert_close(alternate_reverse.progress(0.25).unwrap(), 0.75);
+        assert_close(alternate_reverse.progress(1.25).unwrap(), 0.25);
+    }
+
+    #[test]
+    fn applies_easings() {
+        assert_close(Easing::Linear.apply(0.3), 0.3);
+        assert_eq!(Easing::Discrete.apply(0.9), 0.0);
+        assert_close(Easing::Steps(4, false).apply(0.3), 0.25);
+        assert_close(Easing::Steps(4, true).apply(0.3), 0.5);
+        assert_close(Easing::Steps(4, true).apply(1.0), 1.0);
+
+        let ease_in_out = timing_function("ease-in-out").unwrap();
+        assert_close(ease_in_out.apply(0.0), 0.0);
+        assert_close(ease_in_out.apply(0.5), 0.5);
+        assert_close(ease_in_out.apply(1.0), 1.0);
+        assert!(ease_in_out.apply(0.25) < 0.25);
+        assert!(EASE.apply(0.25) > 0.25);
+        assert_close(timing_function("cubic-bezier(0, 0, 1, 1)").unwrap().apply(0.7), 0.7);
+        assert!(matches!(timing_function("steps(3, jump-start)"), Some(Easing::Steps(3, true))));
+        assert!(timing_function("bounce").is_none());
+    }
+
+    #[test]
+    fn parses_clock_values() {
+        assert_eq!(clock_value("2s"), Some(2.0));
+        assert_eq!(clock_value("150ms"), Some(0.15));
+        assert_eq!(clock_value("1.5min"), Some(90.0));
+        assert_eq!(clock_value("1h"), Some(3600.0));
+        assert_eq!(clock_value(" 3 "), Some(3.0));
+        assert_eq!(clock_value("01:02.5"), Some(62.5));
+        assert_eq!(clock_value("0:01:00"), Some(60.0));
+        assert_eq!(clock_value("click"), None);
+        assert_eq!(clock_value("infs"), None);
+    }
+
+    #[test]
+    fn splits_and_interpolates_numbers() {
+        assert_eq!(split_numbers("M10,-2.5 L.5 3"), (vec!["M", ",", " L", " ", ""], vec!["10", "-2.5", ".5", "3"]));
+        assert_eq!(split_numbers("none"), (vec!["none"], vec![]));
+        assert_eq!(interpolate("translate(0 10)", "translate(10 20)", 0.5), "translate(5 15)");
+        assert_eq!(interpolate("#000000", "#ffffff", 0.5), "#808080");
+        assert_eq!(interpolate("visible", "hidden", 0.4), "visible");
+        assert_eq!(interpolate("M0 0 L1 1", "M0 0 C1 1 2 2 3 3", 0.6), "M0 0 C1 1 2 2 3 3");
+        assert_eq!(add("rotate(10 50 50)", "rotate(80 0 0)").as_deref(), Some("rotate(90 50 50)"));
+        assert_eq!(add("rotate(10)", "rotate(80 0 0)"), None);
+    }
+
+    #[test]
+    fn animates_from_to_and_by_values() {
+        let svg = svg(r#"
+            <rect id="to" width="10"><animate attributeName="width" from="10" to="30" dur="2s"/></rect>
+            <rect id="by" width="10"><animate attributeName="width" by="20" dur="2s"/></rect>
+            <rect id="set" width="10"><set attributeName="width" to="50" begin="1s" dur="1s" fill="freeze"/></rect>
+        "#);
+        assert_eq!(value_at(&svg, "to", "width", 1.0).as_deref(), Some("20"));
+        assert_eq!(value_at(&svg, "by", "width", 1.5).as_deref(), Some("25"));
+        assert_eq!(value_at(&svg, "set", "width", 0.5).as_deref(), Some("10"));
+        assert_eq!(value_at(&svg, "set", "width", 5.0).as_deref(), Some("50"));
+        // Without fill="freeze" the element goes back to its own value
+        assert_eq!(value_at(&svg, "to", "width", 3.0).as_deref(), Some("10"));
+    }
+
+    #[test]
+    fn follows_key_times_and_discrete_values() {
+        let svg = svg(r#"
+            <rect id="timed" x="0"><animate attributeName="x" values="0;10;100" keyTimes="0;0.8;1" dur="10s"/></rect>
+            <rect id="discrete" x="0"><animate attributeName="x" values="1;2;3;4" calcMode="discrete" dur="4s"/></rect>
+            <rect id="mismatched" x="7"><animate attributeName="x" values="0;10" keyTimes="0;0.5;1" dur="1s"/></rect>
+        "#);
+        assert_eq!(value_at(&svg, "timed", "x", 4.0).as_deref(), Some("5"));
+        assert_eq!(value_at(&svg, "timed", "x", 9.0).as_deref(), Some("55"));
+        assert_eq!(value_at(&svg, "discrete", "x", 2.5).as_deref(), Some("3"));
+        assert_eq!(value_at(&svg, "mismatched", "x", 0.5).as_deref(), Some("7"));
+    }
+
+    #[test]
+    fn animates_css_keyframes() {
+        let svg = svg(r#"
+            <style>@keyframes grow { from { opacity: 0 } to { opacity: 1 } } .dot { animation: grow 2s linear infinite }</style>
+            <circle id="dot" class="dot" r="5"/>
+        "#);
+        let animation = Animation::parse(&svg).unwrap();
+        assert!(animation.is_animated());
+        assert_eq!(animation.duration(), Some(2.0));
+        assert_eq!(value_at(&svg, "dot", "opacity", 0.5).as_deref(), Some("0.25"));
+        assert_eq!(value_at(&svg, "dot", "opacity", 2.5).as_deref(), Some("0.25"));
+    }
+
+    #[test]
+    fn ignores_empty_animation_longhands() {
+        let animations = css_animations(&[
+            ("animation".to_string(), "spin 1s".to_string()),
+            ("animation-delay".to_string(), String::new()),
+        ]);
+        assert_eq!(animations.len(), 1);
+        assert_eq!(animations[0].name, "spin");
+        assert_eq!(animations[0].timing.begin, 0.0);
+
+        let svg = svg(r#"<style>@keyframes spin { to { opacity: 0 } }</style><rect id="r" style="animation: spin 1s; animation-delay:"/>"#);
+        assert!(Animation::parse(&svg).unwrap().is_animated());
+    }
+
+    #[test]
+    fn repeats_shorter_longhand_lists() {
+        let animations = css_animations(&[
+            ("animation-name".to_string(), "a, b, c".to_string()),
+            ("animation-duration".to_string(), "1s, 2s".to_string()),
+        ]);
+        let durations = animations.iter().map(|animation| animation.timing.duration).collect::<Vec<_>>();
+        assert_eq!(durations, [1.0, 2.0, 1.0]);
+    }
+
+    #[test]
+    fn matches_simple_selectors() {
+        let document = Document::parse(r#"<svg xmlns="http://www.w3.org/2000/svg"><g class="icons"><circle id="first" class="dot big"/></g></svg>"#).unwrap();
+        let circle = document.descendants().find(|node| node.has_tag_name("circle")).unwrap();
+        for selector in ["circle", "*", ".dot", "circle.dot.big#first", "#first", "g circle", "svg .icons .dot"] {
+            assert!(selector_matches(selector, circle), "{}", selector);
+        }
+        for selector in ["rect", ".small", "circle#second", "g > circle", "circle:hover", "rect circle", ""] {
+            assert!(!selector_matches(selector, circle), "{}", selector);
+        }
+    }
+
+    #[test]
+    fn writes_frames_without_animation_elements() {
+        let svg = r##"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" xmlns:x="urn:other" x:data="1"><rect id="r" style="fill:red;stroke:blue" width="5"><animate attributeName="fill" values="#000000;#ffffff" dur="1s"/></rect><text>a &lt; b</text><use xlink:href="#r"/></svg>"##;
+        let frame = Animation::parse(svg).unwrap().frame(0.5);
+        assert!(!frame.contains("<animate"));
+        assert!(!frame.contains("urn:other") && !frame.contains("x:data"));
+        assert!(frame.contains(r#"style="stroke:blue;fill:#808080""#), "{}", frame);
+        assert!(frame.contains("a &lt; b"));
+        assert!(frame.contains(r##"xlink:href="#r""##));
+        assert!(Document::parse(&frame).is_ok());
+    }
+}
@@ -0,0 +1,65 @@
+use futures::StreamExt;
+
+use crate::error::{Error, Result};
+
+// Sources larger than this are refused
+pub const MAX_SVG_SIZE: usize = 1024 * 1024; // 1MB
+
+// Rejects documents that aren't SVG or that carry script, for fetched and local files alike
+pub fn check_content(text: &str) -> Result<()> {
+    // Basic SVG validation
+    if !text.contains("<svg") {
+        return Err(Error::InvalidContent("Response does not contain SVG content".to_string()));
+    }
+
+    // Additional SVG validation
+    if text.contains("<script") || text.contains("javascript:") {
+        return Err(Error::InvalidContent("SVG contains potentially unsafe content".to_string()));
+    }
+
+    Ok(())
+}
+
+pub fn check_size(size: usize, max_size: usize) -> Result<()> {
+    if size > max_size {
+        return Err(Error::InvalidContent(format!("SVG file too large: {} bytes (max {})", size, max_size)));
+    }
+    Ok(())
+}
+
+// Reads and checks the SVG in a successful response, streamed with a size limit for
+// chunked responses and servers that send more than they announced. `on_chunk` sees
+// the size of every chunk received, e.g. for byte counters.
+pub async fn read_svg(response: reqwest::Response, max_size: usize, mut on_chunk: impl FnMut(usize)) -> Result<String> {
+    // Refuse oversized sources before downloading anything, when the size is announced
+    if let Some(size) = response.content_length() {
+        check_size(usize::try_from(size).unwrap_or(usize::MAX), max_size)?;
+    }
+
+    let content_type = response.headers()
+        .get("content-type")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("");
+    log::debug!("Response content-type: {}", content_type);
+
+    let mut total_size = 0;
+    let mut bytes = Vec::new();
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        total_size += chunk.len();
+        on_chunk(chunk.len());
+
+        // Check running total against limit
+        if total_size > max_size {
+            return Err(Error::InvalidContent(format!("SVG file too large: exceeded {} bytes", max_size)));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+
+    let text = String::from_utf8(bytes)
+        .map_err(|e| Error::Parse(format!("Invalid UTF-8 content: {}", e)))?;
+    check_content(&text)?;
+
+    Ok(text)
+}
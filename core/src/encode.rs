@@ -0,0 +1,270 @@
+use resvg::tiny_skia::Pixmap;
+
+use crate::error::{Error, Result};
+
+const WEBP_QUALITY: f32 = 80.0;
+const JPEG_QUALITY: u8 = 85;
+const AVIF_QUALITY: u8 = 70;
+// 1 is the slowest and smallest, 10 the fastest. 6 keeps a render within a request's time.
+const AVIF_SPEED: u8 = 6;
+// Lossless WebP reads quality as how hard to try compressing, 75 is libwebp's default
+const WEBP_LOSSLESS_EFFORT: f32 = 75.0;
+// 1 is the best palette and slowest, 30 the fastest. 10 is gif's own default.
+const GIF_QUANTIZER_SPEED: i32 = 10;
+
+// Encoder settings, the defaults for whatever is left out
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EncodeOptions {
+    // 1-100 for the lossy formats, JPEG, WebP and AVIF. PNG and GIF are lossless and ignore it.
+    pub quality: Option<u8>,
+    // Lossless WebP, usually smaller and sharper for flat-color vector art. Quality
+    // doesn't apply then.
+    pub lossless: bool,
+}
+
+impl EncodeOptions {
+    fn webp_quality(&self) -> f32 {
+        if self.lossless {
+            return WEBP_LOSSLESS_EFFORT;
+        }
+        self.quality.map_or(WEBP_QUALITY, |quality| f32::from(quality.clamp(1, 100)))
+    }
+
+    fn jpeg_quality(&self) -> u8 {
+        self.quality.map_or(JPEG_QUALITY, |quality| quality.clamp(1, 100))
+    }
+
+    fn avif_quality(&self) -> f32 {
+        f32::from(self.quality.map_or(AVIF_QUALITY, |quality| quality.clamp(1, 100)))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    Webp,
+    Jpeg,
+    Gif,
+    Avif,
+}
+
+impl ImageFormat {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "png" => Some(ImageFormat::Png),
+            "webp" => Some(ImageFormat::Webp),
+            "jpeg" | "jpg" => Some(ImageFormat::Jpeg),
+            "gif" => Some(ImageFormat::Gif),
+            "avif" => Some(ImageFormat::Avif),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Webp => "image/webp",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::Avif => "image/avif",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Webp => "webp",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Avif => "avif",
+        }
+    }
+
+    // The format an output file name asks for, e.g. `icon.webp`
+    pub fn from_path(path: &std::path::Path) -> Option<Self> {
+        path.extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(Self::parse)
+    }
+}
+
+// Encodes a rendered pixmap. Deterministic PNGs use fixed encoder settings, JPEG has
+// no alpha channel so transparent areas come out white.
+pub fn encode(pixmap: &Pixmap, format: ImageFormat, deterministic: bool) -> Result<Vec<u8>> {
+    encode_with_options(pixmap, format, deterministic, EncodeOptions::default())
+}
+
+pub fn encode_with_options(pixmap: &Pixmap, format: ImageFormat, deterministic: bool, options: EncodeOptions) -> Result<Vec<u8>> {
+    match format {
+        ImageFormat::Png => encode_png(pixmap, deterministic),
+        ImageFormat::Webp => {
+            let rgba = demultiplied(pixmap, |[r, g, b, a]| [r, g, b, a]);
+            let encoded = webp::Encoder::from_rgba(&rgba, pixmap.width(), pixmap.height())
+                .encode_simple(options.lossless, options.webp_quality())
+                .map_err(|e| Error::Render(format!("Failed to encode WebP: {:?}", e)))?;
+            Ok(encoded.to_vec())
+        },
+        ImageFormat::Jpeg => {
+            let (width, height) = size_16(pixmap, "JPEG")?;
+            let rgb = demultiplied(pixmap, |[r, g, b, a]| {
+                let over_white = |channel: u8| (channel as u16 * a as u16 / 255 + (255 - a) as u16) as u8;
+                [over_white(r), over_white(g), over_white(b)]
+            });
+            let mut jpeg_data = Vec::new();
+            jpeg_encoder::Encoder::new(&mut jpeg_data, options.jpeg_quality())
+                .encode(&rgb, width, height, jpeg_encoder::ColorType::Rgb)
+                .map_err(|e| Error::Render(format!("Failed to encode JPEG: {}", e)))?;
+            Ok(jpeg_data)
+        },
+        ImageFormat::Gif => encode_gif(std::slice::from_ref(pixmap), 0),
+        ImageFormat::Avif => encode_avif(pixmap, options),
+    }
+}
+
+// Encodes frames shown `delay_ms` apart as an animation that loops forever: an
+// animated WebP, an APNG for PNG, or a GIF. JPEG has no animation.
+pub fn encode_animation(frames: &[Pixmap], delay_ms: u32, format: ImageFormat, options: EncodeOptions) -> Result<Vec<u8>> {
+    let Some(first) = frames.first() else {
+        return Err(Error::Render("An animation needs at least one frame".to_string()));
+    };
+    let (width, height) = (first.width(), first.height());
+
+    match format {
+        ImageFormat::Png => {
+            let encode_error = |e: png::EncodingError| Error::Render(format!("Failed to encode APNG: {}", e));
+            let mut png_data = Vec::new();
+            {
+                let mut encoder = png::Encoder::new(&mut png_data, width, height);
+                encoder.set_color(png::ColorType::Rgba);
+                encoder.set_depth(png::BitDepth::Eight);
+                encoder.set_animated(frames.len() as u32, 0).map_err(encode_error)?;
+                encoder.set_frame_delay(delay_ms.min(u16::MAX as u32) as u16, 1000).map_err(encode_error)?;
+
+                let mut writer = encoder.write_header().map_err(encode_error)?;
+                for frame in frames {
+                    writer.write_image_data(&demultiplied(frame, |[r, g, b, a]| [r, g, b, a])).map_err(encode_error)?;
+                }
+                writer.finish().map_err(encode_error)?;
+            }
+            Ok(png_data)
+        },
+        ImageFormat::Webp => {
+            let rgba = frames.iter().map(|frame| demultiplied(frame, |[r, g, b, a]| [r, g, b, a])).collect::<Vec<_>>();
+            let mut config = webp::WebPConfig::new()
+                .map_err(|_| Error::Render("Failed to set up the WebP encoder".to_string()))?;
+            config.lossless = options.lossless.into();
+            config.quality = options.webp_quality();
+            let mut encoder = webp::AnimEncoder::new(width, height, &config);
+            encoder.set_loop_count(0);
+            for (i, data) in rgba.iter().enumerate() {
+                encoder.add_frame(webp::AnimFrame::from_rgba(data, width, height, (i as u32 * delay_ms) as i32));
+            }
+            let encoded = encoder.try_encode()
+                .map_err(|e| Error::Render(format!("Failed to encode animated WebP: {:?}", e)))?;
+            Ok(encoded.to_vec())
+        },
+        ImageFormat::Gif => encode_gif(frames, delay_ms),
+        ImageFormat::Jpeg | ImageFormat::Avif => Err(Error::InvalidContent(format!(
+            "{} can't be animated, use png, webp or gif", format.extension().to_uppercase()))),
+    }
+}
+
+// AVIF is lossy here, `lossless` only applies to WebP. Transparent pixels get their
+// colors smoothed out, which they don't show but would otherwise cost bytes.
+fn encode_avif(pixmap: &Pixmap, options: EncodeOptions) -> Result<Vec<u8>> {
+    let rgba = pixmap.pixels().iter()
+        .map(|pixel| {
+            let color = pixel.demultiply();
+            ravif::RGBA8::new(color.red(), color.green(), color.blue(), color.alpha())
+        })
+        .collect::<Vec<_>>();
+    let image = ravif::Img::new(rgba.as_slice(), pixmap.width() as usize, pixmap.height() as usize);
+    let encoded = ravif::Encoder::new()
+        .with_quality(options.avif_quality())
+        .with_alpha_quality(options.avif_quality())
+        .with_speed(AVIF_SPEED)
+        .with_alpha_color_mode(ravif::AlphaColorMode::UnassociatedClean)
+        .encode_rgba(image)
+        .map_err(|e| Error::Render(format!("Failed to encode AVIF: {}", e)))?;
+    Ok(encoded.avif_file)
+}
+
+// GIFs have a palette of 256 colors per frame and on/off transparency, so edges
+// against transparent areas come out jagged
+fn encode_gif(frames: &[Pixmap], delay_ms: u32) -> Result<Vec<u8>> {
+    let (width, height) = size_16(&frames[0], "GIF")?;
+    let encode_error = |e: gif::EncodingError| Error::Render(format!("Failed to encode GIF: {}", e));
+
+    let mut gif_data = Vec::new();
+    {
+        let mut encoder = gif::Encoder::new(&mut gif_data, width, height, &[]).map_err(encode_error)?;
+        if frames.len() > 1 {
+            encoder.set_repeat(gif::Repeat::Infinite).map_err(encode_error)?;
+        }
+        for frame in frames {
+            let mut rgba = demultiplied(frame, |[r, g, b, a]| [r, g, b, a]);
+            let mut frame = gif::Frame::from_rgba_speed(width, height, &mut rgba, GIF_QUANTIZER_SPEED);
+            // In hundredths of a second
+            frame.delay = (delay_ms / 10).min(u16::MAX as u32) as u16;
+            // Transparent areas show the background, not the previous frame
+            frame.dispose = gif::DisposalMethod::Background;
+            encoder.write_frame(&frame).map_err(encode_error)?;
+        }
+    }
+    Ok(gif_data)
+}
+
+pub fn encode_png(pixmap: &Pixmap, deterministic: bool) -> Result<Vec<u8>> {
+    if deterministic {
+        return encode_png_with(pixmap, png::Compression::Default);
+    }
+
+    // Encode as PNG
+    log::debug!("Encoding to PNG");
+    let png_data = pixmap.encode_png()
+        .map_err(|e| Error::Render(format!("Failed to encode PNG: {}", e)))?;
+
+    log::debug!("PNG encoded successfully, size: {} bytes", png_data.len());
+
+    Ok(png_data)
+}
+
+// PNG with fixed compression and filter settings and no ancillary chunks
+// (timestamps, gamma, text), so identical pixels always give identical bytes
+pub fn encode_png_with(pixmap: &Pixmap, compression: png::Compression) -> Result<Vec<u8>> {
+    let rgba = demultiplied(pixmap, |[r, g, b, a]| [r, g, b, a]);
+    let encode_error = |e: png::EncodingError| Error::Render(format!("Failed to encode PNG: {}", e));
+
+    let mut png_data = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut png_data, pixmap.width(), pixmap.height());
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(compression);
+        encoder.set_filter(png::FilterType::Sub);
+        encoder.set_adaptive_filter(png::AdaptiveFilterType::NonAdaptive);
+
+        let mut writer = encoder.write_header().map_err(encode_error)?;
+        writer.write_image_data(&rgba).map_err(encode_error)?;
+    }
+
+    Ok(png_data)
+}
+
+fn demultiplied<const N: usize>(pixmap: &Pixmap, channels: impl Fn([u8; 4]) -> [u8; N]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(pixmap.width() as usize * pixmap.height() as usize * N);
+    for pixel in pixmap.pixels() {
+        let color = pixel.demultiply();
+        data.extend_from_slice(&channels([color.red(), color.green(), color.blue(), color.alpha()]));
+    }
+    data
+}
+
+// JPEG and GIF dimensions are 16-bit
+fn size_16(pixmap: &Pixmap, format: &str) -> Result<(u16, u16)> {
+    match (u16::try_from(pixmap.width()), u16::try_from(pixmap.height())) {
+        (Ok(width), Ok(height)) => Ok((width, height)),
+        _ => Err(Error::InvalidContent(format!(
+            "{}x{} is too large for {}, which allows at most 65535 pixels per side", pixmap.width(), pixmap.height(), format))),
+    }
+}
@@ -0,0 +1,28 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("Request failed: {0}")]
+    Request(#[from] reqwest::Error),
+
+    #[error("Failed to fetch SVG: HTTP {0}")]
+    Status(reqwest::StatusCode),
+
+    // The source isn't acceptable SVG: too large, not SVG at all, or unsafe
+    #[error("{0}")]
+    InvalidContent(String),
+
+    // Not UTF-8 or not well-formed, with the full message
+    #[error("{0}")]
+    Parse(String),
+
+    // Pixel buffers and encoders, failures here point at resource limits or bugs
+    #[error("{0}")]
+    Render(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+pub(crate) fn pixel_buffer_error() -> Error {
+    Error::Render("Failed to create pixel buffer".to_string())
+}
@@ -0,0 +1,111 @@
+use resvg::tiny_skia::{ColorU8, Pixmap};
+
+// Pixel operations applied to rendered pixmaps. All of them work on tiny-skia's
+// premultiplied RGBA data directly.
+
+// How much of the difference from a blurred copy sharpen(1.0) adds back
+const SHARPEN_MAX_GAIN: f32 = 2.0;
+
+// Unsharp mask for small renders, which antialiasing leaves soft. `amount` is 0-1.
+pub fn sharpen(pixmap: &mut Pixmap, amount: f32) {
+    let mut blurred = pixmap.clone();
+    blur(&mut blurred, 1);
+    let gain = amount.clamp(0.0, 1.0) * SHARPEN_MAX_GAIN;
+
+    for (pixel, blurred) in pixmap.data_mut().chunks_exact_mut(4).zip(blurred.data().chunks_exact(4)) {
+        // Premultiplied color can't exceed alpha, which is left as it is
+        let alpha = pixel[3] as f32;
+        for c in 0..3 {
+            let value = pixel[c] as f32;
+            pixel[c] = (value + (value - blurred[c] as f32) * gain).clamp(0.0, alpha).round() as u8;
+        }
+    }
+}
+
+// Color adjustments, applied to straight color in this order. Alpha is left alone.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorAdjustments {
+    // 1 leaves colors as they are, higher values lighten the midtones
+    pub gamma: f32,
+    // -100 to 100, the percentage of white added or taken away
+    pub brightness: f32,
+    // -100 to 100, -100 leaves flat gray, 100 doubles the distance from it
+    pub contrast: f32,
+}
+
+impl Default for ColorAdjustments {
+    fn default() -> Self {
+        Self {
+            gamma: 1.0,
+            brightness: 0.0,
+            contrast: 0.0,
+        }
+    }
+}
+
+pub fn adjust_colors(pixmap: &mut Pixmap, adjustments: ColorAdjustments) {
+    let table: [u8; 256] = std::array::from_fn(|value| {
+        let value = (value as f32 / 255.0).powf(1.0 / adjustments.gamma) + adjustments.brightness / 100.0;
+        let value = (value - 0.5) * (1.0 + adjustments.contrast / 100.0) + 0.5;
+        (value.clamp(0.0, 1.0) * 255.0).round() as u8
+    });
+
+    for pixel in pixmap.pixels_mut() {
+        let color = pixel.demultiply();
+        if color.alpha() == 0 {
+            continue;
+        }
+        *pixel = ColorU8::from_rgba(
+            table[color.red() as usize],
+            table[color.green() as usize],
+            table[color.blue() as usize],
+            color.alpha(),
+        ).premultiply();
+    }
+}
+
+// Approximates a gaussian blur with three box blur passes
+pub fn blur(pixmap: &mut Pixmap, radius: u32) {
+    if radius == 0 {
+        return;
+    }
+
+    let (width, height) = (pixmap.width() as usize, pixmap.height() as usize);
+    let data = pixmap.data_mut();
+    let mut buffer = vec![0u8; data.len()];
+
+    for _ in 0..3 {
+        box_blur_pass(data, &mut buffer, width, height, radius as usize, true);
+        box_blur_pass(&buffer, data, width, height, radius as usize, false);
+    }
+}
+
+// One horizontal or vertical box blur pass from `src` into `dst`, clamping at the edges
+fn box_blur_pass(src: &[u8], dst: &mut [u8], width: usize, height: usize, radius: usize, horizontal: bool) {
+    let (lines, length) = if horizontal { (height, width) } else { (width, height) };
+    let index = |line: usize, pos: usize| if horizontal { line * width + pos } else { pos * width + line } * 4;
+    let window = (radius * 2 + 1) as u32;
+
+    for line in 0..lines {
+        let mut sums = [0u32; 4];
+        for offset in 0..window as usize {
+            let pos = (offset as isize - radius as isize).clamp(0, length as isize - 1) as usize;
+            for (c, sum) in sums.iter_mut().enumerate() {
+                *sum += src[index(line, pos) + c] as u32;
+            }
+        }
+
+        for pos in 0..length {
+            let i = index(line, pos);
+            for c in 0..4 {
+                dst[i + c] = (sums[c] / window) as u8;
+            }
+
+            let outgoing = (pos as isize - radius as isize).clamp(0, length as isize - 1) as usize;
+            let incoming = (pos + radius + 1).min(length - 1);
+            for (c, sum) in sums.iter_mut().enumerate() {
+                *sum = *sum + src[index(line, incoming) + c] as u32 - src[index(line, outgoing) + c] as u32;
+            }
+        }
+    }
+}
@@ -0,0 +1,27 @@
+use resvg::usvg::fontdb;
+
+// Fonts from `font_dir`, or the system fonts
+pub fn load_fonts(font_dir: Option<&str>) -> fontdb::Database {
+    let mut db = fontdb::Database::new();
+    match font_dir {
+        Some(dir) => db.load_fonts_dir(dir),
+        None => db.load_system_fonts(),
+    }
+    log::info!("Loaded {} font faces", db.len());
+
+    // fontdb maps sans-serif to Arial, which slim Linux images rarely have
+    let installed = |family: &str| db.faces().any(|face| face.families.iter().any(|(name, _)| name == family));
+    let preferred = ["Arial", "Helvetica", "DejaVu Sans", "Liberation Sans"].into_iter().find(|f| installed(f));
+
+    match preferred {
+        Some(family) => db.set_sans_serif_family(family),
+        // Otherwise pick a family that only depends on the installed fonts, not on load order
+        None => {
+            if let Some(family) = db.faces().flat_map(|face| face.families.iter().map(|(name, _)| name.clone())).min() {
+                db.set_sans_serif_family(family);
+            }
+        }
+    }
+
+    db
+}
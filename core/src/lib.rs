@@ -0,0 +1,22 @@
+// The rendering behind svg-rasterizer as a library: fetching with size limits,
+// content checks, parsing, rendering and encoding. The service renders through the
+// same Rasterizer, with caching, pooling, metrics and retries around it.
+
+mod animation;
+mod content;
+mod encode;
+mod error;
+pub mod filters;
+mod fonts;
+mod pipeline;
+mod rasterizer;
+pub mod render;
+
+pub use animation::Animation;
+pub use content::{check_content, check_size, read_svg, MAX_SVG_SIZE};
+pub use encode::{encode, encode_animation, encode_png, encode_png_with, encode_with_options, EncodeOptions, ImageFormat};
+pub use error::{Error, Result};
+pub use fonts::load_fonts;
+pub use pipeline::{Overlay, Position, Surface, Tiling};
+pub use rasterizer::{Rasterizer, RasterizerBuilder, RenderOptions};
+pub use resvg::{tiny_skia, usvg};
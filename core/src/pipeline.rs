@@ -0,0 +1,300 @@
+use resvg::tiny_skia::{Color, Pixmap, Transform};
+use resvg::usvg;
+
+use crate::error::{pixel_buffer_error, Error};
+use crate::filters;
+use crate::rasterizer::RenderOptions;
+use crate::render;
+
+// Another SVG fit within `scale` of the render's size, at `position`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Overlay {
+    pub url: String,
+    pub position: Position,
+    pub scale: f32,
+    pub opacity: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Position {
+    Center,
+    Top,
+    Bottom,
+    Left,
+    Right,
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Position {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_ascii_lowercase().as_str() {
+            "center" => Some(Position::Center),
+            "top" => Some(Position::Top),
+            "bottom" => Some(Position::Bottom),
+            "left" => Some(Position::Left),
+            "right" => Some(Position::Right),
+            "top-left" => Some(Position::TopLeft),
+            "top-right" => Some(Position::TopRight),
+            "bottom-left" => Some(Position::BottomLeft),
+            "bottom-right" => Some(Position::BottomRight),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Position::Center => "center",
+            Position::Top => "top",
+            Position::Bottom => "bottom",
+            Position::Left => "left",
+            Position::Right => "right",
+            Position::TopLeft => "top-left",
+            Position::TopRight => "top-right",
+            Position::BottomLeft => "bottom-left",
+            Position::BottomRight => "bottom-right",
+        }
+    }
+
+    // Top left corner of something `width`x`height` placed here on the canvas
+    fn offset(self, canvas: &Pixmap, width: u32, height: u32) -> (i32, i32) {
+        let (free_x, free_y) = (canvas.width().saturating_sub(width) as i32, canvas.height().saturating_sub(height) as i32);
+        let x = match self {
+            Position::Left | Position::TopLeft | Position::BottomLeft => 0,
+            Position::Right | Position::TopRight | Position::BottomRight => free_x,
+            _ => free_x / 2,
+        };
+        let y = match self {
+            Position::Top | Position::TopLeft | Position::TopRight => 0,
+            Position::Bottom | Position::BottomLeft | Position::BottomRight => free_y,
+            _ => free_y / 2,
+        };
+        (x, y)
+    }
+}
+
+// Columns and rows of the render, with `spacing` pixels after every tile so the
+// result repeats evenly too
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tiling {
+    pub columns: u32,
+    pub rows: u32,
+    pub spacing: u32,
+}
+
+impl Tiling {
+    pub fn canvas_size(&self, width: u32, height: u32) -> (u64, u64) {
+        (
+            (width as u64 + self.spacing as u64) * self.columns as u64,
+            (height as u64 + self.spacing as u64) * self.rows as u64,
+        )
+    }
+}
+
+// Where the pipeline gets its pixel buffers and how it draws trees into them. The
+// defaults allocate every buffer and draw in one piece; the service pools buffers,
+// splits large renders over threads and runs its hooks.
+pub trait Surface {
+    type Error: From<Error>;
+
+    // A transparent buffer
+    fn pixmap(&self, width: u32, height: u32) -> Result<Pixmap, Self::Error>;
+
+    // Takes back a buffer the pipeline is done with
+    fn release(&self, _pixmap: Pixmap) {}
+
+    fn draw(&self, rtree: &usvg::Tree, width: u32, height: u32, transform: Transform) -> Result<Pixmap, Self::Error> {
+        let mut pixmap = self.pixmap(width, height)?;
+        render::draw_with(rtree, &mut pixmap, transform);
+        Ok(pixmap)
+    }
+
+    // The SVG itself at the output size, by far the largest draw
+    fn draw_main(&self, rtree: &usvg::Tree, width: u32, height: u32, transform: Transform) -> Result<Pixmap, Self::Error> {
+        self.draw(rtree, width, height, transform)
+    }
+
+    // Runs after the color adjustments, before overlays are drawn
+    fn post_render(&self, _pixmap: &mut Pixmap) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+// Buffers straight from the allocator
+pub(crate) struct Unpooled;
+
+impl Surface for Unpooled {
+    type Error = Error;
+
+    fn pixmap(&self, width: u32, height: u32) -> Result<Pixmap, Error> {
+        Pixmap::new(width, height).ok_or_else(pixel_buffer_error)
+    }
+}
+
+// Every render goes through these steps in this order: the SVG itself (an LQIP
+// placeholder, a maskable icon, or fit or stretched over its background), rounded
+// corners, sharpening, color adjustments, the surface's post-render step, overlays,
+// tiling and flattening. `overlays` are the parsed sources of options.overlays,
+// overlays without one are left out.
+pub(crate) fn render<S: Surface>(surface: &S, rtree: &usvg::Tree, overlays: &[usvg::Tree], options: &RenderOptions) -> Result<Pixmap, S::Error> {
+    let mut pixmap = render_pixels(surface, rtree, options)?;
+    if let Some(amount) = options.sharpen {
+        filters::sharpen(&mut pixmap, amount);
+    }
+    if let Some(adjustments) = options.adjustments {
+        filters::adjust_colors(&mut pixmap, adjustments);
+    }
+    surface.post_render(&mut pixmap)?;
+    for (overlay, overlay_tree) in options.overlays.iter().zip(overlays) {
+        draw_overlay(surface, &mut pixmap, overlay, overlay_tree)?;
+    }
+    if let Some(tiling) = options.tile {
+        let (width, height) = tiling.canvas_size(pixmap.width(), pixmap.height());
+        let mut canvas = surface.pixmap(width as u32, height as u32)?;
+        // The spacing shows the background as well
+        if let Some(background) = options.background {
+            canvas.fill(background);
+        }
+        render::tile(&mut canvas, &pixmap, tiling.spacing);
+        surface.release(std::mem::replace(&mut pixmap, canvas));
+    }
+    if let Some(matte) = options.flatten {
+        render::flatten(&mut pixmap, matte);
+    }
+    Ok(pixmap)
+}
+
+fn render_pixels<S: Surface>(surface: &S, rtree: &usvg::Tree, options: &RenderOptions) -> Result<Pixmap, S::Error> {
+    if options.lqip {
+        // Placeholders keep the requested width and the SVG's own aspect ratio
+        let (width, height) = render::lqip_size(rtree, options.width);
+        let mut pixmap = surface.draw(rtree, width, height, render::fit_transform(rtree, width, height))?;
+        filters::blur(&mut pixmap, render::lqip_blur_radius(options.width));
+        return Ok(pixmap);
+    }
+
+    let mut pixmap = if options.maskable {
+        maskable(surface, rtree, options.width.min(options.height), options.background.unwrap_or(Color::WHITE))?
+    } else {
+        let transform = if options.stretch {
+            render::stretch_transform(rtree, options.width, options.height)
+        } else {
+            render::fit_transform(rtree, options.width, options.height)
+        };
+        let mut pixmap = surface.draw_main(rtree, options.width, options.height, transform)?;
+        if let Some(background) = options.background {
+            let mut canvas = surface.pixmap(options.width, options.height)?;
+            render::compose_background(&mut canvas, &pixmap, background);
+            surface.release(std::mem::replace(&mut pixmap, canvas));
+        }
+        pixmap
+    };
+
+    if let Some(radius) = options.corner_radius {
+        render::round_corners(&mut pixmap, radius as f32);
+    }
+
+    Ok(pixmap)
+}
+
+fn maskable<S: Surface>(surface: &S, rtree: &usvg::Tree, size: u32, background: Color) -> Result<Pixmap, S::Error> {
+    let (content_width, content_height) = render::maskable_content_size(rtree, size);
+    let content = surface.draw(rtree, content_width, content_height, render::fit_transform(rtree, content_width, content_height))?;
+    let mut pixmap = surface.pixmap(size, size)?;
+    render::compose_maskable(&mut pixmap, &content, background);
+    surface.release(content);
+    Ok(pixmap)
+}
+
+fn draw_overlay<S: Surface>(surface: &S, pixmap: &mut Pixmap, overlay: &Overlay, rtree: &usvg::Tree) -> Result<(), S::Error> {
+    let max_width = ((pixmap.width() as f32 * overlay.scale).round() as u32).max(1);
+    let max_height = ((pixmap.height() as f32 * overlay.scale).round() as u32).max(1);
+    let (width, height) = render::fit_size(rtree, max_width, max_height);
+
+    let layer = surface.draw(rtree, width, height, render::fit_transform(rtree, width, height))?;
+    let (x, y) = overlay.position.offset(pixmap, width, height);
+    render::compose_overlay(pixmap, &layer, x, y, overlay.opacity);
+    surface.release(layer);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rasterizer;
+
+    const SQUARE: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"><rect width="10" height="10" fill="#ff0000"/></svg>"##;
+    const DOT: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"><rect width="10" height="10" fill="#0000ff"/></svg>"##;
+
+    fn pixel(pixmap: &Pixmap, x: u32, y: u32) -> (u8, u8, u8, u8) {
+        let color = pixmap.pixel(x, y).unwrap().demultiply();
+        (color.red(), color.green(), color.blue(), color.alpha())
+    }
+
+    fn render(options: &RenderOptions, overlays: &[&str]) -> Pixmap {
+        let rasterizer = Rasterizer::default();
+        let rtree = rasterizer.parse(SQUARE).unwrap();
+        let overlays = overlays.iter().map(|svg| rasterizer.parse(svg).unwrap()).collect::<Vec<_>>();
+        rasterizer.render(&rtree, &overlays, options).unwrap()
+    }
+
+    #[test]
+    fn tiles_with_background_in_the_spacing() {
+        let mut options = RenderOptions::new(10, 10);
+        options.tile = Some(Tiling { columns: 2, rows: 3, spacing: 2 });
+        options.background = Some(Color::from_rgba8(0, 255, 0, 255));
+        let pixmap = render(&options, &[]);
+
+        assert_eq!((pixmap.width(), pixmap.height()), options.output_size());
+        assert_eq!((pixmap.width(), pixmap.height()), (24, 36));
+        assert_eq!(pixel(&pixmap, 5, 5), (255, 0, 0, 255));
+        assert_eq!(pixel(&pixmap, 11, 5), (0, 255, 0, 255));
+        assert_eq!(pixel(&pixmap, 17, 29), (255, 0, 0, 255));
+    }
+
+    #[test]
+    fn flattens_after_rounding_and_tiling() {
+        let mut options = RenderOptions::new(10, 10);
+        options.corner_radius = Some(5);
+        options.tile = Some(Tiling { columns: 2, rows: 1, spacing: 1 });
+        options.flatten = Some(Color::WHITE);
+        let pixmap = render(&options, &[]);
+
+        assert!(pixmap.pixels().iter().all(|pixel| pixel.alpha() == 255));
+        assert_eq!(pixel(&pixmap, 0, 0), (255, 255, 255, 255));
+        assert_eq!(pixel(&pixmap, 10, 5), (255, 255, 255, 255));
+    }
+
+    #[test]
+    fn places_overlays_before_tiling() {
+        let mut options = RenderOptions::new(10, 10);
+        options.overlays = vec![Overlay { url: String::new(), position: Position::BottomRight, scale: 0.5, opacity: 1.0 }];
+        options.tile = Some(Tiling { columns: 2, rows: 1, spacing: 0 });
+        let pixmap = render(&options, &[DOT]);
+
+        for x in [7, 17] {
+            assert_eq!(pixel(&pixmap, x, 7), (0, 0, 255, 255));
+            assert_eq!(pixel(&pixmap, x - 5, 2), (255, 0, 0, 255));
+        }
+    }
+
+    #[test]
+    fn leaves_out_overlays_without_a_source() {
+        let mut options = RenderOptions::new(10, 10);
+        options.overlays = vec![Overlay { url: String::new(), position: Position::Center, scale: 1.0, opacity: 1.0 }];
+        let pixmap = render(&options, &[]);
+
+        assert_eq!(pixel(&pixmap, 5, 5), (255, 0, 0, 255));
+    }
+
+    #[test]
+    fn parses_position_names() {
+        for name in ["center", "top", "bottom", "left", "right", "top-left", "top-right", "bottom-left", "bottom-right"] {
+            assert_eq!(Position::parse(name).map(Position::name), Some(name));
+        }
+        assert_eq!(Position::parse("Top-Left"), Some(Position::TopLeft));
+        assert_eq!(Position::parse("middle"), None);
+    }
+}
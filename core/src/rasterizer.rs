@@ -0,0 +1,203 @@
+use resvg::tiny_skia::{Color, Pixmap};
+use resvg::usvg::{self, fontdb};
+use std::sync::Arc;
+
+use crate::content::{self, MAX_SVG_SIZE};
+use crate::encode::{self, EncodeOptions, ImageFormat};
+use crate::error::{Error, Result};
+use crate::filters::ColorAdjustments;
+use crate::fonts;
+use crate::pipeline::{self, Overlay, Surface, Tiling, Unpooled};
+use crate::render;
+
+// Everything besides the source that changes the rendered image
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderOptions {
+    pub width: u32,
+    pub height: u32,
+    pub background: Option<Color>,
+    pub maskable: bool,
+    pub corner_radius: Option<u32>,
+    pub lqip: bool,
+    // Maps the viewBox to the whole output, ignoring the SVG's aspect ratio
+    pub stretch: bool,
+    // Unsharp mask strength, 0-1
+    pub sharpen: Option<f32>,
+    pub adjustments: Option<ColorAdjustments>,
+    // Opaque color the finished image is composited onto, leaving no transparency
+    pub flatten: Option<Color>,
+    // Other SVGs drawn over the render in order
+    pub overlays: Vec<Overlay>,
+    // The render repeated in a grid
+    pub tile: Option<Tiling>,
+    pub format: ImageFormat,
+    pub encode: EncodeOptions,
+}
+
+impl RenderOptions {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            background: None,
+            maskable: false,
+            corner_radius: None,
+            lqip: false,
+            stretch: false,
+            sharpen: None,
+            adjustments: None,
+            flatten: None,
+            overlays: Vec::new(),
+            tile: None,
+            format: ImageFormat::Png,
+            encode: EncodeOptions::default(),
+        }
+    }
+
+    // Size of the image as delivered, the whole grid when tiled
+    pub fn output_size(&self) -> (u32, u32) {
+        match self.tile {
+            Some(tiling) => {
+                let (width, height) = tiling.canvas_size(self.width, self.height);
+                (width as u32, height as u32)
+            },
+            None => (self.width, self.height),
+        }
+    }
+}
+
+// Fetches, checks, renders and encodes SVGs the way the service does, without its
+// caching, pooling, metrics or retries
+#[derive(Clone)]
+pub struct Rasterizer {
+    client: reqwest::Client,
+    fonts: Arc<fontdb::Database>,
+    deterministic: bool,
+    max_svg_size: usize,
+}
+
+#[derive(Default)]
+pub struct RasterizerBuilder {
+    client: Option<reqwest::Client>,
+    fonts: Option<Arc<fontdb::Database>>,
+    font_dir: Option<String>,
+    deterministic: bool,
+    max_svg_size: Option<usize>,
+}
+
+impl RasterizerBuilder {
+    // Client for fetching sources, with the caller's timeouts and proxy settings
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    // Fonts that are already loaded, shared rather than loaded again
+    pub fn fonts(mut self, fonts: Arc<fontdb::Database>) -> Self {
+        self.fonts = Some(fonts);
+        self
+    }
+
+    // Load fonts from a directory instead of the system fonts
+    pub fn font_dir(mut self, font_dir: impl Into<String>) -> Self {
+        self.font_dir = Some(font_dir.into());
+        self
+    }
+
+    // Byte-identical output across hosts and versions, text rendered with the loaded fonts
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.deterministic = deterministic;
+        self
+    }
+
+    pub fn max_svg_size(mut self, max_svg_size: usize) -> Self {
+        self.max_svg_size = Some(max_svg_size);
+        self
+    }
+
+    pub fn build(self) -> Rasterizer {
+        Rasterizer {
+            client: self.client.unwrap_or_default(),
+            fonts: self.fonts.unwrap_or_else(|| Arc::new(fonts::load_fonts(self.font_dir.as_deref()))),
+            deterministic: self.deterministic,
+            max_svg_size: self.max_svg_size.unwrap_or(MAX_SVG_SIZE),
+        }
+    }
+}
+
+impl Default for Rasterizer {
+    fn default() -> Self {
+        Self::builder().build()
+    }
+}
+
+impl Rasterizer {
+    pub fn builder() -> RasterizerBuilder {
+        RasterizerBuilder::default()
+    }
+
+    // Fetches an SVG over http(s) and checks it is safe to render
+    pub async fn fetch(&self, url: &str) -> Result<String> {
+        let response = self.client.get(url).send().await?;
+        if !response.status().is_success() {
+            return Err(Error::Status(response.status()));
+        }
+        content::read_svg(response, self.max_svg_size, |_| {}).await
+    }
+
+    // Checks SVG from elsewhere, e.g. a file, the same way as fetched SVG
+    pub fn check(&self, svg_data: &str) -> Result<()> {
+        content::check_size(svg_data.len(), self.max_svg_size)?;
+        content::check_content(svg_data)
+    }
+
+    pub fn parse(&self, svg_data: &str) -> Result<usvg::Tree> {
+        render::parse(svg_data, self.deterministic, &self.fonts)
+    }
+
+    // Renders with buffers straight from the allocator. `overlays` are the parsed
+    // sources of options.overlays.
+    pub fn render(&self, rtree: &usvg::Tree, overlays: &[usvg::Tree], options: &RenderOptions) -> Result<Pixmap> {
+        self.render_on(&Unpooled, rtree, overlays, options)
+    }
+
+    // Renders with the surface's buffers, drawing and post-render step
+    pub fn render_on<S: Surface>(&self, surface: &S, rtree: &usvg::Tree, overlays: &[usvg::Tree], options: &RenderOptions) -> std::result::Result<Pixmap, S::Error> {
+        pipeline::render(surface, rtree, overlays, options)
+    }
+
+    // Encodes a render in options.format. LQIP placeholders get the smallest PNG
+    // there is, whatever it takes to compress.
+    pub fn encode(&self, pixmap: &Pixmap, options: &RenderOptions) -> Result<Vec<u8>> {
+        match options.format {
+            ImageFormat::Png if options.lqip => encode::encode_png_with(pixmap, png::Compression::Best),
+            format => encode::encode_with_options(pixmap, format, self.deterministic, options.encode),
+        }
+    }
+
+    // Encodes frames rendered with `options`, `delay_ms` apart
+    pub fn encode_animation(&self, frames: &[Pixmap], delay_ms: u32, options: &RenderOptions) -> Result<Vec<u8>> {
+        encode::encode_animation(frames, delay_ms, options.format, options.encode)
+    }
+
+    // Checks, parses, renders and encodes SVG text. `overlays` are the sources of
+    // options.overlays, checked the same way.
+    pub fn rasterize(&self, svg_data: &str, overlays: &[String], options: &RenderOptions) -> Result<Vec<u8>> {
+        self.check(svg_data)?;
+        let rtree = self.parse(svg_data)?;
+        let overlays = overlays.iter()
+            .map(|overlay| {
+                self.check(overlay)?;
+                self.parse(overlay)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let pixmap = self.render(&rtree, &overlays, options)?;
+        self.encode(&pixmap, options)
+    }
+
+    pub async fn rasterize_url(&self, url: &str, options: &RenderOptions) -> Result<Vec<u8>> {
+        let svg_data = self.fetch(url).await?;
+        let overlays = futures::future::try_join_all(options.overlays.iter().map(|overlay| self.fetch(&overlay.url))).await?;
+        self.rasterize(&svg_data, &overlays, options)
+    }
+}
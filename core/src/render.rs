@@ -0,0 +1,197 @@
+use resvg::usvg::{self, fontdb, TreeParsing, TreeTextToPath, Options};
+use resvg::tiny_skia::{Color, FillRule, Mask, PathBuilder, Pixmap, PixmapPaint, PremultipliedColorU8, Transform};
+
+use crate::error::{Error, Result};
+
+// Aspect ratio limit of LQIP placeholders, which keep the SVG's own aspect ratio
+pub const LQIP_MAX_ASPECT: u32 = 4;
+
+// Parses an SVG. Deterministic parsing pins every option and converts text to paths
+// with the given fonts, so output doesn't depend on usvg defaults or system fonts.
+pub fn parse(svg_data: &str, deterministic: bool, fonts: &fontdb::Database) -> Result<usvg::Tree> {
+    let opt = if deterministic { pinned_options() } else { Options::default() };
+
+    // Parse the SVG string into a tree
+    let mut rtree = usvg::Tree::from_str(svg_data, &opt)
+        .map_err(|e| Error::Parse(format!("Failed to parse SVG: {}", e)))?;
+
+    if deterministic {
+        rtree.convert_text(fonts);
+    }
+
+    Ok(rtree)
+}
+
+// Every option spelled out, so output doesn't drift when usvg changes its defaults
+fn pinned_options() -> Options {
+    Options {
+        resources_dir: None,
+        dpi: 96.0,
+        font_family: "sans-serif".to_string(),
+        font_size: 12.0,
+        languages: vec!["en".to_string()],
+        shape_rendering: usvg::ShapeRendering::GeometricPrecision,
+        text_rendering: usvg::TextRendering::OptimizeLegibility,
+        image_rendering: usvg::ImageRendering::OptimizeQuality,
+        default_size: usvg::Size::from_wh(100.0, 100.0).expect("valid default size"),
+        image_href_resolver: usvg::ImageHrefResolver::default(),
+    }
+}
+
+// Scales the SVG to fit the output while keeping its aspect ratio, centered
+pub fn fit_transform(rtree: &usvg::Tree, width: u32, height: u32) -> Transform {
+    let view_box = rtree.view_box;
+    let svg_width = view_box.rect.width();
+    let svg_height = view_box.rect.height();
+
+    let scale = (width as f32 / svg_width).min(height as f32 / svg_height);
+    let translate_x = (width as f32 - svg_width * scale) / 2.0;
+    let translate_y = (height as f32 - svg_height * scale) / 2.0;
+
+    Transform::from_scale(scale, scale)
+        .pre_translate(translate_x / scale, translate_y / scale)
+}
+
+// Scales the viewBox to cover the output exactly, ignoring the SVG's aspect ratio
+// and preserveAspectRatio. resvg applies the SVG's own viewBox transform first,
+// so that is undone here.
+pub fn stretch_transform(rtree: &usvg::Tree, width: u32, height: u32) -> Transform {
+    let view_box = rtree.view_box;
+    let own = usvg::utils::view_box_to_transform(view_box.rect, view_box.aspect, rtree.size);
+
+    Transform::from_scale(width as f32 / view_box.rect.width(), height as f32 / view_box.rect.height())
+        .pre_translate(-view_box.rect.x(), -view_box.rect.y())
+        .pre_concat(own.invert().unwrap_or_default())
+}
+
+// Renders the SVG scaled to fit a transparent pixmap, centered
+pub fn draw(rtree: &usvg::Tree, pixmap: &mut Pixmap) {
+    let transform = fit_transform(rtree, pixmap.width(), pixmap.height());
+    draw_with(rtree, pixmap, transform);
+}
+
+pub fn draw_with(rtree: &usvg::Tree, pixmap: &mut Pixmap, transform: Transform) {
+    resvg::Tree::from_usvg(rtree).render(transform, &mut pixmap.as_mut());
+}
+
+pub fn has_filters(rtree: &usvg::Tree) -> bool {
+    rtree.root.descendants().any(|node| matches!(&*node.borrow(), usvg::NodeKind::Group(group) if !group.filters.is_empty()))
+}
+
+// Height that keeps the SVG's aspect ratio at the given output width
+pub fn height_for_width(rtree: &usvg::Tree, width: u32) -> u32 {
+    let rect = rtree.view_box.rect;
+    ((width as f32 * rect.height() / rect.width()).round() as u32).max(1)
+}
+
+// Largest size that fits within the bounds while keeping the SVG's aspect ratio
+pub fn fit_size(rtree: &usvg::Tree, max_width: u32, max_height: u32) -> (u32, u32) {
+    let rect = rtree.view_box.rect;
+    let scale = (max_width as f32 / rect.width()).min(max_height as f32 / rect.height());
+    (
+        ((rect.width() * scale).round() as u32).clamp(1, max_width),
+        ((rect.height() * scale).round() as u32).clamp(1, max_height),
+    )
+}
+
+// Size of an LQIP placeholder: the requested width at the SVG's aspect ratio, within limits
+pub fn lqip_size(rtree: &usvg::Tree, width: u32) -> (u32, u32) {
+    (width, height_for_width(rtree, width).min(width * LQIP_MAX_ASPECT))
+}
+
+// Blur radius that leaves only the rough shapes and colors of a placeholder
+pub fn lqip_blur_radius(width: u32) -> u32 {
+    (width / 16).max(1)
+}
+
+// Maskable PWA icons must keep their content inside a centered circle with a
+// radius of 40% of the icon size. This is the size the content is rendered at.
+pub fn maskable_content_size(rtree: &usvg::Tree, size: u32) -> (u32, u32) {
+    let rect = rtree.view_box.rect;
+    let diagonal = (rect.width().powi(2) + rect.height().powi(2)).sqrt();
+    let scale = size as f32 * 0.8 / diagonal;
+    (
+        ((rect.width() * scale).floor() as u32).max(1),
+        ((rect.height() * scale).floor() as u32).max(1),
+    )
+}
+
+// Centers the maskable content on the icon canvas, on an opaque background
+pub fn compose_maskable(canvas: &mut Pixmap, content: &Pixmap, background: Color) {
+    canvas.fill(Color::from_rgba(background.red(), background.green(), background.blue(), 1.0).unwrap_or(Color::WHITE));
+    canvas.draw_pixmap(
+        ((canvas.width() - content.width()) / 2) as i32,
+        ((canvas.height() - content.height()) / 2) as i32,
+        content.as_ref(),
+        &PixmapPaint::default(),
+        Transform::identity(),
+        None,
+    );
+}
+
+// Draws the render over a canvas filled with the background color
+pub fn compose_background(canvas: &mut Pixmap, content: &Pixmap, background: Color) {
+    canvas.fill(background);
+    canvas.draw_pixmap(0, 0, content.as_ref(), &PixmapPaint::default(), Transform::identity(), None);
+}
+
+// Composites the pixmap onto an opaque matte in place, so nothing stays transparent
+pub fn flatten(pixmap: &mut Pixmap, matte: Color) {
+    let matte = matte.to_color_u8();
+    for pixel in pixmap.pixels_mut() {
+        // Premultiplied, so the matte only adds what the pixel's alpha leaves uncovered
+        let uncovered = 255 - pixel.alpha() as u32;
+        let blend = |channel: u8, matte: u8| (channel as u32 + (matte as u32 * uncovered + 127) / 255) as u8;
+        *pixel = PremultipliedColorU8::from_rgba(
+            blend(pixel.red(), matte.red()),
+            blend(pixel.green(), matte.green()),
+            blend(pixel.blue(), matte.blue()),
+            255,
+        ).expect("opaque pixels are valid");
+    }
+}
+
+// Draws `overlay` over the canvas with its top left at `x`, `y`
+pub fn compose_overlay(canvas: &mut Pixmap, overlay: &Pixmap, x: i32, y: i32, opacity: f32) {
+    let paint = PixmapPaint { opacity, ..PixmapPaint::default() };
+    canvas.draw_pixmap(x, y, overlay.as_ref(), &paint, Transform::identity(), None);
+}
+
+// Repeats `tile` across the canvas from the top left, `spacing` pixels apart
+pub fn tile(canvas: &mut Pixmap, tile: &Pixmap, spacing: u32) {
+    let (step_x, step_y) = ((tile.width() + spacing) as usize, (tile.height() + spacing) as usize);
+    for y in (0..canvas.height()).step_by(step_y) {
+        for x in (0..canvas.width()).step_by(step_x) {
+            canvas.draw_pixmap(x as i32, y as i32, tile.as_ref(), &PixmapPaint::default(), Transform::identity(), None);
+        }
+    }
+}
+
+// Clears everything outside a rounded rectangle covering the whole pixmap
+pub fn round_corners(pixmap: &mut Pixmap, radius: f32) {
+    let (w, h) = (pixmap.width() as f32, pixmap.height() as f32);
+    let r = radius.min(w / 2.0).min(h / 2.0);
+    if r <= 0.0 {
+        return;
+    }
+
+    // Control point offset for approximating a quarter circle with a cubic
+    let k = r * 0.552_284_8;
+    let mut builder = PathBuilder::new();
+    builder.move_to(r, 0.0);
+    builder.line_to(w - r, 0.0);
+    builder.cubic_to(w - r + k, 0.0, w, r - k, w, r);
+    builder.line_to(w, h - r);
+    builder.cubic_to(w, h - r + k, w - r + k, h, w - r, h);
+    builder.line_to(r, h);
+    builder.cubic_to(r - k, h, 0.0, h - r + k, 0.0, h - r);
+    builder.line_to(0.0, r);
+    builder.cubic_to(0.0, r - k, r - k, 0.0, r, 0.0);
+    builder.close();
+
+    let (Some(path), Some(mut mask)) = (builder.finish(), Mask::new(pixmap.width(), pixmap.height())) else {
+        return;
+    };
+    mask.fill_path(&path, FillRule::Winding, true, Transform::identity());
+    pixmap.apply_mask(&mask);
+}
@@ -0,0 +1,174 @@
+use chrono::{DateTime, Utc};
+use std::fs::{self, File, OpenOptions};
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::config::{AccessLogDestination, Config, LogRotation};
+
+const DEFAULT_FORMAT: &str = r#"{client_ip} "{request}" {status} {bytes} "{referer}" "{user_agent}" {latency}"#;
+const COMMON_FORMAT: &str = r#"{client_ip} - - [{time}] "{request}" {status} {bytes}"#;
+const COMBINED_FORMAT: &str = r#"{client_ip} - - [{time}] "{request}" {status} {bytes} "{referer}" "{user_agent}""#;
+
+static ACCESS_LOG: OnceLock<AccessLog> = OnceLock::new();
+
+pub struct AccessLogEntry<'a> {
+    pub request_id: &'a str,
+    pub client_ip: &'a str,
+    pub method: &'a str,
+    pub uri: &'a str,
+    pub version: &'a str,
+    pub status: u16,
+    pub bytes: Option<u64>,
+    pub referer: &'a str,
+    pub user_agent: &'a str,
+    pub latency: Duration,
+}
+
+struct AccessLog {
+    template: String,
+    redact_urls: bool,
+    file: Option<Mutex<RotatingFile>>,
+    enabled: bool,
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    rotation: LogRotation,
+    max_files: usize,
+    period: String,
+    writer: LineWriter<File>,
+}
+
+pub fn init(config: &Config) -> std::io::Result<()> {
+    let template = match config.access_log_format.as_str() {
+        "default" => DEFAULT_FORMAT,
+        "common" => COMMON_FORMAT,
+        "combined" => COMBINED_FORMAT,
+        template => template,
+    }.to_string();
+
+    let file = match &config.access_log {
+        AccessLogDestination::File(path) => Some(Mutex::new(RotatingFile::open(
+            PathBuf::from(path), config.access_log_rotation, config.access_log_max_files,
+        )?)),
+        _ => None,
+    };
+
+    let _ = ACCESS_LOG.set(AccessLog {
+        template,
+        redact_urls: config.log_redact_urls,
+        file,
+        enabled: config.access_log != AccessLogDestination::Off,
+    });
+    Ok(())
+}
+
+pub fn write(entry: &AccessLogEntry) {
+    let Some(access_log) = ACCESS_LOG.get() else {
+        return;
+    };
+    if !access_log.enabled {
+        return;
+    }
+
+    let line = access_log.format(entry);
+    match &access_log.file {
+        Some(file) => {
+            if let Err(e) = file.lock().unwrap().write_line(&line) {
+                log::error!("Failed to write access log: {}", e);
+            }
+        },
+        None => log::info!(target: "access", "{}", line),
+    }
+}
+
+impl AccessLog {
+    fn format(&self, entry: &AccessLogEntry) -> String {
+        // The query string holds the customer's source URL
+        let uri = if self.redact_urls {
+            entry.uri.split('?').next().unwrap_or_default()
+        } else {
+            entry.uri
+        };
+        let bytes = entry.bytes.map(|b| b.to_string()).unwrap_or_else(|| "-".to_string());
+
+        self.template
+            .replace("{time}", &Utc::now().format("%d/%b/%Y:%H:%M:%S %z").to_string())
+            .replace("{request_id}", entry.request_id)
+            .replace("{client_ip}", entry.client_ip)
+            .replace("{request}", &format!("{} {} {}", entry.method, uri, entry.version))
+            .replace("{method}", entry.method)
+            .replace("{uri}", uri)
+            .replace("{status}", &entry.status.to_string())
+            .replace("{bytes}", &bytes)
+            .replace("{referer}", entry.referer)
+            .replace("{user_agent}", entry.user_agent)
+            .replace("{latency_ms}", &format!("{:.3}", entry.latency.as_secs_f64() * 1000.0))
+            .replace("{latency}", &format!("{:.6}", entry.latency.as_secs_f64()))
+    }
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, rotation: LogRotation, max_files: usize) -> std::io::Result<Self> {
+        let writer = LineWriter::new(OpenOptions::new().create(true).append(true).open(&path)?);
+        // Start from the file's age so a restart doesn't keep writing into yesterday's log
+        let modified: DateTime<Utc> = fs::metadata(&path)?.modified()?.into();
+
+        Ok(Self { period: period(rotation, modified), path, rotation, max_files, writer })
+    }
+
+    fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        let now = period(self.rotation, Utc::now());
+        if now != self.period {
+            self.rotate()?;
+            self.period = now;
+        }
+        writeln!(self.writer, "{}", line)
+    }
+
+    // Moves the current file to `<path>.<period>` and removes the oldest rotated files
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.writer.flush()?;
+        fs::rename(&self.path, rotated_path(&self.path, &self.period))?;
+        self.writer = LineWriter::new(OpenOptions::new().create(true).append(true).open(&self.path)?);
+
+        let (Some(dir), Some(name)) = (self.path.parent(), self.path.file_name()) else {
+            return Ok(());
+        };
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        let prefix = format!("{}.", name.to_string_lossy());
+
+        let mut rotated: Vec<PathBuf> = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_string_lossy().starts_with(&prefix))
+            .map(|entry| entry.path())
+            .collect();
+        // Period suffixes sort chronologically
+        rotated.sort();
+
+        let excess = rotated.len().saturating_sub(self.max_files);
+        for path in &rotated[..excess] {
+            if let Err(e) = fs::remove_file(path) {
+                log::warn!("Failed to remove old access log {}: {}", path.display(), e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn period(rotation: LogRotation, time: DateTime<Utc>) -> String {
+    match rotation {
+        LogRotation::Never => String::new(),
+        LogRotation::Hourly => time.format("%Y-%m-%d-%H").to_string(),
+        LogRotation::Daily => time.format("%Y-%m-%d").to_string(),
+    }
+}
+
+fn rotated_path(path: &Path, period: &str) -> PathBuf {
+    let mut rotated = path.as_os_str().to_owned();
+    rotated.push(format!(".{}", period));
+    PathBuf::from(rotated)
+}
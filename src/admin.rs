@@ -0,0 +1,81 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use crate::cache::RedisCache;
+use crate::config::{self, Config};
+use crate::error::{ServiceResult, ServiceError};
+use crate::listeners;
+
+// Cache entries derived from a source URL, keyed as `<prefix>:<url>:...`
+const URL_KEY_PREFIXES: [&str; 5] = ["svg", "s3:svg", "blurhash", "colors", "optimize"];
+
+#[derive(Deserialize)]
+pub struct PurgeRequest {
+    pub url: String,
+}
+
+// Every /admin route goes through this before its handler. Admin endpoints require
+// `Authorization: Bearer <ADMIN_TOKEN>` and are disabled without a token, except on
+// admin listeners that verified a client certificate against ADMIN_CLIENT_CA.
+pub fn authorize(req: &HttpRequest, config: &Config) -> ServiceResult<()> {
+    if listeners::admin_client_verified(req) {
+        return Ok(());
+    }
+
+    let Some(token) = &config.admin_token else {
+        return Err(ServiceError::Unauthorized("Admin API is disabled, set ADMIN_TOKEN to enable it".to_string()));
+    };
+
+    let provided = req.headers()
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .unwrap_or("");
+
+    // Compare digests so the comparison time doesn't depend on how much of the token matched
+    if Sha256::digest(provided.as_bytes()) != Sha256::digest(token.as_bytes()) {
+        return Err(ServiceError::Unauthorized("Invalid admin token".to_string()));
+    }
+
+    Ok(())
+}
+
+// The configuration in effect, after ENV_FILE, CONFIG_PATH and reloads, with credentials redacted
+pub async fn config_handler() -> ServiceResult<HttpResponse> {
+    Ok(HttpResponse::Ok().json(config::current().redacted()))
+}
+
+// Drops every cached render and analysis of a source URL, e.g. after the SVG changed
+pub async fn purge_handler(
+    req: web::Query<PurgeRequest>,
+    cache: web::Data<Arc<RedisCache>>,
+) -> ServiceResult<HttpResponse> {
+    url::Url::parse(&req.url)
+        .map_err(|_| ServiceError::ValidationError("Invalid URL".to_string()))?;
+
+    let mut deleted = 0;
+    for prefix in URL_KEY_PREFIXES {
+        deleted += cache.delete_matching(&format!("{}:{}:*", prefix, escape_pattern(&req.url))).await?;
+    }
+
+    log::info!("Purged {} cache entries for {}", deleted, req.url);
+    Ok(HttpResponse::Ok().json(json!({
+        "url": req.url,
+        "deleted": deleted,
+    })))
+}
+
+// URLs may contain characters that are wildcards in a SCAN pattern
+fn escape_pattern(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,266 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cache::RedisCache;
+use crate::config::{self, Config};
+use crate::error::{ServiceError, ServiceResult};
+use crate::metrics::metrics;
+use crate::request_context::API_KEY_HEADER;
+
+const KEY_PREFIX: &str = "sk_";
+const MAX_NAME_LENGTH: usize = 128;
+// Routes that authorize requests their own way, or that monitoring calls without a key
+const OPEN_PATHS: [&str; 8] = [
+    "/health", "/livez", "/readyz", "/metrics", "/openapi.json", "/content-signature-key", "/sign", "/v1/rasterize/signed",
+];
+
+// API keys managed through /admin/api-keys. Only a hash of each key is stored, under
+// `api_key:{sha256}`, with `api_key:id:{id}` pointing at it so keys can be managed by id.
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    // The start of the key, so owners can tell their keys apart
+    pub prefix: String,
+    pub created_at: String,
+    pub revoked_at: Option<String>,
+    // Requests per RATE_LIMIT_WINDOW, on top of the service-wide RATE_LIMIT
+    pub rate_limit: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CreateKeyRequest {
+    pub name: String,
+    pub rate_limit: Option<u32>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct LimitsRequest {
+    // null removes the key's own limit
+    pub rate_limit: Option<u32>,
+}
+
+fn hash_key(hash: &str) -> String {
+    format!("api_key:{}", hash)
+}
+
+fn id_key(id: &str) -> String {
+    format!("api_key:id:{}", id)
+}
+
+async fn load(cache: &RedisCache, hash: &str) -> ServiceResult<Option<ApiKey>> {
+    let Some(data) = cache.read(&hash_key(hash)).await? else {
+        return Ok(None);
+    };
+    serde_json::from_slice(&data)
+        .map(Some)
+        .map_err(|e| ServiceError::CacheError(format!("Failed to deserialize API key: {}", e)))
+}
+
+async fn save(cache: &RedisCache, hash: &str, key: &ApiKey) -> ServiceResult<()> {
+    let data = serde_json::to_vec(key)
+        .map_err(|e| ServiceError::CacheError(format!("Failed to serialize API key: {}", e)))?;
+    cache.set_persistent(&hash_key(hash), &data).await
+}
+
+// The hash and record of the key with this id
+async fn find(cache: &RedisCache, id: &str) -> ServiceResult<(String, ApiKey)> {
+    let not_found = || ServiceError::NotFound(format!("API key {} not found", id));
+    let hash = cache.read(&id_key(id)).await?
+        .and_then(|hash| String::from_utf8(hash).ok())
+        .ok_or_else(not_found)?;
+    let key = load(cache, &hash).await?.ok_or_else(not_found)?;
+    Ok((hash, key))
+}
+
+// Middleware for the public routes, see authorize
+pub async fn handle(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    // Below the scope's mount path
+    let path = req.match_info().unprocessed();
+    let open = OPEN_PATHS.iter().any(|prefix| path.starts_with(prefix));
+
+    if !open {
+        let cache = req.app_data::<web::Data<Arc<RedisCache>>>().map(|cache| cache.get_ref().clone());
+        let provided = req.headers().get(API_KEY_HEADER).and_then(|v| v.to_str().ok());
+        authorize(&config::current(), cache.as_deref(), provided).await?;
+    }
+    next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+}
+
+// Revoked keys are rejected and keys with their own limit are counted against it.
+// With REQUIRE_API_KEY, requests without a valid key are rejected too. Applies to
+// the HTTP routes and to gRPC.
+pub async fn authorize(config: &Config, cache: Option<&RedisCache>, provided: Option<&str>) -> ServiceResult<()> {
+    let Some(provided) = provided else {
+        if config.require_api_key {
+            return Err(ServiceError::Unauthorized("An API key is required".to_string()));
+        }
+        return Ok(());
+    };
+
+    // Keys from PRIORITY_API_KEYS aren't managed, but are valid. They're compared by
+    // digest like the admin token, and without Redis so they keep working when it's down.
+    let digest = Sha256::digest(provided.as_bytes());
+    if config.priority_api_keys.iter().any(|key| Sha256::digest(key.as_bytes()) == digest) {
+        return Ok(());
+    }
+
+    let invalid = || ServiceError::Unauthorized("Invalid API key".to_string());
+    let Some(cache) = cache else {
+        return if config.require_api_key { Err(invalid()) } else { Ok(()) };
+    };
+
+    let key = match load(cache, &hex::encode(digest)).await {
+        Ok(key) => key,
+        // Without REQUIRE_API_KEY a key only matters for revocation and its own limit,
+        // so requests go through like they would without a key
+        Err(e) if !config.require_api_key => {
+            log::warn!("Couldn't check API key, letting the request through: {}", e);
+            return Ok(());
+        },
+        Err(e) => {
+            log::error!("Couldn't check API key: {}", e);
+            return Err(ServiceError::UpstreamUnavailable("API keys can't be checked right now".to_string(), 5));
+        },
+    };
+
+    match key {
+        Some(key) if key.revoked_at.is_some() => {
+            return Err(ServiceError::Unauthorized("API key has been revoked".to_string()));
+        },
+        Some(key) => {
+            if let Some(limit) = key.rate_limit {
+                let window = Duration::from_secs(config.rate_limit_window_secs);
+                // Fails open like the service-wide limit
+                match cache.increment_counter(&format!("rate_limit:key:{}", key.id), window).await {
+                    Ok(count) if i64::from(count) > i64::from(limit) => {
+                        metrics().rate_limit_rejections.inc();
+                        return Err(ServiceError::RateLimitExceeded);
+                    },
+                    Ok(_) => {},
+                    Err(e) => log::warn!("Couldn't count request against API key {}: {}", key.id, e),
+                }
+            }
+        },
+        None if config.require_api_key => return Err(invalid()),
+        None => {},
+    }
+    Ok(())
+}
+
+// POST /admin/api-keys: the key itself is only part of this response
+pub async fn create_key(
+    req: web::Json<CreateKeyRequest>,
+    cache: web::Data<Arc<RedisCache>>,
+) -> ServiceResult<HttpResponse> {
+    let name = req.name.trim();
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return Err(ServiceError::InvalidParameter("name".to_string(), format!("must be 1-{} characters", MAX_NAME_LENGTH)));
+    }
+
+    let secret = format!("{}{}", KEY_PREFIX, hex::encode(rand::thread_rng().gen::<[u8; 32]>()));
+    let hash = hex::encode(Sha256::digest(secret.as_bytes()));
+    let key = ApiKey {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: name.to_string(),
+        prefix: secret[..KEY_PREFIX.len() + 8].to_string(),
+        created_at: chrono::Utc::now().to_rfc3339(),
+        revoked_at: None,
+        rate_limit: req.rate_limit,
+    };
+    save(&cache, &hash, &key).await?;
+    cache.set_persistent(&id_key(&key.id), hash.as_bytes()).await?;
+    log::info!("Created API key {} ({})", key.id, key.name);
+
+    let mut response = serde_json::to_value(&key).unwrap_or_default();
+    response["key"] = secret.into();
+    Ok(HttpResponse::Created().json(response))
+}
+
+// GET /admin/api-keys: every key, revoked ones included, without the keys themselves
+pub async fn list_keys(cache: web::Data<Arc<RedisCache>>) -> ServiceResult<HttpResponse> {
+    let mut keys = Vec::new();
+    for index in cache.keys_matching(&id_key("*")).await? {
+        let Some(hash) = cache.read(&index).await?.and_then(|hash| String::from_utf8(hash).ok()) else {
+            continue;
+        };
+        if let Some(key) = load(&cache, &hash).await? {
+            keys.push(key);
+        }
+    }
+    keys.sort_by(|a, b| a.created_at.cmp(&b.created_at));
+    Ok(HttpResponse::Ok().json(json!({ "keys": keys })))
+}
+
+// DELETE /admin/api-keys/{id}: the record stays, so the key shows up as revoked
+pub async fn revoke_key(
+    id: web::Path<String>,
+    cache: web::Data<Arc<RedisCache>>,
+) -> ServiceResult<HttpResponse> {
+    let (hash, mut key) = find(&cache, &id).await?;
+    if key.revoked_at.is_none() {
+        key.revoked_at = Some(chrono::Utc::now().to_rfc3339());
+        save(&cache, &hash, &key).await?;
+        log::info!("Revoked API key {} ({})", key.id, key.name);
+    }
+    Ok(HttpResponse::Ok().json(key))
+}
+
+// PUT /admin/api-keys/{id}/limits
+pub async fn set_limits(
+    id: web::Path<String>,
+    req: web::Json<LimitsRequest>,
+    cache: web::Data<Arc<RedisCache>>,
+) -> ServiceResult<HttpResponse> {
+    let (hash, mut key) = find(&cache, &id).await?;
+    key.rate_limit = req.rate_limit;
+    save(&cache, &hash, &key).await?;
+    Ok(HttpResponse::Ok().json(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(require_api_key: bool) -> Config {
+        Config { require_api_key, priority_api_keys: vec!["priority-key".to_string()], ..Config::default() }
+    }
+
+    // Nothing listens on port 1, so every command fails
+    fn unreachable_cache() -> RedisCache {
+        RedisCache::new("redis://127.0.0.1:1").unwrap()
+    }
+
+    #[tokio::test]
+    async fn requires_a_key_only_when_configured() {
+        assert!(authorize(&config(false), None, None).await.is_ok());
+        assert!(matches!(authorize(&config(true), None, None).await, Err(ServiceError::Unauthorized(_))));
+        assert!(matches!(authorize(&config(true), None, Some("other-key")).await, Err(ServiceError::Unauthorized(_))));
+    }
+
+    #[tokio::test]
+    async fn accepts_priority_keys_while_redis_is_down() {
+        let cache = unreachable_cache();
+        assert!(authorize(&config(true), Some(&cache), Some("priority-key")).await.is_ok());
+        assert!(authorize(&config(true), None, Some("priority-key")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn handles_redis_errors_by_whether_keys_are_required() {
+        let cache = unreachable_cache();
+        assert!(authorize(&config(false), Some(&cache), Some("sk_unknown")).await.is_ok());
+        assert!(matches!(
+            authorize(&config(true), Some(&cache), Some("sk_unknown")).await,
+            Err(ServiceError::UpstreamUnavailable(..))
+        ));
+    }
+}
@@ -0,0 +1,184 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use crate::cache::RedisCache;
+use crate::config::{self, Config};
+use crate::error::{ServiceResult, ServiceError};
+use crate::request_context::{is_operational_route, RequestFields};
+use crate::shutdown;
+
+const AUDIT_STREAM: &str = "audit:requests";
+const DEFAULT_QUERY_LIMIT: usize = 100;
+const MAX_QUERY_LIMIT: usize = 1000;
+
+static RETENTION: OnceLock<Option<Duration>> = OnceLock::new();
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditEntry {
+    pub timestamp: String,
+    pub request_id: String,
+    pub method: String,
+    pub route: String,
+    pub url: Option<String>,
+    pub params: String,
+    pub client: String,
+    pub client_ip: Option<String>,
+    pub status: u16,
+    pub outcome: String,
+    pub duration_ms: f64,
+    pub bytes: Option<u64>,
+    pub cache: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct AuditQuery {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub limit: Option<usize>,
+}
+
+pub fn configure(config: &Config) {
+    let retention = config.audit_log.then(|| Duration::from_secs(config.audit_retention_days * 24 * 60 * 60));
+    let _ = RETENTION.set(retention);
+}
+
+pub fn enabled() -> bool {
+    matches!(RETENTION.get(), Some(Some(_)))
+}
+
+// Builds the audit entry for a finished request, or None for requests that aren't audited
+pub fn entry(request_id: &str, fields: &RequestFields, query: &str, bytes: Option<u64>) -> Option<AuditEntry> {
+    let route = fields.route.clone()?;
+    if is_operational_route(&route) {
+        return None;
+    }
+
+    let url = url::form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == "url")
+        .map(|(_, value)| value.into_owned());
+    let status = fields.status.unwrap_or_default();
+
+    Some(AuditEntry {
+        timestamp: chrono::Utc::now().to_rfc3339(),
+        request_id: request_id.to_string(),
+        method: fields.method.clone(),
+        route,
+        url,
+        params: query.to_string(),
+        client: fields.client_id.clone()
+            .or_else(|| fields.client_ip.clone())
+            .unwrap_or_else(|| "-".to_string()),
+        client_ip: fields.client_ip.clone(),
+        status,
+        outcome: if status < 400 { "ok" } else { "error" }.to_string(),
+        duration_ms: fields.latency.map(|l| (l.as_secs_f64() * 100_000.0).round() / 100.0).unwrap_or_default(),
+        bytes,
+        cache: fields.cache.map(str::to_string),
+    })
+}
+
+// Writes the entry in the background so auditing never delays the response
+pub fn record(cache: Arc<RedisCache>, entry: AuditEntry) {
+    let Some(Some(retention)) = RETENTION.get().copied() else {
+        return;
+    };
+
+    shutdown::spawn(async move {
+        let data = match serde_json::to_string(&entry) {
+            Ok(data) => data,
+            Err(e) => return log::error!("Failed to serialize audit entry: {}", e),
+        };
+        if let Err(e) = cache.stream_add(AUDIT_STREAM, &[("entry", data)]).await {
+            return log::error!("Failed to write audit entry {}: {}", entry.request_id, e);
+        }
+
+        // Stream ids start with the insertion time in milliseconds
+        let cutoff = chrono::Utc::now().timestamp_millis() - retention.as_millis() as i64;
+        if let Err(e) = cache.stream_trim_before(AUDIT_STREAM, &cutoff.to_string()).await {
+            log::warn!("Failed to trim audit log: {}", e);
+        }
+    });
+}
+
+// Audit entries in a time range, newest first
+pub async fn audit_query(
+    req: web::Query<AuditQuery>,
+    cache: web::Data<Arc<RedisCache>>,
+) -> ServiceResult<HttpResponse> {
+    let config = config::current();
+    if !config.audit_log {
+        return Err(ServiceError::NotFound("Audit log is disabled, set AUDIT_LOG=true to enable it".to_string()));
+    }
+
+    let limit = req.limit.unwrap_or(DEFAULT_QUERY_LIMIT);
+    if limit == 0 || limit > MAX_QUERY_LIMIT {
+        return Err(ServiceError::ValidationError(
+            format!("limit must be between 1 and {}", MAX_QUERY_LIMIT)
+        ));
+    }
+
+    let start = match &req.from {
+        Some(from) => parse_time(from)?.timestamp_millis().to_string(),
+        None => "-".to_string(),
+    };
+    let end = match &req.to {
+        Some(to) => end_time(to)?.timestamp_millis().to_string(),
+        None => "+".to_string(),
+    };
+
+    let entries: Vec<AuditEntry> = cache.stream_range_rev(AUDIT_STREAM, &end, &start, limit)
+        .await?
+        .into_iter()
+        .filter_map(|id| id.get::<String>("entry"))
+        .filter_map(|data| serde_json::from_str(&data).ok())
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({
+        "count": entries.len(),
+        "entries": entries,
+    })))
+}
+
+// RFC 3339 timestamps, or plain dates meaning midnight UTC
+fn parse_time(value: &str) -> ServiceResult<chrono::DateTime<chrono::Utc>> {
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&chrono::Utc));
+    }
+    parse_date(value).map(|date| date.and_time(chrono::NaiveTime::MIN).and_utc())
+}
+
+// Inclusive end of a range: a plain date covers that whole day, up to the last
+// millisecond before the next midnight UTC
+fn end_time(value: &str) -> ServiceResult<chrono::DateTime<chrono::Utc>> {
+    if chrono::DateTime::parse_from_rfc3339(value).is_ok() {
+        return parse_time(value);
+    }
+    let next_day = parse_date(value)?.succ_opt()
+        .ok_or_else(|| ServiceError::ValidationError(format!("Invalid time: {}", value)))?;
+    Ok(next_day.and_time(chrono::NaiveTime::MIN).and_utc() - chrono::Duration::milliseconds(1))
+}
+
+fn parse_date(value: &str) -> ServiceResult<chrono::NaiveDate> {
+    chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| ServiceError::ValidationError(format!("Invalid time: {}", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn millis(value: &str) -> i64 {
+        chrono::DateTime::parse_from_rfc3339(value).unwrap().timestamp_millis()
+    }
+
+    #[test]
+    fn plain_end_dates_cover_the_whole_day() {
+        assert_eq!(parse_time("2024-01-02").unwrap().timestamp_millis(), millis("2024-01-02T00:00:00Z"));
+        assert_eq!(end_time("2024-01-02").unwrap().timestamp_millis(), millis("2024-01-02T23:59:59.999Z"));
+        assert_eq!(end_time("2024-01-02T12:00:00Z").unwrap().timestamp_millis(), millis("2024-01-02T12:00:00Z"));
+        assert!(end_time("2024-01-32").is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::cache::RedisCache;
+use crate::config::Config;
+use crate::error::ServiceResult;
+use crate::svg::RenderOptions;
+use crate::handlers::{render_cached, render_url};
+
+// Render request as published on the Kafka topic / NATS subject
+#[derive(Deserialize, Debug)]
+pub struct RenderMessage {
+    pub id: Option<String>,
+    pub url: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub preset: Option<String>,
+}
+
+// Completion event published after each render request
+#[derive(Serialize, Debug)]
+pub struct RenderEvent {
+    pub id: Option<String>,
+    pub url: Option<String>,
+    pub status: &'static str,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub size: Option<usize>,
+    #[serde(rename = "resultUrl")]
+    pub result_url: Option<String>,
+    pub error: Option<String>,
+}
+
+async fn render_message(message: &RenderMessage, config: &Config, cache: &RedisCache, client: &reqwest::Client) -> ServiceResult<(u32, u32, usize)> {
+    let (width, height) = config.resolve_size(message.width, message.height, message.preset.as_deref())?;
+    let png_data = render_cached(&message.url, &RenderOptions::new(width, height), cache, client).await?;
+    Ok((width, height, png_data.len()))
+}
+
+// Renders the request in `payload` into the cache and builds the completion event
+pub async fn handle_payload(payload: &[u8], config: &Config, cache: &RedisCache, client: &reqwest::Client) -> RenderEvent {
+    let message: RenderMessage = match serde_json::from_slice(payload) {
+        Ok(message) => message,
+        Err(e) => {
+            log::warn!("Ignoring malformed render message: {}", e);
+            return RenderEvent {
+                id: None,
+                url: None,
+                status: "failed",
+                width: None,
+                height: None,
+                size: None,
+                result_url: None,
+                error: Some(format!("Malformed render message: {}", e)),
+            };
+        }
+    };
+
+    log::info!("Rendering bus message {:?}", message);
+
+    match render_message(&message, config, cache, client).await {
+        Ok((width, height, size)) => RenderEvent {
+            result_url: Some(render_url(config, &message.url, width, height)),
+            id: message.id,
+            url: Some(message.url),
+            status: "completed",
+            width: Some(width),
+            height: Some(height),
+            size: Some(size),
+            error: None,
+        },
+        Err(e) => {
+            log::error!("Bus render of {} failed: {}", message.url, e);
+            RenderEvent {
+                id: message.id,
+                url: Some(message.url),
+                status: "failed",
+                width: None,
+                height: None,
+                size: None,
+                result_url: None,
+                error: Some(e.to_string()),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "kafka")]
+pub async fn run_kafka(
+    config: web::Data<Config>,
+    cache: Arc<RedisCache>,
+    client: reqwest::Client,
+) -> std::io::Result<()> {
+    use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+    use rdkafka::producer::{FutureProducer, FutureRecord};
+    use rdkafka::{ClientConfig, Message};
+
+    use crate::worker::Backoff;
+
+    let to_io = |e: rdkafka::error::KafkaError| std::io::Error::other(e.to_string());
+
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", &config.kafka_brokers)
+        .set("group.id", &config.kafka_group_id)
+        .set("enable.auto.commit", "false")
+        .create()
+        .map_err(to_io)?;
+    let producer: FutureProducer = ClientConfig::new()
+        .set("bootstrap.servers", &config.kafka_brokers)
+        .create()
+        .map_err(to_io)?;
+
+    consumer.subscribe(&[config.bus_request_topic.as_str()]).map_err(to_io)?;
+    log::info!("Consuming Kafka topic {} as group {}", config.bus_request_topic, config.kafka_group_id);
+
+    let mut backoff = Backoff::new();
+    loop {
+        let message = match consumer.recv().await {
+            Ok(message) => {
+                backoff.reset();
+                message
+            },
+            Err(e) => {
+                log::error!("Kafka receive failed: {}", e);
+                backoff.wait().await;
+                continue;
+            }
+        };
+
+        let event = handle_payload(message.payload().unwrap_or_default(), &config, &cache, &client).await;
+        let payload = serde_json::to_vec(&event).unwrap_or_default();
+        let key = event.id.clone().unwrap_or_default();
+
+        if let Err((e, _)) = producer
+            .send(
+                FutureRecord::to(&config.bus_event_topic).key(&key).payload(&payload),
+                std::time::Duration::from_secs(5),
+            )
+            .await
+        {
+            log::error!("Failed to publish Kafka render event: {}", e);
+        }
+
+        if let Err(e) = consumer.commit_message(&message, CommitMode::Async) {
+            log::error!("Failed to commit Kafka offset: {}", e);
+        }
+    }
+}
+
+#[cfg(feature = "nats")]
+pub async fn run_nats(
+    config: web::Data<Config>,
+    cache: Arc<RedisCache>,
+    client: reqwest::Client,
+) -> std::io::Result<()> {
+    use futures::StreamExt;
+
+    let nats = async_nats::connect(config.nats_url.as_str())
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    let mut subscriber = nats
+        .queue_subscribe(config.bus_request_topic.clone(), config.render_group.clone())
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+    log::info!("Consuming NATS subject {} as queue group {}", config.bus_request_topic, config.render_group);
+
+    while let Some(message) = subscriber.next().await {
+        let event = handle_payload(&message.payload, &config, &cache, &client).await;
+        let payload = bytes::Bytes::from(serde_json::to_vec(&event).unwrap_or_default());
+
+        // Requests sent with a reply subject also get the event as the response
+        if let Some(reply) = message.reply {
+            if let Err(e) = nats.publish(reply, payload.clone()).await {
+                log::error!("Failed to reply to NATS render request: {}", e);
+            }
+        }
+
+        if let Err(e) = nats.publish(config.bus_event_topic.clone(), payload).await {
+            log::error!("Failed to publish NATS render event: {}", e);
+        }
+    }
+
+    Ok(())
+}
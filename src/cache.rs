@@ -1,6 +1,14 @@
+use std::collections::HashMap;
 use std::time::Duration;
 use redis::AsyncCommands;
+use redis::streams::{StreamId, StreamRangeReply, StreamReadOptions, StreamReadReply};
 use crate::error::{ServiceResult, ServiceError};
+use crate::error_reporting;
+use crate::metrics::metrics;
+use crate::request_context;
+
+// Keys per DEL command when deleting by pattern
+const DELETE_BATCH_SIZE: usize = 500;
 
 #[derive(Clone)]
 pub struct RedisCache {
@@ -10,70 +18,285 @@ pub struct RedisCache {
 impl RedisCache {
     pub fn new(redis_url: &str) -> ServiceResult<Self> {
         let client = redis::Client::open(redis_url)
-            .map_err(|e| ServiceError::CacheError(format!("Failed to create Redis client: {}", e)))?;
+            .map_err(|e| cache_error(e, "Failed to create Redis client"))?;
         Ok(Self { client })
     }
 
     pub async fn initialize(&self) -> ServiceResult<()> {
         let mut conn = self.client.get_async_connection()
             .await
-            .map_err(|e| ServiceError::CacheError(format!("Failed to connect to Redis: {}", e)))?;
+            .map_err(|e| cache_error(e, "Failed to connect to Redis"))?;
             
         // Try a PING to verify connection
         redis::cmd("PING")
             .query_async::<_, String>(&mut conn)
             .await
-            .map_err(|e| ServiceError::CacheError(format!("Redis PING failed: {}", e)))?;
+            .map_err(|e| cache_error(e, "Redis PING failed"))?;
             
         Ok(())
     }
 
-    pub async fn get(&self, key: &str) -> ServiceResult<Option<Vec<u8>>> {
-        let mut conn = self.client.get_async_connection()
+    async fn connection(&self) -> ServiceResult<redis::aio::Connection> {
+        let _timer = metrics().redis_connection_duration.start_timer();
+        self.client.get_async_connection()
             .await
-            .map_err(|e| ServiceError::CacheError(format!("Failed to get Redis connection: {}", e)))?;
+            .map_err(|e| cache_error(e, "Failed to get Redis connection"))
+    }
+
+    pub async fn get(&self, key: &str) -> ServiceResult<Option<Vec<u8>>> {
+        let mut conn = self.connection().await?;
             
+        let timer = metrics().redis_command_duration.with_label_values(&["get"]).start_timer();
+        let value: Option<Vec<u8>> = conn.get(key)
+            .await
+            .map_err(|e| cache_error(e, format!("Failed to get key {}", key)))?;
+        timer.observe_duration();
+
+        metrics().observe_cache_lookup(key, value.is_some());
+        request_context::record_cache_lookup(value.is_some());
+        Ok(value)
+    }
+
+    // Reads a key that isn't a cache entry, without counting a cache hit or miss
+    pub async fn read(&self, key: &str) -> ServiceResult<Option<Vec<u8>>> {
+        let mut conn = self.connection().await?;
+
+        let _timer = metrics().redis_command_duration.with_label_values(&["get"]).start_timer();
         conn.get(key)
             .await
-            .map_err(|e| ServiceError::CacheError(format!("Failed to get key {}: {}", key, e)))
+            .map_err(|e| cache_error(e, format!("Failed to get key {}", key)))
     }
 
     pub async fn set(&self, key: &str, value: &[u8], expiry: Duration) -> ServiceResult<()> {
-        let mut conn = self.client.get_async_connection()
-            .await
-            .map_err(|e| ServiceError::CacheError(format!("Failed to get Redis connection: {}", e)))?;
+        let mut conn = self.connection().await?;
             
+        let _timer = metrics().redis_command_duration.with_label_values(&["set"]).start_timer();
         conn.set_ex(key, value, expiry.as_secs() as usize)
             .await
-            .map_err(|e| ServiceError::CacheError(format!("Failed to set key {}: {}", key, e)))
+            .map_err(|e| cache_error(e, format!("Failed to set key {}", key)))
     }
 
-    pub async fn increment_counter(&self, key: &str, window: Duration) -> ServiceResult<i32> {
-        let mut conn = self.client.get_async_connection()
+    // For data that has to outlive CACHE_TTL, e.g. stored templates
+    pub async fn set_persistent(&self, key: &str, value: &[u8]) -> ServiceResult<()> {
+        let mut conn = self.connection().await?;
+
+        let _timer = metrics().redis_command_duration.with_label_values(&["set"]).start_timer();
+        conn.set(key, value)
+            .await
+            .map_err(|e| cache_error(e, format!("Failed to set key {}", key)))
+    }
+
+    // SET NX with an expiry, true when the key was set
+    pub async fn set_if_absent(&self, key: &str, value: &[u8], expiry: Duration) -> ServiceResult<bool> {
+        let mut conn = self.connection().await?;
+
+        let _timer = metrics().redis_command_duration.with_label_values(&["set"]).start_timer();
+        let set: Option<String> = redis::cmd("SET")
+            .arg(key)
+            .arg(value)
+            .arg("NX")
+            .arg("EX")
+            .arg(expiry.as_secs())
+            .query_async(&mut conn)
+            .await
+            .map_err(|e| cache_error(e, format!("Failed to set key {}", key)))?;
+        Ok(set.is_some())
+    }
+
+    pub async fn delete(&self, key: &str) -> ServiceResult<()> {
+        let mut conn = self.connection().await?;
+
+        let _timer = metrics().redis_command_duration.with_label_values(&["del"]).start_timer();
+        conn.del(key)
             .await
-            .map_err(|e| ServiceError::CacheError(format!("Failed to get Redis connection: {}", e)))?;
+            .map_err(|e| cache_error(e, format!("Failed to delete key {}", key)))
+    }
+
+    pub async fn increment_counter(&self, key: &str, window: Duration) -> ServiceResult<i32> {
+        let mut conn = self.connection().await?;
             
-        let count: i32 = redis::pipe()
+        let (count,): (i32,) = redis::pipe()
             .atomic()
             .incr(key, 1)
-            .expire(key, window.as_secs() as usize)
+            .expire(key, window.as_secs() as usize).ignore()
             .query_async(&mut conn)
             .await
-            .map_err(|e| ServiceError::CacheError(format!("Failed to increment counter {}: {}", key, e)))?;
+            .map_err(|e| cache_error(e, format!("Failed to increment counter {}", key)))?;
             
         Ok(count)
     }
 
+    // Increments several hash fields at once and (re)sets the hash's expiry
+    pub async fn hash_increment(&self, key: &str, increments: &[(String, i64)], expiry: Duration) -> ServiceResult<()> {
+        let mut conn = self.connection().await?;
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for (field, delta) in increments {
+            pipe.hincr(key, field, *delta).ignore();
+        }
+        pipe.expire(key, expiry.as_secs() as usize).ignore();
+
+        pipe.query_async(&mut conn)
+            .await
+            .map_err(|e| cache_error(e, format!("Failed to increment {}", key)))
+    }
+
+    pub async fn hash_get_all(&self, key: &str) -> ServiceResult<HashMap<String, i64>> {
+        let mut conn = self.connection().await?;
+
+        conn.hgetall(key)
+            .await
+            .map_err(|e| cache_error(e, format!("Failed to read {}", key)))
+    }
+
+    // Every key matching a SCAN pattern
+    pub async fn keys_matching(&self, pattern: &str) -> ServiceResult<Vec<String>> {
+        let mut conn = self.connection().await?;
+
+        let mut iter = conn.scan_match::<_, String>(pattern)
+            .await
+            .map_err(|e| cache_error(e, format!("Failed to scan {}", pattern)))?;
+        let mut keys = Vec::new();
+        while let Some(key) = iter.next_item().await {
+            keys.push(key);
+        }
+        Ok(keys)
+    }
+
+    // Deletes every key matching a SCAN pattern, returning how many were removed
+    pub async fn delete_matching(&self, pattern: &str) -> ServiceResult<usize> {
+        let keys = self.keys_matching(pattern).await?;
+        let mut conn = self.connection().await?;
+
+        let mut deleted = 0;
+        for chunk in keys.chunks(DELETE_BATCH_SIZE) {
+            deleted += conn.del::<_, usize>(chunk)
+                .await
+                .map_err(|e| cache_error(e, format!("Failed to delete keys matching {}", pattern)))?;
+        }
+        Ok(deleted)
+    }
+
     pub async fn check_connection(&self) -> ServiceResult<()> {
         let mut conn = self.client.get_async_connection()
             .await
-            .map_err(|e| ServiceError::CacheError(format!("Redis connection failed: {}", e)))?;
+            .map_err(|e| cache_error(e, "Redis connection failed"))?;
 
         redis::cmd("PING")
             .query_async::<_, String>(&mut conn)
             .await
-            .map_err(|e| ServiceError::CacheError(format!("Redis PING failed: {}", e)))?;
+            .map_err(|e| cache_error(e, "Redis PING failed"))?;
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    pub async fn stream_create_group(&self, stream: &str, group: &str) -> ServiceResult<()> {
+        let mut conn = self.connection().await?;
+
+        let result: redis::RedisResult<()> = conn.xgroup_create_mkstream(stream, group, "0").await;
+        match result {
+            Ok(()) => Ok(()),
+            // The group already exists, which is fine when several workers start up
+            Err(e) if e.code() == Some("BUSYGROUP") => Ok(()),
+            Err(e) => Err(cache_error(e, format!("Failed to create group {} on {}", group, stream))),
+        }
+    }
+
+    pub async fn stream_add(&self, stream: &str, fields: &[(&str, String)]) -> ServiceResult<String> {
+        let mut conn = self.connection().await?;
+
+        conn.xadd(stream, "*", fields)
+            .await
+            .map_err(|e| cache_error(e, format!("Failed to add to stream {}", stream)))
+    }
+
+    // Drops entries older than `min_id`, approximately, which lets Redis trim whole nodes
+    pub async fn stream_trim_before(&self, stream: &str, min_id: &str) -> ServiceResult<()> {
+        let mut conn = self.connection().await?;
+
+        redis::cmd("XTRIM")
+            .arg(stream)
+            .arg("MINID")
+            .arg("~")
+            .arg(min_id)
+            .query_async::<_, usize>(&mut conn)
+            .await
+            .map(|_| ())
+            .map_err(|e| cache_error(e, format!("Failed to trim stream {}", stream)))
+    }
+
+    pub async fn stream_range_rev(&self, stream: &str, end: &str, start: &str, count: usize) -> ServiceResult<Vec<StreamId>> {
+        let mut conn = self.connection().await?;
+
+        let reply: StreamRangeReply = conn.xrevrange_count(stream, end, start, count)
+            .await
+            .map_err(|e| cache_error(e, format!("Failed to read stream {}", stream)))?;
+
+        Ok(reply.ids)
+    }
+
+    pub async fn stream_read_group(
+        &self,
+        stream: &str,
+        group: &str,
+        consumer: &str,
+        id: &str,
+        block: Duration,
+        count: usize,
+    ) -> ServiceResult<Vec<StreamId>> {
+        let mut conn = self.connection().await?;
+
+        let options = StreamReadOptions::default()
+            .group(group, consumer)
+            .block(block.as_millis() as usize)
+            .count(count);
+
+        let reply: Option<StreamReadReply> = conn.xread_options(&[stream], &[id], &options)
+            .await
+            .map_err(|e| cache_error(e, format!("Failed to read stream {}", stream)))?;
+
+        Ok(reply
+            .map(|r| r.keys.into_iter().flat_map(|k| k.ids).collect())
+            .unwrap_or_default())
+    }
+
+    pub async fn stream_ack(&self, stream: &str, group: &str, id: &str) -> ServiceResult<()> {
+        let mut conn = self.connection().await?;
+
+        conn.xack(stream, group, &[id])
+            .await
+            .map_err(|e| cache_error(e, format!("Failed to ack {} on {}", id, stream)))
+    }
+}
+
+// Every Redis failure goes through here so it shows up in the metrics. Only
+// unexpected ones are reported: an unreachable Redis fails every request and is
+// better seen in the metrics than as a flood of identical reports.
+fn cache_error(error: redis::RedisError, context: impl std::fmt::Display) -> ServiceError {
+    metrics().redis_errors.inc();
+    let cache_error = ServiceError::CacheError(format!("{}: {}", context, error));
+    if is_unavailable(&error) {
+        return cache_error;
+    }
+    error_reporting::report(cache_error)
+}
+
+fn is_unavailable(error: &redis::RedisError) -> bool {
+    error.is_io_error() || error.is_connection_refusal() || error.is_connection_dropped() || error.is_timeout()
+        || matches!(error.kind(), redis::ErrorKind::BusyLoadingError | redis::ErrorKind::TryAgain | redis::ErrorKind::ClusterDown | redis::ErrorKind::MasterDown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_only_unexpected_redis_errors() {
+        let refused = std::io::Error::from(std::io::ErrorKind::ConnectionRefused);
+        assert!(is_unavailable(&redis::RedisError::from(refused)));
+        assert!(is_unavailable(&redis::RedisError::from((redis::ErrorKind::BusyLoadingError, "loading"))));
+        assert!(!is_unavailable(&redis::RedisError::from((redis::ErrorKind::TypeError, "wrong type"))));
+        assert!(!is_unavailable(&redis::RedisError::from((redis::ErrorKind::ResponseError, "ERR syntax"))));
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::error::{ServiceResult, ServiceError};
+use crate::metrics::metrics;
+
+// Above this many tracked hosts, hosts without recent fetches are forgotten
+const MAX_TRACKED_HOSTS: usize = 1000;
+
+static BREAKER: OnceLock<CircuitBreaker> = OnceLock::new();
+
+// Stops fetching from a source host whose recent fetches mostly failed, so
+// requests for it fail immediately instead of each waiting for a timeout
+struct CircuitBreaker {
+    error_rate: f64,
+    min_requests: u32,
+    window: Duration,
+    open_for: Duration,
+    hosts: Mutex<HashMap<String, HostCircuit>>,
+}
+
+struct HostCircuit {
+    state: State,
+    window_start: Instant,
+    requests: u32,
+    failures: u32,
+}
+
+enum State {
+    Closed,
+    // Fetches fail fast until the deadline
+    Open { until: Instant },
+    // A single trial fetch decides whether the circuit closes again
+    HalfOpen { probe_started: Instant },
+}
+
+pub fn configure(config: &Config) {
+    if config.circuit_breaker_error_rate > 0.0 {
+        let _ = BREAKER.set(CircuitBreaker {
+            error_rate: config.circuit_breaker_error_rate,
+            min_requests: config.circuit_breaker_min_requests,
+            window: Duration::from_secs(config.circuit_breaker_window_secs),
+            open_for: Duration::from_secs(config.circuit_breaker_open_secs),
+            hosts: Mutex::new(HashMap::new()),
+        });
+    }
+}
+
+// Fails fast when the circuit for the URL's host is open
+pub fn check(url: &str) -> ServiceResult<()> {
+    let (Some(breaker), Some(host)) = (BREAKER.get(), host(url)) else {
+        return Ok(());
+    };
+
+    let mut hosts = breaker.hosts.lock().unwrap();
+    let Some(circuit) = hosts.get_mut(&host) else {
+        return Ok(());
+    };
+
+    let now = Instant::now();
+    let retry_after = match circuit.state {
+        State::Closed => return Ok(()),
+        State::Open { until } if now >= until => {
+            log::info!("Circuit for {} is half-open, trying one fetch", host);
+            circuit.state = State::HalfOpen { probe_started: now };
+            return Ok(());
+        },
+        // The trial fetch never reported back, allow another one
+        State::HalfOpen { probe_started } if now.duration_since(probe_started) >= breaker.open_for => {
+            circuit.state = State::HalfOpen { probe_started: now };
+            return Ok(());
+        },
+        State::Open { until } => until.duration_since(now),
+        State::HalfOpen { probe_started } => breaker.open_for.saturating_sub(now.duration_since(probe_started)),
+    };
+
+    metrics().upstream_circuit_rejections.inc();
+    Err(ServiceError::UpstreamUnavailable(
+        format!("{} is failing, fetches from it are paused", host),
+        (retry_after.as_millis() as u64).div_ceil(1000).max(1),
+    ))
+}
+
+// Records the outcome of a fetch, failed meaning the host itself failed
+// (5xx, 429, timeout or connection error) rather than the source being invalid
+pub fn record(url: &str, failed: bool) {
+    let (Some(breaker), Some(host)) = (BREAKER.get(), host(url)) else {
+        return;
+    };
+
+    let now = Instant::now();
+    let mut hosts = breaker.hosts.lock().unwrap();
+    if hosts.len() >= MAX_TRACKED_HOSTS && !hosts.contains_key(&host) {
+        hosts.retain(|_, circuit| !matches!(circuit.state, State::Closed) || now.duration_since(circuit.window_start) < breaker.window);
+    }
+
+    let circuit = hosts.entry(host.clone()).or_insert_with(|| HostCircuit {
+        state: State::Closed,
+        window_start: now,
+        requests: 0,
+        failures: 0,
+    });
+
+    match circuit.state {
+        State::HalfOpen { .. } if failed => {
+            log::warn!("Trial fetch from {} failed, circuit stays open for {}s", host, breaker.open_for.as_secs());
+            circuit.state = State::Open { until: now + breaker.open_for };
+            metrics().upstream_circuit_opens.inc();
+        },
+        State::HalfOpen { .. } => {
+            log::info!("Trial fetch from {} succeeded, circuit closed", host);
+            circuit.state = State::Closed;
+            circuit.window_start = now;
+            circuit.requests = 0;
+            circuit.failures = 0;
+        },
+        // Fetches that started before the circuit opened
+        State::Open { .. } => {},
+        State::Closed => {
+            if now.duration_since(circuit.window_start) >= breaker.window {
+                circuit.window_start = now;
+                circuit.requests = 0;
+                circuit.failures = 0;
+            }
+            circuit.requests += 1;
+            if failed {
+                circuit.failures += 1;
+            }
+
+            let error_rate = circuit.failures as f64 / circuit.requests as f64;
+            if circuit.requests >= breaker.min_requests && error_rate >= breaker.error_rate {
+                log::warn!("{} of the last {} fetches from {} failed, opening circuit for {}s",
+                    circuit.failures, circuit.requests, host, breaker.open_for.as_secs());
+                circuit.state = State::Open { until: now + breaker.open_for };
+                metrics().upstream_circuit_opens.inc();
+            }
+        },
+    }
+}
+
+fn host(url: &str) -> Option<String> {
+    url::Url::parse(url).ok().and_then(|u| u.host_str().map(|h| h.to_lowercase()))
+}
@@ -0,0 +1,102 @@
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+// Flags are shorthands for the environment variables of the same settings and
+// take precedence over them, so a configuration reload keeps them
+#[derive(Parser)]
+#[command(version, about = "SVG to PNG rasterization service")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Configuration file, TOML or YAML (CONFIG_PATH)
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<String>,
+
+    /// File with NAME=VALUE settings (ENV_FILE)
+    #[arg(long, global = true, value_name = "PATH")]
+    pub env_file: Option<String>,
+
+    /// Port to listen on (PORT)
+    #[arg(long, global = true)]
+    pub port: Option<u16>,
+
+    /// Redis connection string (REDIS_URL)
+    #[arg(long, global = true, value_name = "URL")]
+    pub redis_url: Option<String>,
+
+    /// Log filter, e.g. info or warn,svg_rasterizer=debug (RUST_LOG)
+    #[arg(long, global = true, value_name = "FILTER")]
+    pub log_level: Option<String>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the service (default)
+    Serve,
+    /// Validate the configuration and exit
+    CheckConfig,
+    /// Render one SVG to a file, without the HTTP server, Redis or rate limiting
+    Rasterize(RasterizeArgs),
+}
+
+#[derive(Args)]
+pub struct RasterizeArgs {
+    /// SVG file, or an http(s) URL fetched the way the service fetches it
+    #[arg(required_unless_present = "stdin", conflicts_with = "stdin")]
+    pub input: Option<String>,
+
+    /// Read the SVG from stdin, and write the image to stdout unless --output is given
+    #[arg(long)]
+    pub stdin: bool,
+
+    /// Output file
+    #[arg(short, long, value_name = "PATH", required_unless_present = "stdin")]
+    pub output: Option<PathBuf>,
+
+    /// Output width, sized like a render request when any size is given
+    #[arg(long)]
+    pub width: Option<u32>,
+
+    /// Output height
+    #[arg(long)]
+    pub height: Option<u32>,
+
+    /// Named size from SIZE_PRESETS
+    #[arg(long)]
+    pub preset: Option<String>,
+
+    /// png, webp, jpeg, gif or avif (default: from the output file extension, else png)
+    #[arg(long)]
+    pub format: Option<String>,
+
+    /// JPEG, WebP and AVIF quality, 1-100 (default: JPEG_QUALITY, WEBP_QUALITY or AVIF_QUALITY)
+    #[arg(long)]
+    pub quality: Option<u8>,
+
+    /// Lossless WebP (default: WEBP_LOSSLESS)
+    #[arg(long)]
+    pub lossless: bool,
+
+    /// Background color, e.g. ffffff or ffffff80
+    #[arg(long, value_name = "COLOR")]
+    pub background: Option<String>,
+}
+
+impl Cli {
+    // Must run before any threads are started
+    pub fn apply(&self) {
+        let flags = [
+            ("CONFIG_PATH", self.config.clone()),
+            ("ENV_FILE", self.env_file.clone()),
+            ("PORT", self.port.map(|port| port.to_string())),
+            ("REDIS_URL", self.redis_url.clone()),
+            ("RUST_LOG", self.log_level.clone()),
+        ];
+        for (name, value) in flags {
+            if let Some(value) = value {
+                std::env::set_var(name, value);
+            }
+        }
+    }
+}
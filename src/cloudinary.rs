@@ -0,0 +1,130 @@
+use crate::error::{ServiceError, ServiceResult};
+use crate::handlers::SvgRequest;
+
+// Cloudinary transformation strings, `w_400,h_300,c_fit,bg_white,f_webp`, for teams
+// moving URLs over from a hosted image service. Only the parameters that mean
+// something for a rendered SVG are understood, anything else is rejected rather
+// than silently rendering something different.
+
+// Colors Cloudinary URLs commonly use by name, as parse_color's hex
+const NAMED_COLORS: &[(&str, &str)] = &[
+    ("white", "ffffff"),
+    ("black", "000000"),
+    ("red", "ff0000"),
+    ("green", "008000"),
+    ("blue", "0000ff"),
+    ("yellow", "ffff00"),
+    ("gray", "808080"),
+    ("grey", "808080"),
+    ("transparent", "transparent"),
+];
+
+// Applies a whole transformation, overriding the request's own parameters. Chained
+// transformations (separated by `/`) aren't supported.
+pub fn apply(req: &mut SvgRequest, transformation: &str) -> ServiceResult<()> {
+    if transformation.contains('/') {
+        return Err(invalid("t", "chained transformations are not supported"));
+    }
+    for component in transformation.split(',').filter(|component| !component.is_empty()) {
+        apply_component(req, component)?;
+    }
+    Ok(())
+}
+
+// A single `name_value` component
+pub fn apply_component(req: &mut SvgRequest, component: &str) -> ServiceResult<()> {
+    let (name, value) = component.split_once('_')
+        .ok_or_else(|| invalid(component, "components are written as name_value"))?;
+    let number = || value.parse::<u32>().map_err(|_| invalid(name, "must be a whole number of pixels"));
+
+    match name {
+        "w" => req.width = Some(number()?),
+        "h" => req.height = Some(number()?),
+        // Renders fit the SVG within the size, padding the rest, or stretch it over the size
+        "c" => match value {
+            "fit" | "limit" | "pad" => req.stretch = Some(false),
+            "scale" => req.stretch = Some(true),
+            _ => return Err(invalid(name, "only the fit, limit, pad and scale crop modes are supported")),
+        },
+        "b" | "bg" => req.background = Some(color(value).ok_or_else(|| invalid(name, "must be rgb:<hex> or a color name"))?),
+        "f" => req.format = Some(value.to_string()),
+        "r" => req.radius = Some(number()?),
+        // q_auto is the configured default
+        "q" => req.quality = match value {
+            "auto" => None,
+            _ => Some(value.parse().map_err(|_| invalid(name, "must be a quality from 1 to 100 or auto"))?),
+        },
+        "t" => req.preset = Some(value.to_string()),
+        _ => return Err(invalid(name, "unsupported transformation parameter")),
+    }
+    Ok(())
+}
+
+// `rgb:ff0000`, `rgb:ff000080` or a color name
+fn color(value: &str) -> Option<String> {
+    match value.strip_prefix("rgb:") {
+        Some(hex) => Some(hex.to_string()),
+        None => NAMED_COLORS.iter()
+            .find(|(name, _)| value.eq_ignore_ascii_case(name))
+            .map(|(_, hex)| hex.to_string()),
+    }
+}
+
+fn invalid(name: &str, message: &str) -> ServiceError {
+    ServiceError::InvalidParameter(name.to_string(), message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::is_invalid;
+
+    fn applied(transformation: &str) -> ServiceResult<SvgRequest> {
+        let mut req = SvgRequest::default();
+        apply(&mut req, transformation).map(|_| req)
+    }
+
+    #[test]
+    fn applies_a_transformation() {
+        let req = applied("w_400,h_300,c_fit,bg_white,f_webp,r_12,q_80,t_thumb").unwrap();
+        assert_eq!((req.width, req.height), (Some(400), Some(300)));
+        assert_eq!(req.stretch, Some(false));
+        assert_eq!(req.background.as_deref(), Some("ffffff"));
+        assert_eq!(req.format.as_deref(), Some("webp"));
+        assert_eq!(req.radius, Some(12));
+        assert_eq!(req.quality, Some(80));
+        assert_eq!(req.preset.as_deref(), Some("thumb"));
+    }
+
+    #[test]
+    fn maps_crop_modes_and_colors() {
+        assert_eq!(applied("c_scale").unwrap().stretch, Some(true));
+        assert_eq!(applied("c_pad").unwrap().stretch, Some(false));
+        assert_eq!(applied("b_rgb:ff000080").unwrap().background.as_deref(), Some("ff000080"));
+        assert_eq!(applied("bg_Grey").unwrap().background.as_deref(), Some("808080"));
+    }
+
+    #[test]
+    fn q_auto_leaves_the_default_quality() {
+        let mut req = SvgRequest { quality: Some(50), ..SvgRequest::default() };
+        apply(&mut req, "q_auto").unwrap();
+        assert_eq!(req.quality, None);
+    }
+
+    #[test]
+    fn skips_empty_components() {
+        assert_eq!(applied("w_10,,h_20,").unwrap().height, Some(20));
+    }
+
+    #[test]
+    fn rejects_malformed_components() {
+        assert!(is_invalid(applied("w400"), "w400"));
+        assert!(is_invalid(applied("w_wide"), "w"));
+        assert!(is_invalid(applied("w_-1"), "w"));
+        assert!(is_invalid(applied("c_crop"), "c"));
+        assert!(is_invalid(applied("bg_chartreuse"), "bg"));
+        assert!(is_invalid(applied("q_best"), "q"));
+        assert!(is_invalid(applied("e_sepia"), "e"));
+        assert!(is_invalid(applied("w_100/h_100"), "t"));
+    }
+}
@@ -1,4 +1,200 @@
-#[derive(Clone, Debug)]
+use actix_web::web;
+use arc_swap::ArcSwap;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+// Connection-level headers that would break the upstream request if copied over
+const NON_FORWARDABLE_HEADERS: [&str; 8] = [
+    "host", "connection", "content-length", "transfer-encoding", "upgrade", "te", "keep-alive", "proxy-authorization",
+];
+
+// Built-in preset for iOS home screen icons, can be overridden through SIZE_PRESETS
+pub const APPLE_TOUCH_PRESET: &str = "apple-touch-icon";
+const APPLE_TOUCH_SIZE: u32 = 180;
+
+// Settings given in CONFIG_PATH as a table that are passed on as JSON, or as name=value pairs
+const JSON_SETTINGS: [&str; 2] = ["FETCH_HEADERS", "TENANTS"];
+const PAIR_SETTINGS: [&str; 1] = ["SIZE_PRESETS"];
+
+// Settings that may hold credentials, kept out of error messages and GET /admin/config
+const SECRET_SETTINGS: [&str; 13] = [
+    "REDIS_URL", "WEBHOOK_SECRET", "S3_ACCESS_KEY_ID", "S3_SECRET_ACCESS_KEY", "SENTRY_DSN",
+    "ADMIN_TOKEN", "PRIORITY_API_KEYS", "FETCH_PROXY", "FETCH_PROXY_PASSWORD", "SIGNED_URL_SECRET",
+    "IMGPROXY_KEY", "IMGPROXY_SALT", "CONTENT_SIGNATURE_KEY",
+];
+const REDACTED: &str = "[redacted]";
+
+// The configuration in effect, replaced as a whole when it is reloaded
+static CURRENT: OnceLock<ArcSwap<Config>> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RunMode {
+    Server,
+    Worker,
+    #[cfg(feature = "kafka")]
+    Kafka,
+    #[cfg(feature = "nats")]
+    Nats,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputMode {
+    // Respond with the rendered image
+    Image,
+    // Upload to object storage and redirect to the CDN-fronted object
+    CdnRedirect,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobQueue {
+    // Jobs run as tasks inside the HTTP server process
+    Local,
+    // Jobs are pushed onto the render stream for worker processes
+    Stream,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    Text,
+    // One JSON object per line, with request context fields
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogDestination {
+    // Through the application logger, so LOG_FORMAT applies
+    Stdout,
+    Off,
+    File(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    Daily,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Listener {
+    Http(SocketAddr),
+    // Needs TLS_CERT_PATH and TLS_KEY_PATH
+    Https(SocketAddr),
+    Unix(String),
+    // Serves the /admin routes, which other listeners then don't
+    Admin(SocketAddr),
+}
+
+// Failure classes of a source fetch that are worth retrying
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    ServerError,
+    TooManyRequests,
+    Timeout,
+    Connect,
+}
+
+// Address family source fetches connect over
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IpVersion {
+    Any,
+    V4,
+    V6,
+}
+
+// How X-Content-Signature headers are computed
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignatureAlgorithm {
+    HmacSha256,
+    // Verifiable with the public key from GET /content-signature-key
+    Ed25519,
+}
+
+// A CIDR block such as 10.0.0.0/8, or a single address
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct IpNetwork {
+    pub addr: IpAddr,
+    pub prefix: u8,
+}
+
+// Policies for one tenant's requests, replacing the service-wide settings they set
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TenantProfile {
+    // Keys identifying the tenant's requests, as sent in X-Api-Key
+    #[serde(default)]
+    pub api_keys: Vec<String>,
+    // SHA-256 hex digests of keys, as stored for tenants managed through the admin API
+    #[serde(default)]
+    pub api_key_hashes: Vec<String>,
+    // Hosts the tenant's requests arrive on, `*.example.com` for any subdomain
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    pub rate_limit: Option<u32>,
+    #[serde(default)]
+    pub allowed_domains: Vec<String>,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub cache_namespace: Option<String>,
+}
+
+impl TenantProfile {
+    // The service-wide configuration with this tenant's policies applied
+    pub fn apply(&self, base: &Config) -> Config {
+        let mut config = base.clone();
+        if let Some(rate_limit) = self.rate_limit {
+            config.rate_limit = rate_limit;
+        }
+        if !self.allowed_domains.is_empty() {
+            config.allowed_source_domains = self.allowed_domains.clone();
+        }
+        if let Some(max_width) = self.max_width {
+            config.max_width = max_width;
+        }
+        if let Some(max_height) = self.max_height {
+            config.max_height = max_height;
+        }
+        if let Some(namespace) = &self.cache_namespace {
+            config.cache_namespace = namespace.clone();
+        }
+        config
+    }
+
+    // Problems with the profile, for Config::validate and the admin API
+    pub fn problems(&self, name: &str) -> Vec<String> {
+        let mut problems = Vec::new();
+        if !is_identifier(name) {
+            problems.push(format!("Tenant name {:?} must be letters, digits, '-' or '_'", name));
+        }
+        if self.cache_namespace.as_deref().is_some_and(|namespace| !is_identifier(namespace)) {
+            problems.push(format!("Tenant {} cache_namespace must be letters, digits, '-' or '_'", name));
+        }
+        if self.max_width == Some(0) || self.max_height == Some(0) {
+            problems.push(format!("Tenant {} max_width and max_height must be at least 1", name));
+        }
+        problems
+    }
+}
+
+fn is_identifier(value: &str) -> bool {
+    !value.is_empty() && value.len() <= 64 && value.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[derive(Clone, Debug, Serialize)]
 pub struct Config {
     pub port: u16,
     pub redis_url: String,
@@ -7,6 +203,197 @@ pub struct Config {
     pub default_width: u32,
     pub default_height: u32,
     pub min_dimension: u32,
+    pub max_srcset_widths: usize,
+    // overlay= parameters per render, each one another SVG to fetch
+    pub max_overlays: usize,
+    pub public_base_url: String,
+    pub presets: HashMap<String, (u32, u32)>,
+    pub presets_only: bool,
+    pub job_ttl_secs: u64,
+    pub webhook_secret: Option<String>,
+    pub run_mode: RunMode,
+    pub job_queue: JobQueue,
+    pub render_stream: String,
+    pub render_group: String,
+    pub stream_max_attempts: u32,
+    pub kafka_brokers: String,
+    pub kafka_group_id: String,
+    pub nats_url: String,
+    pub bus_request_topic: String,
+    pub bus_event_topic: String,
+    pub s3_bucket: Option<String>,
+    pub s3_prefix: String,
+    pub s3_region: String,
+    pub s3_endpoint: Option<String>,
+    pub s3_access_key_id: Option<String>,
+    pub s3_secret_access_key: Option<String>,
+    pub s3_public_url: Option<String>,
+    pub output_mode: OutputMode,
+    pub redirect_max_age: u64,
+    pub max_sprites: usize,
+    pub apple_touch_background: String,
+    pub deterministic_rendering: bool,
+    pub font_dir: Option<String>,
+    pub lqip_width: u32,
+    // Frame sampling of animate=true renders
+    pub animation_default_fps: u32,
+    pub animation_max_fps: u32,
+    pub animation_max_duration_secs: u32,
+    // Pixels over all frames of an animation, which are held in memory until encoded
+    pub animation_max_pixels: u64,
+    // format=auto renders above this many pixels aren't encoded in every candidate
+    // format to compare sizes, they get the client's preferred format
+    pub auto_format_max_pixels: u64,
+    // Encoder quality, 1-100, when a request doesn't ask for one
+    pub jpeg_quality: u8,
+    pub webp_quality: u8,
+    pub avif_quality: u8,
+    // WebP output is lossless unless a request asks otherwise
+    pub webp_lossless: bool,
+    pub otel_endpoint: Option<String>,
+    pub otel_service_name: String,
+    pub log_format: LogFormat,
+    pub log_redact_urls: bool,
+    pub access_log: AccessLogDestination,
+    pub access_log_format: String,
+    pub access_log_rotation: LogRotation,
+    pub access_log_max_files: usize,
+    pub slow_request_ms: u64,
+    pub sentry_dsn: Option<String>,
+    pub admin_token: Option<String>,
+    // CA whose client certificates admin listeners require, in place of ADMIN_TOKEN
+    pub admin_client_ca: Option<String>,
+    pub audit_log: bool,
+    pub audit_retention_days: u64,
+    pub usage_tracking: bool,
+    pub usage_retention_days: u64,
+    pub render_workers: usize,
+    pub max_concurrent_renders: usize,
+    pub render_queue_size: usize,
+    pub load_shed_queue_depth: usize,
+    pub load_shed_wait_ms: u64,
+    pub load_shed_retry_after: u64,
+    pub priority_api_keys: Vec<String>,
+    pub priority_networks: Vec<IpNetwork>,
+    pub pixmap_pool_max_bytes: usize,
+    pub tree_cache_max_bytes: usize,
+    pub render_threads: usize,
+    pub cpu_throttle_threshold: u8,
+    pub http_workers: Option<usize>,
+    pub http_max_connections: Option<usize>,
+    pub http_request_timeout_ms: u64,
+    pub http_keep_alive_secs: u64,
+    pub fetch_pool_max_idle_per_host: Option<usize>,
+    pub fetch_pool_idle_timeout_secs: u64,
+    pub fetch_connect_timeout_ms: u64,
+    pub fetch_tcp_keepalive_secs: u64,
+    pub fetch_max_per_host: usize,
+    pub dns_cache: bool,
+    pub dns_cache_max_ttl: u64,
+    pub fetch_proxy: Option<String>,
+    pub fetch_no_proxy: Option<String>,
+    pub fetch_proxy_username: Option<String>,
+    pub fetch_proxy_password: Option<String>,
+    pub fetch_user_agent: Option<String>,
+    // Extra headers for fetches from matching hosts ("assets.example.com" or "*.example.com")
+    pub fetch_headers: HashMap<String, Vec<(String, String)>>,
+    // Lowercase names of incoming request headers passed on to source fetches
+    pub forward_headers: Vec<String>,
+    // Hosts forwarded headers go to besides the FETCH_HEADERS hosts, "*.example.com" for subdomains
+    pub forward_headers_hosts: Vec<String>,
+    // Additional attempts after a failed fetch, 0 disables retries
+    pub fetch_retries: u32,
+    pub fetch_retry_backoff_ms: u64,
+    pub fetch_retry_max_backoff_ms: u64,
+    pub fetch_retry_jitter: bool,
+    pub fetch_retry_on: Vec<RetryOn>,
+    // Share of failed fetches from a host that opens its circuit, 0 disables the breaker
+    pub circuit_breaker_error_rate: f64,
+    pub circuit_breaker_min_requests: u32,
+    pub circuit_breaker_window_secs: u64,
+    pub circuit_breaker_open_secs: u64,
+    // Placeholder SVG or PNG served when a render fails
+    pub fallback_image_url: Option<String>,
+    pub fallback_image_path: Option<String>,
+    // PEM certificate chain and private key, the server speaks HTTPS when both are set
+    pub tls_cert_path: Option<String>,
+    pub tls_key_path: Option<String>,
+    // CA whose client certificates https listeners verify
+    pub client_ca: Option<String>,
+    // Path prefixes that need a verified client certificate, none requires one on every https connection
+    pub client_cert_paths: Vec<String>,
+    // Accept HTTP/2 with prior knowledge (h2c) next to HTTP/1.1 on plain listeners
+    pub http_h2c: bool,
+    // Unix domain socket to listen on in addition to (or, with listen_tcp off, instead of) PORT
+    pub listen_socket: Option<String>,
+    pub listen_socket_mode: Option<u32>,
+    pub listen_tcp: bool,
+    // From LISTENERS, or derived from PORT, the TLS settings and LISTEN_SOCKET
+    pub listeners: Vec<Listener>,
+    // How long in-flight requests and background writes get to finish after SIGTERM
+    pub shutdown_timeout_secs: u64,
+    // RUST_LOG filter directives
+    pub log_filter: String,
+    // Requests allowed per window, 0 disables rate limiting
+    pub rate_limit: u32,
+    pub rate_limit_window_secs: u64,
+    // Only serve requests with a managed API key (or one of PRIORITY_API_KEYS)
+    pub require_api_key: bool,
+    pub cache_ttl_secs: u64,
+    // Address the listeners derived from PORT bind to, [::] serves IPv4 and IPv6
+    pub bind_address: IpAddr,
+    pub fetch_ip_version: IpVersion,
+    // Source address for outbound fetches, e.g. one allowlisted by the source's firewall
+    pub fetch_local_address: Option<IpAddr>,
+    // Render a built-in SVG on every readiness check
+    pub health_canary: bool,
+    // Render it on this interval instead and report the last result, 0 disables
+    pub health_canary_interval_secs: u64,
+    // JSON list of assets and sizes rendered into the cache before reporting ready
+    pub warmup_manifest: Option<String>,
+    // Repeat the warm-up on this interval, 0 only warms at startup
+    pub warmup_interval_secs: u64,
+    pub warmup_concurrency: usize,
+    // A warm-up still running after this long is abandoned
+    pub warmup_timeout_secs: u64,
+    // Origins browsers may call the API from, "*" for any, empty disables CORS
+    pub cors_allowed_origins: Vec<String>,
+    pub cors_allowed_methods: Vec<String>,
+    pub cors_allowed_headers: Vec<String>,
+    pub cors_max_age_secs: usize,
+    // Address of the gRPC service, which requires the grpc feature
+    pub grpc_listen: Option<SocketAddr>,
+    // Items allowed in one RasterizeBatch call
+    pub grpc_max_batch: usize,
+    // WebAssembly module that transforms fetched SVGs, requires the wasm feature
+    pub wasm_transform: Option<String>,
+    // Instructions a single transform may execute, roughly
+    pub wasm_transform_fuel: u64,
+    pub wasm_transform_max_memory: usize,
+    // Key/salt pairs for imgproxy-style URL signatures, none accepts any signature
+    pub imgproxy_keys: Vec<Vec<u8>>,
+    pub imgproxy_salts: Vec<Vec<u8>>,
+    // Bytes of the HMAC digest a signature carries
+    pub imgproxy_signature_size: usize,
+    // Key prefixes `url=redis://key` may read, none disables Redis sources
+    pub redis_source_prefixes: Vec<String>,
+    // How long responses to requests with an Idempotency-Key are replayed
+    pub idempotency_ttl_secs: u64,
+    // Key for POST /sign links, none disables signed URLs
+    pub signed_url_secret: Option<String>,
+    pub signed_url_max_ttl_secs: u64,
+    // HMAC secret, or hex Ed25519 private key, none leaves responses unsigned
+    pub content_signature_key: Option<String>,
+    pub content_signature_algorithm: SignatureAlgorithm,
+    // Hosts SVGs may be fetched from, `*.example.com` for any subdomain, empty allows any
+    pub allowed_source_domains: Vec<String>,
+    // Keeps these renders apart from others cached in the same Redis
+    pub cache_namespace: String,
+    // Tenant profiles by name, alongside those stored in Redis through the admin API
+    pub tenants: BTreeMap<String, TenantProfile>,
+    // Reverse proxies whose Forwarded and X-Forwarded-Host headers are believed when
+    // matching tenants by host. Other clients are matched on their Host header.
+    pub trusted_proxies: Vec<IpNetwork>,
 }
 
 impl Default for Config {
@@ -19,33 +406,1171 @@ impl Default for Config {
             default_width: 1024,
             default_height: 1024,
             min_dimension: 32,
+            max_srcset_widths: 8,
+            max_overlays: 4,
+            public_base_url: String::new(),
+            presets: HashMap::new(),
+            presets_only: false,
+            job_ttl_secs: 24 * 60 * 60,
+            webhook_secret: None,
+            run_mode: RunMode::Server,
+            job_queue: JobQueue::Local,
+            render_stream: "svg:render-stream".to_string(),
+            render_group: "svg-rasterizer".to_string(),
+            stream_max_attempts: 3,
+            kafka_brokers: "localhost:9092".to_string(),
+            kafka_group_id: "svg-rasterizer".to_string(),
+            nats_url: "nats://localhost:4222".to_string(),
+            bus_request_topic: "svg.render.requests".to_string(),
+            bus_event_topic: "svg.render.events".to_string(),
+            s3_bucket: None,
+            s3_prefix: String::new(),
+            s3_region: "us-east-1".to_string(),
+            s3_endpoint: None,
+            s3_access_key_id: None,
+            s3_secret_access_key: None,
+            s3_public_url: None,
+            output_mode: OutputMode::Image,
+            redirect_max_age: 60 * 60,
+            max_sprites: 64,
+            apple_touch_background: "#ffffff".to_string(),
+            deterministic_rendering: false,
+            font_dir: None,
+            lqip_width: 32,
+            animation_default_fps: 15,
+            animation_max_fps: 50,
+            animation_max_duration_secs: 10,
+            animation_max_pixels: 50_000_000,
+            auto_format_max_pixels: 4_194_304,
+            jpeg_quality: 85,
+            webp_quality: 80,
+            avif_quality: 70,
+            webp_lossless: false,
+            otel_endpoint: None,
+            otel_service_name: "svg-rasterizer".to_string(),
+            log_format: LogFormat::Text,
+            log_redact_urls: false,
+            access_log: AccessLogDestination::Stdout,
+            access_log_format: "default".to_string(),
+            access_log_rotation: LogRotation::Daily,
+            access_log_max_files: 7,
+            slow_request_ms: 0,
+            sentry_dsn: None,
+            admin_token: None,
+            admin_client_ca: None,
+            audit_log: false,
+            audit_retention_days: 30,
+            usage_tracking: false,
+            usage_retention_days: 400,
+            render_workers: crate::render_pool::default_workers(),
+            max_concurrent_renders: crate::render_pool::default_workers(),
+            render_queue_size: 100,
+            load_shed_queue_depth: 0,
+            load_shed_wait_ms: 0,
+            load_shed_retry_after: 5,
+            priority_api_keys: Vec::new(),
+            priority_networks: Vec::new(),
+            pixmap_pool_max_bytes: 256 * 1024 * 1024,
+            tree_cache_max_bytes: 64 * 1024 * 1024,
+            render_threads: 1,
+            cpu_throttle_threshold: 0,
+            http_workers: None,
+            http_max_connections: None,
+            http_request_timeout_ms: 5000,
+            http_keep_alive_secs: 5,
+            fetch_pool_max_idle_per_host: None,
+            fetch_pool_idle_timeout_secs: 90,
+            fetch_connect_timeout_ms: 0,
+            fetch_tcp_keepalive_secs: 0,
+            fetch_max_per_host: 0,
+            dns_cache: false,
+            dns_cache_max_ttl: 300,
+            fetch_proxy: None,
+            fetch_no_proxy: None,
+            fetch_proxy_username: None,
+            fetch_proxy_password: None,
+            fetch_user_agent: None,
+            fetch_headers: HashMap::new(),
+            forward_headers: Vec::new(),
+            forward_headers_hosts: Vec::new(),
+            fetch_retries: 0,
+            fetch_retry_backoff_ms: 100,
+            fetch_retry_max_backoff_ms: 2000,
+            fetch_retry_jitter: true,
+            fetch_retry_on: vec![RetryOn::ServerError, RetryOn::Timeout, RetryOn::Connect],
+            circuit_breaker_error_rate: 0.0,
+            circuit_breaker_min_requests: 20,
+            circuit_breaker_window_secs: 60,
+            circuit_breaker_open_secs: 30,
+            fallback_image_url: None,
+            fallback_image_path: None,
+            tls_cert_path: None,
+            tls_key_path: None,
+            client_ca: None,
+            client_cert_paths: Vec::new(),
+            http_h2c: false,
+            listen_socket: None,
+            listen_socket_mode: None,
+            listen_tcp: true,
+            listeners: Vec::new(),
+            shutdown_timeout_secs: 30,
+            log_filter: "debug".to_string(),
+            rate_limit: 60,
+            rate_limit_window_secs: 60,
+            require_api_key: false,
+            cache_ttl_secs: 24 * 60 * 60,
+            bind_address: IpAddr::from([0, 0, 0, 0]),
+            fetch_ip_version: IpVersion::Any,
+            fetch_local_address: None,
+            health_canary: false,
+            health_canary_interval_secs: 0,
+            warmup_manifest: None,
+            warmup_interval_secs: 0,
+            warmup_concurrency: 4,
+            warmup_timeout_secs: 300,
+            cors_allowed_origins: Vec::new(),
+            cors_allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            cors_allowed_headers: vec!["Content-Type".to_string(), "X-Api-Key".to_string(), "Idempotency-Key".to_string()],
+            cors_max_age_secs: 3600,
+            grpc_listen: None,
+            grpc_max_batch: 32,
+            wasm_transform: None,
+            wasm_transform_fuel: 100_000_000,
+            wasm_transform_max_memory: 64 * 1024 * 1024,
+            imgproxy_keys: Vec::new(),
+            imgproxy_salts: Vec::new(),
+            imgproxy_signature_size: 32,
+            redis_source_prefixes: Vec::new(),
+            idempotency_ttl_secs: 86400,
+            signed_url_secret: None,
+            signed_url_max_ttl_secs: 7 * 24 * 60 * 60,
+            content_signature_key: None,
+            content_signature_algorithm: SignatureAlgorithm::HmacSha256,
+            allowed_source_domains: Vec::new(),
+            cache_namespace: String::new(),
+            tenants: BTreeMap::new(),
+            trusted_proxies: Vec::new(),
         }
     }
 }
 
 impl Config {
     pub fn from_env() -> crate::error::ServiceResult<Self> {
+        // The environment takes precedence over ENV_FILE, which takes precedence over CONFIG_PATH
+        let env_file = match std::env::var("ENV_FILE").ok().filter(|path| !path.is_empty()) {
+            Some(path) => read_env_file(&path)?,
+            None => HashMap::new(),
+        };
+        let config_path = std::env::var("CONFIG_PATH").ok().filter(|path| !path.is_empty());
+        let config_file = match &config_path {
+            Some(path) => read_config_file(path)?,
+            None => HashMap::new(),
+        };
+        let known = RefCell::new(HashSet::new());
+        let var = |name: &str| {
+            known.borrow_mut().insert(name.to_string());
+            std::env::var(name)
+                .or_else(|e| env_file.get(name).or_else(|| config_file.get(name)).cloned().ok_or(e))
+        };
+        // Names the rejected value and where it came from, secrets are left out
+        let invalid = |name: &str| {
+            let source = if std::env::var(name).is_ok() {
+                "environment".to_string()
+            } else if env_file.contains_key(name) {
+                "ENV_FILE".to_string()
+            } else {
+                config_path.clone().unwrap_or_else(|| "CONFIG_PATH".to_string())
+            };
+            crate::error::ServiceError::ValidationError(match var(name) {
+                Ok(_) if SECRET_SETTINGS.contains(&name) => format!("Invalid {} value (from {})", name, source),
+                Ok(value) => format!("Invalid {} value {:?} (from {})", name, value, source),
+                Err(_) => format!("Invalid {} value", name),
+            })
+        };
+
         let mut config = Config::default();
 
-        if let Ok(port) = std::env::var("PORT") {
+        if let Ok(port) = var("PORT") {
             config.port = port.parse().map_err(|_| 
-                crate::error::ServiceError::ValidationError("Invalid PORT value".to_string()))?;
+                invalid("PORT"))?;
         }
 
-        if let Ok(redis_url) = std::env::var("REDIS_URL") {
+        if let Ok(redis_url) = var("REDIS_URL") {
             config.redis_url = redis_url;
         }
 
-        if let Ok(max_dim) = std::env::var("MAX_DIMENSION") {
+        if let Ok(max_dim) = var("MAX_DIMENSION") {
             let max = max_dim.parse().map_err(|_| 
-                crate::error::ServiceError::ValidationError("Invalid MAX_DIMENSION value".to_string()))?;
+                invalid("MAX_DIMENSION"))?;
             config.max_width = max;
             config.max_height = max;
         }
 
+        if let Ok(max_widths) = var("MAX_SRCSET_WIDTHS") {
+            config.max_srcset_widths = max_widths.parse().map_err(|_| 
+                invalid("MAX_SRCSET_WIDTHS"))?;
+        }
+
+        if let Ok(max_overlays) = var("MAX_OVERLAYS") {
+            config.max_overlays = max_overlays.parse().map_err(|_| invalid("MAX_OVERLAYS"))?;
+        }
+
+        if let Ok(base_url) = var("PUBLIC_BASE_URL") {
+            config.public_base_url = base_url.trim_end_matches('/').to_string();
+        }
+
+        if let Ok(presets) = var("SIZE_PRESETS") {
+            config.presets = parse_presets(&presets)?;
+        }
+
+        if let Ok(presets_only) = var("PRESETS_ONLY") {
+            config.presets_only = presets_only.parse().map_err(|_| 
+                invalid("PRESETS_ONLY"))?;
+        }
+
+        if let Ok(ttl) = var("JOB_TTL_SECS") {
+            config.job_ttl_secs = ttl.parse().map_err(|_| 
+                invalid("JOB_TTL_SECS"))?;
+        }
+
+        if let Ok(secret) = var("WEBHOOK_SECRET") {
+            config.webhook_secret = Some(secret);
+        }
+
+        if let Ok(mode) = var("RUN_MODE") {
+            config.run_mode = match mode.as_str() {
+                "server" => RunMode::Server,
+                "worker" => RunMode::Worker,
+                #[cfg(feature = "kafka")]
+                "kafka" => RunMode::Kafka,
+                #[cfg(feature = "nats")]
+                "nats" => RunMode::Nats,
+                _ => return Err(invalid("RUN_MODE")),
+            };
+        }
+
+        if let Ok(queue) = var("JOB_QUEUE") {
+            config.job_queue = match queue.as_str() {
+                "local" => JobQueue::Local,
+                "stream" => JobQueue::Stream,
+                _ => return Err(invalid("JOB_QUEUE")),
+            };
+        }
+
+        if let Ok(stream) = var("RENDER_STREAM") {
+            config.render_stream = stream;
+        }
+
+        if let Ok(group) = var("RENDER_GROUP") {
+            config.render_group = group;
+        }
+
+        if let Ok(attempts) = var("STREAM_MAX_ATTEMPTS") {
+            config.stream_max_attempts = attempts.parse().map_err(|_| 
+                invalid("STREAM_MAX_ATTEMPTS"))?;
+        }
+
+        if let Ok(brokers) = var("KAFKA_BROKERS") {
+            config.kafka_brokers = brokers;
+        }
+
+        if let Ok(group_id) = var("KAFKA_GROUP_ID") {
+            config.kafka_group_id = group_id;
+        }
+
+        if let Ok(nats_url) = var("NATS_URL") {
+            config.nats_url = nats_url;
+        }
+
+        if let Ok(topic) = var("BUS_REQUEST_TOPIC") {
+            config.bus_request_topic = topic;
+        }
+
+        if let Ok(topic) = var("BUS_EVENT_TOPIC") {
+            config.bus_event_topic = topic;
+        }
+
+        config.s3_bucket = var("S3_BUCKET").ok();
+        config.s3_endpoint = var("S3_ENDPOINT").ok();
+        config.s3_access_key_id = var("S3_ACCESS_KEY_ID").ok();
+        config.s3_secret_access_key = var("S3_SECRET_ACCESS_KEY").ok();
+        config.s3_public_url = var("S3_PUBLIC_URL").ok();
+
+        if let Ok(prefix) = var("S3_PREFIX") {
+            config.s3_prefix = prefix;
+        }
+
+        if let Ok(region) = var("S3_REGION") {
+            config.s3_region = region;
+        }
+
+        if let Ok(mode) = var("OUTPUT_MODE") {
+            config.output_mode = match mode.as_str() {
+                "image" => OutputMode::Image,
+                "cdn-redirect" => OutputMode::CdnRedirect,
+                _ => return Err(invalid("OUTPUT_MODE")),
+            };
+        }
+
+        if config.output_mode == OutputMode::CdnRedirect && config.s3_bucket.is_none() {
+            return Err(crate::error::ServiceError::ValidationError(
+                "OUTPUT_MODE=cdn-redirect requires S3_BUCKET".to_string()));
+        }
+
+        if let Ok(max_age) = var("REDIRECT_MAX_AGE") {
+            config.redirect_max_age = max_age.parse().map_err(|_| 
+                invalid("REDIRECT_MAX_AGE"))?;
+        }
+
+        if let Ok(max_sprites) = var("MAX_SPRITES") {
+            config.max_sprites = max_sprites.parse().map_err(|_| 
+                invalid("MAX_SPRITES"))?;
+        }
+
+        if let Ok(background) = var("APPLE_TOUCH_BACKGROUND") {
+            config.apple_touch_background = background;
+        }
+
+        if let Ok(deterministic) = var("DETERMINISTIC_RENDERING") {
+            config.deterministic_rendering = deterministic.parse().map_err(|_| 
+                invalid("DETERMINISTIC_RENDERING"))?;
+        }
+
+        config.font_dir = var("FONT_DIR").ok();
+
+        if let Ok(lqip_width) = var("LQIP_WIDTH") {
+            config.lqip_width = lqip_width.parse().map_err(|_| 
+                invalid("LQIP_WIDTH"))?;
+        }
+
+        if let Ok(fps) = var("ANIMATION_DEFAULT_FPS") {
+            config.animation_default_fps = fps.parse().map_err(|_| invalid("ANIMATION_DEFAULT_FPS"))?;
+        }
+
+        if let Ok(fps) = var("ANIMATION_MAX_FPS") {
+            config.animation_max_fps = fps.parse().map_err(|_| invalid("ANIMATION_MAX_FPS"))?;
+        }
+
+        if let Ok(duration) = var("ANIMATION_MAX_DURATION_SECS") {
+            config.animation_max_duration_secs = duration.parse().map_err(|_| invalid("ANIMATION_MAX_DURATION_SECS"))?;
+        }
+
+        if let Ok(pixels) = var("ANIMATION_MAX_PIXELS") {
+            config.animation_max_pixels = pixels.parse().map_err(|_| invalid("ANIMATION_MAX_PIXELS"))?;
+        }
+
+        if let Ok(pixels) = var("AUTO_FORMAT_MAX_PIXELS") {
+            config.auto_format_max_pixels = pixels.parse().map_err(|_| invalid("AUTO_FORMAT_MAX_PIXELS"))?;
+        }
+
+        if let Ok(quality) = var("JPEG_QUALITY") {
+            config.jpeg_quality = quality.parse().map_err(|_| invalid("JPEG_QUALITY"))?;
+        }
+
+        if let Ok(quality) = var("WEBP_QUALITY") {
+            config.webp_quality = quality.parse().map_err(|_| invalid("WEBP_QUALITY"))?;
+        }
+
+        if let Ok(quality) = var("AVIF_QUALITY") {
+            config.avif_quality = quality.parse().map_err(|_| invalid("AVIF_QUALITY"))?;
+        }
+
+        if let Ok(lossless) = var("WEBP_LOSSLESS") {
+            config.webp_lossless = lossless.parse().map_err(|_| invalid("WEBP_LOSSLESS"))?;
+        }
+
+        if let Ok(endpoint) = var("OTEL_EXPORTER_OTLP_ENDPOINT") {
+            config.otel_endpoint = Some(endpoint.trim_end_matches('/').to_string());
+        }
+
+        if let Ok(service_name) = var("OTEL_SERVICE_NAME") {
+            config.otel_service_name = service_name;
+        }
+
+        if let Ok(log_format) = var("LOG_FORMAT") {
+            config.log_format = match log_format.as_str() {
+                "text" => LogFormat::Text,
+                "json" => LogFormat::Json,
+                _ => return Err(invalid("LOG_FORMAT")),
+            };
+        }
+
+        if let Ok(redact) = var("LOG_REDACT_URLS") {
+            config.log_redact_urls = redact.parse().map_err(|_| 
+                invalid("LOG_REDACT_URLS"))?;
+        }
+
+        if let Ok(access_log) = var("ACCESS_LOG") {
+            config.access_log = match access_log.as_str() {
+                "stdout" => AccessLogDestination::Stdout,
+                "off" => AccessLogDestination::Off,
+                path => AccessLogDestination::File(path.to_string()),
+            };
+        }
+
+        if let Ok(format) = var("ACCESS_LOG_FORMAT") {
+            config.access_log_format = format;
+        }
+
+        if let Ok(rotation) = var("ACCESS_LOG_ROTATION") {
+            config.access_log_rotation = match rotation.as_str() {
+                "never" => LogRotation::Never,
+                "hourly" => LogRotation::Hourly,
+                "daily" => LogRotation::Daily,
+                _ => return Err(invalid("ACCESS_LOG_ROTATION")),
+            };
+        }
+
+        if let Ok(max_files) = var("ACCESS_LOG_MAX_FILES") {
+            config.access_log_max_files = max_files.parse().map_err(|_| 
+                invalid("ACCESS_LOG_MAX_FILES"))?;
+        }
+
+        if let Ok(slow_request_ms) = var("SLOW_REQUEST_MS") {
+            config.slow_request_ms = slow_request_ms.parse().map_err(|_| 
+                invalid("SLOW_REQUEST_MS"))?;
+        }
+
+        config.sentry_dsn = var("SENTRY_DSN").ok().filter(|dsn| !dsn.is_empty());
+
+        config.admin_token = var("ADMIN_TOKEN").ok().filter(|token| !token.is_empty());
+        config.admin_client_ca = var("ADMIN_CLIENT_CA").ok().filter(|path| !path.is_empty());
+        config.client_ca = var("CLIENT_CA").ok().filter(|path| !path.is_empty());
+
+        if let Ok(audit_log) = var("AUDIT_LOG") {
+            config.audit_log = audit_log.parse().map_err(|_| 
+                invalid("AUDIT_LOG"))?;
+        }
+
+        if let Ok(retention) = var("AUDIT_RETENTION_DAYS") {
+            config.audit_retention_days = retention.parse().map_err(|_| 
+                invalid("AUDIT_RETENTION_DAYS"))?;
+        }
+
+        if let Ok(usage_tracking) = var("USAGE_TRACKING") {
+            config.usage_tracking = usage_tracking.parse().map_err(|_| 
+                invalid("USAGE_TRACKING"))?;
+        }
+
+        if let Ok(retention) = var("USAGE_RETENTION_DAYS") {
+            config.usage_retention_days = retention.parse().map_err(|_| 
+                invalid("USAGE_RETENTION_DAYS"))?;
+        }
+
+        if let Ok(render_workers) = var("RENDER_WORKERS") {
+            config.render_workers = render_workers.parse().ok().filter(|&n| n > 0).ok_or_else(|| 
+                invalid("RENDER_WORKERS"))?;
+        }
+
+        // Defaults to one render per render thread
+        config.max_concurrent_renders = config.render_workers;
+        if let Ok(max_renders) = var("MAX_CONCURRENT_RENDERS") {
+            config.max_concurrent_renders = max_renders.parse().ok().filter(|&n| n > 0).ok_or_else(|| 
+                invalid("MAX_CONCURRENT_RENDERS"))?;
+        }
+
+        if let Ok(queue_size) = var("RENDER_QUEUE_SIZE") {
+            config.render_queue_size = queue_size.parse().map_err(|_| 
+                invalid("RENDER_QUEUE_SIZE"))?;
+        }
+
+        if let Ok(depth) = var("LOAD_SHED_QUEUE_DEPTH") {
+            config.load_shed_queue_depth = depth.parse().map_err(|_| 
+                invalid("LOAD_SHED_QUEUE_DEPTH"))?;
+        }
+
+        if let Ok(wait_ms) = var("LOAD_SHED_WAIT_MS") {
+            config.load_shed_wait_ms = wait_ms.parse().map_err(|_| 
+                invalid("LOAD_SHED_WAIT_MS"))?;
+        }
+
+        if let Ok(retry_after) = var("LOAD_SHED_RETRY_AFTER") {
+            config.load_shed_retry_after = retry_after.parse().map_err(|_| 
+                invalid("LOAD_SHED_RETRY_AFTER"))?;
+        }
+
+        if let Ok(keys) = var("PRIORITY_API_KEYS") {
+            config.priority_api_keys = keys.split(',')
+                .map(str::trim)
+                .filter(|key| !key.is_empty())
+                .map(str::to_string)
+                .collect();
+        }
+
+        if let Ok(networks) = var("PRIORITY_TRUSTED_NETWORKS") {
+            config.priority_networks = networks.split(',')
+                .map(str::trim)
+                .filter(|network| !network.is_empty())
+                .map(|network| IpNetwork::parse(network).ok_or_else(|| crate::error::ServiceError::ValidationError(
+                    format!("Invalid PRIORITY_TRUSTED_NETWORKS entry: {}", network))))
+                .collect::<crate::error::ServiceResult<_>>()?;
+        }
+
+        if let Ok(max_bytes) = var("PIXMAP_POOL_MAX_BYTES") {
+            config.pixmap_pool_max_bytes = max_bytes.parse().map_err(|_| 
+                invalid("PIXMAP_POOL_MAX_BYTES"))?;
+        }
+
+        if let Ok(max_bytes) = var("TREE_CACHE_MAX_BYTES") {
+            config.tree_cache_max_bytes = max_bytes.parse().map_err(|_| 
+                invalid("TREE_CACHE_MAX_BYTES"))?;
+        }
+
+        if let Ok(render_threads) = var("RENDER_THREADS") {
+            config.render_threads = render_threads.parse().ok().filter(|&n| n > 0).ok_or_else(|| 
+                invalid("RENDER_THREADS"))?;
+        }
+
+        if let Ok(threshold) = var("CPU_THROTTLE_THRESHOLD") {
+            config.cpu_throttle_threshold = threshold.parse().ok().filter(|&t| t <= 100).ok_or_else(|| 
+                invalid("CPU_THROTTLE_THRESHOLD"))?;
+        }
+
+        if let Ok(workers) = var("HTTP_WORKERS") {
+            config.http_workers = Some(workers.parse().ok().filter(|&n| n > 0).ok_or_else(|| 
+                invalid("HTTP_WORKERS"))?);
+        }
+
+        if let Ok(max_connections) = var("HTTP_MAX_CONNECTIONS") {
+            config.http_max_connections = Some(max_connections.parse().ok().filter(|&n| n > 0).ok_or_else(|| 
+                invalid("HTTP_MAX_CONNECTIONS"))?);
+        }
+
+        if let Ok(timeout) = var("HTTP_REQUEST_TIMEOUT_MS") {
+            config.http_request_timeout_ms = timeout.parse().map_err(|_| 
+                invalid("HTTP_REQUEST_TIMEOUT_MS"))?;
+        }
+
+        if let Ok(keep_alive) = var("HTTP_KEEP_ALIVE") {
+            config.http_keep_alive_secs = keep_alive.parse().map_err(|_| 
+                invalid("HTTP_KEEP_ALIVE"))?;
+        }
+
+        if let Ok(max_idle) = var("FETCH_POOL_MAX_IDLE_PER_HOST") {
+            config.fetch_pool_max_idle_per_host = Some(max_idle.parse().map_err(|_| 
+                invalid("FETCH_POOL_MAX_IDLE_PER_HOST"))?);
+        }
+
+        if let Ok(idle_timeout) = var("FETCH_POOL_IDLE_TIMEOUT") {
+            config.fetch_pool_idle_timeout_secs = idle_timeout.parse().map_err(|_| 
+                invalid("FETCH_POOL_IDLE_TIMEOUT"))?;
+        }
+
+        if let Ok(connect_timeout) = var("FETCH_CONNECT_TIMEOUT_MS") {
+            config.fetch_connect_timeout_ms = connect_timeout.parse().map_err(|_| 
+                invalid("FETCH_CONNECT_TIMEOUT_MS"))?;
+        }
+
+        if let Ok(keepalive) = var("FETCH_TCP_KEEPALIVE") {
+            config.fetch_tcp_keepalive_secs = keepalive.parse().map_err(|_| 
+                invalid("FETCH_TCP_KEEPALIVE"))?;
+        }
+
+        if let Ok(max_per_host) = var("FETCH_MAX_PER_HOST") {
+            config.fetch_max_per_host = max_per_host.parse().map_err(|_| 
+                invalid("FETCH_MAX_PER_HOST"))?;
+        }
+
+        if let Ok(dns_cache) = var("DNS_CACHE") {
+            config.dns_cache = dns_cache.parse().map_err(|_| 
+                invalid("DNS_CACHE"))?;
+        }
+
+        if let Ok(max_ttl) = var("DNS_CACHE_MAX_TTL") {
+            config.dns_cache_max_ttl = max_ttl.parse().map_err(|_| 
+                invalid("DNS_CACHE_MAX_TTL"))?;
+        }
+
+        if let Ok(proxy) = var("FETCH_PROXY") {
+            let valid = url::Url::parse(&proxy)
+                .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.host_str().is_some());
+            if !valid {
+                return Err(invalid("FETCH_PROXY"));
+            }
+            config.fetch_proxy = Some(proxy);
+        }
+
+        config.fetch_no_proxy = var("FETCH_NO_PROXY").ok().filter(|hosts| !hosts.is_empty());
+        config.fetch_proxy_username = var("FETCH_PROXY_USERNAME").ok().filter(|username| !username.is_empty());
+        config.fetch_proxy_password = var("FETCH_PROXY_PASSWORD").ok();
+
+        if let Ok(agent) = var("FETCH_USER_AGENT") {
+            if reqwest::header::HeaderValue::from_str(&agent).is_err() {
+                return Err(invalid("FETCH_USER_AGENT"));
+            }
+            config.fetch_user_agent = Some(agent).filter(|agent| !agent.is_empty());
+        }
+
+        if let Ok(headers) = var("FETCH_HEADERS") {
+            config.fetch_headers = parse_fetch_headers(&headers)?;
+        }
+
+        if let Ok(headers) = var("FORWARD_HEADERS") {
+            config.forward_headers = headers.split(',')
+                .map(|name| name.trim().to_lowercase())
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    let valid = reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_ok()
+                        && !NON_FORWARDABLE_HEADERS.contains(&name.as_str());
+                    if valid {
+                        Ok(name)
+                    } else {
+                        Err(crate::error::ServiceError::ValidationError(format!("Header can't be forwarded: {}", name)))
+                    }
+                })
+                .collect::<crate::error::ServiceResult<_>>()?;
+        }
+
+        if let Ok(retries) = var("FETCH_RETRIES") {
+            config.fetch_retries = retries.parse()
+                .map_err(|_| invalid("FETCH_RETRIES"))?;
+        }
+
+        if let Ok(backoff) = var("FETCH_RETRY_BACKOFF_MS") {
+            config.fetch_retry_backoff_ms = backoff.parse()
+                .map_err(|_| invalid("FETCH_RETRY_BACKOFF_MS"))?;
+        }
+
+        if let Ok(backoff) = var("FETCH_RETRY_MAX_BACKOFF_MS") {
+            config.fetch_retry_max_backoff_ms = backoff.parse()
+                .map_err(|_| invalid("FETCH_RETRY_MAX_BACKOFF_MS"))?;
+        }
+
+        if let Ok(jitter) = var("FETCH_RETRY_JITTER") {
+            config.fetch_retry_jitter = jitter.parse()
+                .map_err(|_| invalid("FETCH_RETRY_JITTER"))?;
+        }
+
+        if let Ok(classes) = var("FETCH_RETRY_ON") {
+            config.fetch_retry_on = classes.split(',')
+                .map(str::trim)
+                .filter(|class| !class.is_empty())
+                .map(|class| match class.to_lowercase().as_str() {
+                    "5xx" => Ok(RetryOn::ServerError),
+                    "429" => Ok(RetryOn::TooManyRequests),
+                    "timeout" => Ok(RetryOn::Timeout),
+                    "connect" => Ok(RetryOn::Connect),
+                    _ => Err(invalid("FETCH_RETRY_ON")),
+                })
+                .collect::<crate::error::ServiceResult<_>>()?;
+        }
+
+        if let Ok(rate) = var("CIRCUIT_BREAKER_ERROR_RATE") {
+            config.circuit_breaker_error_rate = rate.parse().ok().filter(|r| (0.0..=1.0).contains(r)).ok_or_else(|| 
+                invalid("CIRCUIT_BREAKER_ERROR_RATE"))?;
+        }
+
+        if let Ok(min_requests) = var("CIRCUIT_BREAKER_MIN_REQUESTS") {
+            config.circuit_breaker_min_requests = min_requests.parse().ok().filter(|&n| n > 0).ok_or_else(|| 
+                invalid("CIRCUIT_BREAKER_MIN_REQUESTS"))?;
+        }
+
+        if let Ok(window) = var("CIRCUIT_BREAKER_WINDOW") {
+            config.circuit_breaker_window_secs = window.parse().ok().filter(|&n| n > 0).ok_or_else(|| 
+                invalid("CIRCUIT_BREAKER_WINDOW"))?;
+        }
+
+        if let Ok(open) = var("CIRCUIT_BREAKER_OPEN_SECS") {
+            config.circuit_breaker_open_secs = open.parse().ok().filter(|&n| n > 0).ok_or_else(|| 
+                invalid("CIRCUIT_BREAKER_OPEN_SECS"))?;
+        }
+
+        config.fallback_image_url = var("FALLBACK_IMAGE_URL").ok().filter(|url| !url.is_empty());
+        config.fallback_image_path = var("FALLBACK_IMAGE_PATH").ok().filter(|path| !path.is_empty());
+        if config.fallback_image_url.is_some() && config.fallback_image_path.is_some() {
+            return Err(crate::error::ServiceError::ValidationError(
+                "Set either FALLBACK_IMAGE_URL or FALLBACK_IMAGE_PATH, not both".to_string()));
+        }
+
+        config.tls_cert_path = var("TLS_CERT_PATH").ok().filter(|path| !path.is_empty());
+        config.tls_key_path = var("TLS_KEY_PATH").ok().filter(|path| !path.is_empty());
+        if config.tls_cert_path.is_some() != config.tls_key_path.is_some() {
+            return Err(crate::error::ServiceError::ValidationError(
+                "TLS_CERT_PATH and TLS_KEY_PATH must be set together".to_string()));
+        }
+
+        if let Ok(h2c) = var("HTTP_H2C") {
+            config.http_h2c = h2c.parse()
+                .map_err(|_| invalid("HTTP_H2C"))?;
+        }
+
+        config.listen_socket = var("LISTEN_SOCKET").ok().filter(|path| !path.is_empty());
+
+        if let Ok(mode) = var("LISTEN_SOCKET_MODE") {
+            config.listen_socket_mode = Some(u32::from_str_radix(mode.trim_start_matches("0o"), 8).ok().filter(|&m| m <= 0o777).ok_or_else(|| 
+                invalid("LISTEN_SOCKET_MODE"))?);
+        }
+
+        if let Ok(listen_tcp) = var("LISTEN_TCP") {
+            config.listen_tcp = listen_tcp.parse()
+                .map_err(|_| invalid("LISTEN_TCP"))?;
+        }
+        if let Ok(address) = var("BIND_ADDRESS") {
+            config.bind_address = address.trim_start_matches('[').trim_end_matches(']').parse()
+                .map_err(|_| invalid("BIND_ADDRESS"))?;
+        }
+        config.listeners = match var("LISTENERS") {
+            Ok(listeners) => listeners.split(',')
+                .map(str::trim)
+                .filter(|listener| !listener.is_empty())
+                .map(|listener| Listener::parse(listener).ok_or_else(|| 
+                    crate::error::ServiceError::ValidationError(format!("Invalid LISTENERS entry: {}", listener))))
+                .collect::<crate::error::ServiceResult<_>>()?,
+            Err(_) => {
+                let tcp = SocketAddr::new(config.bind_address, config.port);
+                let mut listeners = Vec::new();
+                if config.listen_tcp && config.tls_cert_path.is_some() {
+                    listeners.push(Listener::Https(tcp));
+                } else if config.listen_tcp {
+                    listeners.push(Listener::Http(tcp));
+                }
+                listeners.extend(config.listen_socket.clone().map(Listener::Unix));
+                listeners
+            },
+        };
+        if config.listeners.is_empty() {
+            return Err(crate::error::ServiceError::ValidationError(
+                "No listeners configured, LISTEN_TCP=false requires LISTEN_SOCKET".to_string()));
+        }
+        if config.tls_cert_path.is_none() && config.listeners.iter().any(|l| matches!(l, Listener::Https(_))) {
+            return Err(crate::error::ServiceError::ValidationError(
+                "https listeners require TLS_CERT_PATH and TLS_KEY_PATH".to_string()));
+        }
+
+        if let Ok(timeout) = var("SHUTDOWN_TIMEOUT") {
+            config.shutdown_timeout_secs = timeout.parse()
+                .map_err(|_| invalid("SHUTDOWN_TIMEOUT"))?;
+        }
+
+        config.log_filter = var("RUST_LOG").unwrap_or_else(|_| config.log_filter.clone());
+
+        if let Ok(limit) = var("RATE_LIMIT") {
+            config.rate_limit = limit.parse()
+                .map_err(|_| invalid("RATE_LIMIT"))?;
+        }
+
+        if let Ok(window) = var("RATE_LIMIT_WINDOW") {
+            config.rate_limit_window_secs = window.parse().ok().filter(|&secs| secs > 0)
+                .ok_or_else(|| invalid("RATE_LIMIT_WINDOW"))?;
+        }
+
+        if let Ok(require) = var("REQUIRE_API_KEY") {
+            config.require_api_key = require.parse()
+                .map_err(|_| invalid("REQUIRE_API_KEY"))?;
+        }
+
+        if let Ok(ttl) = var("CACHE_TTL") {
+            config.cache_ttl_secs = ttl.parse().ok().filter(|&secs| secs > 0)
+                .ok_or_else(|| invalid("CACHE_TTL"))?;
+        }
+
+        if let Ok(version) = var("FETCH_IP_VERSION") {
+            config.fetch_ip_version = IpVersion::parse(&version)
+                .ok_or_else(|| crate::error::ServiceError::ValidationError("Invalid FETCH_IP_VERSION value, expected any, 4 or 6".to_string()))?;
+        }
+
+        if let Ok(address) = var("FETCH_LOCAL_ADDRESS") {
+            let address: IpAddr = address.parse()
+                .map_err(|_| invalid("FETCH_LOCAL_ADDRESS"))?;
+            // A connection over the other family would leave from an address the source doesn't allow
+            let family = if address.is_ipv4() { IpVersion::V4 } else { IpVersion::V6 };
+            match config.fetch_ip_version {
+                IpVersion::Any => config.fetch_ip_version = family,
+                version if version != family => return Err(crate::error::ServiceError::ValidationError(
+                    "FETCH_LOCAL_ADDRESS doesn't match FETCH_IP_VERSION".to_string())),
+                _ => {},
+            }
+            config.fetch_local_address = Some(address);
+        }
+
+        if let Ok(canary) = var("HEALTH_CANARY") {
+            config.health_canary = canary.parse()
+                .map_err(|_| invalid("HEALTH_CANARY"))?;
+        }
+
+        if let Ok(interval) = var("HEALTH_CANARY_INTERVAL") {
+            config.health_canary_interval_secs = interval.parse()
+                .map_err(|_| invalid("HEALTH_CANARY_INTERVAL"))?;
+        }
+
+        config.warmup_manifest = var("WARMUP_MANIFEST").ok().filter(|manifest| !manifest.is_empty());
+
+        if let Ok(interval) = var("WARMUP_INTERVAL") {
+            config.warmup_interval_secs = interval.parse()
+                .map_err(|_| invalid("WARMUP_INTERVAL"))?;
+        }
+
+        if let Ok(concurrency) = var("WARMUP_CONCURRENCY") {
+            config.warmup_concurrency = concurrency.parse().ok().filter(|&n| n > 0)
+                .ok_or_else(|| invalid("WARMUP_CONCURRENCY"))?;
+        }
+
+        if let Ok(timeout) = var("WARMUP_TIMEOUT") {
+            config.warmup_timeout_secs = timeout.parse().ok().filter(|&secs| secs > 0)
+                .ok_or_else(|| invalid("WARMUP_TIMEOUT"))?;
+        }
+
+        // Comma-separated lists, empty entries are ignored
+        let list = |value: String| value.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+
+        if let Ok(origins) = var("CORS_ALLOWED_ORIGINS") {
+            config.cors_allowed_origins = list(origins);
+        }
+
+        if let Ok(methods) = var("CORS_ALLOWED_METHODS") {
+            config.cors_allowed_methods = list(methods.to_uppercase());
+            if config.cors_allowed_methods.iter().any(|method| actix_web::http::Method::from_bytes(method.as_bytes()).is_err()) {
+                return Err(invalid("CORS_ALLOWED_METHODS"));
+            }
+        }
+
+        if let Ok(headers) = var("CORS_ALLOWED_HEADERS") {
+            config.cors_allowed_headers = list(headers);
+            if config.cors_allowed_headers.iter().any(|name| name != "*" && reqwest::header::HeaderName::from_bytes(name.as_bytes()).is_err()) {
+                return Err(invalid("CORS_ALLOWED_HEADERS"));
+            }
+        }
+
+        if let Ok(max_age) = var("CORS_MAX_AGE") {
+            config.cors_max_age_secs = max_age.parse()
+                .map_err(|_| invalid("CORS_MAX_AGE"))?;
+        }
+
+        if let Ok(address) = var("GRPC_LISTEN") {
+            config.grpc_listen = Some(address.parse()
+                .map_err(|_| invalid("GRPC_LISTEN"))?);
+        }
+
+        if let Ok(max_batch) = var("GRPC_MAX_BATCH") {
+            config.grpc_max_batch = max_batch.parse()
+                .map_err(|_| invalid("GRPC_MAX_BATCH"))?;
+        }
+
+        if let Ok(path) = var("WASM_TRANSFORM") {
+            config.wasm_transform = Some(path).filter(|path| !path.is_empty());
+        }
+
+        if let Ok(fuel) = var("WASM_TRANSFORM_FUEL") {
+            config.wasm_transform_fuel = fuel.parse()
+                .map_err(|_| invalid("WASM_TRANSFORM_FUEL"))?;
+        }
+
+        if let Ok(max_memory) = var("WASM_TRANSFORM_MAX_MEMORY") {
+            config.wasm_transform_max_memory = max_memory.parse()
+                .map_err(|_| invalid("WASM_TRANSFORM_MAX_MEMORY"))?;
+        }
+
+        // Hex encoded like imgproxy's, several comma-separated pairs allow rotating them
+        if let Ok(keys) = var("IMGPROXY_KEY") {
+            config.imgproxy_keys = list(keys).iter().map(hex::decode).collect::<Result<_, _>>()
+                .map_err(|_| invalid("IMGPROXY_KEY"))?;
+        }
+
+        if let Ok(salts) = var("IMGPROXY_SALT") {
+            config.imgproxy_salts = list(salts).iter().map(hex::decode).collect::<Result<_, _>>()
+                .map_err(|_| invalid("IMGPROXY_SALT"))?;
+        }
+
+        if let Ok(size) = var("IMGPROXY_SIGNATURE_SIZE") {
+            config.imgproxy_signature_size = size.parse()
+                .map_err(|_| invalid("IMGPROXY_SIGNATURE_SIZE"))?;
+        }
+
+        if let Ok(prefixes) = var("REDIS_SOURCE_PREFIXES") {
+            config.redis_source_prefixes = list(prefixes);
+        }
+
+        if let Ok(ttl) = var("IDEMPOTENCY_TTL_SECS") {
+            config.idempotency_ttl_secs = ttl.parse()
+                .map_err(|_| invalid("IDEMPOTENCY_TTL_SECS"))?;
+        }
+
+        if let Ok(secret) = var("SIGNED_URL_SECRET") {
+            config.signed_url_secret = Some(secret);
+        }
+
+        if let Ok(ttl) = var("SIGNED_URL_MAX_TTL_SECS") {
+            config.signed_url_max_ttl_secs = ttl.parse()
+                .map_err(|_| invalid("SIGNED_URL_MAX_TTL_SECS"))?;
+        }
+
+        if let Ok(key) = var("CONTENT_SIGNATURE_KEY") {
+            config.content_signature_key = Some(key);
+        }
+
+        if let Ok(algorithm) = var("CONTENT_SIGNATURE_ALGORITHM") {
+            config.content_signature_algorithm = match algorithm.as_str() {
+                "hmac-sha256" => SignatureAlgorithm::HmacSha256,
+                "ed25519" => SignatureAlgorithm::Ed25519,
+                _ => return Err(invalid("CONTENT_SIGNATURE_ALGORITHM")),
+            };
+        }
+
+        if let Ok(paths) = var("CLIENT_CERT_PATHS") {
+            config.client_cert_paths = list(paths);
+        }
+
+        if let Ok(domains) = var("ALLOWED_SOURCE_DOMAINS") {
+            config.allowed_source_domains = list(domains.to_lowercase());
+        }
+
+        if let Ok(hosts) = var("FORWARD_HEADERS_HOSTS") {
+            config.forward_headers_hosts = list(hosts.to_lowercase());
+        }
+
+        if let Ok(namespace) = var("CACHE_NAMESPACE") {
+            config.cache_namespace = namespace;
+        }
+
+        if let Ok(tenants) = var("TENANTS") {
+            config.tenants = serde_json::from_str(&tenants)
+                .map_err(|e| crate::error::ServiceError::ValidationError(format!("Invalid TENANTS value: {}", e)))?;
+        }
+
+        if let Ok(networks) = var("TRUSTED_PROXIES") {
+            config.trusted_proxies = networks.split(',')
+                .map(str::trim)
+                .filter(|network| !network.is_empty())
+                .map(|network| IpNetwork::parse(network).ok_or_else(|| crate::error::ServiceError::ValidationError(
+                    format!("Invalid TRUSTED_PROXIES entry: {}", network))))
+                .collect::<crate::error::ServiceResult<_>>()?;
+        }
+
+        // Catches typos, which would otherwise leave a setting at its default without a word.
+        // Must stay the last thing before validation: it only knows the settings read above it,
+        // so any setting added after it would be reported as unknown.
+        if let Some(path) = &config_path {
+            let known = known.borrow();
+            let mut unknown: Vec<String> = config_file.keys()
+                .filter(|name| !known.contains(*name))
+                .map(|name| name.to_lowercase())
+                .collect();
+            if !unknown.is_empty() {
+                unknown.sort();
+                return Err(crate::error::ServiceError::ValidationError(
+                    format!("Unknown settings in {}: {}", path, unknown.join(", "))));
+            }
+        }
+
+        config.validate()?;
         Ok(config)
     }
 
+    // Checks that only hold across settings, reporting every problem at once rather
+    // than one per restart
+    fn validate(&self) -> crate::error::ServiceResult<()> {
+        let mut problems = Vec::new();
+
+        if self.max_width == 0 || self.max_height == 0 {
+            problems.push("MAX_DIMENSION must be at least 1".to_string());
+        }
+        if self.min_dimension > self.max_width.min(self.max_height) {
+            problems.push(format!("MIN_DIMENSION {} exceeds MAX_DIMENSION {}",
+                self.min_dimension, self.max_width.min(self.max_height)));
+        }
+        if self.lqip_width == 0 || self.lqip_width > self.max_width {
+            problems.push(format!("LQIP_WIDTH {} must be between 1 and MAX_DIMENSION {}", self.lqip_width, self.max_width));
+        }
+        // Caller credentials must never reach a host nobody vouched for
+        if !self.forward_headers.is_empty() && self.forward_headers_hosts.is_empty() && self.fetch_headers.is_empty() {
+            problems.push("FORWARD_HEADERS needs FORWARD_HEADERS_HOSTS or FETCH_HEADERS to name the hosts they go to".to_string());
+        }
+        if self.max_srcset_widths == 0 {
+            problems.push("MAX_SRCSET_WIDTHS must be at least 1".to_string());
+        }
+        // Browsers show GIF frames shorter than 20ms for 100ms
+        if self.animation_max_fps == 0 || self.animation_max_fps > 50 {
+            problems.push(format!("ANIMATION_MAX_FPS {} must be between 1 and 50", self.animation_max_fps));
+        }
+        if self.animation_default_fps == 0 || self.animation_default_fps > self.animation_max_fps {
+            problems.push(format!("ANIMATION_DEFAULT_FPS {} must be between 1 and ANIMATION_MAX_FPS {}",
+                self.animation_default_fps, self.animation_max_fps));
+        }
+        if self.animation_max_duration_secs == 0 {
+            problems.push("ANIMATION_MAX_DURATION_SECS must be at least 1".to_string());
+        }
+        for (name, quality) in [("JPEG_QUALITY", self.jpeg_quality), ("WEBP_QUALITY", self.webp_quality), ("AVIF_QUALITY", self.avif_quality)] {
+            if !(1..=100).contains(&quality) {
+                problems.push(format!("{} {} must be between 1 and 100", name, quality));
+            }
+        }
+        if self.fetch_retry_backoff_ms > self.fetch_retry_max_backoff_ms {
+            problems.push(format!("FETCH_RETRY_BACKOFF {}ms exceeds FETCH_RETRY_MAX_BACKOFF {}ms",
+                self.fetch_retry_backoff_ms, self.fetch_retry_max_backoff_ms));
+        }
+        if self.audit_log && self.audit_retention_days == 0 {
+            problems.push("AUDIT_RETENTION_DAYS must be at least 1 with AUDIT_LOG enabled".to_string());
+        }
+        if self.usage_tracking && self.usage_retention_days == 0 {
+            problems.push("USAGE_RETENTION_DAYS must be at least 1 with USAGE_TRACKING enabled".to_string());
+        }
+
+        let urls = [
+            ("REDIS_URL", Some(&self.redis_url), &["redis", "rediss", "redis+unix", "unix"][..]),
+            ("S3_ENDPOINT", self.s3_endpoint.as_ref(), &["http", "https"][..]),
+            ("S3_PUBLIC_URL", self.s3_public_url.as_ref(), &["http", "https"][..]),
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", self.otel_endpoint.as_ref(), &["http", "https"][..]),
+            ("SENTRY_DSN", self.sentry_dsn.as_ref(), &["http", "https"][..]),
+            ("FALLBACK_IMAGE_URL", self.fallback_image_url.as_ref(), &["http", "https"][..]),
+            ("WARMUP_MANIFEST", self.warmup_manifest.as_ref().filter(|m| m.contains("://")), &["http", "https"][..]),
+        ];
+        for (name, value, schemes) in urls {
+            let Some(value) = value else { continue };
+            match url::Url::parse(value) {
+                Ok(url) if schemes.contains(&url.scheme()) => {},
+                Ok(url) => problems.push(format!("{} has scheme {}, expected {}", name, url.scheme(), schemes.join(" or "))),
+                Err(e) => problems.push(format!("{} is not a valid URL: {}", name, e)),
+            }
+        }
+        // A path is served relative to the host the request came in on
+        if !self.public_base_url.is_empty() && !self.public_base_url.starts_with('/') {
+            match url::Url::parse(&self.public_base_url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {},
+                _ => problems.push(format!("PUBLIC_BASE_URL {:?} must be an http(s) URL or a path starting with /", self.public_base_url)),
+            }
+        }
+
+        if self.s3_access_key_id.is_some() != self.s3_secret_access_key.is_some() {
+            problems.push("S3_ACCESS_KEY_ID and S3_SECRET_ACCESS_KEY must be set together".to_string());
+        }
+        if self.fetch_proxy.is_none() {
+            if self.fetch_proxy_username.is_some() || self.fetch_proxy_password.is_some() {
+                problems.push("FETCH_PROXY_USERNAME and FETCH_PROXY_PASSWORD require FETCH_PROXY".to_string());
+            }
+            if self.fetch_no_proxy.is_some() {
+                problems.push("FETCH_NO_PROXY requires FETCH_PROXY".to_string());
+            }
+        }
+        // Admin listeners may also come from systemd, so only the certificate can be checked here
+        if self.admin_client_ca.is_some() && self.tls_cert_path.is_none() {
+            problems.push("ADMIN_CLIENT_CA requires TLS_CERT_PATH and TLS_KEY_PATH".to_string());
+        }
+        if self.client_ca.is_some() && self.tls_cert_path.is_none() {
+            problems.push("CLIENT_CA requires TLS_CERT_PATH and TLS_KEY_PATH".to_string());
+        }
+        if !self.client_cert_paths.is_empty() && self.client_ca.is_none() {
+            problems.push("CLIENT_CERT_PATHS requires CLIENT_CA".to_string());
+        }
+        if self.listen_socket_mode.is_some() && !self.listeners.iter().any(|l| matches!(l, Listener::Unix(_))) {
+            problems.push("LISTEN_SOCKET_MODE requires a unix socket listener".to_string());
+        }
+        let mut addresses = HashSet::new();
+        for listener in &self.listeners {
+            let address = match listener {
+                Listener::Http(addr) | Listener::Https(addr) | Listener::Admin(addr) => addr.to_string(),
+                Listener::Unix(path) => path.clone(),
+            };
+            if !addresses.insert(address.clone()) {
+                problems.push(format!("Listener address {} is used more than once", address));
+            }
+        }
+
+        if let Some(address) = self.grpc_listen {
+            if !cfg!(feature = "grpc") {
+                problems.push("GRPC_LISTEN requires building with the grpc feature".to_string());
+            }
+            if !addresses.insert(address.to_string()) {
+                problems.push(format!("GRPC_LISTEN {} is also an HTTP listener", address));
+            }
+        }
+        if self.grpc_max_batch == 0 {
+            problems.push("GRPC_MAX_BATCH must be at least 1".to_string());
+        }
+        if self.imgproxy_keys.len() != self.imgproxy_salts.len() {
+            problems.push("IMGPROXY_KEY and IMGPROXY_SALT must list the same number of values".to_string());
+        }
+        if !(1..=32).contains(&self.imgproxy_signature_size) {
+            problems.push("IMGPROXY_SIGNATURE_SIZE must be between 1 and 32".to_string());
+        }
+        if let (Some(key), SignatureAlgorithm::Ed25519) = (&self.content_signature_key, self.content_signature_algorithm) {
+            if hex::decode(key).map_or(true, |key| key.len() != 32) {
+                problems.push("CONTENT_SIGNATURE_KEY must be a hex encoded 32-byte Ed25519 private key".to_string());
+            }
+        }
+        if !self.cache_namespace.is_empty() && !is_identifier(&self.cache_namespace) {
+            problems.push("CACHE_NAMESPACE must be letters, digits, '-' or '_'".to_string());
+        }
+        for (name, tenant) in &self.tenants {
+            problems.extend(tenant.problems(name));
+        }
+        if self.wasm_transform.is_some() && !cfg!(feature = "wasm") {
+            problems.push("WASM_TRANSFORM requires building with the wasm feature".to_string());
+        }
+
+        for origin in self.cors_allowed_origins.iter().filter(|origin| *origin != "*") {
+            match url::Url::parse(origin) {
+                Ok(url) if url.path() == "/" && origin.trim_end_matches('/') == url.origin().ascii_serialization() => {},
+                _ => problems.push(format!("CORS_ALLOWED_ORIGINS entry {:?} must be a scheme and host like https://app.example.com", origin)),
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(crate::error::ServiceError::ValidationError(problems.join("; ")))
+        }
+    }
+
+    // The effective configuration for GET /admin/config, with credentials replaced
+    pub fn redacted(&self) -> serde_json::Value {
+        let secret = |value: &Option<String>| value.as_ref().map(|_| REDACTED);
+        let mut dump = serde_json::to_value(self).unwrap_or_default();
+        dump["redis_url"] = redact_url(&self.redis_url).into();
+        dump["webhook_secret"] = secret(&self.webhook_secret).into();
+        dump["s3_access_key_id"] = secret(&self.s3_access_key_id).into();
+        dump["s3_secret_access_key"] = secret(&self.s3_secret_access_key).into();
+        dump["sentry_dsn"] = self.sentry_dsn.as_deref().map(redact_url).into();
+        dump["admin_token"] = secret(&self.admin_token).into();
+        dump["signed_url_secret"] = secret(&self.signed_url_secret).into();
+        dump["content_signature_key"] = secret(&self.content_signature_key).into();
+        for (name, tenant) in &self.tenants {
+            dump["tenants"][name]["api_keys"] = tenant.api_keys.iter().map(|_| REDACTED).collect::<Vec<_>>().into();
+        }
+        dump["priority_api_keys"] = self.priority_api_keys.iter().map(|_| REDACTED).collect::<Vec<_>>().into();
+        dump["imgproxy_keys"] = self.imgproxy_keys.iter().map(|_| REDACTED).collect::<Vec<_>>().into();
+        dump["imgproxy_salts"] = self.imgproxy_salts.iter().map(|_| REDACTED).collect::<Vec<_>>().into();
+        dump["fetch_proxy"] = self.fetch_proxy.as_deref().map(redact_url).into();
+        dump["fetch_proxy_password"] = secret(&self.fetch_proxy_password).into();
+        // Header values are typically tokens, the names show what is sent where
+        dump["fetch_headers"] = self.fetch_headers.iter()
+            .map(|(host, headers)| (host.clone(), headers.iter().map(|(name, _)| (name.clone(), REDACTED)).collect::<serde_json::Value>()))
+            .collect();
+        dump
+    }
+
+    pub fn cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.cache_ttl_secs)
+    }
+
+    // Resolves the output size of a request, honouring presets and PRESETS_ONLY
+    pub fn resolve_size(&self, width: Option<u32>, height: Option<u32>, preset: Option<&str>) -> crate::error::ServiceResult<(u32, u32)> {
+        if self.presets_only && (width.is_some() || height.is_some()) {
+            return Err(crate::error::ServiceError::ValidationError(
+                "Only preset sizes are allowed, use the preset parameter".to_string()));
+        }
+
+        match preset {
+            Some(preset) => self.resolve_preset(preset),
+            None if self.presets_only => Err(crate::error::ServiceError::ValidationError(
+                "A preset is required".to_string())),
+            None => Ok(self.validate_dimensions(width, height)),
+        }
+    }
+
+    pub fn resolve_preset(&self, name: &str) -> crate::error::ServiceResult<(u32, u32)> {
+        if name == APPLE_TOUCH_PRESET && !self.presets.contains_key(name) {
+            return Ok((APPLE_TOUCH_SIZE, APPLE_TOUCH_SIZE));
+        }
+
+        let (width, height) = self.presets.get(name).ok_or_else(|| 
+            crate::error::ServiceError::ValidationError(format!("Unknown preset: {}", name)))?;
+        Ok(self.validate_dimensions(Some(*width), Some(*height)))
+    }
+
     pub fn validate_dimensions(&self, width: Option<u32>, height: Option<u32>) -> (u32, u32) {
         let w = width.unwrap_or(self.default_width)
             .min(self.max_width)
@@ -57,4 +1582,235 @@ impl Config {
             
         (w, h)
     }
-}
\ No newline at end of file
+}
+
+// Makes `config` the configuration returned by current(), on startup and on reload
+pub fn set_current(config: Config) {
+    match CURRENT.get() {
+        Some(current) => current.store(std::sync::Arc::new(config)),
+        None => {
+            let _ = CURRENT.set(ArcSwap::from_pointee(config));
+        },
+    }
+}
+
+// The configuration in effect, handlers read it once per request so a reload
+// never changes settings halfway through one
+pub fn current() -> web::Data<Config> {
+    // Requests from a tenant see the configuration with its policies applied
+    if let Some(config) = crate::request_context::tenant_config() {
+        return web::Data::from(config);
+    }
+    web::Data::from(CURRENT.get().expect("configuration not loaded").load_full())
+}
+
+// Keeps the scheme, host and path of a URL but not its credentials
+fn redact_url(value: &str) -> String {
+    match url::Url::parse(value) {
+        Ok(mut url) => {
+            if !url.username().is_empty() || url.password().is_some() {
+                let _ = url.set_username("redacted");
+                let _ = url.set_password(None);
+            }
+            url.to_string()
+        },
+        Err(_) => REDACTED.to_string(),
+    }
+}
+
+// Reads KEY=VALUE lines as written for systemd's EnvironmentFile or docker's --env-file
+fn read_env_file(path: &str) -> crate::error::ServiceResult<HashMap<String, String>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| crate::error::ServiceError::ValidationError(format!("Failed to read ENV_FILE {}: {}", path, e)))?;
+
+    let mut vars = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, value) = line.strip_prefix("export ").unwrap_or(line).split_once('=').ok_or_else(||
+            crate::error::ServiceError::ValidationError(format!("Invalid ENV_FILE line {}: expected NAME=VALUE", number + 1)))?;
+        let value = value.trim();
+        let value = [('"', '"'), ('\'', '\'')].iter()
+            .find_map(|&(open, close)| value.strip_prefix(open).and_then(|v| v.strip_suffix(close)))
+            .unwrap_or(value);
+        vars.insert(name.trim().to_string(), value.to_string());
+    }
+    Ok(vars)
+}
+
+// Reads a TOML or YAML file of settings named like the environment variables, in
+// any case. Tables group settings by prefix, so [fetch] retries = 3 is FETCH_RETRIES.
+fn read_config_file(path: &str) -> crate::error::ServiceResult<HashMap<String, String>> {
+    let invalid = |detail: String| crate::error::ServiceError::ValidationError(format!("Invalid config file {}: {}", path, detail));
+
+    let contents = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+    let document: serde_json::Value = match std::path::Path::new(path).extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&contents).map_err(|e| invalid(e.to_string()))?,
+        Some("yaml") | Some("yml") => serde_yaml::from_str(&contents).map_err(|e| invalid(e.to_string()))?,
+        _ => return Err(invalid("expected a .toml, .yaml or .yml file".to_string())),
+    };
+
+    let mut settings = HashMap::new();
+    match document {
+        serde_json::Value::Object(table) => flatten_settings("", table, &mut settings).map_err(invalid)?,
+        // An empty YAML document
+        serde_json::Value::Null => {},
+        _ => return Err(invalid("expected a table of settings".to_string())),
+    }
+    Ok(settings)
+}
+
+fn flatten_settings(prefix: &str, table: serde_json::Map<String, serde_json::Value>, settings: &mut HashMap<String, String>) -> Result<(), String> {
+    for (key, value) in table {
+        let name = format!("{}{}", prefix, key.to_uppercase().replace('-', "_"));
+        let value = match value {
+            serde_json::Value::Null => continue,
+            serde_json::Value::String(value) => value,
+            serde_json::Value::Object(table) if JSON_SETTINGS.contains(&name.as_str()) => serde_json::Value::Object(table).to_string(),
+            serde_json::Value::Object(table) if PAIR_SETTINGS.contains(&name.as_str()) => table.into_iter()
+                .map(|(key, value)| scalar(value).map(|value| format!("{}={}", key, value)).ok_or_else(|| format!("{}.{} must be a string", name.to_lowercase(), key)))
+                .collect::<Result<Vec<_>, _>>()?
+                .join(","),
+            serde_json::Value::Object(table) => {
+                flatten_settings(&format!("{}_", name), table, settings)?;
+                continue;
+            },
+            // Lists are comma-separated in the environment
+            serde_json::Value::Array(items) => items.into_iter()
+                .map(|item| scalar(item).ok_or_else(|| format!("{} must be a list of values", name.to_lowercase())))
+                .collect::<Result<Vec<_>, _>>()?
+                .join(","),
+            value => scalar(value).unwrap_or_default(),
+        };
+        settings.insert(name, value);
+    }
+    Ok(())
+}
+
+fn scalar(value: serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(value) => Some(value),
+        serde_json::Value::Number(value) => Some(value.to_string()),
+        serde_json::Value::Bool(value) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+// Parses {"assets.example.com": {"Authorization": "Bearer ..."}} into per-host headers
+fn parse_fetch_headers(value: &str) -> crate::error::ServiceResult<HashMap<String, Vec<(String, String)>>> {
+    let invalid = |detail: String| crate::error::ServiceError::ValidationError(format!("Invalid FETCH_HEADERS value: {}", detail));
+
+    let parsed: HashMap<String, HashMap<String, String>> = serde_json::from_str(value)
+        .map_err(|e| invalid(e.to_string()))?;
+
+    parsed.into_iter()
+        .map(|(host, headers)| {
+            let headers = headers.into_iter()
+                .map(|(name, value)| {
+                    reqwest::header::HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid(format!("header name {}", name)))?;
+                    reqwest::header::HeaderValue::from_str(&value).map_err(|_| invalid(format!("value of {}", name)))?;
+                    Ok((name, value))
+                })
+                .collect::<crate::error::ServiceResult<_>>()?;
+            Ok((host.to_lowercase(), headers))
+        })
+        .collect()
+}
+
+// Parses "thumbnail=150x150,og=1200x630" into a preset map
+fn parse_presets(value: &str) -> crate::error::ServiceResult<HashMap<String, (u32, u32)>> {
+    let mut presets = HashMap::new();
+
+    for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let invalid = || crate::error::ServiceError::ValidationError(
+            format!("Invalid SIZE_PRESETS entry: {}", entry));
+
+        let (name, size) = entry.split_once('=').ok_or_else(invalid)?;
+        let (width, height) = size.split_once('x').ok_or_else(invalid)?;
+        let width = width.trim().parse().map_err(|_| invalid())?;
+        let height = height.trim().parse().map_err(|_| invalid())?;
+
+        presets.insert(name.trim().to_string(), (width, height));
+    }
+
+    Ok(presets)
+}
+
+impl Listener {
+    // "http://0.0.0.0:3000", "https://0.0.0.0:3443", "admin://127.0.0.1:9090" or "unix:///run/svg-rasterizer.sock"
+    pub fn parse(value: &str) -> Option<Self> {
+        let (scheme, address) = value.split_once("://")?;
+        match scheme {
+            "http" => Some(Listener::Http(address.parse().ok()?)),
+            "https" => Some(Listener::Https(address.parse().ok()?)),
+            "admin" => Some(Listener::Admin(address.parse().ok()?)),
+            "unix" if !address.is_empty() => Some(Listener::Unix(address.to_string())),
+            _ => None,
+        }
+    }
+}
+
+impl RetryOn {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RetryOn::ServerError => "5xx",
+            RetryOn::TooManyRequests => "429",
+            RetryOn::Timeout => "timeout",
+            RetryOn::Connect => "connect",
+        }
+    }
+}
+
+impl IpVersion {
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.to_lowercase().as_str() {
+            "any" => Some(IpVersion::Any),
+            "4" | "ipv4" => Some(IpVersion::V4),
+            "6" | "ipv6" => Some(IpVersion::V6),
+            _ => None,
+        }
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        match self {
+            IpVersion::Any => true,
+            IpVersion::V4 => ip.is_ipv4(),
+            IpVersion::V6 => ip.is_ipv6(),
+        }
+    }
+}
+
+impl IpNetwork {
+    pub fn parse(value: &str) -> Option<Self> {
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>().ok()?, Some(prefix.parse::<u8>().ok()?)),
+            None => (value.parse::<IpAddr>().ok()?, None),
+        };
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max_prefix);
+
+        (prefix <= max_prefix).then_some(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Clients on dual-stack sockets show up as IPv4-mapped IPv6 addresses
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+            ip => ip,
+        };
+
+        match (self.addr, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
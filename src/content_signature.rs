@@ -0,0 +1,129 @@
+use actix_web::body::{to_bytes, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::HttpResponse;
+use ed25519_dalek::{Signer, SigningKey};
+use hmac::{Hmac, Mac};
+use serde_json::json;
+use sha2::Sha256;
+
+use crate::config::{self, SignatureAlgorithm};
+use crate::error::{ServiceError, ServiceResult};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const SIGNATURE_HEADER: HeaderName = HeaderName::from_static("x-content-signature");
+
+// Signs the body of every successful response with CONTENT_SIGNATURE_KEY, so
+// consumers can check that caches between us and them didn't alter a render:
+// `X-Content-Signature: ed25519=<hex>` or `hmac-sha256=<hex>`.
+pub async fn handle(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let response = next.call(req).await?;
+    let config = config::current();
+    let Some(key) = config.content_signature_key.as_deref() else {
+        return Ok(response.map_into_boxed_body());
+    };
+    if !response.status().is_success() {
+        return Ok(response.map_into_boxed_body());
+    }
+
+    let (req, response) = response.into_parts();
+    let (mut response, body) = response.into_parts();
+    let body = to_bytes(body).await
+        .map_err(|_| ServiceError::SvgProcessingError("Failed to read response body".to_string()))?;
+    let signature = sign(key, config.content_signature_algorithm, &body);
+    response.headers_mut().insert(SIGNATURE_HEADER, HeaderValue::from_str(&signature).expect("hex is a valid header value"));
+    Ok(ServiceResponse::new(req, response.set_body(body).map_into_boxed_body()))
+}
+
+fn sign(key: &str, algorithm: SignatureAlgorithm, body: &[u8]) -> String {
+    match algorithm {
+        SignatureAlgorithm::HmacSha256 => {
+            let mut mac = HmacSha256::new_from_slice(key.as_bytes())
+                .expect("HMAC accepts keys of any size");
+            mac.update(body);
+            format!("hmac-sha256={}", hex::encode(mac.finalize().into_bytes()))
+        },
+        SignatureAlgorithm::Ed25519 => {
+            format!("ed25519={}", hex::encode(signing_key(key).sign(body).to_bytes()))
+        },
+    }
+}
+
+// Checked by Config::validate
+fn signing_key(key: &str) -> SigningKey {
+    let key = hex::decode(key).ok()
+        .and_then(|key| <[u8; 32]>::try_from(key).ok())
+        .expect("CONTENT_SIGNATURE_KEY is a 32-byte hex key");
+    SigningKey::from_bytes(&key)
+}
+
+// GET /content-signature-key: the Ed25519 public key to verify signatures with.
+// HMAC keys are secret, so there's nothing to publish for them.
+pub async fn public_key_handler() -> ServiceResult<HttpResponse> {
+    let config = config::current();
+    match (&config.content_signature_key, config.content_signature_algorithm) {
+        (Some(key), SignatureAlgorithm::Ed25519) => Ok(HttpResponse::Ok().json(json!({
+            "algorithm": "ed25519",
+            "publicKey": hex::encode(signing_key(key).verifying_key().to_bytes()),
+        }))),
+        _ => Err(ServiceError::NotFound("Responses aren't signed with an Ed25519 key".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{middleware, web, App};
+    use std::sync::Arc;
+
+    use crate::config::Config;
+    use crate::request_context::{self, RequestFields};
+
+    // RFC 8032, test 1
+    const ED25519_KEY: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+
+    #[test]
+    fn signs_rfc_4231_hmac_example() {
+        assert_eq!(
+            sign("Jefe", SignatureAlgorithm::HmacSha256, b"what do ya want for nothing?"),
+            "hmac-sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn signs_rfc_8032_ed25519_example() {
+        assert_eq!(
+            sign(ED25519_KEY, SignatureAlgorithm::Ed25519, b""),
+            "ed25519=e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b"
+        );
+        assert_eq!(
+            hex::encode(signing_key(ED25519_KEY).verifying_key().to_bytes()),
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a"
+        );
+    }
+
+    #[actix_web::test]
+    async fn signs_only_successful_responses() {
+        let config = Config { content_signature_key: Some("secret".to_string()), ..Config::default() };
+        let cx = request_context::detached(RequestFields { tenant_config: Some(Arc::new(config)), ..Default::default() });
+
+        request_context::scope(cx, async {
+            let app = init_service(App::new()
+                .wrap(middleware::from_fn(handle))
+                .route("/ok", web::get().to(|| async { "rendered" }))
+                .route("/missing", web::get().to(|| async { HttpResponse::NotFound().body("missing") })))
+                .await;
+
+            let response = call_service(&app, TestRequest::get().uri("/ok").to_request()).await;
+            let signature = response.headers().get(SIGNATURE_HEADER).cloned();
+            assert_eq!(read_body(response).await, "rendered");
+            assert_eq!(signature.unwrap(), sign("secret", SignatureAlgorithm::HmacSha256, b"rendered").as_str());
+
+            let response = call_service(&app, TestRequest::get().uri("/missing").to_request()).await;
+            assert!(response.headers().get(SIGNATURE_HEADER).is_none());
+        }).await;
+    }
+}
@@ -0,0 +1,95 @@
+use std::io::{Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::cli::RasterizeArgs;
+use crate::config::{self, Config};
+use crate::error::{ServiceError, ServiceResult};
+use crate::svg::{self, RenderOptions, SvgProcessor};
+use svg_rasterizer_core::ImageFormat;
+
+// `rasterize`: renders one SVG straight to a file with the service's parser, renderer
+// and encoders, for reproducing rendering issues with a customer's file. With --stdin
+// it works as a filter, SVG in and image out, so only errors are written to stderr.
+pub fn run(args: RasterizeArgs) -> std::io::Result<()> {
+    let input = args.input.as_deref().unwrap_or("stdin");
+    match rasterize(&args) {
+        Ok((width, height, size, elapsed)) => {
+            if let Some(output) = &args.output {
+                eprintln!("Wrote {} ({}x{}, {} bytes) in {:?}", output.display(), width, height, size, elapsed);
+            }
+            Ok(())
+        },
+        Err(e) => {
+            eprintln!("Failed to rasterize {}: {}", input, e);
+            std::process::exit(1);
+        },
+    }
+}
+
+fn rasterize(args: &RasterizeArgs) -> ServiceResult<(u32, u32, usize, Duration)> {
+    // Sizes, presets, fonts and deterministic rendering follow the service's configuration
+    let config = Config::from_env()?;
+    config::set_current(config.clone());
+    svg::configure(&config);
+
+    let format = match &args.format {
+        Some(format) => ImageFormat::parse(format)
+            .ok_or_else(|| ServiceError::ValidationError(format!("Unsupported format: {}, expected png, webp, jpeg, gif or avif", format)))?,
+        None => args.output.as_deref().and_then(ImageFormat::from_path).unwrap_or(ImageFormat::Png),
+    };
+    let background = args.background.as_deref().map(svg::parse_color).transpose()?;
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    let processor = SvgProcessor::new(&client);
+    let svg_data = match args.input.as_deref() {
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => {
+            actix_web::rt::System::new().block_on(processor.fetch(url))?
+        },
+        Some(path) => {
+            let svg_data = std::fs::read_to_string(path)
+                .map_err(|e| ServiceError::ValidationError(format!("Failed to read {}: {}", path, e)))?;
+            svg_rasterizer_core::check_content(&svg_data)?;
+            svg_data
+        },
+        None => {
+            let mut svg_data = String::new();
+            std::io::stdin().read_to_string(&mut svg_data)
+                .map_err(|e| ServiceError::ValidationError(format!("Failed to read stdin: {}", e)))?;
+            svg_rasterizer_core::check_content(&svg_data)?;
+            svg_data
+        },
+    };
+
+    let start = Instant::now();
+    let rtree = processor.parse(&svg_data)?;
+    let (width, height) = if args.width.is_none() && args.height.is_none() && args.preset.is_none() {
+        // The SVG's own size, rather than the service's default
+        let size = rtree.size.to_int_size();
+        (size.width(), size.height())
+    } else {
+        config.resolve_size(args.width, args.height, args.preset.as_deref())?
+    };
+
+    let mut options = RenderOptions::new(width, height);
+    options.background = background;
+    options.format = format;
+    options.quality = args.quality;
+    options.lossless |= args.lossless;
+    let pixmap = processor.render_with_options(&svg_data, &rtree, &options)?;
+    let encoded = processor.encode(&pixmap, &options)?;
+    let elapsed = start.elapsed();
+
+    match &args.output {
+        Some(output) => std::fs::write(output, &encoded)
+            .map_err(|e| ServiceError::ValidationError(format!("Failed to write {}: {}", output.display(), e)))?,
+        None => {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(&encoded)
+                .and_then(|_| stdout.flush())
+                .map_err(|e| ServiceError::ValidationError(format!("Failed to write stdout: {}", e)))?;
+        },
+    }
+    Ok((pixmap.width(), pixmap.height(), encoded.len(), elapsed))
+}
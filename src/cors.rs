@@ -0,0 +1,35 @@
+use actix_cors::Cors;
+use actix_web::middleware::Condition;
+
+use crate::config::Config;
+
+// Headers of ours that scripts may read from cross-origin responses
+const EXPOSED_HEADERS: [&str; 5] = ["X-BlurHash", "X-Fallback", "Retry-After", "Deprecation", "Link"];
+
+// CORS for browsers calling the API with fetch(), e.g. to draw renders onto a
+// canvas without tainting it. Preflight requests are answered here and never
+// reach the handlers. Without CORS_ALLOWED_ORIGINS the middleware is left out
+// entirely, as the default would reject every request that has an Origin.
+pub fn middleware(config: &Config) -> Condition<Cors> {
+    Condition::new(!config.cors_allowed_origins.is_empty(), cors(config))
+}
+
+fn cors(config: &Config) -> Cors {
+    let mut cors = Cors::default()
+        .allowed_methods(config.cors_allowed_methods.iter().map(String::as_str))
+        .expose_headers(EXPOSED_HEADERS)
+        .max_age(config.cors_max_age_secs);
+
+    cors = if config.cors_allowed_origins.iter().any(|origin| origin == "*") {
+        cors.allow_any_origin()
+    } else {
+        config.cors_allowed_origins.iter()
+            .fold(cors, |cors, origin| cors.allowed_origin(origin.trim_end_matches('/')))
+    };
+
+    if config.cors_allowed_headers.iter().any(|name| name == "*") {
+        cors.allow_any_header()
+    } else {
+        cors.allowed_headers(config.cors_allowed_headers.iter().map(String::as_str))
+    }
+}
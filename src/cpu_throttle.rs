@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::metrics::metrics;
+use crate::render_pool;
+
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(2);
+// Concurrency only comes back once utilization is this far below the threshold,
+// so the limit doesn't flap around it
+const RECOVERY_MARGIN: f64 = 0.15;
+// Saturation caused by our own renders is expected, only back off when
+// something else is taking at least this share of the host
+const MIN_OTHER_LOAD: f64 = 0.1;
+
+// Busy and total CPU time of the host, and CPU time of this process, in clock ticks
+#[derive(Clone, Copy)]
+struct CpuSample {
+    busy: u64,
+    total: u64,
+    process: u64,
+}
+
+// Watches host CPU usage and lowers the render concurrency while other
+// workloads (noisy neighbours, other containers) saturate the CPU, raising it
+// again one render at a time once load drops
+pub fn start(config: &Config) {
+    if config.cpu_throttle_threshold == 0 {
+        return;
+    }
+    let Some(capacity) = render_pool::max_concurrency() else {
+        return;
+    };
+    let Some(mut previous) = sample() else {
+        log::warn!("CPU_THROTTLE_THRESHOLD is set but CPU usage can't be read from /proc, throttling is disabled");
+        return;
+    };
+
+    let threshold = config.cpu_throttle_threshold as f64 / 100.0;
+    log::info!("CPU throttling enabled above {:.0}% host CPU", threshold * 100.0);
+
+    let spawned = std::thread::Builder::new()
+        .name("cpu-throttle".to_string())
+        .spawn(move || {
+            let mut limit = capacity;
+            loop {
+                std::thread::sleep(SAMPLE_INTERVAL);
+                let Some(current) = sample() else {
+                    continue;
+                };
+
+                let total = current.total.saturating_sub(previous.total).max(1) as f64;
+                let busy = current.busy.saturating_sub(previous.busy) as f64 / total;
+                let own = (current.process.saturating_sub(previous.process) as f64 / total).min(busy);
+                previous = current;
+
+                metrics().cpu_utilization.with_label_values(&["self"]).set(own);
+                metrics().cpu_utilization.with_label_values(&["other"]).set(busy - own);
+
+                let new_limit = if busy >= threshold && busy - own >= MIN_OTHER_LOAD {
+                    // Back off quickly, by a quarter of the current limit
+                    (limit - (limit / 4).max(1)).max(1)
+                } else if busy < threshold - RECOVERY_MARGIN {
+                    (limit + 1).min(capacity)
+                } else {
+                    limit
+                };
+
+                if new_limit != limit {
+                    log::info!("Host CPU at {:.0}% ({:.0}% other processes), render concurrency {} -> {}",
+                        busy * 100.0, (busy - own) * 100.0, limit, new_limit);
+                    limit = new_limit;
+                    render_pool::set_concurrency_limit(limit);
+                }
+            }
+        });
+
+    if let Err(e) = spawned {
+        log::error!("Failed to start CPU throttling: {}", e);
+    }
+}
+
+fn sample() -> Option<CpuSample> {
+    // First line: cpu user nice system idle iowait irq softirq steal ...
+    let stat = std::fs::read_to_string("/proc/stat").ok()?;
+    let times: Vec<u64> = stat.lines()
+        .next()?
+        .split_whitespace()
+        .skip(1)
+        .take(8)
+        .map(|value| value.parse().ok())
+        .collect::<Option<_>>()?;
+    if times.len() < 8 {
+        return None;
+    }
+    let total: u64 = times.iter().sum();
+    let idle = times[3] + times[4];
+
+    // utime and stime are fields 14 and 15, counted after the parenthesised
+    // process name, which may itself contain spaces
+    let own = std::fs::read_to_string("/proc/self/stat").ok()?;
+    let fields: Vec<&str> = own.rsplit_once(')')?.1.split_whitespace().collect();
+    let process = fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?;
+
+    Some(CpuSample { busy: total - idle, total, process })
+}
@@ -0,0 +1,136 @@
+use actix_web::{web, HttpResponse};
+use resvg::tiny_skia::{Pixmap, PremultipliedColorU8};
+use serde::Deserialize;
+use serde_json::json;
+use utoipa::{IntoParams, ToSchema};
+
+use crate::config;
+use crate::error::{ServiceResult, ServiceError};
+use crate::params::Query;
+use crate::rate_limit::RateLimiter;
+use crate::svg::SvgProcessor;
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DiffRequest {
+    /// URL of the first SVG
+    pub url_a: String,
+    /// URL of the second SVG
+    pub url_b: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Per-channel difference below which pixels count as equal
+    pub threshold: Option<u8>,
+    /// Respond with the diff image, or with the mismatch numbers as JSON
+    pub format: Option<DiffFormat>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffFormat {
+    Png,
+    Json,
+}
+
+pub struct DiffResult {
+    pub image: Pixmap,
+    pub mismatched_pixels: u64,
+    pub total_pixels: u64,
+}
+
+impl DiffResult {
+    pub fn mismatch_percentage(&self) -> f64 {
+        self.mismatched_pixels as f64 * 100.0 / self.total_pixels as f64
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/diff",
+    tag = "analysis",
+    params(DiffRequest),
+    responses(
+        (status = 200, description = "Diff image, or the mismatch as JSON with format=json", content_type = "image/png"),
+        (status = 400, description = "Invalid parameters or SVG", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+    ),
+)]
+pub async fn visual_diff(
+    req: Query<DiffRequest>,
+    rate_limiter: web::Data<RateLimiter>,
+    client: web::Data<reqwest::Client>,
+) -> ServiceResult<HttpResponse> {
+    let config = config::current();
+    log::info!("Processing diff request: {:?}", req);
+
+    if !rate_limiter.check_rate().await {
+        log::warn!("Rate limit exceeded for diff request");
+        return Err(ServiceError::RateLimitExceeded);
+    }
+
+    let json_response = req.format == Some(DiffFormat::Json);
+
+    let (width, height) = config.validate_dimensions(req.width, req.height);
+    let processor = SvgProcessor::new(&client);
+    let trees = processor.fetch_all(&[req.url_a.clone(), req.url_b.clone()]).await?;
+
+    let a = processor.render_pixmap(&trees[0], width, height)?;
+    let b = processor.render_pixmap(&trees[1], width, height)?;
+    let result = diff_pixmaps(&a, &b, req.threshold.unwrap_or(0))?;
+    let percentage = format!("{:.4}", result.mismatch_percentage());
+    log::info!("Diff of {} and {}: {}% mismatch", req.url_a, req.url_b, percentage);
+
+    if json_response {
+        return Ok(HttpResponse::Ok().json(json!({
+            "width": width,
+            "height": height,
+            "mismatchedPixels": result.mismatched_pixels,
+            "totalPixels": result.total_pixels,
+            "mismatchPercentage": result.mismatch_percentage(),
+        })));
+    }
+
+    let png_data = processor.encode_png(&result.image)?;
+    Ok(HttpResponse::Ok()
+        .content_type("image/png")
+        .insert_header(("X-Mismatch-Percentage", percentage))
+        .insert_header(("X-Mismatched-Pixels", result.mismatched_pixels.to_string()))
+        .body(png_data))
+}
+
+// Differing pixels are drawn in red over a faded grayscale copy of `a`.
+// A pixel differs when any channel differs by more than `threshold`.
+pub fn diff_pixmaps(a: &Pixmap, b: &Pixmap, threshold: u8) -> ServiceResult<DiffResult> {
+    let mut image = Pixmap::new(a.width(), a.height())
+        .ok_or_else(|| ServiceError::SvgProcessingError("Failed to create pixel buffer".into()))?;
+    let red = PremultipliedColorU8::from_rgba(255, 0, 0, 255).expect("opaque color is valid");
+    let mut mismatched_pixels = 0;
+
+    for ((out, pa), pb) in image.pixels_mut().iter_mut().zip(a.pixels()).zip(b.pixels()) {
+        let (ca, cb) = (pa.demultiply(), pb.demultiply());
+        let difference = [
+            ca.red().abs_diff(cb.red()),
+            ca.green().abs_diff(cb.green()),
+            ca.blue().abs_diff(cb.blue()),
+            ca.alpha().abs_diff(cb.alpha()),
+        ].into_iter().max().unwrap_or(0);
+
+        *out = if difference > threshold {
+            mismatched_pixels += 1;
+            red
+        } else {
+            // Luma of `a` composited on white, faded towards white
+            let alpha = ca.alpha() as u32;
+            let luma = (ca.red() as u32 * 299 + ca.green() as u32 * 587 + ca.blue() as u32 * 114) / 1000;
+            let on_white = (luma * alpha + 255 * (255 - alpha)) / 255;
+            let faded = (255 - (255 - on_white) * 3 / 10) as u8;
+            PremultipliedColorU8::from_rgba(faded, faded, faded, 255).expect("opaque color is valid")
+        };
+    }
+
+    Ok(DiffResult {
+        image,
+        mismatched_pixels,
+        total_pixels: a.width() as u64 * a.height() as u64,
+    })
+}
@@ -0,0 +1,140 @@
+use hickory_resolver::system_conf::read_system_conf;
+use hickory_resolver::TokioAsyncResolver;
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::config::{Config, IpVersion};
+use crate::metrics::metrics;
+
+// Distinct host names kept in the cache
+const CACHE_SIZE: usize = 1024;
+
+// Resolves source hosts, with DNS_CACHE through an in-process cache that keeps
+// answers for their DNS TTL, capped at DNS_CACHE_MAX_TTL, instead of asking the
+// system resolver on every new connection. Only addresses of FETCH_IP_VERSION
+// are returned, so connections never go out over the other family.
+pub struct Resolver {
+    cache: Option<TokioAsyncResolver>,
+    ip_version: IpVersion,
+}
+
+// None when reqwest's own resolver will do
+pub fn resolver(config: &Config) -> Option<Arc<Resolver>> {
+    let cache = if config.dns_cache { caching_resolver(config) } else { None };
+    if cache.is_none() && config.fetch_ip_version == IpVersion::Any {
+        return None;
+    }
+
+    Some(Arc::new(Resolver {
+        cache,
+        ip_version: config.fetch_ip_version,
+    }))
+}
+
+// Whether `ip` is reachable on the public internet: not loopback, private, link-local
+// (which includes cloud metadata endpoints), unspecified or otherwise reserved
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified()
+        || ip.is_broadcast() || ip.is_multicast() || ip.is_documentation()
+        // "This network", shared address space (carrier-grade NAT), IETF protocol
+        // assignments, benchmarking and the reserved 240/4
+        || a == 0 || (a == 100 && (64..128).contains(&b)) || (a == 192 && b == 0 && c == 0)
+        || (a == 198 && (b == 18 || b == 19)) || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    // NAT64 addresses reach the IPv4 address in their last 32 bits
+    if ip.segments()[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+        let [.., a, b, c, d] = ip.octets();
+        return is_public_v4(Ipv4Addr::new(a, b, c, d));
+    }
+    !(ip.is_loopback() || ip.is_unspecified() || ip.is_multicast()
+        // Unique local, link-local, deprecated site-local and documentation
+        || (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80 || (first & 0xffc0) == 0xfec0
+        || (first == 0x2001 && ip.segments()[1] == 0xdb8))
+}
+
+fn caching_resolver(config: &Config) -> Option<TokioAsyncResolver> {
+    let (resolver_config, mut options) = match read_system_conf() {
+        Ok(conf) => conf,
+        Err(e) => {
+            log::warn!("Failed to read the system DNS configuration, DNS caching is disabled: {}", e);
+            return None;
+        },
+    };
+    options.cache_size = CACHE_SIZE;
+    options.positive_max_ttl = Some(Duration::from_secs(config.dns_cache_max_ttl));
+    options.negative_max_ttl = Some(Duration::from_secs(config.dns_cache_max_ttl));
+
+    log::info!("DNS cache enabled, answers kept for at most {}s", config.dns_cache_max_ttl);
+    Some(TokioAsyncResolver::tokio(resolver_config, options))
+}
+
+impl Resolve for Resolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let cache = self.cache.clone();
+        let ip_version = self.ip_version;
+        Box::pin(async move {
+            let _timer = metrics().dns_lookup_duration.start_timer();
+            // reqwest fills in the port
+            let addrs: Vec<SocketAddr> = match cache {
+                Some(resolver) => resolver.lookup_ip(name.as_str()).await?
+                    .iter()
+                    .map(|ip| SocketAddr::new(ip, 0))
+                    .collect(),
+                None => tokio::net::lookup_host((name.as_str(), 0)).await?.collect(),
+            };
+
+            let addrs: Vec<SocketAddr> = addrs.into_iter().filter(|addr| ip_version.allows(addr.ip())).collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no {} address", name.as_str(), match ip_version {
+                    IpVersion::V6 => "IPv6",
+                    _ => "IPv4",
+                }).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn rejects_internal_addresses() {
+        for ip in [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "0.0.0.0", "100.64.0.1",
+            "255.255.255.255", "224.0.0.1", "::1", "::", "fd00::1", "fe80::1", "::ffff:127.0.0.1", "::ffff:10.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(!public(ip), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn accepts_public_addresses() {
+        for ip in ["93.184.216.34", "1.1.1.1", "100.128.0.1", "2606:4700::1111", "::ffff:8.8.8.8", "64:ff9b::808:808"] {
+            assert!(public(ip), "{}", ip);
+        }
+    }
+}
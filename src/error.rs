@@ -1,6 +1,7 @@
 use actix_web::{error::ResponseError, HttpResponse, http::StatusCode};
+use serde::Serialize;
 use thiserror::Error;
-use serde_json::json;
+use utoipa::ToSchema;
 
 #[derive(Error, Debug)]
 pub enum ServiceError {
@@ -21,16 +22,61 @@ pub enum ServiceError {
 
     #[error("Invalid input: {0}")]
     ValidationError(String),
+
+    // A query parameter that didn't parse: the parameter's name and what was wrong
+    #[error("Invalid input: {0}: {1}")]
+    InvalidParameter(String, String),
+
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    #[error("Storage error: {0}")]
+    StorageError(String),
+
+    #[error("Unauthorized: {0}")]
+    Unauthorized(String),
+
+    // Message and the number of seconds after which the client should retry
+    #[error("Service overloaded: {0}")]
+    Overloaded(String, u64),
+
+    // Message and the number of seconds until the source host is tried again
+    #[error("Upstream unavailable: {0}")]
+    UpstreamUnavailable(String, u64),
 }
 
 pub type ServiceResult<T> = Result<T, ServiceError>;
 
+impl From<svg_rasterizer_core::Error> for ServiceError {
+    fn from(error: svg_rasterizer_core::Error) -> Self {
+        match error {
+            svg_rasterizer_core::Error::Request(e) => ServiceError::RequestError(e),
+            svg_rasterizer_core::Error::InvalidContent(message) => ServiceError::ValidationError(message),
+            e => ServiceError::SvgProcessingError(e.to_string()),
+        }
+    }
+}
+
+// JSON body of every error response
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// Error class, e.g. `validation_error` or `rate_limit_exceeded`
+    error: &'static str,
+    message: String,
+    /// The query parameter that was rejected, for invalid parameters
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<String>,
+}
+
 impl ResponseError for ServiceError {
     fn error_response(&self) -> HttpResponse {
         let (status, error_type) = match self {
             ServiceError::RateLimitExceeded => 
                 (StatusCode::TOO_MANY_REQUESTS, "rate_limit_exceeded"),
-            ServiceError::ValidationError(_) => 
+            ServiceError::ValidationError(_) | ServiceError::InvalidParameter(..) => 
                 (StatusCode::BAD_REQUEST, "validation_error"),
             ServiceError::CacheError(_) => 
                 (StatusCode::INTERNAL_SERVER_ERROR, "cache_error"),
@@ -40,11 +86,38 @@ impl ResponseError for ServiceError {
                 (StatusCode::BAD_GATEWAY, "request_error"),
             ServiceError::SvgProcessingError(_) => 
                 (StatusCode::BAD_REQUEST, "svg_processing_error"),
+            ServiceError::NotFound(_) => 
+                (StatusCode::NOT_FOUND, "not_found"),
+            ServiceError::Conflict(_) => 
+                (StatusCode::CONFLICT, "conflict"),
+            ServiceError::StorageError(_) => 
+                (StatusCode::BAD_GATEWAY, "storage_error"),
+            ServiceError::Unauthorized(_) => 
+                (StatusCode::UNAUTHORIZED, "unauthorized"),
+            ServiceError::Overloaded(..) => 
+                (StatusCode::SERVICE_UNAVAILABLE, "overloaded"),
+            ServiceError::UpstreamUnavailable(..) => 
+                (StatusCode::SERVICE_UNAVAILABLE, "upstream_unavailable"),
         };
 
-        HttpResponse::build(status).json(json!({
-            "error": error_type,
-            "message": self.to_string()
-        }))
+        let mut response = HttpResponse::build(status);
+        if let ServiceError::Overloaded(_, retry_after) | ServiceError::UpstreamUnavailable(_, retry_after) = self {
+            response.insert_header(("Retry-After", retry_after.to_string()));
+        }
+
+        response.json(ErrorBody {
+            error: error_type,
+            message: self.to_string(),
+            field: match self {
+                ServiceError::InvalidParameter(field, _) => Some(field.clone()),
+                _ => None,
+            },
+        })
     }
-}
\ No newline at end of file
+}
+
+// Whether `result` failed on the query parameter or path option `parameter`
+#[cfg(test)]
+pub(crate) fn is_invalid<T>(result: ServiceResult<T>, parameter: &str) -> bool {
+    matches!(result, Err(ServiceError::InvalidParameter(name, _)) if name == parameter)
+}
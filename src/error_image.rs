@@ -0,0 +1,98 @@
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{HttpResponse, ResponseError};
+
+use crate::error::ServiceError;
+use crate::fallback;
+use crate::render_pool;
+use crate::montage::escape_xml;
+use crate::svg::SvgProcessor;
+
+// Rough average glyph width relative to the font size
+const GLYPH_WIDTH: f32 = 0.6;
+const LINE_HEIGHT: f32 = 1.3;
+
+// The error rendered as a PNG at the requested size, for onerror=image
+pub async fn response(error: &ServiceError, width: u32, height: u32, client: &reqwest::Client) -> Option<HttpResponse> {
+    let status = error.error_response().status();
+    let svg = error_svg(status, &error.to_string(), width, height);
+
+    let processor = SvgProcessor::new(client);
+    let rendered = render_pool::run(move || {
+        let rtree = processor.parse_with_text(&svg)?;
+        processor.render(&rtree, width, height)
+    }).await;
+
+    match rendered {
+        Ok(png_data) => Some(fallback::image_response(error, Bytes::from(png_data))),
+        Err(e) => {
+            log::error!("Failed to render error image: {}", e);
+            None
+        },
+    }
+}
+
+// The status code large at the top, the message wrapped below it
+fn error_svg(status: StatusCode, message: &str, width: u32, height: u32) -> String {
+    let (w, h) = (width as f32, height as f32);
+    let status_size = (h * 0.25).min(w / 5.0).max(6.0);
+    let message_size = (status_size * 0.35).max(6.0);
+
+    let max_chars = ((w * 0.9 / (message_size * GLYPH_WIDTH)) as usize).max(1);
+    let max_lines = ((h - status_size * 2.0) / (message_size * LINE_HEIGHT)).max(0.0) as usize;
+    let lines = wrap(message, max_chars, max_lines);
+
+    let status_y = (h - lines.len() as f32 * message_size * LINE_HEIGHT) / 2.0 + status_size * 0.4;
+    let mut text = format!(
+        r##"<text x="{x}" y="{y}" font-family="sans-serif" font-size="{size}" font-weight="bold" text-anchor="middle" fill="#b3261e">{status}</text>"##,
+        x = w / 2.0,
+        y = status_y,
+        size = status_size,
+        status = status.as_u16(),
+    );
+    for (i, line) in lines.iter().enumerate() {
+        text.push_str(&format!(
+            r##"<text x="{x}" y="{y}" font-family="sans-serif" font-size="{size}" text-anchor="middle" fill="#333333">{line}</text>"##,
+            x = w / 2.0,
+            y = status_y + status_size * 0.6 + (i as f32 + 1.0) * message_size * LINE_HEIGHT,
+            size = message_size,
+            line = escape_xml(line),
+        ));
+    }
+
+    format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}"><rect width="{w}" height="{h}" fill="#f4f4f4"/>{text}</svg>"##,
+        w = width,
+        h = height,
+        text = text,
+    )
+}
+
+// Greedy word wrap, the last line is cut off with an ellipsis when there is more
+fn wrap(message: &str, max_chars: usize, max_lines: usize) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    let mut current = String::new();
+
+    for word in message.split_whitespace() {
+        let word: String = word.chars().take(max_chars).collect();
+        if !current.is_empty() && current.chars().count() + 1 + word.chars().count() > max_chars {
+            lines.push(std::mem::take(&mut current));
+        }
+        if !current.is_empty() {
+            current.push(' ');
+        }
+        current.push_str(&word);
+    }
+    if !current.is_empty() {
+        lines.push(current);
+    }
+
+    if lines.len() > max_lines {
+        lines.truncate(max_lines);
+        if let Some(last) = lines.last_mut() {
+            let kept: String = last.chars().take(max_chars.saturating_sub(1)).collect();
+            *last = format!("{}…", kept);
+        }
+    }
+    lines
+}
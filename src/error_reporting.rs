@@ -0,0 +1,61 @@
+use crate::config::Config;
+use crate::error::ServiceError;
+
+// Keeps the Sentry client alive, pending events are flushed when dropped
+pub struct ReportingGuard {
+    #[cfg(feature = "sentry")]
+    _guard: Option<sentry::ClientInitGuard>,
+}
+
+// Errors are only reported with the `sentry` feature and `SENTRY_DSN` set,
+// otherwise everything below is a no-op
+pub fn init(config: &Config) -> ReportingGuard {
+    #[cfg(feature = "sentry")]
+    {
+        let guard = config.sentry_dsn.as_ref().map(|dsn| {
+            log::info!("Reporting errors to Sentry");
+            sentry::init((dsn.as_str(), sentry::ClientOptions {
+                release: sentry::release_name!(),
+                attach_stacktrace: true,
+                before_send: Some(std::sync::Arc::new(add_request_context)),
+                ..Default::default()
+            }))
+        });
+        ReportingGuard { _guard: guard }
+    }
+
+    #[cfg(not(feature = "sentry"))]
+    {
+        if config.sentry_dsn.is_some() {
+            log::warn!("SENTRY_DSN is set but error reporting is not compiled in (feature `sentry`)");
+        }
+        ReportingGuard {}
+    }
+}
+
+// Reports an unexpected error and hands it back, for use in `map_err`
+pub fn report(error: ServiceError) -> ServiceError {
+    #[cfg(feature = "sentry")]
+    sentry::capture_error(&error);
+
+    error
+}
+
+// Runs on the thread that captured the event, which for request errors and
+// panics is the one handling the request, so its context is still available
+#[cfg(feature = "sentry")]
+fn add_request_context(mut event: sentry::protocol::Event<'static>) -> Option<sentry::protocol::Event<'static>> {
+    if let Some(cx) = crate::request_context::current() {
+        let fields = cx.fields();
+        event.tags.insert("request_id".to_string(), cx.id.clone());
+        event.tags.insert("method".to_string(), fields.method);
+        if let Some(route) = fields.route {
+            event.tags.insert("route".to_string(), route);
+        }
+        if let Some(upstream_host) = fields.upstream_host {
+            event.tags.insert("upstream_host".to_string(), upstream_host);
+        }
+        event.extra.insert("path".to_string(), fields.path.into());
+    }
+    Some(event)
+}
@@ -0,0 +1,131 @@
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::web::Bytes;
+use actix_web::{HttpResponse, ResponseError};
+use resvg::tiny_skia::{FilterQuality, Pixmap, PixmapPaint, Transform};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+use crate::config::Config;
+use crate::error::{ServiceResult, ServiceError};
+use crate::error_reporting;
+use crate::pixmap_pool;
+use crate::render_pool;
+use crate::svg::SvgProcessor;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+// Rendered placeholders kept in memory, the cache starts over when it is full
+const MAX_CACHED_SIZES: usize = 64;
+
+static FALLBACK: OnceLock<Fallback> = OnceLock::new();
+
+// Placeholder served instead of an error when a source can't be fetched or
+// rendered, so pages never show a broken image
+struct Fallback {
+    source: Source,
+    rendered: Mutex<HashMap<(u32, u32), Bytes>>,
+}
+
+enum Source {
+    Svg(String),
+    Png(Vec<u8>),
+}
+
+// Loads FALLBACK_IMAGE_URL or FALLBACK_IMAGE_PATH, an SVG or a PNG
+pub async fn load(config: &Config, client: &reqwest::Client) -> ServiceResult<()> {
+    let data = if let Some(url) = &config.fallback_image_url {
+        let response = client.get(url).send().await?.error_for_status()?;
+        response.bytes().await?.to_vec()
+    } else if let Some(path) = &config.fallback_image_path {
+        std::fs::read(path)
+            .map_err(|e| ServiceError::ValidationError(format!("Failed to read fallback image {}: {}", path, e)))?
+    } else {
+        return Ok(());
+    };
+
+    let source = if data.starts_with(PNG_SIGNATURE) {
+        Pixmap::decode_png(&data)
+            .map_err(|e| ServiceError::ValidationError(format!("Invalid fallback image: {}", e)))?;
+        Source::Png(data)
+    } else {
+        let svg_data = String::from_utf8(data)
+            .map_err(|_| ServiceError::ValidationError("Fallback image is neither a PNG nor an SVG".to_string()))?;
+        SvgProcessor::new(client).parse(&svg_data)?;
+        Source::Svg(svg_data)
+    };
+
+    log::info!("Fallback image loaded, failed renders are answered with a placeholder");
+    let _ = FALLBACK.set(Fallback {
+        source,
+        rendered: Mutex::new(HashMap::new()),
+    });
+    Ok(())
+}
+
+// The error's status and headers with the placeholder at the requested size as
+// body, or None when no fallback image is configured
+pub async fn response(error: &ServiceError, width: u32, height: u32, client: &reqwest::Client) -> Option<HttpResponse> {
+    let fallback = FALLBACK.get()?;
+
+    let cached = fallback.rendered.lock().unwrap().get(&(width, height)).cloned();
+    let png_data = match cached {
+        Some(png_data) => png_data,
+        None => {
+            let processor = SvgProcessor::new(client);
+            let rendered = render_pool::run(move || fallback.render(&processor, width, height)).await;
+            let png_data = match rendered {
+                Ok(png_data) => Bytes::from(png_data),
+                Err(e) => {
+                    log::error!("Failed to render the fallback image at {}x{}: {}", width, height, e);
+                    return None;
+                },
+            };
+
+            let mut rendered = fallback.rendered.lock().unwrap();
+            if rendered.len() >= MAX_CACHED_SIZES {
+                rendered.clear();
+            }
+            rendered.insert((width, height), png_data.clone());
+            png_data
+        },
+    };
+
+    log::warn!("Serving the fallback image instead of error: {}", error);
+    let mut response = image_response(error, png_data);
+    response.headers_mut().insert(header::HeaderName::from_static("x-fallback"), HeaderValue::from_static("1"));
+    Some(response)
+}
+
+// The error's status and headers with a PNG body in place of the JSON
+pub fn image_response(error: &ServiceError, png_data: Bytes) -> HttpResponse {
+    let mut response = error.error_response().set_body(png_data);
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+    // Must not be cached in place of the real image
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    response.map_into_boxed_body()
+}
+
+impl Fallback {
+    fn render(&self, processor: &SvgProcessor, width: u32, height: u32) -> ServiceResult<Vec<u8>> {
+        match &self.source {
+            Source::Svg(svg_data) => processor.render(&processor.parse(svg_data)?, width, height),
+            Source::Png(data) => {
+                let image = Pixmap::decode_png(data)
+                    .map_err(|e| ServiceError::SvgProcessingError(format!("Invalid fallback image: {}", e)))?;
+                let mut pixmap = pixmap_pool::get(width, height)
+                    .ok_or_else(|| error_reporting::report(ServiceError::SvgProcessingError("Failed to create pixel buffer".into())))?;
+
+                // Scale to fit and center, like an SVG
+                let scale = (width as f32 / image.width() as f32).min(height as f32 / image.height() as f32);
+                let x = (width as f32 - image.width() as f32 * scale) / 2.0;
+                let y = (height as f32 - image.height() as f32 * scale) / 2.0;
+                let paint = PixmapPaint { quality: FilterQuality::Bicubic, ..PixmapPaint::default() };
+                pixmap.draw_pixmap(0, 0, image.as_ref(), &paint, Transform::from_scale(scale, scale).post_translate(x, y), None);
+
+                let png_data = processor.encode_png(&pixmap);
+                pixmap_pool::release(pixmap);
+                png_data
+            },
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::sync::Arc;
+use utoipa::IntoParams;
+
+use crate::cache::RedisCache;
+use crate::config;
+use crate::error::{ServiceResult, ServiceError};
+use crate::params::Query;
+use crate::rate_limit::RateLimiter;
+use crate::request_context;
+use crate::svg::{parse_color, SvgProcessor};
+
+const ICO_SIZES: [u32; 3] = [16, 32, 48];
+const PNG_SIZES: [u32; 7] = [16, 32, 48, 64, 96, 128, 256];
+const ANDROID_SIZES: [u32; 2] = [192, 512];
+const APPLE_TOUCH_SIZE: u32 = 180;
+
+#[derive(Deserialize, Serialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FaviconRequest {
+    /// URL of the SVG icon
+    pub url: String,
+    /// App name for the web manifest
+    pub name: Option<String>,
+    pub theme_color: Option<String>,
+    pub background_color: Option<String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/favicon-package",
+    tag = "render",
+    params(FaviconRequest),
+    responses(
+        (status = 200, description = "ZIP with favicons, touch icons and a web manifest", content_type = "application/zip"),
+        (status = 400, description = "Invalid parameters or SVG", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+    ),
+)]
+pub async fn favicon_package(
+    req: Query<FaviconRequest>,
+    cache: web::Data<Arc<RedisCache>>,
+    rate_limiter: web::Data<RateLimiter>,
+    client: web::Data<reqwest::Client>,
+) -> ServiceResult<HttpResponse> {
+    log::info!("Processing favicon package request: {:?}", req);
+
+    if !rate_limiter.check_rate().await {
+        log::warn!("Rate limit exceeded for favicon package request");
+        return Err(ServiceError::RateLimitExceeded);
+    }
+
+    let params = serde_json::to_vec(&*req)
+        .map_err(|e| ServiceError::ValidationError(e.to_string()))?;
+    let cache_key = format!("favicon:{}{}", hex::encode(Sha256::digest(&params)), request_context::forwarded_headers_key());
+
+    let zip_data = match cache.get(&cache_key).await? {
+        Some(cached_data) => {
+            log::debug!("Cache hit for key: {}", cache_key);
+            cached_data
+        },
+        None => {
+            let start = std::time::Instant::now();
+            let zip_data = build_package(&req, &client).await?;
+            log::info!("Favicon package built in {:?}, size: {} bytes", start.elapsed(), zip_data.len());
+            cache.set(&cache_key, &zip_data, config::current().cache_ttl()).await?;
+            zip_data
+        }
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header(("Content-Disposition", "attachment; filename=\"favicon-package.zip\""))
+        .body(zip_data))
+}
+
+async fn build_package(req: &FaviconRequest, client: &reqwest::Client) -> ServiceResult<Vec<u8>> {
+    let processor = SvgProcessor::new(client);
+    let svg_data = processor.fetch(&req.url)
+        .await
+        .map_err(|e| ServiceError::SvgProcessingError(e.to_string()))?;
+    let rtree = processor.parse(&svg_data)?;
+
+    let mut files: Vec<(String, Vec<u8>)> = Vec::new();
+
+    let mut ico_images = Vec::with_capacity(ICO_SIZES.len());
+    for size in ICO_SIZES {
+        ico_images.push((size, processor.render(&rtree, size, size)?));
+    }
+    files.push(("favicon.ico".to_string(), encode_ico(&ico_images)));
+
+    for size in PNG_SIZES {
+        files.push((format!("favicon-{}x{}.png", size, size), processor.render(&rtree, size, size)?));
+    }
+    for size in ANDROID_SIZES {
+        files.push((format!("android-chrome-{}x{}.png", size, size), processor.render(&rtree, size, size)?));
+    }
+    files.push(("apple-touch-icon.png".to_string(), processor.render(&rtree, APPLE_TOUCH_SIZE, APPLE_TOUCH_SIZE)?));
+
+    let background = parse_color(req.background_color.as_deref().unwrap_or("#ffffff"))?;
+    for size in ANDROID_SIZES {
+        let pixmap = processor.render_maskable(&rtree, size, background)?;
+        files.push((format!("maskable-icon-{}x{}.png", size, size), processor.encode_png(&pixmap)?));
+    }
+
+    let manifest = json!({
+        "name": req.name.clone().unwrap_or_default(),
+        "short_name": req.name.clone().unwrap_or_default(),
+        "icons": ANDROID_SIZES.iter().map(|size| json!({
+            "src": format!("/android-chrome-{}x{}.png", size, size),
+            "sizes": format!("{}x{}", size, size),
+            "type": "image/png",
+        })).chain(ANDROID_SIZES.iter().map(|size| json!({
+            "src": format!("/maskable-icon-{}x{}.png", size, size),
+            "sizes": format!("{}x{}", size, size),
+            "type": "image/png",
+            "purpose": "maskable",
+        }))).collect::<Vec<_>>(),
+        "theme_color": req.theme_color.clone().unwrap_or_else(|| "#ffffff".to_string()),
+        "background_color": req.background_color.clone().unwrap_or_else(|| "#ffffff".to_string()),
+        "display": "standalone",
+    });
+    let manifest = serde_json::to_vec_pretty(&manifest)
+        .map_err(|e| ServiceError::SvgProcessingError(e.to_string()))?;
+    files.push(("site.webmanifest".to_string(), manifest));
+
+    write_zip(files)
+}
+
+// ICO container with PNG-compressed entries (supported by every current browser)
+fn encode_ico(images: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let mut ico = Vec::new();
+    ico.extend_from_slice(&0u16.to_le_bytes());
+    ico.extend_from_slice(&1u16.to_le_bytes());
+    ico.extend_from_slice(&(images.len() as u16).to_le_bytes());
+
+    let mut offset = 6 + 16 * images.len() as u32;
+    for (size, png_data) in images {
+        // A dimension of 0 means 256 pixels
+        let dimension = if *size >= 256 { 0 } else { *size as u8 };
+        ico.push(dimension);
+        ico.push(dimension);
+        ico.push(0); // no palette
+        ico.push(0); // reserved
+        ico.extend_from_slice(&1u16.to_le_bytes()); // color planes
+        ico.extend_from_slice(&32u16.to_le_bytes()); // bits per pixel
+        ico.extend_from_slice(&(png_data.len() as u32).to_le_bytes());
+        ico.extend_from_slice(&offset.to_le_bytes());
+        offset += png_data.len() as u32;
+    }
+
+    for (_, png_data) in images {
+        ico.extend_from_slice(png_data);
+    }
+
+    ico
+}
+
+fn write_zip(files: Vec<(String, Vec<u8>)>) -> ServiceResult<Vec<u8>> {
+    let zip_error = |e: zip::result::ZipError| ServiceError::SvgProcessingError(format!("Failed to build ZIP: {}", e));
+    let io_error = |e: std::io::Error| ServiceError::SvgProcessingError(format!("Failed to build ZIP: {}", e));
+
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let options = zip::write::FileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+
+    for (name, data) in files {
+        writer.start_file(name, options).map_err(zip_error)?;
+        writer.write_all(&data).map_err(io_error)?;
+    }
+
+    Ok(writer.finish().map_err(zip_error)?.into_inner())
+}
@@ -0,0 +1,168 @@
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tonic::{Request, Response, Status};
+
+use crate::api_keys;
+use crate::cache::RedisCache;
+use crate::config;
+use crate::error::ServiceError;
+use crate::handlers::render_cached;
+use crate::rate_limit::RateLimiter;
+use crate::request_context::{self, RequestFields, API_KEY_HEADER};
+use crate::shutdown;
+use crate::svg::{RenderOptions, SvgProcessor};
+use crate::tenants;
+
+pub mod proto {
+    tonic::include_proto!("rasterizer.v1");
+}
+
+use proto::rasterizer_server::{Rasterizer, RasterizerServer};
+use proto::rasterize_batch_result::Result as BatchResult;
+
+// The render API over gRPC, for internal services that want typed clients. It shares
+// the cache and render pool with the HTTP routes, and the same API key, tenant and
+// rate limit checks apply.
+pub struct RasterizerService {
+    cache: Arc<RedisCache>,
+    client: reqwest::Client,
+    rate_limiter: RateLimiter,
+}
+
+// The :authority of the call, which tonic doesn't keep, for matching tenants by host
+#[derive(Clone)]
+struct Authority(String);
+
+impl RasterizerService {
+    // Runs the call in a request context of its own, identified as a tenant by API key
+    // or host like an HTTP request, once its key and the rate limit allow it
+    async fn call<T, R>(&self, request: &Request<T>, method: &str, f: impl Future<Output = Result<R, Status>>) -> Result<R, Status> {
+        let api_key = request.metadata().get(API_KEY_HEADER).and_then(|v| v.to_str().ok());
+        let cx = request_context::detached(RequestFields {
+            method: "POST".to_string(),
+            path: format!("/rasterizer.v1.Rasterizer/{}", method),
+            client_ip: request.remote_addr().map(|addr| addr.ip().to_string()),
+            client_id: api_key.map(request_context::api_key_fingerprint),
+            priority: api_key.is_some_and(request_context::is_priority_key),
+            ..RequestFields::default()
+        });
+
+        request_context::scope(cx, async {
+            let host = request.extensions().get::<Authority>().map_or("", |authority| authority.0.as_str());
+            tenants::identify(&config::current(), api_key, host);
+            api_keys::authorize(&config::current(), Some(&self.cache), api_key).await.map_err(status)?;
+            if !self.rate_limiter.check_rate().await {
+                log::warn!("Rate limit exceeded for gRPC {}", method);
+                return Err(status(ServiceError::RateLimitExceeded));
+            }
+            f.await
+        }).await
+    }
+
+    async fn render(&self, request: &proto::RasterizeRequest) -> Result<proto::RasterizeResponse, ServiceError> {
+        let (width, height) = config::current().resolve_size(request.width, request.height, request.preset.as_deref())?;
+        let png = render_cached(&request.url, &RenderOptions::new(width, height), &self.cache, &self.client).await?;
+        Ok(proto::RasterizeResponse { png, width, height })
+    }
+
+    // A failed item is reported in its place, rather than failing the whole batch
+    async fn render_item(&self, item: &proto::RasterizeRequest) -> proto::RasterizeBatchResult {
+        let result = match self.render(item).await {
+            Ok(image) => BatchResult::Image(image),
+            Err(e) => {
+                log::warn!("Batch item {} failed: {}", item.url, e);
+                BatchResult::Error(e.to_string())
+            },
+        };
+        proto::RasterizeBatchResult { result: Some(result) }
+    }
+}
+
+#[tonic::async_trait]
+impl Rasterizer for RasterizerService {
+    async fn rasterize(&self, request: Request<proto::RasterizeRequest>) -> Result<Response<proto::RasterizeResponse>, Status> {
+        self.call(&request, "Rasterize", async {
+            self.render(request.get_ref()).await
+                .map(Response::new)
+                .map_err(status)
+        }).await
+    }
+
+    async fn rasterize_batch(&self, request: Request<proto::RasterizeBatchRequest>) -> Result<Response<proto::RasterizeBatchResponse>, Status> {
+        self.call(&request, "RasterizeBatch", async {
+            let items = &request.get_ref().items;
+            let max_batch = config::current().grpc_max_batch;
+            if items.len() > max_batch {
+                return Err(Status::invalid_argument(format!("At most {} items are allowed per batch", max_batch)));
+            }
+
+            // GRPC_MAX_BATCH bounds the fetches, the render pool bounds the renders
+            let results = futures::future::join_all(items.iter().map(|item| self.render_item(item))).await;
+
+            Ok(Response::new(proto::RasterizeBatchResponse { results }))
+        }).await
+    }
+
+    async fn inspect(&self, request: Request<proto::InspectRequest>) -> Result<Response<proto::InspectResponse>, Status> {
+        self.call(&request, "Inspect", self.inspect_svg(request.get_ref())).await
+    }
+}
+
+impl RasterizerService {
+    async fn inspect_svg(&self, request: &proto::InspectRequest) -> Result<Response<proto::InspectResponse>, Status> {
+        let processor = SvgProcessor::new(&self.client);
+        let svg_data = processor.fetch(&request.url).await.map_err(status)?;
+        let rtree = processor.parse(&svg_data).map_err(status)?;
+
+        let view_box = rtree.view_box.rect;
+        Ok(Response::new(proto::InspectResponse {
+            width: rtree.size.width(),
+            height: rtree.size.height(),
+            view_box: Some(proto::ViewBox {
+                x: view_box.x(),
+                y: view_box.y(),
+                width: view_box.width(),
+                height: view_box.height(),
+            }),
+            node_count: rtree.root.descendants().count() as u32,
+            source_bytes: svg_data.len() as u64,
+        }))
+    }
+}
+
+// The gRPC counterpart of the HTTP status each error is answered with
+fn status(e: ServiceError) -> Status {
+    let message = e.to_string();
+    match e {
+        ServiceError::ValidationError(_) | ServiceError::InvalidParameter(..) | ServiceError::SvgProcessingError(_) =>
+            Status::invalid_argument(message),
+        ServiceError::RateLimitExceeded => Status::resource_exhausted(message),
+        ServiceError::NotFound(_) => Status::not_found(message),
+        ServiceError::Conflict(_) => Status::already_exists(message),
+        ServiceError::Unauthorized(_) => Status::unauthenticated(message),
+        ServiceError::Overloaded(..) | ServiceError::UpstreamUnavailable(..)
+            | ServiceError::RequestError(_) | ServiceError::StorageError(_) => Status::unavailable(message),
+        ServiceError::CacheError(_) | ServiceError::RedisError(_) => Status::internal(message),
+    }
+}
+
+// Serves the gRPC API on GRPC_LISTEN until shutdown is signalled
+pub fn spawn(address: SocketAddr, cache: Arc<RedisCache>, client: reqwest::Client, rate_limiter: RateLimiter) {
+    let service = RasterizerServer::new(RasterizerService { cache, client, rate_limiter });
+    log::info!("Serving gRPC on {}", address);
+    actix_web::rt::spawn(async move {
+        let served = tonic::transport::Server::builder()
+            .layer(tower::util::MapRequestLayer::new(|mut request: tonic::codegen::http::Request<_>| {
+                let authority = request.uri().authority().map(|authority| authority.to_string()).unwrap_or_default();
+                request.extensions_mut().insert(Authority(authority));
+                request
+            }))
+            .add_service(service)
+            .serve_with_shutdown(address, shutdown::signal())
+            .await;
+        if let Err(e) = served {
+            log::error!("gRPC server failed: {}", e);
+        }
+    });
+}
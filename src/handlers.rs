@@ -1,79 +1,770 @@
-use actix_web::{web, HttpResponse};
+use actix_web::{web, HttpRequest, HttpResponse};
+use resvg::tiny_skia::Color;
 use serde::Deserialize;
+use serde_json::json;
 use std::sync::Arc;
-use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
 
+use crate::blurhash;
+use crate::cloudinary;
+use crate::error_image;
+use crate::fallback;
 use crate::cache::RedisCache;
 use crate::rate_limit::RateLimiter;
-use crate::svg::SvgProcessor;
-use crate::config::Config;
+use crate::svg::{parse_color, AnimationOptions, Overlay, Position, RenderOptions, SvgProcessor, Tiling};
+use crate::config::{self, Config, OutputMode, APPLE_TOUCH_PRESET};
 use crate::error::{ServiceResult, ServiceError};
+use crate::params::{self, Query};
+use crate::storage::S3Storage;
+use crate::render_pool;
+use crate::request_context;
+use svg_rasterizer_core::filters::ColorAdjustments;
+use svg_rasterizer_core::ImageFormat;
 
-#[derive(Deserialize, Debug)]
+const MASKABLE_DEFAULT_SIZE: u32 = 512;
+// The size browsers give an <img> without dimensions
+const ERROR_IMAGE_DEFAULT_SIZE: (u32, u32) = (300, 150);
+const AUTO_FORMAT: &str = "auto";
+pub const OVERLAY_PARAM: &str = "overlay";
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct SvgRequest {
+    /// URL of the SVG to render
     pub url: String,
+    /// Output width in pixels
     pub width: Option<u32>,
+    /// Output height in pixels
     pub height: Option<u32>,
+    /// Comma-separated widths, answered with a srcset of render URLs
+    pub widths: Option<String>,
+    /// Named size from SIZE_PRESETS
+    pub preset: Option<String>,
+    /// Respond with the image, or store it in S3 and respond with its URL
+    pub output: Option<Output>,
+    /// Redirect to the stored image, with output=s3
+    pub redirect: Option<bool>,
+    /// Background color, e.g. `ffffff` or `#ffffff80`
+    pub background: Option<String>,
+    /// Composite the finished image onto this opaque color, e.g. `ffffff`
+    pub flatten: Option<String>,
+    /// Unsharp mask strength from 0 to 1, for small outputs that come out soft
+    pub sharpen: Option<f32>,
+    /// Gamma correction, 1 leaves colors as they are and higher values lighten the midtones
+    pub gamma: Option<f32>,
+    /// Brightness, -100 to 100
+    pub brightness: Option<f32>,
+    /// Contrast, -100 to 100
+    pub contrast: Option<f32>,
+    /// Render a maskable PWA icon
+    pub maskable: Option<bool>,
+    /// Corner radius in pixels
+    pub radius: Option<u32>,
+    /// Add an `X-BlurHash` header
+    pub blurhash: Option<bool>,
+    /// Render a blurred low-quality placeholder at LQIP_WIDTH
+    pub lqip: Option<bool>,
+    /// Stretch the SVG over the whole size, ignoring its aspect ratio
+    pub stretch: Option<bool>,
+    /// How the SVG is scaled to the size: `contain` (default) or `fill`, the same as stretch=true
+    pub fit: Option<Fit>,
+    /// Answer errors as JSON, or as a PNG showing the error
+    pub onerror: Option<OnError>,
+    /// Output format: `png` (default), `webp`, `jpeg`, `gif`, `avif`, or `auto` for the smallest
+    /// of the formats the client accepts
+    pub format: Option<String>,
+    /// Encoder quality for JPEG, WebP and AVIF, 1-100
+    pub quality: Option<u8>,
+    // Every overlay= parameter, which the deserializer would reject as duplicates. Filled
+    // in by the handlers, documented on rasterize_svg.
+    #[serde(skip)]
+    pub overlay: Vec<String>,
+    /// Repeat the render in a grid of columns x rows, e.g. `4x3`
+    pub tile: Option<String>,
+    /// Pixels after every tile, with tile
+    pub tile_spacing: Option<u32>,
+    /// Lossless WebP output, defaults to WEBP_LOSSLESS
+    pub lossless: Option<bool>,
+    /// Render the SVG's SMIL and CSS animations as an animated PNG, WebP or GIF
+    pub animate: Option<bool>,
+    /// Frames per second of an animation
+    pub fps: Option<u32>,
+    /// Seconds of animation to render, defaults to the length of the SVG's own animations
+    pub duration: Option<f32>,
+    /// Seconds into the SVG's animations to render, e.g. `1.5`
+    pub time: Option<f32>,
+    /// Cloudinary-style transformation, e.g. `w_400,h_300,c_fit,f_webp`, overriding the other parameters
+    pub t: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Output {
+    Image,
+    S3,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Fit {
+    Contain,
+    Fill,
 }
 
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OnError {
+    Json,
+    Image,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/rasterize",
+    tag = "render",
+    params(
+        SvgRequest,
+        ("overlay" = Option<Vec<String>>, Query, description = "SVG drawn over the render, optionally followed by \
+            `|position=bottom-right|scale=0.4|opacity=0.8`. Repeat the parameter for more, they're drawn in order"),
+    ),
+    responses(
+        (status = 200, description = "The rendered image, or the S3 URL with output=s3", content_type = "image/png"),
+        (status = 302, description = "Redirect to the stored image with output=s3 and redirect=true"),
+        (status = 400, description = "Invalid parameters or SVG", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+        (status = 503, description = "Overloaded or source host unavailable", body = ErrorBody),
+    ),
+)]
 pub async fn rasterize_svg(
-    req: web::Query<SvgRequest>,
-    config: web::Data<Config>,                    // No Arc wrapper here
+    http_req: HttpRequest,
+    req: Query<SvgRequest>,
     cache: web::Data<Arc<RedisCache>>,           // Keep Arc wrapper for cache
     rate_limiter: web::Data<RateLimiter>,        // No Arc wrapper here
     client: web::Data<reqwest::Client>,          // No Arc wrapper here
+    storage: web::Data<Option<S3Storage>>,
 ) -> ServiceResult<HttpResponse> {
+    let Query(mut req) = req;
+    req.overlay = params::repeated(http_req.query_string(), OVERLAY_PARAM);
+    apply_transformation(&mut req)?;
+    respond(&req, &cache, &rate_limiter, &client, &storage).await
+}
+
+// Applies a Cloudinary transformation given as `t`
+pub fn apply_transformation(req: &mut SvgRequest) -> ServiceResult<()> {
+    if let Some(transformation) = req.t.take() {
+        cloudinary::apply(req, &transformation)?;
+    }
+    Ok(())
+}
+
+// Answers a render request however it was encoded, as a query string or in the path
+pub async fn respond(
+    req: &SvgRequest,
+    cache: &RedisCache,
+    rate_limiter: &RateLimiter,
+    client: &reqwest::Client,
+    storage: &Option<S3Storage>,
+) -> ServiceResult<HttpResponse> {
+    let config = config::current();
     log::info!("Processing SVG request: {:?}", req);
 
+    let error_image = req.onerror == Some(OnError::Image);
+
+    let result = rasterize(req, &config, cache, rate_limiter, client, storage, error_image).await;
+    match result {
+        Err(e) if error_image => {
+            // Invalid sizes are one of the errors to show, use the size an <img> defaults to
+            let (width, height) = config.resolve_size(req.width, req.height, req.preset.as_deref())
+                .unwrap_or(ERROR_IMAGE_DEFAULT_SIZE);
+            match error_image::response(&e, width, height, client).await {
+                Some(response) => Ok(response),
+                None => Err(e),
+            }
+        },
+        result => result,
+    }
+}
+
+async fn rasterize(
+    req: &SvgRequest,
+    config: &Config,
+    cache: &RedisCache,
+    rate_limiter: &RateLimiter,
+    client: &reqwest::Client,
+    storage: &Option<S3Storage>,
+    error_image: bool,
+) -> ServiceResult<HttpResponse> {
     // Check rate limit
     if !rate_limiter.check_rate().await {
         log::warn!("Rate limit exceeded for request");
         return Err(ServiceError::RateLimitExceeded);
     }
 
+    if config.presets_only && req.widths.is_some() {
+        return Err(ServiceError::ValidationError(
+            "Only preset sizes are allowed, use the preset parameter".to_string()
+        ));
+    }
+
+    if let Some(widths) = &req.widths {
+        return rasterize_srcset(&req.url, widths, config, cache, client).await;
+    }
+
     // Validate dimensions
-    let (width, height) = config.validate_dimensions(req.width, req.height);
+    // The maskable default is held to the same limits as any other size, and with
+    // PRESETS_ONLY a maskable icon needs a preset too
+    let maskable_default = req.maskable.unwrap_or(false) && !config.presets_only
+        && req.preset.is_none() && req.width.is_none() && req.height.is_none();
+    let (width, height) = if maskable_default {
+        config.validate_dimensions(Some(MASKABLE_DEFAULT_SIZE), Some(MASKABLE_DEFAULT_SIZE))
+    } else {
+        config.resolve_size(req.width, req.height, req.preset.as_deref())?
+    };
     log::debug!("Validated dimensions: {}x{}", width, height);
-    
+
+    let mut options = RenderOptions::new(width, height);
+    options.background = req.background.as_deref().map(parse_color).transpose()?;
+    options.maskable = req.maskable.unwrap_or(false);
+    options.corner_radius = req.radius;
+    options.flatten = req.flatten.as_deref().map(matte_color).transpose()?;
+    options.sharpen = req.sharpen.map(sharpen).transpose()?.filter(|amount| *amount > 0.0);
+    options.adjustments = color_adjustments(req)?;
+    if req.overlay.len() > config.max_overlays {
+        return Err(ServiceError::InvalidParameter(OVERLAY_PARAM.to_string(), format!("at most {} overlays are allowed", config.max_overlays)));
+    }
+    options.overlays = req.overlay.iter().map(|overlay| parse_overlay(overlay)).collect::<ServiceResult<_>>()?;
+    options.stretch = req.stretch.unwrap_or(false) || req.fit == Some(Fit::Fill);
+    let auto_formats = req.format.as_deref()
+        .filter(|format| format.eq_ignore_ascii_case(AUTO_FORMAT))
+        .map(|_| accepted_formats(request_context::accept().as_deref(), req.animate.unwrap_or(false)));
+    options.format = match &auto_formats {
+        Some(formats) => formats[0],
+        None => output_format(req.format.as_deref())?,
+    };
+    options.quality = req.quality.map(quality).transpose()?;
+    if let Some(lossless) = req.lossless {
+        options.lossless = lossless;
+    }
+    options.time = req.time.map(snapshot_time).transpose()?;
+    if req.animate.unwrap_or(false) {
+        options.animation = Some(animation_options(req.fps, req.duration, options.format, config)?);
+    }
+
+    if req.preset.as_deref() == Some(APPLE_TOUCH_PRESET) {
+        // iOS shows transparent pixels as black, so always flatten onto an opaque color
+        let background = options.background
+            .map(Ok)
+            .unwrap_or_else(|| parse_color(&config.apple_touch_background))?;
+        options.background = Some(Color::from_rgba(background.red(), background.green(), background.blue(), 1.0)
+            .unwrap_or(Color::WHITE));
+    }
+    if req.lqip.unwrap_or(false) {
+        // The placeholder size is fixed, its height follows the SVG's aspect ratio
+        options.lqip = true;
+        options.width = config.lqip_width;
+        options.height = config.lqip_width;
+    }
+    if options.maskable {
+        // Maskable icons are square
+        let size = width.min(height);
+        options.width = size;
+        options.height = size;
+    }
+
+    if let Some(tile) = &req.tile {
+        let tiling = tiling(tile, req.tile_spacing.unwrap_or(0))?;
+        let (width, height) = tiling.canvas_size(options.width, options.height);
+        if width > config.max_width as u64 || height > config.max_height as u64 {
+            return Err(ServiceError::InvalidParameter("tile".to_string(), format!(
+                "{} tiles of {}x{} come to {}x{}, over the maximum of {}x{}",
+                tile, options.width, options.height, width, height, config.max_width, config.max_height)));
+        }
+        options.tile = Some(tiling);
+    }
+
+    if let Some(formats) = &auto_formats {
+        options.format = auto_format(&req.url, &options, formats, config, cache, client).await;
+    }
+
+    let cdn_redirect = config.output_mode == OutputMode::CdnRedirect;
+    let output = req.output.unwrap_or(if cdn_redirect { Output::S3 } else { Output::Image });
+
+    match output {
+        Output::Image => {},
+        Output::S3 => {
+            let storage = storage.as_ref().ok_or_else(|| 
+                ServiceError::ValidationError("S3 output is not configured".to_string()))?;
+            let object_url = store_in_s3(&req.url, &options, cache, client, storage).await?;
+
+            let redirect = req.redirect.unwrap_or(cdn_redirect);
+            let mut response = if redirect { HttpResponse::Found() } else { HttpResponse::Ok() };
+            // Which object the response points at depends on Accept too
+            if auto_formats.is_some() {
+                response.insert_header(("Vary", "Accept"));
+            }
+            if redirect {
+                return Ok(response
+                    .insert_header(("Location", object_url))
+                    .insert_header(("Cache-Control", format!("public, max-age={}", config.redirect_max_age)))
+                    .finish());
+            }
+
+            return Ok(response.json(json!({
+                "success": true,
+                "url": object_url,
+                "width": options.output_size().0,
+                "height": options.output_size().1,
+                "contentType": options.format.content_type(),
+            })));
+        },
+    }
+
+    let image_data = match render_cached(&req.url, &options, cache, client).await {
+        Ok(image_data) => image_data,
+        // An explicit onerror=image asks for the error itself, not the placeholder
+        Err(e) if error_image => return Err(e),
+        Err(e) => match fallback::response(&e, options.width, options.height, client).await {
+            Some(response) => return Ok(response),
+            None => return Err(e),
+        },
+    };
+
+    // Return the processed image
+    let mut response = HttpResponse::Ok();
+    response.content_type(options.format.content_type());
+    if auto_formats.is_some() {
+        response.insert_header(("Vary", "Accept"));
+    }
+
+    if req.blurhash.unwrap_or(false) {
+        match blurhash::default_blurhash(&req.url, cache, client).await {
+            Ok(hash) => { response.insert_header(("X-BlurHash", hash)); },
+            Err(e) => log::warn!("Failed to compute blurhash for {}: {}", req.url, e),
+        }
+    }
+
+    Ok(response.body(image_data))
+}
+
+// A flatten color, which has to cover what's under it
+fn matte_color(value: &str) -> ServiceResult<Color> {
+    let color = parse_color(value)?;
+    if !color.is_opaque() {
+        return Err(ServiceError::InvalidParameter("flatten".to_string(), "must be an opaque color".to_string()));
+    }
+    Ok(color)
+}
+
+pub fn output_format(format: Option<&str>) -> ServiceResult<ImageFormat> {
+    match format {
+        Some(format) => ImageFormat::parse(format).ok_or_else(|| 
+            ServiceError::InvalidParameter("format".to_string(), "must be png, webp, jpeg, gif or avif".to_string())),
+        None => Ok(ImageFormat::Png),
+    }
+}
+
+// The format=auto candidates the client's Accept header allows, preferred first.
+// Every client gets PNG, AVIF and WebP only when it names them, as `*/*` says
+// nothing about what a client decodes. AVIF can't be animated.
+fn accepted_formats(accept: Option<&str>, animated: bool) -> Vec<ImageFormat> {
+    let accepts = |content_type: &str| accept.unwrap_or_default().split(',').any(|range| {
+        let mut params = range.split(';').map(str::trim);
+        params.next().is_some_and(|media_type| media_type.eq_ignore_ascii_case(content_type))
+            && !params.any(|param| param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0))
+    });
+
+    let mut formats = Vec::new();
+    if !animated && accepts(ImageFormat::Avif.content_type()) {
+        formats.push(ImageFormat::Avif);
+    }
+    if accepts(ImageFormat::Webp.content_type()) {
+        formats.push(ImageFormat::Webp);
+    }
+    formats.push(ImageFormat::Png);
+    formats
+}
+
+// The candidate that encodes this render smallest. Every candidate is rendered and
+// cached under its own key and the winner is remembered, so later requests only read
+// that one. Renders over AUTO_FORMAT_MAX_PIXELS, all frames of an animation together,
+// and comparisons that fail, go with
+// the preferred format, whose render then reports any error.
+async fn auto_format(
+    url: &str,
+    options: &RenderOptions,
+    formats: &[ImageFormat],
+    config: &Config,
+    cache: &RedisCache,
+    client: &reqwest::Client,
+) -> ImageFormat {
+    let preferred = formats[0];
+    let (width, height) = options.output_size();
+    // Animations are counted with every frame, for the longest they may run
+    let frames = options.animation.map_or(1, |animation| {
+        let duration = animation.duration.unwrap_or(config.animation_max_duration_secs as f32);
+        (animation.fps as f32 * duration).ceil() as u64
+    });
+    if formats.len() == 1 || width as u64 * height as u64 * frames > config.auto_format_max_pixels {
+        return preferred;
+    }
+
+    let candidates = formats.iter().map(|format| format.extension()).collect::<Vec<_>>().join(",");
+    let choice_key = format!("{}:auto-{}", cache_key(url, options), candidates);
+    if let Ok(Some(choice)) = cache.get(&choice_key).await {
+        if let Some(format) = std::str::from_utf8(&choice).ok().and_then(ImageFormat::parse) {
+            return format;
+        }
+    }
+
+    let renders = formats.iter().map(|&format| async move {
+        let options = RenderOptions { format, ..options.clone() };
+        render_cached(url, &options, cache, client).await.map(|image_data| (format, image_data.len()))
+    });
+    let Ok(sizes) = futures::future::try_join_all(renders).await else {
+        return preferred;
+    };
+    // Ties go to the preferred format, which comes first
+    let format = sizes.into_iter().min_by_key(|(_, size)| *size).map_or(preferred, |(format, _)| format);
+    if let Err(e) = cache.set(&choice_key, format.extension().as_bytes(), config.cache_ttl()).await {
+        log::warn!("Failed to cache format choice for {}: {}", url, e);
+    }
+    format
+}
+
+// Gamma, brightness and contrast, None when the request doesn't change them
+fn color_adjustments(req: &SvgRequest) -> ServiceResult<Option<ColorAdjustments>> {
+    let check = |name: &str, value: Option<f32>, range: std::ops::RangeInclusive<f32>| match value {
+        Some(value) if !range.contains(&value) => Err(ServiceError::InvalidParameter(
+            name.to_string(), format!("must be between {} and {}", range.start(), range.end()))),
+        _ => Ok(()),
+    };
+    check("gamma", req.gamma, 0.1..=10.0)?;
+    check("brightness", req.brightness, -100.0..=100.0)?;
+    check("contrast", req.contrast, -100.0..=100.0)?;
+
+    let defaults = ColorAdjustments::default();
+    let adjustments = ColorAdjustments {
+        gamma: req.gamma.unwrap_or(defaults.gamma),
+        brightness: req.brightness.unwrap_or(defaults.brightness),
+        contrast: req.contrast.unwrap_or(defaults.contrast),
+    };
+    Ok((adjustments != defaults).then_some(adjustments))
+}
+
+// `https://example.com/badge.svg|position=bottom-right|scale=0.4|opacity=0.8`. The
+// options are the trailing `|` segments with a `=`, the rest is the URL, which may
+// contain a `|` itself as long as what follows it doesn't look like an option.
+fn parse_overlay(value: &str) -> ServiceResult<Overlay> {
+    let invalid = |message: String| ServiceError::InvalidParameter(OVERLAY_PARAM.to_string(), message);
+    let segments = value.split('|').collect::<Vec<_>>();
+    let options = segments.iter().skip(1).rev().take_while(|segment| segment.contains('=')).count();
+    let (url, options) = segments.split_at(segments.len() - options);
+    let mut overlay = Overlay {
+        url: url.join("|"),
+        position: Position::Center,
+        scale: 1.0,
+        opacity: 1.0,
+    };
+    if overlay.url.is_empty() {
+        return Err(invalid("must start with the URL of an SVG".to_string()));
+    }
+
+    let fraction = |name: &str, value: &str| value.parse::<f32>().ok()
+        .filter(|value| *value > 0.0 && *value <= 1.0)
+        .ok_or_else(|| invalid(format!("{} must be more than 0 and at most 1", name)));
+    for option in options {
+        match option.split_once('=') {
+            Some(("position", value)) => overlay.position = Position::parse(value).ok_or_else(|| invalid(
+                "position must be center, top, bottom, left, right, top-left, top-right, bottom-left or bottom-right".to_string()))?,
+            Some(("scale", value)) => overlay.scale = fraction("scale", value)?,
+            Some(("opacity", value)) => overlay.opacity = fraction("opacity", value)?,
+            _ => return Err(invalid(format!("unknown option {}, expected position, scale or opacity", option))),
+        }
+    }
+    Ok(overlay)
+}
+
+// `4x3` for tile
+fn tiling(tile: &str, spacing: u32) -> ServiceResult<Tiling> {
+    let invalid = || ServiceError::InvalidParameter("tile".to_string(), "must be columns x rows, e.g. 4x3".to_string());
+    let count = |value: &str| value.parse::<u32>().ok().filter(|count| *count > 0).ok_or_else(invalid);
+    let (columns, rows) = tile.split_once(['x', 'X']).ok_or_else(invalid)?;
+    Ok(Tiling { columns: count(columns)?, rows: count(rows)?, spacing })
+}
+
+fn sharpen(amount: f32) -> ServiceResult<f32> {
+    if !(0.0..=1.0).contains(&amount) {
+        return Err(ServiceError::InvalidParameter("sharpen".to_string(), "must be between 0 and 1".to_string()));
+    }
+    Ok(amount)
+}
+
+fn quality(quality: u8) -> ServiceResult<u8> {
+    if !(1..=100).contains(&quality) {
+        return Err(ServiceError::InvalidParameter("quality".to_string(), "must be between 1 and 100".to_string()));
+    }
+    Ok(quality)
+}
+
+// Seconds into the animations, for `time=1.5`
+pub fn snapshot_time(time: f32) -> ServiceResult<f32> {
+    if !time.is_finite() || time < 0.0 {
+        return Err(ServiceError::InvalidParameter("time".to_string(), "must be a time in seconds of 0 or more".to_string()));
+    }
+    Ok(time)
+}
+
+pub fn animation_options(fps: Option<u32>, duration: Option<f32>, format: ImageFormat, config: &Config) -> ServiceResult<AnimationOptions> {
+    if matches!(format, ImageFormat::Jpeg | ImageFormat::Avif) {
+        return Err(ServiceError::InvalidParameter("format".to_string(), "animations must be png, webp or gif".to_string()));
+    }
+    let fps = fps.unwrap_or(config.animation_default_fps);
+    if fps == 0 || fps > config.animation_max_fps {
+        return Err(ServiceError::InvalidParameter("fps".to_string(), format!("must be between 1 and {}", config.animation_max_fps)));
+    }
+    if duration.is_some_and(|duration| !(duration > 0.0 && duration <= config.animation_max_duration_secs as f32)) {
+        return Err(ServiceError::InvalidParameter(
+            "duration".to_string(), format!("must be more than 0 and at most {} seconds", config.animation_max_duration_secs)));
+    }
+    Ok(AnimationOptions { fps, duration })
+}
+
+pub fn cache_key(url: &str, options: &RenderOptions) -> String {
+    let namespace = &config::current().cache_namespace;
+    let namespace = if namespace.is_empty() { String::new() } else { format!(":ns-{}", namespace) };
+    format!("svg:{}:{}x{}{}{}{}", url, options.width, options.height, options.variant_key(), request_context::forwarded_headers_key(), namespace)
+}
+
+// Uploads the render to S3 unless a previous request already did, returning its URL
+pub async fn store_in_s3(
+    url: &str,
+    options: &RenderOptions,
+    cache: &RedisCache,
+    client: &reqwest::Client,
+    storage: &S3Storage,
+) -> ServiceResult<String> {
+    let cache_key = cache_key(url, options);
+    let object_key = storage.object_key(&cache_key, options.format.extension());
+    let marker_key = format!("s3:{}", cache_key);
+
+    if cache.get(&marker_key).await?.is_none() {
+        let image_data = render_cached(url, options, cache, client).await?;
+        log::info!("Uploading {} ({} bytes) to S3", object_key, image_data.len());
+        storage.put(client, &object_key, image_data, options.format.content_type()).await?;
+        cache.set(&marker_key, b"1", config::current().cache_ttl()).await?;
+    }
+
+    Ok(storage.object_url(&object_key))
+}
+
+// Returns the image for the given URL and options, rendering and caching it on a miss
+pub async fn render_cached(
+    url: &str,
+    options: &RenderOptions,
+    cache: &RedisCache,
+    client: &reqwest::Client,
+) -> ServiceResult<Vec<u8>> {
     // Generate cache key
-    let cache_key = format!("svg:{}:{}x{}", req.url, width, height);
+    let cache_key = cache_key(url, options);
     
     // Try to get from cache
-    if let Some(cached_data) = cache.get(&cache_key).await? {
+    let span = request_context::stage("cache.lookup");
+    let cached = cache.get(&cache_key).await?;
+    drop(span);
+    if let Some(cached_data) = cached {
         log::debug!("Cache hit for key: {}", cache_key);
-        return Ok(HttpResponse::Ok()
-            .content_type("image/png")
-            .body(cached_data));
+        return Ok(cached_data);
     }
 
     log::debug!("Cache miss for key: {}", cache_key);
 
+    render_pool::check_load()?;
+
     // Process SVG
-    log::info!("Converting SVG from URL: {}", req.url);
-    let processor = SvgProcessor::new(client.get_ref());
+    log::info!("Converting SVG from URL: {}", url);
+    let processor = SvgProcessor::new(client);
     let start = std::time::Instant::now();
     
-    let png_data = processor.process(&req.url, width, height)
+    let png_data = processor.process(url, options)
         .await
-        .map_err(|e| {
-            log::error!("Failed to process SVG: {}", e);
-            ServiceError::SvgProcessingError(e.to_string())
+        .map_err(|e| match e {
+            // Not a problem with the SVG, keep the 503
+            ServiceError::Overloaded(..) | ServiceError::UpstreamUnavailable(..) => e,
+            e => {
+                log::error!("Failed to process SVG: {}", e);
+                ServiceError::SvgProcessingError(e.to_string())
+            },
         })?;
     
     log::info!("SVG conversion completed in {:?}", start.elapsed());
     
     // Cache the result
     log::debug!("Caching result with key: {}", cache_key);
+    let _span = request_context::stage("cache.store");
     cache.set(
         &cache_key,
         &png_data,
-        Duration::from_secs(24 * 60 * 60)
+        config::current().cache_ttl()
     ).await?;
     
     log::info!("Successfully processed SVG. Size: {} bytes", png_data.len());
 
-    // Return the processed image
-    Ok(HttpResponse::Ok()
-        .content_type("image/png")
-        .body(png_data))
-}
\ No newline at end of file
+    Ok(png_data)
+}
+
+fn parse_widths(widths: &str, config: &Config) -> ServiceResult<Vec<u32>> {
+    let mut parsed = Vec::new();
+
+    for part in widths.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let width: u32 = part.parse().map_err(|_| 
+            ServiceError::ValidationError(format!("Invalid width in widths: {}", part)))?;
+        let (width, _) = config.validate_dimensions(Some(width), None);
+        if !parsed.contains(&width) {
+            parsed.push(width);
+        }
+    }
+
+    if parsed.is_empty() {
+        return Err(ServiceError::ValidationError("widths must list at least one width".to_string()));
+    }
+
+    if parsed.len() > config.max_srcset_widths {
+        return Err(ServiceError::ValidationError(
+            format!("Too many widths: {} (max {})", parsed.len(), config.max_srcset_widths)
+        ));
+    }
+
+    parsed.sort_unstable();
+    Ok(parsed)
+}
+
+pub fn render_url(config: &Config, url: &str, width: u32, height: u32) -> String {
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("url", url)
+        .append_pair("width", &width.to_string())
+        .append_pair("height", &height.to_string())
+        .finish();
+
+    format!("{}/v1/rasterize?{}", config.public_base_url, query)
+}
+
+// Size for one srcset width at the SVG's aspect ratio. Tall SVGs that would pass
+// the height limit get a smaller width too, instead of a squashed render.
+fn srcset_size(rtree: &usvg::Tree, width: u32, config: &Config) -> (u32, u32) {
+    let (width, height) = svg_rasterizer_core::render::fit_size(rtree, width, config.max_height);
+    config.validate_dimensions(Some(width), Some(height))
+}
+
+// Renders every requested width from a single fetch/parse and returns a
+// manifest pointing at the (now cached) single-size URLs
+async fn rasterize_srcset(
+    url: &str,
+    widths: &str,
+    config: &Config,
+    cache: &RedisCache,
+    client: &reqwest::Client,
+) -> ServiceResult<HttpResponse> {
+    let widths = parse_widths(widths, config)?;
+    log::info!("Rendering srcset for {} with widths {:?}", url, widths);
+
+    let processor = SvgProcessor::new(client);
+    let start = std::time::Instant::now();
+
+    let svg_data = processor.fetch(url)
+        .await
+        .map_err(|e| {
+            log::error!("Failed to fetch SVG: {}", e);
+            ServiceError::SvgProcessingError(e.to_string())
+        })?;
+    let rtree = processor.parse(&svg_data)
+        .map_err(|e| ServiceError::SvgProcessingError(e.to_string()))?;
+
+    let mut images: Vec<serde_json::Value> = Vec::with_capacity(widths.len());
+    let mut srcset = Vec::with_capacity(widths.len());
+
+    for width in widths {
+        let (width, height) = srcset_size(&rtree, width, config);
+        // Widths above the limit can shrink to the same size
+        if images.iter().any(|image| image["width"] == width) {
+            continue;
+        }
+        let cache_key = cache_key(url, &RenderOptions::new(width, height));
+
+        let size = match cache.get(&cache_key).await? {
+            Some(cached_data) => cached_data.len(),
+            None => {
+                let png_data = processor.render(&rtree, width, height)
+                    .map_err(|e| ServiceError::SvgProcessingError(e.to_string()))?;
+                cache.set(&cache_key, &png_data, config::current().cache_ttl()).await?;
+                png_data.len()
+            }
+        };
+
+        let image_url = render_url(config, url, width, height);
+        srcset.push(format!("{} {}w", image_url, width));
+        images.push(json!({
+            "width": width,
+            "height": height,
+            "size": size,
+            "url": image_url,
+        }));
+    }
+
+    log::info!("Srcset rendering completed in {:?}", start.elapsed());
+
+    Ok(HttpResponse::Ok().json(json!({
+        "success": true,
+        "contentType": "image/png",
+        "images": images,
+        "srcset": srcset.join(", "),
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::is_invalid;
+
+    #[test]
+    fn parses_overlay_options() {
+        let overlay = parse_overlay("https://example.com/badge.svg|position=bottom-right|scale=0.4|opacity=0.8").unwrap();
+        assert_eq!(overlay.url, "https://example.com/badge.svg");
+        assert_eq!(overlay.position, Position::BottomRight);
+        assert_eq!((overlay.scale, overlay.opacity), (0.4, 0.8));
+
+        let overlay = parse_overlay("https://example.com/badge.svg?v=2").unwrap();
+        assert_eq!(overlay.url, "https://example.com/badge.svg?v=2");
+        assert_eq!(overlay.position, Position::Center);
+    }
+
+    #[test]
+    fn keeps_pipes_in_overlay_urls() {
+        let overlay = parse_overlay("https://example.com/icons|badge.svg|scale=0.5").unwrap();
+        assert_eq!(overlay.url, "https://example.com/icons|badge.svg");
+        assert_eq!(overlay.scale, 0.5);
+
+        let overlay = parse_overlay("https://example.com/a.svg?set=1|2").unwrap();
+        assert_eq!(overlay.url, "https://example.com/a.svg?set=1|2");
+    }
+
+    #[test]
+    fn rejects_invalid_overlays() {
+        assert!(is_invalid(parse_overlay("|scale=0.5"), OVERLAY_PARAM));
+        assert!(is_invalid(parse_overlay("https://example.com/a.svg|size=2"), OVERLAY_PARAM));
+        assert!(is_invalid(parse_overlay("https://example.com/a.svg|scale=2"), OVERLAY_PARAM));
+        assert!(is_invalid(parse_overlay("https://example.com/a.svg|position=middle"), OVERLAY_PARAM));
+    }
+
+    #[test]
+    fn keeps_the_aspect_ratio_of_tall_srcset_images() {
+        use svg_rasterizer_core::usvg::{self, TreeParsing};
+
+        let config = Config { max_width: 4000, max_height: 1000, min_dimension: 1, ..Config::default() };
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 100 400"/>"#;
+        let rtree = usvg::Tree::from_str(svg, &usvg::Options::default()).unwrap();
+
+        assert_eq!(srcset_size(&rtree, 200, &config), (200, 800));
+        assert_eq!(srcset_size(&rtree, 400, &config), (250, 1000));
+        assert_eq!(srcset_size(&rtree, 800, &config), (250, 1000));
+    }
+}
@@ -1,10 +1,171 @@
 use actix_web::{HttpResponse, web};
+use arc_swap::ArcSwapOption;
+use serde::Serialize;
 use serde_json::json;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use crate::config::Config;
 use crate::error::ServiceResult;
 use crate::cache::RedisCache;
+use crate::metrics::metrics;
+use crate::render_pool;
+use crate::shutdown;
+use crate::svg::SvgProcessor;
 
-pub async fn health_check(
+// A filled square on the left and text on the right, so a broken font database
+// shows up as an empty right half rather than as a successful render
+const CANARY_SVG: &str = r##"<svg xmlns="http://www.w3.org/2000/svg" width="64" height="32" viewBox="0 0 64 32">
+<rect width="32" height="32" fill="#ff0000"/>
+<text x="34" y="26" font-family="sans-serif" font-size="24" fill="#000000">Ag</text>
+</svg>"##;
+const CANARY_WIDTH: u32 = 64;
+const CANARY_HEIGHT: u32 = 32;
+// With HEALTH_CANARY, readiness checks within this long of the last render report
+// its result instead of rendering again, so frequent probes don't add up
+const CANARY_MIN_INTERVAL: Duration = Duration::from_secs(5);
+
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static WARMED_UP: AtomicBool = AtomicBool::new(false);
+static CANARY: OnceLock<Canary> = OnceLock::new();
+static LAST_CANARY: ArcSwapOption<CanaryResult> = ArcSwapOption::const_empty();
+// Milliseconds since STARTED from which a readiness check may render the canary again
+static NEXT_CANARY_MS: AtomicU64 = AtomicU64::new(0);
+
+struct Canary {
+    processor: SvgProcessor,
+    // Rendered by readiness checks, rather than on an interval
+    per_request: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CanaryResult {
+    ok: bool,
+    duration_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    checked_at: String,
+}
+
+// Enables the render canary with HEALTH_CANARY or HEALTH_CANARY_INTERVAL, rendering
+// in the background on the interval when one is set
+pub fn start_canary(config: &Config, client: &reqwest::Client) {
+    let interval = config.health_canary_interval_secs;
+    if !config.health_canary && interval == 0 {
+        return;
+    }
+    let _ = CANARY.set(Canary {
+        processor: SvgProcessor::new(client),
+        per_request: interval == 0,
+    });
+
+    if interval > 0 {
+        log::info!("Render canary runs every {}s", interval);
+        actix_web::rt::spawn(async move {
+            let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+            loop {
+                ticker.tick().await;
+                run_canary().await;
+            }
+        });
+    } else {
+        log::info!("Render canary runs on readiness checks, at most every {:?}", CANARY_MIN_INTERVAL);
+    }
+}
+
+// Renders the canary off the async executor and keeps the result for /readyz
+async fn run_canary() {
+    let Some(canary) = CANARY.get() else {
+        return;
+    };
+
+    let start = Instant::now();
+    let rendered = actix_web::rt::task::spawn_blocking(|| render_canary(&canary.processor)).await
+        .unwrap_or_else(|_| Err("Canary render panicked".to_string()));
+    let duration = start.elapsed();
+
+    if let Err(e) = &rendered {
+        log::error!("Render canary failed after {:?}: {}", duration, e);
+    }
+    LAST_CANARY.store(Some(Arc::new(CanaryResult {
+        ok: rendered.is_ok(),
+        duration_ms: (duration.as_secs_f64() * 10_000.0).round() / 10.0,
+        error: rendered.err(),
+        checked_at: chrono::Utc::now().to_rfc3339(),
+    })));
+}
+
+// Whether this readiness check renders the canary, at most once per
+// CANARY_MIN_INTERVAL however many checks arrive at once
+fn claim_canary_run() -> bool {
+    let now = STARTED.get_or_init(Instant::now).elapsed().as_millis() as u64;
+    let next = NEXT_CANARY_MS.load(Ordering::Relaxed);
+    let claimed = now + CANARY_MIN_INTERVAL.as_millis() as u64;
+    now >= next && NEXT_CANARY_MS.compare_exchange(next, claimed, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+}
+
+// Parses, renders and encodes the canary, bypassing the render pool and the
+// parsed tree cache so neither hides a broken renderer
+fn render_canary(processor: &SvgProcessor) -> Result<(), String> {
+    let rtree = processor.parse_with_text(CANARY_SVG).map_err(|e| e.to_string())?;
+    let pixmap = processor.render_pixmap(&rtree, CANARY_WIDTH, CANARY_HEIGHT).map_err(|e| e.to_string())?;
+
+    let shape = pixmap.pixel(CANARY_WIDTH / 4, CANARY_HEIGHT / 2)
+        .is_some_and(|pixel| pixel.red() == 255 && pixel.alpha() == 255);
+    let text = (CANARY_WIDTH / 2..CANARY_WIDTH)
+        .any(|x| (0..CANARY_HEIGHT).any(|y| pixmap.pixel(x, y).is_some_and(|pixel| pixel.alpha() > 0)));
+    let encoded = processor.encode_png(&pixmap);
+    crate::pixmap_pool::release(pixmap);
+
+    encoded.map_err(|e| e.to_string())?;
+    if !shape {
+        return Err("Canary shape rendered incorrectly".to_string());
+    }
+    if !text {
+        return Err("Canary text rendered nothing, check the installed fonts".to_string());
+    }
+    Ok(())
+}
+
+// Marks the start of the process for the reported uptime
+pub fn init() {
+    let _ = STARTED.set(Instant::now());
+}
+
+// Set once fonts are loaded and the cache warm-up has run, until then the first
+// requests would be slow
+pub fn set_warmed_up() {
+    WARMED_UP.store(true, Ordering::SeqCst);
+}
+
+// Liveness: the process is up and serving requests. Dependencies are left out,
+// a Redis outage shouldn't get every pod restarted
+#[utoipa::path(
+    get,
+    path = "/livez",
+    tag = "health",
+    responses((status = 200, description = "The process is up", content_type = "application/json")),
+)]
+pub async fn livez() -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "status": "ok",
+        "version": env!("CARGO_PKG_VERSION"),
+    }))
+}
+
+// Readiness: whether the instance should receive traffic, answered with 503 when
+// it shouldn't so load balancers route around it. `/health` is the same check.
+#[utoipa::path(
+    get,
+    path = "/readyz",
+    tag = "health",
+    responses(
+        (status = 200, description = "Ready for traffic, with dependency and load details", content_type = "application/json"),
+        (status = 503, description = "Degraded, saturated, warming up or draining", content_type = "application/json"),
+    ),
+)]
+pub async fn readyz(
     cache: web::Data<Arc<RedisCache>>,
 ) -> ServiceResult<HttpResponse> {
     let mut status = json!({
@@ -17,9 +178,11 @@ pub async fn health_check(
     });
 
     // Check Redis connection
+    let redis_start = Instant::now();
     match cache.check_connection().await {
         Ok(_) => {
             status["dependencies"]["redis"] = json!("ok");
+            status["redisLatencyMs"] = json!((redis_start.elapsed().as_secs_f64() * 10_000.0).round() / 10.0);
         },
         Err(e) => {
             log::error!("Health check failed - Redis error: {}", e);
@@ -31,5 +194,58 @@ pub async fn health_check(
         }
     }
 
-    Ok(HttpResponse::Ok().json(status))
-}
\ No newline at end of file
+    if let Some(started) = STARTED.get() {
+        status["uptimeSecs"] = json!(started.elapsed().as_secs());
+    }
+    let (hits, lookups) = metrics().recent_cache_lookups();
+    status["cache"] = json!({
+        "lookupsLastMinute": lookups,
+        "hitRateLastMinute": (lookups > 0).then(|| (hits as f64 / lookups as f64 * 1000.0).round() / 1000.0),
+    });
+
+    if let Some(load) = render_pool::load() {
+        status["renders"] = json!(load);
+    }
+    if render_pool::saturated() {
+        status["status"] = json!("saturated");
+    }
+
+    if CANARY.get().is_some_and(|canary| canary.per_request) && claim_canary_run() {
+        run_canary().await;
+    }
+    if let Some(canary) = LAST_CANARY.load_full() {
+        if !canary.ok {
+            status["status"] = json!("render_failed");
+        }
+        status["canary"] = json!(&*canary);
+    }
+
+    if !WARMED_UP.load(Ordering::SeqCst) {
+        status["status"] = json!("warming_up");
+    }
+
+    // Take the instance out of load balancing while it shuts down
+    if shutdown::draining() {
+        status["status"] = json!("draining");
+    }
+
+    if status["status"] == "ok" {
+        Ok(HttpResponse::Ok().json(status))
+    } else {
+        Ok(HttpResponse::ServiceUnavailable().json(status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_the_canary_at_most_once_per_interval() {
+        assert!(claim_canary_run());
+        assert!(!claim_canary_run());
+
+        NEXT_CANARY_MS.store(0, Ordering::Relaxed);
+        assert!(claim_canary_run());
+    }
+}
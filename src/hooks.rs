@@ -0,0 +1,87 @@
+use std::sync::{Arc, RwLock};
+use svg_rasterizer_core::tiny_skia::Pixmap;
+
+use crate::error::ServiceResult;
+use crate::request_context;
+pub use crate::svg::RenderOptions;
+
+// Extension points for deployments, registered before the service starts, e.g. from
+// a main that calls these and then `svg_rasterizer::run()`. Hooks run in the order
+// they were registered. Cached results aren't keyed on them, purge the cache after
+// changing what a hook does.
+
+// Runs before every source fetch
+pub trait PreFetchHook: Send + Sync {
+    // The URL to fetch instead, None to keep it, or an error to refuse the request
+    fn rewrite(&self, url: &str) -> ServiceResult<Option<String>>;
+}
+
+// Runs on every fetched SVG, after the content checks and before parsing, on the
+// render pool
+pub trait SvgTransformHook: Send + Sync {
+    fn transform(&self, url: &str, svg: String) -> ServiceResult<String>;
+}
+
+// Runs on every rendered pixmap before it is encoded, e.g. to stamp a watermark
+pub trait PostRenderHook: Send + Sync {
+    fn after_render(&self, pixmap: &mut Pixmap, options: &RenderOptions) -> ServiceResult<()>;
+}
+
+struct Hooks {
+    pre_fetch: Vec<Arc<dyn PreFetchHook>>,
+    svg_transform: Vec<Arc<dyn SvgTransformHook>>,
+    post_render: Vec<Arc<dyn PostRenderHook>>,
+}
+
+static HOOKS: RwLock<Hooks> = RwLock::new(Hooks {
+    pre_fetch: Vec::new(),
+    svg_transform: Vec::new(),
+    post_render: Vec::new(),
+});
+
+pub fn register_pre_fetch(hook: impl PreFetchHook + 'static) {
+    HOOKS.write().unwrap().pre_fetch.push(Arc::new(hook));
+}
+
+pub fn register_svg_transform(hook: impl SvgTransformHook + 'static) {
+    HOOKS.write().unwrap().svg_transform.push(Arc::new(hook));
+}
+
+pub fn register_post_render(hook: impl PostRenderHook + 'static) {
+    HOOKS.write().unwrap().post_render.push(Arc::new(hook));
+}
+
+// Decoded query parameters of the request being handled, for hooks whose behavior
+// depends on the request. Empty outside of HTTP requests.
+pub fn query_params() -> Vec<(String, String)> {
+    request_context::query_params()
+}
+
+// The URL after every rewrite, None when no hook changed it
+pub(crate) fn pre_fetch(url: &str) -> ServiceResult<Option<String>> {
+    let mut rewritten: Option<String> = None;
+    for hook in &HOOKS.read().unwrap().pre_fetch {
+        if let Some(url) = hook.rewrite(rewritten.as_deref().unwrap_or(url))? {
+            rewritten = Some(url);
+        }
+    }
+    Ok(rewritten)
+}
+
+pub(crate) fn has_svg_transforms() -> bool {
+    !HOOKS.read().unwrap().svg_transform.is_empty()
+}
+
+pub(crate) fn svg_transform(url: &str, mut svg: String) -> ServiceResult<String> {
+    for hook in &HOOKS.read().unwrap().svg_transform {
+        svg = hook.transform(url, svg)?;
+    }
+    Ok(svg)
+}
+
+pub(crate) fn post_render(pixmap: &mut Pixmap, options: &RenderOptions) -> ServiceResult<()> {
+    for hook in &HOOKS.read().unwrap().post_render {
+        hook.after_render(pixmap, options)?;
+    }
+    Ok(())
+}
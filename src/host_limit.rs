@@ -0,0 +1,92 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::Config;
+use crate::error::{ServiceResult, ServiceError};
+use crate::metrics::metrics;
+use crate::request_context;
+
+// Fetches beyond the limit give up after waiting this long, like a fetch that timed out
+const MAX_WAIT: Duration = Duration::from_secs(10);
+
+static LIMITER: OnceLock<HostLimiter> = OnceLock::new();
+
+// Caps simultaneous fetches per source host, so one slow host can't tie up
+// every connection and every request waiting on it
+struct HostLimiter {
+    max_per_host: usize,
+    retry_after_secs: u64,
+    hosts: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+// Held for the duration of a fetch
+pub struct HostPermit {
+    host: String,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+pub fn configure(config: &Config) {
+    if config.fetch_max_per_host > 0 {
+        let _ = LIMITER.set(HostLimiter {
+            max_per_host: config.fetch_max_per_host,
+            retry_after_secs: config.load_shed_retry_after,
+            hosts: Mutex::new(HashMap::new()),
+        });
+    }
+}
+
+// Waits for a fetch slot for the URL's host, returns None when fetches aren't limited
+pub async fn acquire(url: &str) -> ServiceResult<Option<HostPermit>> {
+    let Some(limiter) = LIMITER.get() else {
+        return Ok(None);
+    };
+    let Some(host) = url::Url::parse(url).ok().and_then(|u| u.host_str().map(|h| h.to_lowercase())) else {
+        return Ok(None);
+    };
+
+    let semaphore = limiter.hosts.lock().unwrap()
+        .entry(host.clone())
+        .or_insert_with(|| Arc::new(Semaphore::new(limiter.max_per_host)))
+        .clone();
+
+    let permit = match semaphore.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            log::debug!("Fetch limit reached for {}, waiting", host);
+            metrics().upstream_host_limit_waits.inc();
+            let _span = request_context::stage("fetch.wait");
+
+            match tokio::time::timeout(MAX_WAIT, semaphore.acquire_owned()).await {
+                Ok(Ok(permit)) => permit,
+                _ => {
+                    log::warn!("Gave up waiting for a fetch slot for {}", host);
+                    return Err(ServiceError::Overloaded(
+                        format!("Too many fetches in progress for {}, try again later", host),
+                        limiter.retry_after_secs,
+                    ));
+                },
+            }
+        },
+    };
+
+    Ok(Some(HostPermit { host, permit: Some(permit) }))
+}
+
+impl Drop for HostPermit {
+    fn drop(&mut self) {
+        drop(self.permit.take());
+
+        let Some(limiter) = LIMITER.get() else {
+            return;
+        };
+        // Forget hosts nobody is fetching from, so the map doesn't grow with every host ever seen
+        let mut hosts = limiter.hosts.lock().unwrap();
+        let idle = hosts.get(&self.host)
+            .is_some_and(|semaphore| Arc::strong_count(semaphore) == 1 && semaphore.available_permits() == limiter.max_per_host);
+        if idle {
+            hosts.remove(&self.host);
+        }
+    }
+}
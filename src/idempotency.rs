@@ -0,0 +1,124 @@
+use actix_web::body::{to_bytes, BoxBody, MessageBody};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderValue};
+use actix_web::http::{Method, StatusCode};
+use actix_web::middleware::Next;
+use actix_web::{web, HttpResponse};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cache::RedisCache;
+use crate::config;
+use crate::error::ServiceError;
+use crate::request_context::{self, API_KEY_HEADER};
+
+const IDEMPOTENCY_HEADER: &str = "idempotency-key";
+const REPLAYED_HEADER: &str = "idempotent-replayed";
+const MAX_KEY_LENGTH: usize = 255;
+// Marks a key whose first request is still running. It expires on its own in case
+// that request never finishes, e.g. when the process is killed.
+const IN_PROGRESS: &[u8] = b"in-progress";
+const IN_PROGRESS_TTL: Duration = Duration::from_secs(300);
+
+#[derive(Serialize, Deserialize)]
+struct StoredResponse {
+    // Hash of the request the key was first used with
+    fingerprint: String,
+    status: u16,
+    content_type: Option<String>,
+    // Base64
+    body: String,
+}
+
+// Middleware for POST routes: a request with an `Idempotency-Key` header runs once,
+// retries with the same key and request get the stored response instead of enqueuing
+// or rendering again. Other responses than 2xx aren't stored, so the retry runs again.
+pub async fn handle(mut req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let key = req.headers().get(IDEMPOTENCY_HEADER).map(|v| v.to_str().unwrap_or_default().to_string());
+    let cache = req.app_data::<web::Data<Arc<RedisCache>>>().map(|cache| cache.get_ref().clone());
+    let (Some(key), Some(cache), &Method::POST) = (key, cache, req.method()) else {
+        return next.call(req).await.map(ServiceResponse::map_into_boxed_body);
+    };
+
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH || !key.bytes().all(|b| b.is_ascii_graphic()) {
+        return Err(ServiceError::InvalidParameter(
+            "Idempotency-Key".to_string(), format!("must be 1-{} printable ASCII characters", MAX_KEY_LENGTH)).into());
+    }
+
+    // The body is read here to fingerprint it, and handed back for the handler
+    let body = req.extract::<web::Bytes>().await?;
+    let mut hasher = Sha256::new();
+    hasher.update(req.path().as_bytes());
+    hasher.update(b"?");
+    hasher.update(req.query_string().as_bytes());
+    hasher.update(b"\n");
+    hasher.update(&body);
+    let fingerprint = hex::encode(hasher.finalize());
+    req.set_payload(Payload::from(body));
+
+    // Keys are per API key, so callers can't collide with or replay each other's
+    let client = req.headers().get(API_KEY_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(request_context::api_key_fingerprint)
+        .unwrap_or_default();
+    let storage_key = format!("idempotency:{}:{}", client, key);
+
+    if !cache.set_if_absent(&storage_key, IN_PROGRESS, IN_PROGRESS_TTL).await? {
+        let Some(stored) = cache.get(&storage_key).await? else {
+            return Err(ServiceError::Conflict("A request with this Idempotency-Key just finished, retry".to_string()).into());
+        };
+        if stored == IN_PROGRESS {
+            return Err(ServiceError::Conflict("A request with this Idempotency-Key is still in progress".to_string()).into());
+        }
+        let stored: StoredResponse = serde_json::from_slice(&stored)
+            .map_err(|e| ServiceError::CacheError(format!("Failed to deserialize stored response: {}", e)))?;
+        if stored.fingerprint != fingerprint {
+            return Err(ServiceError::Conflict("This Idempotency-Key was used for a different request".to_string()).into());
+        }
+        return Ok(req.into_response(replay(stored)?));
+    }
+
+    let response = match next.call(req).await {
+        Ok(response) => response,
+        Err(e) => {
+            cache.delete(&storage_key).await?;
+            return Err(e);
+        },
+    };
+    // A retry after e.g. a 429 or a source that wasn't published yet should run again
+    if !response.status().is_success() {
+        cache.delete(&storage_key).await?;
+        return Ok(response.map_into_boxed_body());
+    }
+
+    let (req, response) = response.into_parts();
+    let (response, body) = response.into_parts();
+    let body = to_bytes(body).await.map_err(|_| ServiceError::CacheError("Failed to read response body".to_string()))?;
+    let stored = StoredResponse {
+        fingerprint,
+        status: response.status().as_u16(),
+        content_type: response.headers().get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).map(str::to_string),
+        body: STANDARD.encode(&body),
+    };
+    let stored = serde_json::to_vec(&stored)
+        .map_err(|e| ServiceError::CacheError(format!("Failed to serialize response: {}", e)))?;
+    let ttl = Duration::from_secs(config::current().idempotency_ttl_secs);
+    cache.set(&storage_key, &stored, ttl).await?;
+
+    Ok(ServiceResponse::new(req, response.set_body(body).map_into_boxed_body()))
+}
+
+fn replay(stored: StoredResponse) -> Result<HttpResponse, ServiceError> {
+    let body = STANDARD.decode(&stored.body)
+        .map_err(|e| ServiceError::CacheError(format!("Failed to decode stored response: {}", e)))?;
+    let mut response = HttpResponse::build(StatusCode::from_u16(stored.status).unwrap_or(StatusCode::OK));
+    if let Some(content_type) = stored.content_type {
+        response.content_type(content_type);
+    }
+    response.insert_header((REPLAYED_HEADER, HeaderValue::from_static("true")));
+    Ok(response.body(body))
+}
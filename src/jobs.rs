@@ -0,0 +1,264 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::sync::Arc;
+use std::time::Duration;
+use utoipa::ToSchema;
+
+use crate::cache::RedisCache;
+use crate::config::{self, Config, JobQueue};
+use crate::error::{ServiceResult, ServiceError};
+use crate::svg::RenderOptions;
+use crate::handlers::render_cached;
+use crate::rate_limit::RateLimiter;
+use crate::request_context::{self, RequestFields};
+use crate::shutdown;
+use crate::tenants;
+use crate::webhook;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Processing,
+    Completed,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Job {
+    pub id: String,
+    pub status: JobStatus,
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+    pub error: Option<String>,
+    pub callback_url: Option<String>,
+    // The tenant that created the job, whose policies apply when it runs
+    #[serde(default)]
+    pub tenant: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct JobRequest {
+    /// URL of the SVG to render
+    pub url: String,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub preset: Option<String>,
+    /// Webhook called when the job completes or fails
+    pub callback_url: Option<String>,
+}
+
+fn job_key(id: &str) -> String {
+    format!("job:{}", id)
+}
+
+fn result_key(id: &str) -> String {
+    format!("job:{}:result", id)
+}
+
+async fn save_job(cache: &RedisCache, job: &Job, ttl: Duration) -> ServiceResult<()> {
+    let data = serde_json::to_vec(job)
+        .map_err(|e| ServiceError::CacheError(format!("Failed to serialize job: {}", e)))?;
+    cache.set(&job_key(&job.id), &data, ttl).await
+}
+
+pub async fn load_job(cache: &RedisCache, id: &str) -> ServiceResult<Job> {
+    let data = cache.get(&job_key(id)).await?
+        .ok_or_else(|| ServiceError::NotFound(format!("Job {} not found", id)))?;
+    serde_json::from_slice(&data)
+        .map_err(|e| ServiceError::CacheError(format!("Failed to deserialize job {}: {}", id, e)))
+}
+
+fn job_response(job: &Job, config: &Config) -> serde_json::Value {
+    json!({
+        "job": job,
+        "statusUrl": format!("{}/jobs/{}", config.public_base_url, job.id),
+        "resultUrl": format!("{}/jobs/{}/result", config.public_base_url, job.id),
+    })
+}
+
+#[utoipa::path(
+    post,
+    path = "/jobs",
+    tag = "jobs",
+    request_body = JobRequest,
+    responses(
+        (status = 202, description = "The queued job with its status and result URLs", content_type = "application/json"),
+        (status = 400, description = "Invalid parameters", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+    ),
+)]
+pub async fn create_job(
+    req: web::Json<JobRequest>,
+    cache: web::Data<Arc<RedisCache>>,
+    rate_limiter: web::Data<RateLimiter>,
+    client: web::Data<reqwest::Client>,
+) -> ServiceResult<HttpResponse> {
+    let config = config::current();
+    log::info!("Creating render job: {:?}", req);
+
+    if !rate_limiter.check_rate().await {
+        log::warn!("Rate limit exceeded for job request");
+        return Err(ServiceError::RateLimitExceeded);
+    }
+
+    let (width, height) = config.resolve_size(req.width, req.height, req.preset.as_deref())?;
+    if let Some(callback_url) = &req.callback_url {
+        webhook::validate_callback_url(callback_url).await?;
+    }
+
+    let now = chrono::Utc::now().to_rfc3339();
+    let job = Job {
+        id: uuid::Uuid::new_v4().to_string(),
+        status: JobStatus::Queued,
+        url: req.url.clone(),
+        width,
+        height,
+        error: None,
+        callback_url: req.callback_url.clone(),
+        tenant: request_context::tenant(),
+        created_at: now.clone(),
+        updated_at: now,
+    };
+
+    save_job(&cache, &job, Duration::from_secs(config.job_ttl_secs)).await?;
+    log::info!("Queued job {} for {}", job.id, job.url);
+
+    match config.job_queue {
+        JobQueue::Local => {
+            shutdown::spawn(run_job(
+                job.clone(),
+                cache.get_ref().clone(),
+                client.get_ref().clone(),
+            ));
+        },
+        JobQueue::Stream => {
+            cache.stream_add(&config.render_stream, &[("job_id", job.id.clone())]).await?;
+        }
+    }
+
+    Ok(HttpResponse::Accepted().json(job_response(&job, &config)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}",
+    tag = "jobs",
+    params(("id" = String, Path, description = "Job id")),
+    responses(
+        (status = 200, description = "The job with its status and result URLs", content_type = "application/json"),
+        (status = 404, description = "Unknown or expired job", body = ErrorBody),
+    ),
+)]
+pub async fn job_status(
+    id: web::Path<String>,
+    cache: web::Data<Arc<RedisCache>>,
+) -> ServiceResult<HttpResponse> {
+    let config = config::current();
+    let job = load_job(&cache, &id).await?;
+    Ok(HttpResponse::Ok().json(job_response(&job, &config)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/jobs/{id}/result",
+    tag = "jobs",
+    params(("id" = String, Path, description = "Job id")),
+    responses(
+        (status = 200, description = "The rendered PNG", content_type = "image/png"),
+        (status = 404, description = "Unknown job, or its result expired", body = ErrorBody),
+        (status = 409, description = "The job failed or hasn't completed yet", body = ErrorBody),
+    ),
+)]
+pub async fn job_result(
+    id: web::Path<String>,
+    cache: web::Data<Arc<RedisCache>>,
+) -> ServiceResult<HttpResponse> {
+    let job = load_job(&cache, &id).await?;
+
+    match job.status {
+        JobStatus::Completed => {
+            let png_data = cache.get(&result_key(&job.id)).await?
+                .ok_or_else(|| ServiceError::NotFound(format!("Result for job {} has expired", job.id)))?;
+            Ok(HttpResponse::Ok()
+                .content_type("image/png")
+                .body(png_data))
+        },
+        JobStatus::Failed => Err(ServiceError::Conflict(format!(
+            "Job {} failed: {}", job.id, job.error.unwrap_or_default()
+        ))),
+        _ => Err(ServiceError::Conflict(format!("Job {} is not completed yet", job.id))),
+    }
+}
+
+// Jobs run after the request that created them, in the background or on a worker, so
+// they get a context of their own with the tenant's configuration
+pub async fn run_job(job: Job, cache: Arc<RedisCache>, client: reqwest::Client) {
+    let cx = request_context::detached(RequestFields {
+        method: "POST".to_string(),
+        path: "/jobs".to_string(),
+        ..RequestFields::default()
+    });
+
+    request_context::scope(cx, async move {
+        let tenant = match &job.tenant {
+            Some(name) => match tenants::config_for(&config::current(), name) {
+                Some(tenant_config) => {
+                    request_context::set_tenant(name, tenant_config);
+                    Ok(())
+                },
+                // Never fall back to the global policies
+                None => Err(ServiceError::Unauthorized(format!("Tenant {} no longer exists", name))),
+            },
+            None => Ok(()),
+        };
+        execute_job(job, tenant, &cache, &client).await
+    }).await
+}
+
+async fn execute_job(mut job: Job, tenant: ServiceResult<()>, cache: &RedisCache, client: &reqwest::Client) {
+    let config = config::current();
+    let ttl = Duration::from_secs(config.job_ttl_secs);
+
+    job.status = JobStatus::Processing;
+    job.updated_at = chrono::Utc::now().to_rfc3339();
+    if let Err(e) = save_job(cache, &job, ttl).await {
+        log::error!("Failed to update job {}: {}", job.id, e);
+    }
+
+    let result = match tenant {
+        Ok(()) => match render_cached(&job.url, &RenderOptions::new(job.width, job.height), cache, client).await {
+            Ok(png_data) => cache.set(&result_key(&job.id), &png_data, ttl).await,
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+
+    match result {
+        Ok(()) => {
+            log::info!("Job {} completed", job.id);
+            job.status = JobStatus::Completed;
+        },
+        Err(e) => {
+            log::error!("Job {} failed: {}", job.id, e);
+            job.status = JobStatus::Failed;
+            job.error = Some(e.to_string());
+        }
+    }
+
+    job.updated_at = chrono::Utc::now().to_rfc3339();
+    if let Err(e) = save_job(cache, &job, ttl).await {
+        log::error!("Failed to update job {}: {}", job.id, e);
+    }
+
+    if let Some(callback_url) = &job.callback_url {
+        match webhook::send_job_callback(&config, callback_url, &job).await {
+            Ok(()) => log::info!("Delivered callback for job {} to {}", job.id, callback_url),
+            Err(e) => log::warn!("Callback for job {} to {} failed: {}", job.id, callback_url, e),
+        }
+    }
+}
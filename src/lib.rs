@@ -0,0 +1,390 @@
+use actix_web::{dev::Service, http::KeepAlive, web, App, HttpServer};
+#[cfg(unix)]
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub mod config;
+mod handlers;
+mod svg;
+mod cache;
+mod rate_limit;
+pub mod error;
+mod health;
+mod idempotency;
+mod jobs;
+mod webhook;
+mod worker;
+mod storage;
+mod spritesheet;
+mod templates;
+mod tenants;
+mod montage;
+mod favicon;
+mod diff;
+mod palette;
+mod blurhash;
+mod cloudinary;
+mod content_signature;
+mod optimize;
+mod metrics;
+mod telemetry;
+mod request_context;
+mod logging;
+mod access_log;
+mod error_reporting;
+mod admin;
+mod api_keys;
+mod audit;
+mod usage;
+mod render_pool;
+mod pixmap_pool;
+mod tree_cache;
+mod cpu_throttle;
+mod host_limit;
+mod dns;
+mod circuit_breaker;
+mod fallback;
+mod error_image;
+mod tls;
+mod listeners;
+mod systemd;
+mod shutdown;
+mod reload;
+mod cli;
+mod cors;
+mod params;
+mod path_api;
+mod signed_url;
+mod openapi;
+mod warmup;
+mod convert;
+mod service;
+pub mod hooks;
+#[cfg(any(feature = "kafka", feature = "nats"))]
+mod bus;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "wasm")]
+mod wasm_transform;
+
+use clap::Parser;
+
+use crate::cli::{Cli, Command};
+use crate::config::{Config, Listener, RunMode};
+use crate::systemd::Socket;
+use crate::cache::RedisCache;
+use crate::error::ServiceError;
+
+pub use crate::service::{RasterizerService, RasterizerServiceBuilder};
+
+// The proxy URL without credentials, for logging
+fn proxy_display(proxy_url: &str) -> String {
+    match url::Url::parse(proxy_url) {
+        Ok(url) => format!("{}://{}", url.scheme(), url.host_str().map(|host| match url.port() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        }).unwrap_or_default()),
+        Err(_) => "(invalid URL)".to_string(),
+    }
+}
+
+// The svg-rasterizer binary
+pub fn run() -> std::io::Result<()> {
+    // Flags are applied to the environment before the runtime starts any threads
+    let cli = Cli::parse();
+    cli.apply();
+
+    match cli.command.unwrap_or(Command::Serve) {
+        Command::Serve => actix_web::rt::System::new().block_on(serve()),
+        Command::CheckConfig => check_config(),
+        Command::Rasterize(args) => convert::run(args),
+    }
+}
+
+// Loads the configuration and the TLS certificate the way serve would, without starting
+fn check_config() -> std::io::Result<()> {
+    let checked = Config::from_env()
+        .and_then(|config| match tls::server_config(&config)? {
+            Some(tls_config) => tls::admin_server_config(&config, &tls_config).map(|_| config),
+            None => Ok(config),
+        });
+    match checked {
+        Ok(config) => {
+            println!("Configuration OK, {} listener(s), run mode {:?}", config.listeners.len(), config.run_mode);
+            Ok(())
+        },
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            std::process::exit(1);
+        },
+    }
+}
+
+async fn serve() -> std::io::Result<()> {
+
+    let config = Config::from_env().expect("Failed to load config");
+    config::set_current(config.clone());
+    shutdown::init();
+    health::init();
+    logging::init(&config);
+    access_log::init(&config).expect("Failed to open access log");
+    service::configure(&config);
+    // Before the run mode is picked, so workers and consumers apply it too
+    #[cfg(feature = "wasm")]
+    wasm_transform::register(&config).expect("Failed to load WASM_TRANSFORM");
+    cpu_throttle::start(&config);
+    let activated = systemd::activated_sockets();
+    listeners::configure(&config, &activated);
+    let _reporting = error_reporting::init(&config);
+
+    log::info!("Starting SVG rasterizer service...");
+    log::info!("Configuration loaded. Port: {}", config.port);
+    telemetry::init(&config);
+    if config.deterministic_rendering {
+        log::info!("Deterministic rendering enabled");
+    }
+    let redis_cache = Arc::new(RedisCache::new(&config.redis_url)
+        .expect("Failed to create Redis client"));
+        
+    // Initialize Redis connection
+    redis_cache.initialize().await
+        .expect("Failed to initialize Redis connection");
+    log::info!("Redis connection established at {}", config.redis_url);
+    
+    let mut client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        // Idle connections never expire with a zero timeout
+        .pool_idle_timeout((config.fetch_pool_idle_timeout_secs > 0).then(|| Duration::from_secs(config.fetch_pool_idle_timeout_secs)))
+        .tcp_keepalive((config.fetch_tcp_keepalive_secs > 0).then(|| Duration::from_secs(config.fetch_tcp_keepalive_secs)));
+    if let Some(max_idle) = config.fetch_pool_max_idle_per_host {
+        client = client.pool_max_idle_per_host(max_idle);
+    }
+    if config.fetch_connect_timeout_ms > 0 {
+        client = client.connect_timeout(Duration::from_millis(config.fetch_connect_timeout_ms));
+    }
+    if let Some(resolver) = dns::resolver(&config) {
+        client = client.dns_resolver(resolver);
+    }
+    if let Some(address) = config.fetch_local_address {
+        client = client.local_address(address);
+        log::info!("Fetching from local address {}", address);
+    }
+    // Without an explicit proxy reqwest uses HTTP_PROXY, HTTPS_PROXY and NO_PROXY
+    if let Some(proxy_url) = &config.fetch_proxy {
+        let mut proxy = reqwest::Proxy::all(proxy_url.as_str())
+            .expect("Invalid FETCH_PROXY")
+            .no_proxy(reqwest::NoProxy::from_string(config.fetch_no_proxy.as_deref().unwrap_or_default()));
+        if let Some(username) = &config.fetch_proxy_username {
+            proxy = proxy.basic_auth(username, config.fetch_proxy_password.as_deref().unwrap_or_default());
+        }
+        client = client.proxy(proxy);
+        log::info!("Fetching through proxy {}", proxy_display(proxy_url));
+    }
+    if let Some(user_agent) = &config.fetch_user_agent {
+        client = client.user_agent(user_agent.as_str());
+    }
+    let client = client.build()
+        .expect("Failed to create HTTP client");
+    log::info!("HTTP client created with 10s timeout");
+
+    health::start_canary(&config, &client);
+
+    fallback::load(&config, &client).await
+        .expect("Failed to load fallback image");
+
+    // Create web::Data instances with correct types
+    let config = web::Data::new(config);
+
+    match config.run_mode {
+        RunMode::Server => {},
+        RunMode::Worker => {
+            log::info!("Starting render stream worker");
+            return worker::run(config, redis_cache, client).await;
+        },
+        #[cfg(feature = "kafka")]
+        RunMode::Kafka => {
+            log::info!("Starting Kafka render consumer");
+            return bus::run_kafka(config, redis_cache, client).await;
+        },
+        #[cfg(feature = "nats")]
+        RunMode::Nats => {
+            log::info!("Starting NATS render consumer");
+            return bus::run_nats(config, redis_cache, client).await;
+        },
+    }
+
+    let service = RasterizerService::builder()
+        .config(config.get_ref().clone())
+        .cache(redis_cache)
+        .client(client)
+        .standalone()
+        .build()
+        .await
+        .expect("Failed to create the rasterizer service");
+
+    #[cfg(feature = "grpc")]
+    if let Some(address) = config.grpc_listen {
+        grpc::spawn(address, service.cache().clone(), service.client().clone(), service.rate_limiter().clone());
+    }
+
+    let warmup_cache = service.cache().clone();
+    let warmup_client = service.client().clone();
+
+    let settings = config.clone();
+    let mut server = HttpServer::new(move || {
+        App::new()
+            .wrap_fn(|req, srv| {
+                // Admin routes only answer on admin listeners, when there are any
+                let response = listeners::serves(&req).then(|| srv.call(req));
+                async move {
+                    match response {
+                        Some(response) => response.await,
+                        None => Err(actix_web::error::ErrorNotFound("Not found")),
+                    }
+                }
+            })
+            .wrap_fn(|req, srv| {
+                let response = tls::client_allowed(&req).then(|| srv.call(req));
+                async move {
+                    match response {
+                        Some(response) => response.await,
+                        None => Err(ServiceError::Unauthorized("A client certificate is required".to_string()).into()),
+                    }
+                }
+            })
+            .wrap(cors::middleware(&config))
+            .wrap_fn(request_context::handle_request)
+            .wrap_fn(telemetry::trace_request)
+            .wrap_fn(|req, srv| {
+                let start = Instant::now();
+                let response = srv.call(req);
+                async move {
+                    let response = response.await?;
+                    metrics::metrics().observe_response(&response, start.elapsed());
+                    Ok(response)
+                }
+            })
+            .app_data(service.cache_data())
+            .service(service.admin_scope("/admin"))
+            .service(service.scope(""))
+    })
+    .on_connect(tls::on_connect)
+    // Signals are handled below, so background tasks can be drained after the workers stop
+    .disable_signals()
+    .shutdown_timeout(settings.shutdown_timeout_secs)
+    .client_request_timeout(Duration::from_millis(settings.http_request_timeout_ms))
+    .keep_alive(match settings.http_keep_alive_secs {
+        0 => KeepAlive::Disabled,
+        secs => KeepAlive::Timeout(Duration::from_secs(secs)),
+    });
+
+    if let Some(workers) = settings.http_workers {
+        server = server.workers(workers);
+    }
+    if let Some(max_connections) = settings.http_max_connections {
+        server = server.max_connections(max_connections);
+    }
+
+    let tls_config = tls::server_config(&settings)
+        .expect("Failed to load TLS certificate");
+    let admin_tls_config = match &tls_config {
+        Some(tls_config) => tls::admin_server_config(&settings, tls_config).expect("Failed to load ADMIN_CLIENT_CA"),
+        None => None,
+    };
+    // Sockets from systemd replace the configured listeners
+    let listeners = if activated.is_empty() { settings.listeners.as_slice() } else { &[] };
+    for activated in activated {
+        server = match activated.socket {
+            Socket::Tcp(tcp) => {
+                let addr = tcp.local_addr()?;
+                log::info!("Listening on {} from systemd ({})", addr, activated.name.as_deref().unwrap_or("http"));
+                match activated.name.as_deref() {
+                    Some("https") => server.listen_rustls_0_21(tcp, tls_config.clone().expect("https sockets require a TLS certificate"))?,
+                    Some("admin") if admin_tls_config.is_some() => server.listen_rustls_0_21(tcp, admin_tls_config.clone().unwrap())?,
+                    _ if settings.http_h2c => server.listen_auto_h2c(tcp)?,
+                    _ => server.listen(tcp)?,
+                }
+            },
+            #[cfg(unix)]
+            Socket::Unix(unix) => {
+                log::info!("Listening on a unix socket from systemd");
+                server.listen_uds(unix)?
+            },
+        };
+    }
+    for listener in listeners {
+        server = match listener {
+            Listener::Https(addr) => {
+                let tls_config = tls_config.clone().expect("https listeners require a TLS certificate");
+                log::info!("Listening on https://{}", addr);
+                server.listen_rustls_0_21(listeners::bind_tcp(*addr)?, tls_config)?
+            },
+            Listener::Admin(addr) if admin_tls_config.is_some() => {
+                log::info!("Listening on https://{} (admin, client certificates required)", addr);
+                server.listen_rustls_0_21(listeners::bind_tcp(*addr)?, admin_tls_config.clone().unwrap())?
+            },
+            Listener::Http(addr) | Listener::Admin(addr) => {
+                log::info!("Listening on http://{}{}", addr, if matches!(listener, Listener::Admin(_)) { " (admin)" } else { "" });
+                if settings.http_h2c {
+                    server.listen_auto_h2c(listeners::bind_tcp(*addr)?)?
+                } else {
+                    server.listen(listeners::bind_tcp(*addr)?)?
+                }
+            },
+            #[cfg(unix)]
+            Listener::Unix(path) => {
+                // Replace a socket file left behind by a previous run
+                if std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+                let server = server.bind_uds(path)?;
+                if let Some(mode) = settings.listen_socket_mode {
+                    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+                }
+                log::info!("Listening on unix socket {}", path);
+                server
+            },
+            #[cfg(not(unix))]
+            Listener::Unix(path) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("Can't listen on unix socket {}: unix sockets need a Unix platform", path),
+                ));
+            },
+        };
+    }
+
+    let server = server.run();
+    actix_web::rt::spawn(async move {
+        if actix_web::rt::task::spawn_blocking(svg::warm_up).await.is_ok() {
+            warmup::run(&warmup_cache, &warmup_client).await;
+            health::set_warmed_up();
+            log::info!("Warmup complete, ready for traffic");
+            warmup::schedule(warmup_cache, warmup_client);
+        }
+    });
+    let handle = server.handle();
+    let shutdown_timeout = settings.shutdown_timeout_secs;
+    actix_web::rt::spawn(async move {
+        shutdown::signal().await;
+        log::info!("Shutting down, waiting up to {}s for in-flight requests", shutdown_timeout);
+        handle.stop(true).await;
+    });
+    #[cfg(unix)]
+    actix_web::rt::spawn(reload::watch_signals());
+    let result = server.await;
+
+    let abandoned = shutdown::drain_tasks(Duration::from_secs(shutdown_timeout)).await;
+    if abandoned > 0 {
+        log::warn!("Shutdown timeout reached, abandoning {} background tasks", abandoned);
+    }
+    for listener in listeners {
+        if let Listener::Unix(path) = listener {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+    telemetry::shutdown();
+    log::info!("Shutdown complete");
+    result
+}
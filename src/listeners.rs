@@ -0,0 +1,79 @@
+use actix_web::dev::ServiceRequest;
+use actix_web::HttpRequest;
+use socket2::{Domain, Protocol, Type};
+use std::net::{Ipv6Addr, SocketAddr, TcpListener};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::config::{Config, Listener};
+use crate::systemd::{Activated, Socket};
+
+// Routes an admin listener serves, everything else stays on the public listeners
+const ADMIN_PATHS: [&str; 5] = ["/admin/", "/health", "/livez", "/readyz", "/metrics"];
+// actix-web's default
+const LISTEN_BACKLOG: i32 = 2048;
+
+static ADMIN_ADDRS: OnceLock<Vec<SocketAddr>> = OnceLock::new();
+// Admin listeners only complete the TLS handshake for clients with a certificate from ADMIN_CLIENT_CA
+static ADMIN_MTLS: AtomicBool = AtomicBool::new(false);
+
+// Sockets from systemd replace the configured listeners, those named "admin" are admin listeners
+pub fn configure(config: &Config, activated: &[Activated]) {
+    let admin: Vec<SocketAddr> = if activated.is_empty() {
+        config.listeners.iter()
+            .filter_map(|listener| match listener {
+                Listener::Admin(addr) => Some(*addr),
+                _ => None,
+            })
+            .collect()
+    } else {
+        activated.iter()
+            .filter(|socket| socket.name.as_deref() == Some("admin"))
+            .filter_map(|socket| match &socket.socket {
+                Socket::Tcp(tcp) => tcp.local_addr().ok(),
+                #[cfg(unix)]
+                Socket::Unix(_) => None,
+            })
+            .collect()
+    };
+    ADMIN_MTLS.store(config.admin_client_ca.is_some() && !admin.is_empty(), Ordering::Relaxed);
+    let _ = ADMIN_ADDRS.set(admin);
+}
+
+// Whether the listener the request came in on serves its path. Without admin
+// listeners every listener serves every route.
+pub fn serves(req: &ServiceRequest) -> bool {
+    let admin_addrs = ADMIN_ADDRS.get().map(Vec::as_slice).unwrap_or_default();
+    if admin_addrs.is_empty() {
+        return true;
+    }
+
+    let path = req.path();
+    if admin_addrs.contains(&req.app_config().local_addr()) {
+        ADMIN_PATHS.iter().any(|prefix| path.starts_with(prefix))
+    } else {
+        !path.starts_with("/admin/")
+    }
+}
+
+// Whether the request came in on an admin listener that verified the client's certificate
+pub fn admin_client_verified(req: &HttpRequest) -> bool {
+    ADMIN_MTLS.load(Ordering::Relaxed)
+        && ADMIN_ADDRS.get().is_some_and(|addrs| addrs.contains(&req.app_config().local_addr()))
+}
+
+// Binds like actix-web does, except that [::] always accepts IPv4 connections
+// as well, whatever the system's net.ipv6.bindv6only default is
+pub fn bind_tcp(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let bind = || {
+        let socket = socket2::Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_reuse_address(true)?;
+        if addr.ip() == Ipv6Addr::UNSPECIFIED {
+            socket.set_only_v6(false)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.listen(LISTEN_BACKLOG)?;
+        Ok(socket.into())
+    };
+    bind().map_err(|e: std::io::Error| std::io::Error::new(e.kind(), format!("Failed to bind {}: {}", addr, e)))
+}
@@ -0,0 +1,137 @@
+use arc_swap::ArcSwap;
+use env_logger::filter::Filter;
+use serde_json::json;
+use std::io::Write;
+use std::sync::OnceLock;
+
+use crate::config::{Config, LogFormat};
+use crate::request_context;
+
+// RUST_LOG directives in effect, kept outside env_logger so a reload can replace them
+static FILTER: OnceLock<ArcSwap<Filter>> = OnceLock::new();
+
+struct ReloadableLogger(env_logger::Logger);
+
+pub fn init(config: &Config) {
+    let redact = config.log_redact_urls;
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(log::LevelFilter::Trace);
+
+    match config.log_format {
+        LogFormat::Text => builder.format(move |buf, record| {
+            // Tag every line logged while handling a request with its id
+            let request_id = request_context::current_id()
+                .map(|id| format!(" request_id={}", id))
+                .unwrap_or_default();
+            writeln!(buf, "[{} {:<5} {}{}] {}",
+                buf.timestamp(), record.level(), record.target(), request_id, message(record, redact))
+        }),
+        LogFormat::Json => builder.format(move |buf, record| {
+            let mut line = json!({
+                "timestamp": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": message(record, redact),
+            });
+
+            // Lines logged while handling a request carry what is known about it so far
+            if let Some(cx) = request_context::current() {
+                let fields = cx.fields();
+                line["request_id"] = json!(cx.id);
+                line["method"] = json!(fields.method);
+                line["path"] = json!(fields.path);
+                line["route"] = json!(fields.route);
+                line["client_ip"] = json!(fields.client_ip);
+                if let Some(status) = fields.status {
+                    line["status"] = json!(status);
+                }
+                if let Some(latency) = fields.latency {
+                    line["latency_ms"] = json!((latency.as_secs_f64() * 100_000.0).round() / 100.0);
+                }
+                if let Some(cache) = fields.cache {
+                    line["cache"] = json!(cache);
+                }
+                if let Some(upstream_host) = fields.upstream_host {
+                    line["upstream_host"] = json!(upstream_host);
+                }
+                if !fields.timings.is_empty() {
+                    line["timings_ms"] = fields.timings.iter()
+                        .map(|(name, elapsed)| (name.to_string(), json!((elapsed.as_secs_f64() * 100_000.0).round() / 100.0)))
+                        .collect::<serde_json::Map<_, _>>()
+                        .into();
+                }
+            }
+
+            writeln!(buf, "{}", line)
+        }),
+    };
+
+    let filter = parse_filter(&config.log_filter);
+    log::set_max_level(filter.filter());
+    let _ = FILTER.set(ArcSwap::from_pointee(filter));
+    log::set_boxed_logger(Box::new(ReloadableLogger(builder.build())))
+        .expect("Failed to initialize logger");
+}
+
+// Applies new RUST_LOG directives to everything logged from now on
+pub fn set_filter(directives: &str) {
+    let Some(current) = FILTER.get() else {
+        return;
+    };
+    let filter = parse_filter(directives);
+    log::set_max_level(filter.filter());
+    current.store(std::sync::Arc::new(filter));
+}
+
+fn parse_filter(directives: &str) -> Filter {
+    env_logger::filter::Builder::new().parse(directives).build()
+}
+
+impl log::Log for ReloadableLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        FILTER.get().is_some_and(|filter| filter.load().enabled(metadata))
+    }
+
+    fn log(&self, record: &log::Record) {
+        if FILTER.get().is_some_and(|filter| filter.load().matches(record)) {
+            self.0.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.0.flush();
+    }
+}
+
+fn message(record: &log::Record, redact: bool) -> String {
+    let message = record.args().to_string();
+    if redact { redact_urls(&message) } else { message }
+}
+
+// Cuts every http(s) URL in a log message down to its origin, since paths and
+// query strings of source URLs can identify customers
+fn redact_urls(message: &str) -> String {
+    let mut redacted = String::with_capacity(message.len());
+    let mut rest = message;
+
+    while let Some(start) = ["http://", "https://"].iter().filter_map(|scheme| rest.find(scheme)).min() {
+        redacted.push_str(&rest[..start]);
+        let url = &rest[start..];
+        let end = url.find(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '<' | '>' | ')'))
+            .unwrap_or(url.len());
+
+        let scheme_end = url.find("://").unwrap() + 3;
+        let host_end = url[scheme_end..end].find(['/', '?', '#'])
+            .map(|i| scheme_end + i)
+            .unwrap_or(end);
+        redacted.push_str(&url[..host_end]);
+        if host_end < end {
+            redacted.push_str("/[redacted]");
+        }
+
+        rest = &url[end..];
+    }
+
+    redacted.push_str(rest);
+    redacted
+}
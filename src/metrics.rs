@@ -0,0 +1,438 @@
+use actix_web::dev::ServiceResponse;
+use actix_web::HttpResponse;
+use prometheus::{
+    Encoder, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec,
+    Opts, Registry, TextEncoder,
+};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::error::ServiceResult;
+
+const SMALL_RENDER_MAX: u32 = 256;
+const MEDIUM_RENDER_MAX: u32 = 1024;
+
+// Redis round trips are sub-millisecond when healthy
+const REDIS_BUCKETS: [f64; 10] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0];
+
+// Source hosts are customer controlled, so cap the label cardinality
+const MAX_TRACKED_HOSTS: usize = 500;
+const OTHER_HOST: &str = "other";
+
+// Window the cache hit rate in the health output covers, in one-second buckets
+const RECENT_WINDOW_SECS: usize = 60;
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+pub struct Metrics {
+    registry: Registry,
+    pub http_requests: IntCounterVec,
+    pub http_request_duration: HistogramVec,
+    pub render_duration: HistogramVec,
+    pub cache_lookups: IntCounterVec,
+    pub upstream_fetch_duration: Histogram,
+    pub upstream_responses: IntCounterVec,
+    pub upstream_bytes: IntCounter,
+    pub redis_connection_duration: Histogram,
+    pub redis_command_duration: HistogramVec,
+    pub rate_limit_rejections: IntCounter,
+    pub redis_errors: IntCounter,
+    pub upstream_host_requests: IntCounterVec,
+    pub upstream_host_duration: HistogramVec,
+    pub upstream_host_limit_waits: IntCounter,
+    pub upstream_retries: IntCounterVec,
+    pub upstream_retries_exhausted: IntCounter,
+    pub upstream_circuit_opens: IntCounter,
+    pub upstream_circuit_rejections: IntCounter,
+    pub dns_lookup_duration: Histogram,
+    pub renders_in_flight: IntGauge,
+    pub render_queue_depth: IntGaugeVec,
+    pub render_queue_wait: Histogram,
+    pub render_rejections: IntCounterVec,
+    pub render_concurrency_limit: IntGauge,
+    pub cpu_utilization: GaugeVec,
+    pub pixmap_pool_requests: IntCounterVec,
+    pub pixmap_pool_resident_bytes: IntGauge,
+    pub tree_cache_lookups: IntCounterVec,
+    pub tree_cache_bytes: IntGauge,
+    upstreams: Mutex<HashMap<String, UpstreamStats>>,
+    recent_cache_lookups: RecentLookups,
+}
+
+// Cache hits and misses per second over the last RECENT_WINDOW_SECS, which
+// Prometheus counters can't answer without a scraper doing the math. Every cache
+// lookup records here, so it uses atomics instead of a lock; a lookup racing the
+// reset of its bucket can get lost, which is fine for a rate.
+struct RecentLookups {
+    started: Instant,
+    buckets: [RecentBucket; RECENT_WINDOW_SECS],
+}
+
+#[derive(Default)]
+struct RecentBucket {
+    // Second since `started` the bucket was last written in
+    second: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl RecentLookups {
+    fn new() -> Self {
+        Self { started: Instant::now(), buckets: std::array::from_fn(|_| RecentBucket::default()) }
+    }
+
+    fn record(&self, hit: bool) {
+        let second = self.started.elapsed().as_secs();
+        let bucket = &self.buckets[second as usize % RECENT_WINDOW_SECS];
+        let last = bucket.second.load(Ordering::Relaxed);
+        if last != second && bucket.second.compare_exchange(last, second, Ordering::Relaxed, Ordering::Relaxed).is_ok() {
+            bucket.hits.store(0, Ordering::Relaxed);
+            bucket.misses.store(0, Ordering::Relaxed);
+        }
+        let counter = if hit { &bucket.hits } else { &bucket.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Hits and total lookups over the window
+    fn totals(&self) -> (u64, u64) {
+        let now = self.started.elapsed().as_secs();
+        self.buckets.iter()
+            .filter(|bucket| now.saturating_sub(bucket.second.load(Ordering::Relaxed)) < RECENT_WINDOW_SECS as u64)
+            .fold((0, 0), |(hits, total), bucket| {
+                let (bucket_hits, bucket_misses) = (bucket.hits.load(Ordering::Relaxed), bucket.misses.load(Ordering::Relaxed));
+                (hits + bucket_hits, total + bucket_hits + bucket_misses)
+            })
+    }
+}
+
+#[derive(Default)]
+struct UpstreamStats {
+    requests: u64,
+    errors: u64,
+    total_duration: Duration,
+    last_error: Option<String>,
+    last_error_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP requests by route and status"),
+            &["method", "route", "status"],
+        ).unwrap();
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request duration by route"),
+            &["route"],
+        ).unwrap();
+        let render_duration = HistogramVec::new(
+            HistogramOpts::new("render_duration_seconds", "Time spent parsing, rendering and encoding an SVG")
+                .buckets(vec![0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]),
+            &["size_class", "format"],
+        ).unwrap();
+        let cache_lookups = IntCounterVec::new(
+            Opts::new("cache_lookups_total", "Redis cache lookups by key type and result"),
+            &["kind", "result"],
+        ).unwrap();
+        let upstream_fetch_duration = Histogram::with_opts(
+            HistogramOpts::new("upstream_fetch_duration_seconds", "Time spent fetching source SVGs"),
+        ).unwrap();
+        let upstream_responses = IntCounterVec::new(
+            Opts::new("upstream_responses_total", "Source SVG responses by HTTP status, or error when no response arrived"),
+            &["status"],
+        ).unwrap();
+        let upstream_bytes = IntCounter::new(
+            "upstream_fetched_bytes_total", "Bytes of source SVG data fetched",
+        ).unwrap();
+        let redis_connection_duration = Histogram::with_opts(
+            HistogramOpts::new("redis_connection_duration_seconds", "Time spent acquiring a Redis connection")
+                .buckets(REDIS_BUCKETS.to_vec()),
+        ).unwrap();
+        let redis_command_duration = HistogramVec::new(
+            HistogramOpts::new("redis_command_duration_seconds", "Redis cache command latency")
+                .buckets(REDIS_BUCKETS.to_vec()),
+            &["command"],
+        ).unwrap();
+        let rate_limit_rejections = IntCounter::new(
+            "rate_limit_rejections_total", "Requests rejected by the rate limiter",
+        ).unwrap();
+        let redis_errors = IntCounter::new(
+            "redis_errors_total", "Failed Redis operations",
+        ).unwrap();
+        let upstream_host_requests = IntCounterVec::new(
+            Opts::new("upstream_host_requests_total", "Source SVG fetches by host and outcome"),
+            &["host", "outcome"],
+        ).unwrap();
+        let upstream_host_duration = HistogramVec::new(
+            HistogramOpts::new("upstream_host_fetch_duration_seconds", "Source SVG fetch duration by host"),
+            &["host"],
+        ).unwrap();
+
+        let renders_in_flight = IntGauge::new(
+            "renders_in_flight", "Renders currently running on the render pool",
+        ).unwrap();
+        let render_queue_depth = IntGaugeVec::new(
+            Opts::new("render_queue_depth", "Renders waiting for a free render slot by queue lane"),
+            &["lane"],
+        ).unwrap();
+        let render_queue_wait = Histogram::with_opts(
+            HistogramOpts::new("render_queue_wait_seconds", "Time renders spent waiting for a free render slot")
+                .buckets(vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]),
+        ).unwrap();
+        let render_rejections = IntCounterVec::new(
+            Opts::new("render_rejections_total", "Renders rejected because the service was saturated"),
+            &["reason"],
+        ).unwrap();
+        let pixmap_pool_requests = IntCounterVec::new(
+            Opts::new("pixmap_pool_requests_total", "Pixel buffer requests served from the pool (hit) or newly allocated (miss)"),
+            &["result"],
+        ).unwrap();
+        let pixmap_pool_resident_bytes = IntGauge::new(
+            "pixmap_pool_resident_bytes", "Memory held by idle pooled pixel buffers",
+        ).unwrap();
+        let tree_cache_lookups = IntCounterVec::new(
+            Opts::new("tree_cache_lookups_total", "Parsed SVG tree cache lookups by result"),
+            &["result"],
+        ).unwrap();
+        let tree_cache_bytes = IntGauge::new(
+            "tree_cache_bytes", "Estimated memory held by cached parsed SVG trees",
+        ).unwrap();
+        let render_concurrency_limit = IntGauge::new(
+            "render_concurrency_limit", "Renders currently allowed to run at once, lowered while the CPU is saturated",
+        ).unwrap();
+        let cpu_utilization = GaugeVec::new(
+            Opts::new("cpu_utilization_ratio", "Share of host CPU time in use, by this process (self) or anything else (other)"),
+            &["source"],
+        ).unwrap();
+        let upstream_host_limit_waits = IntCounter::new(
+            "upstream_host_limit_waits_total", "Source fetches that waited because their host was at FETCH_MAX_PER_HOST",
+        ).unwrap();
+        let upstream_retries = IntCounterVec::new(
+            Opts::new("upstream_fetch_retries_total", "Source fetches retried, by the failure that caused the retry"),
+            &["reason"],
+        ).unwrap();
+        let upstream_retries_exhausted = IntCounter::new(
+            "upstream_fetch_retries_exhausted_total", "Source fetches that still failed after FETCH_RETRIES retries",
+        ).unwrap();
+        let upstream_circuit_opens = IntCounter::new(
+            "upstream_circuit_opens_total", "Times a source host's circuit opened after too many failed fetches",
+        ).unwrap();
+        let upstream_circuit_rejections = IntCounter::new(
+            "upstream_circuit_rejections_total", "Source fetches refused because the host's circuit was open",
+        ).unwrap();
+        let dns_lookup_duration = Histogram::with_opts(
+            HistogramOpts::new("dns_lookup_duration_seconds", "Source host lookups through the DNS cache, near zero for cached answers")
+                .buckets(vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0]),
+        ).unwrap();
+
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(http_request_duration.clone())).unwrap();
+        registry.register(Box::new(render_duration.clone())).unwrap();
+        registry.register(Box::new(cache_lookups.clone())).unwrap();
+        registry.register(Box::new(upstream_fetch_duration.clone())).unwrap();
+        registry.register(Box::new(upstream_responses.clone())).unwrap();
+        registry.register(Box::new(upstream_bytes.clone())).unwrap();
+        registry.register(Box::new(redis_connection_duration.clone())).unwrap();
+        registry.register(Box::new(redis_command_duration.clone())).unwrap();
+        registry.register(Box::new(rate_limit_rejections.clone())).unwrap();
+        registry.register(Box::new(redis_errors.clone())).unwrap();
+        registry.register(Box::new(upstream_host_requests.clone())).unwrap();
+        registry.register(Box::new(upstream_host_duration.clone())).unwrap();
+        registry.register(Box::new(upstream_host_limit_waits.clone())).unwrap();
+        registry.register(Box::new(upstream_retries.clone())).unwrap();
+        registry.register(Box::new(upstream_retries_exhausted.clone())).unwrap();
+        registry.register(Box::new(upstream_circuit_opens.clone())).unwrap();
+        registry.register(Box::new(upstream_circuit_rejections.clone())).unwrap();
+        registry.register(Box::new(dns_lookup_duration.clone())).unwrap();
+        registry.register(Box::new(renders_in_flight.clone())).unwrap();
+        registry.register(Box::new(render_queue_depth.clone())).unwrap();
+        registry.register(Box::new(render_queue_wait.clone())).unwrap();
+        registry.register(Box::new(render_rejections.clone())).unwrap();
+        registry.register(Box::new(render_concurrency_limit.clone())).unwrap();
+        registry.register(Box::new(cpu_utilization.clone())).unwrap();
+        registry.register(Box::new(pixmap_pool_requests.clone())).unwrap();
+        registry.register(Box::new(pixmap_pool_resident_bytes.clone())).unwrap();
+        registry.register(Box::new(tree_cache_lookups.clone())).unwrap();
+        registry.register(Box::new(tree_cache_bytes.clone())).unwrap();
+
+        Self {
+            registry,
+            http_requests,
+            http_request_duration,
+            render_duration,
+            cache_lookups,
+            upstream_fetch_duration,
+            upstream_responses,
+            upstream_bytes,
+            redis_connection_duration,
+            redis_command_duration,
+            rate_limit_rejections,
+            redis_errors,
+            upstream_host_requests,
+            upstream_host_duration,
+            upstream_host_limit_waits,
+            upstream_retries,
+            upstream_retries_exhausted,
+            upstream_circuit_opens,
+            upstream_circuit_rejections,
+            dns_lookup_duration,
+            renders_in_flight,
+            render_queue_depth,
+            render_queue_wait,
+            render_rejections,
+            render_concurrency_limit,
+            cpu_utilization,
+            pixmap_pool_requests,
+            pixmap_pool_resident_bytes,
+            tree_cache_lookups,
+            tree_cache_bytes,
+            upstreams: Mutex::new(HashMap::new()),
+            recent_cache_lookups: RecentLookups::new(),
+        }
+    }
+
+    pub fn observe_response<B>(&self, res: &ServiceResponse<B>, elapsed: Duration) {
+        // Label by the matched route pattern so ids and query strings don't explode cardinality
+        let route = res.request().match_pattern().unwrap_or_else(|| "unmatched".to_string());
+
+        self.http_requests
+            .with_label_values(&[res.request().method().as_str(), &route, res.status().as_str()])
+            .inc();
+        self.http_request_duration
+            .with_label_values(&[&route])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_render(&self, width: u32, height: u32, format: &str, elapsed: Duration) {
+        self.render_duration
+            .with_label_values(&[size_class(width, height), format])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_upstream_status(&self, status: Option<reqwest::StatusCode>) {
+        let status = status.map(|s| s.as_u16().to_string()).unwrap_or_else(|| "error".to_string());
+        self.upstream_responses.with_label_values(&[&status]).inc();
+    }
+
+    // Records a complete source fetch, including failures that happen after the response arrived
+    pub fn observe_upstream_fetch(&self, url: &str, elapsed: Duration, error: Option<&str>) {
+        let host = url::Url::parse(url)
+            .ok()
+            .and_then(|u| u.host_str().map(|h| h.to_lowercase()))
+            .unwrap_or_else(|| OTHER_HOST.to_string());
+
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let host = if upstreams.contains_key(&host) || upstreams.len() < MAX_TRACKED_HOSTS {
+            host
+        } else {
+            OTHER_HOST.to_string()
+        };
+
+        self.upstream_host_requests
+            .with_label_values(&[&host, if error.is_some() { "error" } else { "ok" }])
+            .inc();
+        self.upstream_host_duration
+            .with_label_values(&[&host])
+            .observe(elapsed.as_secs_f64());
+
+        let stats = upstreams.entry(host).or_default();
+        stats.requests += 1;
+        stats.total_duration += elapsed;
+        if let Some(error) = error {
+            stats.errors += 1;
+            stats.last_error = Some(error.to_string());
+            stats.last_error_at = Some(chrono::Utc::now());
+        }
+    }
+
+    pub fn observe_cache_lookup(&self, key: &str, hit: bool) {
+        let kind = key.split(':').next().unwrap_or("");
+        self.cache_lookups
+            .with_label_values(&[kind, if hit { "hit" } else { "miss" }])
+            .inc();
+        self.recent_cache_lookups.record(hit);
+    }
+
+    // Cache hits and total lookups over the last minute
+    pub fn recent_cache_lookups(&self) -> (u64, u64) {
+        self.recent_cache_lookups.totals()
+    }
+}
+
+// Buckets renders by their longest side
+fn size_class(width: u32, height: u32) -> &'static str {
+    match width.max(height) {
+        side if side <= SMALL_RENDER_MAX => "small",
+        side if side <= MEDIUM_RENDER_MAX => "medium",
+        _ => "large",
+    }
+}
+
+pub async fn metrics_handler() -> HttpResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+
+    if let Err(e) = encoder.encode(&metrics().registry.gather(), &mut buffer) {
+        log::error!("Failed to encode metrics: {}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer)
+}
+
+// Per-host fetch statistics since startup, slowest hosts first
+pub async fn upstream_stats_handler() -> ServiceResult<HttpResponse> {
+    let upstreams = metrics().upstreams.lock().unwrap();
+
+    let mut hosts: Vec<_> = upstreams.iter()
+        .map(|(host, stats)| {
+            let avg_latency_ms = stats.total_duration.as_secs_f64() * 1000.0 / stats.requests as f64;
+            (host, stats, avg_latency_ms)
+        })
+        .collect();
+    hosts.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+    let hosts: Vec<_> = hosts.into_iter()
+        .map(|(host, stats, avg_latency_ms)| json!({
+            "host": host,
+            "requests": stats.requests,
+            "errors": stats.errors,
+            "errorRate": stats.errors as f64 / stats.requests as f64,
+            "avgLatencyMs": (avg_latency_ms * 10.0).round() / 10.0,
+            "lastError": stats.last_error,
+            "lastErrorAt": stats.last_error_at.map(|t| t.to_rfc3339()),
+        }))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(json!({ "upstreams": hosts })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_recent_cache_lookups() {
+        let recent = RecentLookups::new();
+        for hit in [true, true, false] {
+            recent.record(hit);
+        }
+        assert_eq!(recent.totals(), (2, 3));
+
+        // A full window later the buckets are stale and start over when written
+        let recent = RecentLookups { started: Instant::now() - Duration::from_secs(RECENT_WINDOW_SECS as u64), ..recent };
+        assert_eq!(recent.totals(), (0, 0));
+        recent.record(true);
+        assert_eq!(recent.totals(), (1, 1));
+    }
+}
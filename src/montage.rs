@@ -0,0 +1,178 @@
+use actix_web::{web, HttpResponse};
+use resvg::tiny_skia::{Pixmap, PixmapPaint, Transform};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use crate::cache::RedisCache;
+use crate::config::{self, Config};
+use crate::error::{ServiceResult, ServiceError};
+use crate::rate_limit::RateLimiter;
+use crate::request_context;
+use crate::svg::{parse_color, SvgProcessor};
+
+const MAX_PADDING: u32 = 64;
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct ContactSheetRequest {
+    pub urls: Vec<String>,
+    pub cell_size: Option<u32>,
+    pub columns: Option<u32>,
+    pub padding: Option<u32>,
+    pub captions: Option<bool>,
+    pub labels: Option<Vec<String>>,
+    pub background: Option<String>,
+}
+
+pub async fn create_contact_sheet(
+    req: web::Json<ContactSheetRequest>,
+    cache: web::Data<Arc<RedisCache>>,
+    rate_limiter: web::Data<RateLimiter>,
+    client: web::Data<reqwest::Client>,
+) -> ServiceResult<HttpResponse> {
+    let config = config::current();
+    log::info!("Processing contact sheet request for {} URLs", req.urls.len());
+
+    if !rate_limiter.check_rate().await {
+        log::warn!("Rate limit exceeded for contact sheet request");
+        return Err(ServiceError::RateLimitExceeded);
+    }
+
+    if req.urls.is_empty() {
+        return Err(ServiceError::ValidationError("urls must not be empty".to_string()));
+    }
+
+    if req.urls.len() > config.max_sprites {
+        return Err(ServiceError::ValidationError(
+            format!("Too many URLs: {} (max {})", req.urls.len(), config.max_sprites)
+        ));
+    }
+
+    if let Some(labels) = &req.labels {
+        if labels.len() != req.urls.len() {
+            return Err(ServiceError::ValidationError("labels must have one entry per URL".to_string()));
+        }
+    }
+
+    let body = serde_json::to_vec(&*req)
+        .map_err(|e| ServiceError::ValidationError(e.to_string()))?;
+    let cache_key = format!("contact:{}{}", hex::encode(Sha256::digest(&body)), request_context::forwarded_headers_key());
+
+    if let Some(cached_data) = cache.get(&cache_key).await? {
+        log::debug!("Cache hit for key: {}", cache_key);
+        return Ok(HttpResponse::Ok()
+            .content_type("image/png")
+            .body(cached_data));
+    }
+
+    let start = std::time::Instant::now();
+    let processor = SvgProcessor::new(&client);
+    let sheet = render_contact_sheet(&req, &config, &processor).await?;
+    let png_data = processor.encode_png(&sheet)?;
+    log::info!("Contact sheet rendered in {:?}, size: {} bytes", start.elapsed(), png_data.len());
+
+    cache.set(&cache_key, &png_data, config.cache_ttl()).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("image/png")
+        .body(png_data))
+}
+
+async fn render_contact_sheet(
+    req: &ContactSheetRequest,
+    config: &Config,
+    processor: &SvgProcessor,
+) -> ServiceResult<Pixmap> {
+    let (cell_size, _) = config.validate_dimensions(Some(req.cell_size.unwrap_or(128)), None);
+    let padding = req.padding.unwrap_or(16);
+    if padding > MAX_PADDING {
+        return Err(ServiceError::ValidationError(
+            format!("padding too large: {} (max {})", padding, MAX_PADDING)
+        ));
+    }
+
+    let background = parse_color(req.background.as_deref().unwrap_or("#ffffff"))?;
+    let captions = req.captions.unwrap_or(true);
+    let font_size = (cell_size / 10).clamp(10, 24);
+    let caption_height = if captions { font_size * 2 } else { 0 };
+
+    let count = req.urls.len() as u32;
+    let columns = req.columns
+        .unwrap_or_else(|| (count as f64).sqrt().ceil() as u32)
+        .clamp(1, count);
+    let rows = count.div_ceil(columns);
+
+    let pitch_x = cell_size + padding;
+    let pitch_y = cell_size + caption_height + padding;
+    let sheet_width = columns * pitch_x + padding;
+    let sheet_height = rows * pitch_y + padding;
+
+    if sheet_width > config.max_width || sheet_height > config.max_height {
+        return Err(ServiceError::ValidationError(format!(
+            "Contact sheet too large: {}x{} (max {}x{})",
+            sheet_width, sheet_height, config.max_width, config.max_height
+        )));
+    }
+
+    let trees = processor.fetch_all(&req.urls).await?;
+
+    let mut sheet = Pixmap::new(sheet_width, sheet_height)
+        .ok_or_else(|| ServiceError::SvgProcessingError("Failed to create pixel buffer".into()))?;
+    sheet.fill(background);
+
+    for (i, rtree) in trees.iter().enumerate() {
+        let x = padding + (i as u32 % columns) * pitch_x;
+        let y = padding + (i as u32 / columns) * pitch_y;
+
+        let image = processor.render_pixmap(rtree, cell_size, cell_size)?;
+        sheet.draw_pixmap(x as i32, y as i32, image.as_ref(), &PixmapPaint::default(), Transform::identity(), None);
+
+        if captions {
+            let label = match &req.labels {
+                Some(labels) => labels[i].clone(),
+                None => file_name(&req.urls[i]),
+            };
+            let caption = render_caption(processor, &label, cell_size, caption_height, font_size)?;
+            sheet.draw_pixmap(x as i32, (y + cell_size) as i32, caption.as_ref(), &PixmapPaint::default(), Transform::identity(), None);
+        }
+    }
+
+    Ok(sheet)
+}
+
+fn file_name(url: &str) -> String {
+    url.split(['?', '#']).next().unwrap_or(url)
+        .rsplit('/').next().unwrap_or(url)
+        .to_string()
+}
+
+fn render_caption(processor: &SvgProcessor, label: &str, width: u32, height: u32, font_size: u32) -> ServiceResult<Pixmap> {
+    // Rough average glyph width, so long names are cut off instead of overflowing the cell
+    let max_chars = ((width as f32 / (font_size as f32 * 0.6)) as usize).max(1);
+    let label = if label.chars().count() > max_chars {
+        let truncated: String = label.chars().take(max_chars.saturating_sub(1)).collect();
+        format!("{}…", truncated)
+    } else {
+        label.to_string()
+    };
+
+    let svg = format!(
+        r##"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}"><text x="{x}" y="{y}" font-family="sans-serif" font-size="{size}" text-anchor="middle" fill="#333333">{label}</text></svg>"##,
+        w = width,
+        h = height,
+        x = width / 2,
+        y = height * 2 / 3,
+        size = font_size,
+        label = escape_xml(&label),
+    );
+
+    let rtree = processor.parse_with_text(&svg)?;
+    processor.render_pixmap(&rtree, width, height)
+}
+
+pub fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,40 @@
+use actix_web::HttpResponse;
+use utoipa::OpenApi;
+
+use crate::blurhash;
+use crate::diff;
+use crate::error::ErrorBody;
+use crate::favicon;
+use crate::handlers;
+use crate::health;
+use crate::jobs;
+use crate::optimize;
+use crate::palette;
+use crate::path_api;
+
+// The public API, generated from the annotated handlers and request types. Admin
+// routes are left out, they aren't meant for API clients.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "SVG Rasterizer"),
+    paths(
+        handlers::rasterize_svg,
+        path_api::rasterize_path,
+        optimize::optimize_svg,
+        favicon::favicon_package,
+        blurhash::blurhash_handler,
+        palette::dominant_colors_handler,
+        diff::visual_diff,
+        jobs::create_job,
+        jobs::job_status,
+        jobs::job_result,
+        health::livez,
+        health::readyz,
+    ),
+    components(schemas(ErrorBody, handlers::Output, handlers::Fit, handlers::OnError, diff::DiffFormat, jobs::JobRequest)),
+)]
+struct ApiDoc;
+
+pub async fn openapi_json() -> HttpResponse {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
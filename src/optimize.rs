@@ -0,0 +1,219 @@
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use std::sync::Arc;
+use usvg::TreeWriting;
+use utoipa::IntoParams;
+
+use crate::cache::RedisCache;
+use crate::config;
+use crate::error::{ServiceResult, ServiceError};
+use crate::montage::escape_xml;
+use crate::params::Query;
+use crate::rate_limit::RateLimiter;
+use crate::request_context;
+use crate::svg::SvgProcessor;
+
+const DEFAULT_PRECISION: u8 = 3;
+// usvg warns that very low precision can produce malformed paths
+const MIN_PRECISION: u8 = 1;
+const MAX_PRECISION: u8 = 8;
+// usvg always writes these even though they are the defaults. Groups in the
+// simplified tree never carry paint, so nothing can inherit a different value.
+const DEFAULT_ATTRIBUTES: [(&str, &str); 2] = [("stroke", "none"), ("fill", "#000000")];
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct OptimizeRequest {
+    /// URL of the SVG
+    pub url: String,
+    /// Decimal places kept in coordinates
+    pub precision: Option<u8>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/optimize",
+    tag = "render",
+    params(OptimizeRequest),
+    responses(
+        (status = 200, description = "The optimized SVG", content_type = "image/svg+xml"),
+        (status = 400, description = "Invalid parameters or SVG", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+    ),
+)]
+pub async fn optimize_svg(
+    req: Query<OptimizeRequest>,
+    cache: web::Data<Arc<RedisCache>>,
+    rate_limiter: web::Data<RateLimiter>,
+    client: web::Data<reqwest::Client>,
+) -> ServiceResult<HttpResponse> {
+    log::info!("Processing optimize request: {:?}", req);
+
+    if !rate_limiter.check_rate().await {
+        log::warn!("Rate limit exceeded for optimize request");
+        return Err(ServiceError::RateLimitExceeded);
+    }
+
+    let precision = req.precision.unwrap_or(DEFAULT_PRECISION);
+    if !(MIN_PRECISION..=MAX_PRECISION).contains(&precision) {
+        return Err(ServiceError::ValidationError(
+            format!("precision must be between {} and {}", MIN_PRECISION, MAX_PRECISION)
+        ));
+    }
+
+    let cache_key = format!("optimize:{}:{}{}", req.url, precision, request_context::forwarded_headers_key());
+    if let Some(cached_data) = cache.get(&cache_key).await? {
+        log::debug!("Cache hit for key: {}", cache_key);
+        let (original_size, optimized) = split_cached(&cached_data);
+        let mut response = HttpResponse::Ok();
+        response.content_type("image/svg+xml");
+        if let Some(original_size) = original_size {
+            response.insert_header(("X-Original-Size", original_size.to_string()));
+        }
+        return Ok(response.body(optimized.to_vec()));
+    }
+
+    let processor = SvgProcessor::new(&client);
+    let svg_data = processor.fetch(&req.url).await?;
+    let optimized = optimize(&processor, &svg_data, precision)?;
+
+    log::info!("Optimized {}: {} -> {} bytes", req.url, svg_data.len(), optimized.len());
+
+    let cached_data = format!("{}\n{}", svg_data.len(), optimized);
+    cache.set(&cache_key, cached_data.as_bytes(), config::current().cache_ttl()).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("image/svg+xml")
+        .insert_header(("X-Original-Size", svg_data.len().to_string()))
+        .body(optimized))
+}
+
+// Cached entries are the original size on a line of its own, then the optimized SVG.
+// Entries without the size line are served without it.
+fn split_cached(cached_data: &[u8]) -> (Option<usize>, &[u8]) {
+    let Some(newline) = cached_data.iter().position(|&byte| byte == b'\n') else {
+        return (None, cached_data);
+    };
+    match std::str::from_utf8(&cached_data[..newline]).ok().and_then(|size| size.parse().ok()) {
+        Some(original_size) => (Some(original_size), &cached_data[newline + 1..]),
+        None => (None, cached_data),
+    }
+}
+
+// Round-trips the SVG through usvg: the simplified tree has no comments, metadata,
+// editor namespaces, unused defs or default-valued attributes, and text is
+// converted to paths so the output doesn't depend on installed fonts.
+pub fn optimize(processor: &SvgProcessor, svg_data: &str, precision: u8) -> ServiceResult<String> {
+    let rtree = processor.parse_with_text(svg_data)?;
+
+    let options = usvg::XmlOptions {
+        coordinates_precision: precision,
+        transforms_precision: precision,
+        writer_opts: xmlwriter::Options {
+            use_single_quote: false,
+            indent: xmlwriter::Indent::None,
+            attributes_indent: xmlwriter::Indent::None,
+        },
+        ..usvg::XmlOptions::default()
+    };
+
+    strip_defaults(&rtree.to_string(&options))
+}
+
+// Written again without empty <defs> and the attributes in DEFAULT_ATTRIBUTES
+fn strip_defaults(svg_data: &str) -> ServiceResult<String> {
+    let document = roxmltree::Document::parse(svg_data)
+        .map_err(|e| ServiceError::SvgProcessingError(format!("Failed to read the optimized SVG: {}", e)))?;
+
+    let mut output = String::with_capacity(svg_data.len());
+    write_element(document.root_element(), &mut output);
+    Ok(output)
+}
+
+fn write_element(node: roxmltree::Node, output: &mut String) {
+    let name = qualified_name(node, node.tag_name().namespace(), node.tag_name().name());
+    output.push('<');
+    output.push_str(&name);
+
+    if node.parent_element().is_none() {
+        for namespace in node.namespaces().filter(|namespace| namespace.name() != Some("xml")) {
+            match namespace.name() {
+                Some(prefix) => output.push_str(&format!(" xmlns:{}=\"{}\"", prefix, escape_xml(namespace.uri()))),
+                None => output.push_str(&format!(" xmlns=\"{}\"", escape_xml(namespace.uri()))),
+            }
+        }
+    }
+    for attribute in node.attributes() {
+        if DEFAULT_ATTRIBUTES.contains(&(attribute.name(), attribute.value())) && attribute.namespace().is_none() {
+            continue;
+        }
+        let attribute_name = qualified_name(node, attribute.namespace(), attribute.name());
+        output.push_str(&format!(" {}=\"{}\"", attribute_name, escape_xml(attribute.value())));
+    }
+
+    let children = node.children()
+        .filter(|child| child.is_text() || (child.is_element() && !is_empty_defs(*child)))
+        .collect::<Vec<_>>();
+    if children.is_empty() {
+        output.push_str("/>");
+        return;
+    }
+
+    output.push('>');
+    for child in children {
+        if child.is_text() {
+            output.push_str(&escape_xml(child.text().unwrap_or_default()));
+        } else {
+            write_element(child, output);
+        }
+    }
+    output.push_str(&format!("</{}>", name));
+}
+
+fn qualified_name(node: roxmltree::Node, namespace: Option<&str>, name: &str) -> String {
+    match namespace.and_then(|namespace| node.lookup_prefix(namespace)).filter(|prefix| !prefix.is_empty()) {
+        Some(prefix) => format!("{}:{}", prefix, name),
+        None => name.to_string(),
+    }
+}
+
+fn is_empty_defs(node: roxmltree::Node) -> bool {
+    node.tag_name().name() == "defs" && !node.children().any(|child| child.is_element())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_default_attributes_and_empty_defs() {
+        let output = strip_defaults(concat!(
+            r#"<svg width="10" height="10" xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink"><defs/>"#,
+            r##"<path fill="#000000" stroke="none" d="M 0 0 L 10 10"/><path fill="#ff0000" stroke="#000000" d="M 1 1"/>"##,
+            r##"<defs><linearGradient id="a"/></defs><use xlink:href="#a" data-label="a &amp; &quot;b&quot;"/></svg>"##,
+        )).unwrap();
+
+        assert_eq!(output, concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink" width="10" height="10">"#,
+            r##"<path d="M 0 0 L 10 10"/><path fill="#ff0000" stroke="#000000" d="M 1 1"/>"##,
+            r##"<defs><linearGradient id="a"/></defs><use xlink:href="#a" data-label="a &amp; &quot;b&quot;"/></svg>"##,
+        ));
+    }
+
+    #[test]
+    fn keeps_text_content() {
+        let output = strip_defaults(r#"<svg xmlns="http://www.w3.org/2000/svg"><style>a &lt; b</style></svg>"#).unwrap();
+        assert_eq!(output, r#"<svg xmlns="http://www.w3.org/2000/svg"><style>a &lt; b</style></svg>"#);
+    }
+
+    #[test]
+    fn optimizes_through_usvg() {
+        let processor = SvgProcessor::new(&reqwest::Client::new());
+        let svg = r#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"><!-- editor --><metadata/><rect width="10" height="10"/></svg>"#;
+
+        let output = optimize(&processor, svg, DEFAULT_PRECISION).unwrap();
+        assert!(output.starts_with("<svg") && output.contains("<path"), "{}", output);
+        assert!(!output.contains("editor") && !output.contains("metadata") && !output.contains("<defs"), "{}", output);
+        assert!(!output.contains(r#"stroke="none""#) && !output.contains(r##"fill="#000000""##), "{}", output);
+    }
+}
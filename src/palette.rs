@@ -0,0 +1,157 @@
+use actix_web::{web, HttpResponse};
+use resvg::tiny_skia::Pixmap;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use utoipa::IntoParams;
+
+use crate::cache::RedisCache;
+use crate::config;
+use crate::error::{ServiceResult, ServiceError};
+use crate::params::Query;
+use crate::rate_limit::RateLimiter;
+use crate::request_context;
+use crate::svg::SvgProcessor;
+
+const ANALYSIS_SIZE: u32 = 64;
+const DEFAULT_COLOR_COUNT: usize = 5;
+const MAX_COLOR_COUNT: usize = 16;
+// Minimum RGB distance between two palette entries
+const MIN_COLOR_DISTANCE: f64 = 48.0;
+// Buckets below this share are mostly anti-aliasing blends between shapes
+const MIN_COLOR_SHARE: f64 = 0.01;
+
+#[derive(Deserialize, Debug, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ColorsRequest {
+    /// URL of the SVG
+    pub url: String,
+    /// Number of colors to return
+    pub count: Option<usize>,
+}
+
+pub struct PaletteColor {
+    pub rgb: [u8; 3],
+    pub share: f64,
+}
+
+impl PaletteColor {
+    pub fn hex(&self) -> String {
+        format!("#{:02x}{:02x}{:02x}", self.rgb[0], self.rgb[1], self.rgb[2])
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/colors",
+    tag = "analysis",
+    params(ColorsRequest),
+    responses(
+        (status = 200, description = "Dominant colors with their share of the image", content_type = "application/json"),
+        (status = 400, description = "Invalid parameters or SVG", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+    ),
+)]
+pub async fn dominant_colors_handler(
+    req: Query<ColorsRequest>,
+    cache: web::Data<Arc<RedisCache>>,
+    rate_limiter: web::Data<RateLimiter>,
+    client: web::Data<reqwest::Client>,
+) -> ServiceResult<HttpResponse> {
+    log::info!("Processing colors request: {:?}", req);
+
+    if !rate_limiter.check_rate().await {
+        log::warn!("Rate limit exceeded for colors request");
+        return Err(ServiceError::RateLimitExceeded);
+    }
+
+    let count = req.count.unwrap_or(DEFAULT_COLOR_COUNT);
+    if count == 0 || count > MAX_COLOR_COUNT {
+        return Err(ServiceError::ValidationError(
+            format!("count must be between 1 and {}", MAX_COLOR_COUNT)
+        ));
+    }
+
+    let cache_key = format!("colors:{}:{}{}", req.url, count, request_context::forwarded_headers_key());
+    if let Some(cached_data) = cache.get(&cache_key).await? {
+        log::debug!("Cache hit for key: {}", cache_key);
+        return Ok(HttpResponse::Ok()
+            .content_type("application/json")
+            .body(cached_data));
+    }
+
+    let processor = SvgProcessor::new(&client);
+    let pixmap = processor.fetch_pixmap(&req.url, ANALYSIS_SIZE, ANALYSIS_SIZE)
+        .await
+        .map_err(|e| ServiceError::SvgProcessingError(e.to_string()))?;
+    let palette = dominant_colors(&pixmap, count);
+
+    let body = json!({
+        "colors": palette.iter().map(|c| c.hex()).collect::<Vec<_>>(),
+        "palette": palette.iter().map(|c| json!({
+            "color": c.hex(),
+            "percentage": (c.share * 1000.0).round() / 10.0,
+        })).collect::<Vec<_>>(),
+    });
+    let body = serde_json::to_vec(&body)
+        .map_err(|e| ServiceError::SvgProcessingError(e.to_string()))?;
+
+    cache.set(&cache_key, &body, config::current().cache_ttl()).await?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("application/json")
+        .body(body))
+}
+
+// Buckets mostly-opaque pixels by their top 4 bits per channel, then picks the
+// most common buckets, skipping ones too close to an already picked color.
+// Fewer than `count` colors are returned when the image has no more distinct ones.
+pub fn dominant_colors(pixmap: &Pixmap, count: usize) -> Vec<PaletteColor> {
+    let mut buckets: HashMap<u16, (u64, [u64; 3])> = HashMap::new();
+    let mut total = 0u64;
+
+    for pixel in pixmap.pixels() {
+        let color = pixel.demultiply();
+        if color.alpha() < 128 {
+            continue;
+        }
+
+        let rgb = [color.red(), color.green(), color.blue()];
+        let key = ((rgb[0] as u16 >> 4) << 8) | ((rgb[1] as u16 >> 4) << 4) | (rgb[2] as u16 >> 4);
+        let bucket = buckets.entry(key).or_insert((0, [0; 3]));
+        bucket.0 += 1;
+        for (sum, value) in bucket.1.iter_mut().zip(rgb) {
+            *sum += value as u64;
+        }
+        total += 1;
+    }
+
+    let mut buckets: Vec<(u64, [u8; 3])> = buckets.into_values()
+        .map(|(n, sums)| (n, sums.map(|sum| (sum / n) as u8)))
+        .collect();
+    // Ties are broken by color so the palette is stable
+    buckets.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+
+    let mut palette: Vec<PaletteColor> = Vec::with_capacity(count);
+    for (n, rgb) in buckets {
+        let share = n as f64 / total as f64;
+        if palette.len() == count || share < MIN_COLOR_SHARE {
+            break;
+        }
+
+        let distinct = palette.iter().all(|c| distance(c.rgb, rgb) >= MIN_COLOR_DISTANCE);
+        if distinct {
+            palette.push(PaletteColor { rgb, share });
+        }
+    }
+
+    palette
+}
+
+fn distance(a: [u8; 3], b: [u8; 3]) -> f64 {
+    a.iter().zip(b.iter())
+        .map(|(x, y)| (*x as f64 - *y as f64).powi(2))
+        .sum::<f64>()
+        .sqrt()
+}
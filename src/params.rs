@@ -0,0 +1,53 @@
+use actix_web::dev::Payload;
+use actix_web::{FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use serde::de::DeserializeOwned;
+use std::ops::Deref;
+
+use crate::error::ServiceError;
+
+// Query string extractor like web::Query, except that a parameter that doesn't
+// parse is reported by name as a 400 `validation_error` with a `field`, instead
+// of serde's bare message
+#[derive(Debug)]
+pub struct Query<T>(pub T);
+
+impl<T> Deref for Query<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: DeserializeOwned> FromRequest for Query<T> {
+    type Error = ServiceError;
+    type Future = Ready<Result<Self, ServiceError>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(parse(req.query_string()).map(Query))
+    }
+}
+
+// Every value of a parameter that may be repeated, e.g. `overlay=a&overlay=b`, which
+// the struct deserializer rejects as a duplicate field
+pub fn repeated(query: &str, name: &str) -> Vec<String> {
+    url::form_urlencoded::parse(query.as_bytes())
+        .filter(|(key, _)| key == name)
+        .map(|(_, value)| value.into_owned())
+        .collect()
+}
+
+pub fn parse<T: DeserializeOwned>(query: &str) -> Result<T, ServiceError> {
+    let deserializer = serde_urlencoded::Deserializer::new(url::form_urlencoded::parse(query.as_bytes()));
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        let field = e.path().to_string();
+        let message = e.into_inner().to_string();
+        // Missing parameters fail on the query as a whole, serde names them in the message
+        match message.strip_prefix("missing field `").and_then(|rest| rest.strip_suffix('`')) {
+            Some(missing) => ServiceError::InvalidParameter(missing.to_string(), "required parameter is missing".to_string()),
+            None if field == "." => ServiceError::ValidationError(message),
+            None => ServiceError::InvalidParameter(field, message),
+        }
+    })
+}
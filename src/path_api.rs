@@ -0,0 +1,351 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::sync::Arc;
+
+use crate::cache::RedisCache;
+use crate::cloudinary;
+use crate::config::{self, Config};
+use crate::error::{ServiceError, ServiceResult};
+use crate::handlers::{self, OnError, SvgRequest};
+use crate::rate_limit::RateLimiter;
+use crate::storage::S3Storage;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// The render API with everything in the path, `/r/w:512,h:512,f:webp/aHR0cHM6Ly9...`,
+// so CDNs key their caches on it and intermediaries that strip query strings
+// don't lose the options. `-` stands for no options.
+
+#[utoipa::path(
+    get,
+    path = "/r/{options}/{url}",
+    tag = "render",
+    params(
+        ("options" = String, Path, description = "Comma-separated `name:value` options, e.g. `w:512,h:512,f:webp`, Cloudinary-style `w_512` components, or `-` for none"),
+        ("url" = String, Path, description = "Source URL as unpadded base64url, optionally followed by an extension that sets the format"),
+    ),
+    responses(
+        (status = 200, description = "The rendered image", content_type = "image/png"),
+        (status = 400, description = "Invalid options, URL or SVG", body = ErrorBody),
+        (status = 429, description = "Rate limit exceeded", body = ErrorBody),
+        (status = 503, description = "Overloaded or source host unavailable", body = ErrorBody),
+    ),
+)]
+pub async fn rasterize_path(
+    path: web::Path<(String, String)>,
+    cache: web::Data<Arc<RedisCache>>,
+    rate_limiter: web::Data<RateLimiter>,
+    client: web::Data<reqwest::Client>,
+    storage: web::Data<Option<S3Storage>>,
+) -> ServiceResult<HttpResponse> {
+    let (options, encoded_url) = path.into_inner();
+    let mut req = parse_options(options.split(',').filter(|option| *option != "-"))?;
+    decode_source(&mut req, &encoded_url)?;
+    handlers::respond(&req, &cache, &rate_limiter, &client, &storage).await
+}
+
+// imgproxy's URL format, `/imgproxy/{signature}/{options}/{source}`, with every option
+// in its own path segment and signed the way imgproxy's SDKs sign with IMGPROXY_KEY
+// and IMGPROXY_SALT. Without keys any signature is accepted, e.g. `insecure`.
+pub async fn rasterize_imgproxy(
+    http_req: HttpRequest,
+    signature: web::Path<(String, String)>,
+    cache: web::Data<Arc<RedisCache>>,
+    rate_limiter: web::Data<RateLimiter>,
+    client: web::Data<reqwest::Client>,
+    storage: web::Data<Option<S3Storage>>,
+) -> ServiceResult<HttpResponse> {
+    // Signed as sent, before actix decodes the path
+    let (signature, _) = signature.into_inner();
+    let signed_path = http_req.path()
+        .split_once(&format!("/{}/", signature))
+        .map(|(_, path)| path)
+        .unwrap_or_default();
+    verify_signature(&config::current(), &signature, &format!("/{}", signed_path))?;
+
+    // Options are the leading segments with arguments, base64url never has a colon
+    let segments = signed_path.split('/').collect::<Vec<_>>();
+    let options_end = segments.iter().position(|segment| !segment.contains(':')).unwrap_or(segments.len());
+    let (options, source) = segments.split_at(options_end);
+
+    let mut req = parse_options(options.iter().copied())?;
+    match source.split_first() {
+        // `plain/{percent-encoded URL}@{extension}`
+        Some((&"plain", url)) => {
+            let url = url.join("/");
+            let (url, extension) = url.rsplit_once('@').map_or((url.as_str(), None), |(url, extension)| (url, Some(extension)));
+            req.url = percent_decode(url);
+            if let Some(extension) = extension {
+                req.format = Some(extension.to_string());
+            }
+        },
+        _ => decode_source(&mut req, &source.join("/"))?,
+    }
+    handlers::respond(&req, &cache, &rate_limiter, &client, &storage).await
+}
+
+// The signature is the unpadded base64url HMAC-SHA256 of salt and path, truncated to
+// IMGPROXY_SIGNATURE_SIZE bytes. Any of the configured key/salt pairs may have signed it.
+fn verify_signature(config: &Config, signature: &str, path: &str) -> ServiceResult<()> {
+    if config.imgproxy_keys.is_empty() {
+        return Ok(());
+    }
+
+    let invalid = || ServiceError::Unauthorized("Invalid URL signature".to_string());
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| invalid())?;
+    if signature.len() != config.imgproxy_signature_size {
+        return Err(invalid());
+    }
+
+    let signed = config.imgproxy_keys.iter().zip(&config.imgproxy_salts).any(|(key, salt)| {
+        let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+        mac.update(salt);
+        mac.update(path.as_bytes());
+        mac.verify_truncated_left(&signature).is_ok()
+    });
+    if signed { Ok(()) } else { Err(invalid()) }
+}
+
+fn parse_options<'a>(options: impl Iterator<Item = &'a str>) -> ServiceResult<SvgRequest> {
+    let mut req = SvgRequest::default();
+
+    for option in options.filter(|option| !option.is_empty()) {
+        // Cloudinary's `w_512` works as well as `w:512`. Options with a colon are imgproxy's,
+        // whose names and values may have underscores too, like `resizing_type:force`.
+        if !option.contains(':') && option.contains('_') {
+            cloudinary::apply_component(&mut req, option)?;
+            continue;
+        }
+        let mut parts = option.split(':');
+        let name = parts.next().unwrap_or_default();
+        let args = parts.collect::<Vec<_>>();
+        if args.is_empty() {
+            return Err(ServiceError::InvalidParameter(option.to_string(), "options are written as name:value".to_string()));
+        }
+        apply_option(&mut req, name, &args)?;
+    }
+
+    Ok(req)
+}
+
+// The query parameters under imgproxy's short names, also taking imgproxy's arguments
+fn apply_option(req: &mut SvgRequest, name: &str, args: &[&str]) -> ServiceResult<()> {
+    let invalid = |message: &str| ServiceError::InvalidParameter(name.to_string(), message.to_string());
+    let value = args[0];
+    // For imgproxy 0 means the dimension follows from the other one, as when it's left out
+    let size = |arg: Option<&&str>| match arg {
+        None | Some(&"") | Some(&"0") => Ok(None),
+        Some(arg) => arg.parse::<u32>().map(Some).map_err(|_| invalid("must be a whole number")),
+    };
+    let flag = || match value {
+        "1" | "t" | "true" => Ok(true),
+        "0" | "f" | "false" => Ok(false),
+        _ => Err(invalid("must be 1 or 0")),
+    };
+
+    match name {
+        "w" | "width" => req.width = size(args.first())?,
+        "h" | "height" => req.height = size(args.first())?,
+        "s" | "size" => {
+            req.width = size(args.first())?;
+            req.height = size(args.get(1))?;
+        },
+        // Only force, which stretches the SVG over the size, changes the render. The
+        // other resizing types fit it within the size.
+        "rs" | "resize" => {
+            req.stretch = Some(value == "force");
+            req.width = size(args.get(1))?;
+            req.height = size(args.get(2))?;
+        },
+        "rt" | "resizing_type" => req.stretch = Some(value == "force"),
+        "stretch" => req.stretch = Some(flag()?),
+        "f" | "format" | "ext" => req.format = Some(value.to_string()),
+        "q" | "quality" => req.quality = Some(value.parse().map_err(|_| invalid("must be a quality from 1 to 100"))?),
+        "pr" | "preset" => req.preset = Some(value.to_string()),
+        "bg" | "background" => req.background = Some(match args {
+            // imgproxy's decimal `bg:R:G:B`
+            [r, g, b] => [r, g, b].iter()
+                .map(|channel| channel.parse::<u8>().map(|channel| format!("{:02x}", channel)))
+                .collect::<Result<String, _>>()
+                .map_err(|_| invalid("must be a hex color or R:G:B"))?,
+            _ => value.to_string(),
+        }),
+        "flatten" => req.flatten = Some(value.to_string()),
+        "r" | "radius" => req.radius = size(args.first())?,
+        "maskable" => req.maskable = Some(flag()?),
+        "lossless" => req.lossless = Some(flag()?),
+        "lqip" => req.lqip = Some(flag()?),
+        "blurhash" => req.blurhash = Some(flag()?),
+        "onerror" => req.onerror = Some(match value {
+            "json" => OnError::Json,
+            "image" => OnError::Image,
+            _ => return Err(invalid("must be json or image")),
+        }),
+        // imgproxy options without an effect on a scaled vector, accepted so URLs built
+        // by its SDKs keep working
+        "g" | "gravity" | "el" | "enlarge" | "cb" | "cachebuster" | "fn" | "filename" => {},
+        _ => return Err(invalid("unknown option")),
+    }
+    Ok(())
+}
+
+// Base64url, unpadded or padded, and possibly split into several path segments. Like
+// imgproxy, an extension after it picks the format.
+fn decode_source(req: &mut SvgRequest, encoded_url: &str) -> ServiceResult<()> {
+    let (encoded, extension) = encoded_url.split_once('.').map_or((encoded_url, None), |(encoded, extension)| (encoded, Some(extension)));
+    req.url = URL_SAFE_NO_PAD.decode(encoded.replace('/', "").trim_end_matches('='))
+        .ok()
+        .and_then(|url| String::from_utf8(url).ok())
+        .ok_or_else(|| ServiceError::InvalidParameter("url".to_string(), "must be base64url encoded".to_string()))?;
+    if let Some(extension) = extension {
+        req.format = Some(extension.to_string());
+    }
+    Ok(())
+}
+
+fn percent_decode(value: &str) -> String {
+    percent_encoding::percent_decode_str(value).decode_utf8_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::is_invalid;
+
+    // imgproxy's example key and salt
+    const KEY: &str = "943b421c9eb07c830af81030552c86009268de4e532ba2ee2eab8247c6da0881";
+    const SALT: &str = "520f986b998545b4785e0defbc4f3c1203f22de2374a3d53cb7a7fe9fea309c5";
+    const PATH: &str = "/rs:fill:300:400:0/g:sm/aHR0cDovL2V4YW1w/bGUuY29tL2ltYWdl/cy9jdXJpb3NpdHku/anBn.png";
+    const SIGNATURE: &str = "90UxdwGRAI2bpLSHKkZculJau5ahfxfS0h3fMuQAf40";
+
+    fn config(pairs: &[(&[u8], &[u8])], signature_size: usize) -> Config {
+        Config {
+            imgproxy_keys: pairs.iter().map(|(key, _)| key.to_vec()).collect(),
+            imgproxy_salts: pairs.iter().map(|(_, salt)| salt.to_vec()).collect(),
+            imgproxy_signature_size: signature_size,
+            ..Config::default()
+        }
+    }
+
+    fn example_config(signature_size: usize) -> Config {
+        let (key, salt) = (hex::decode(KEY).unwrap(), hex::decode(SALT).unwrap());
+        config(&[(&key, &salt)], signature_size)
+    }
+
+    fn is_unauthorized(result: ServiceResult<()>) -> bool {
+        matches!(result, Err(ServiceError::Unauthorized(_)))
+    }
+
+    #[test]
+    fn accepts_example_signature() {
+        assert!(verify_signature(&example_config(32), SIGNATURE, PATH).is_ok());
+    }
+
+    #[test]
+    fn signs_salt_then_path() {
+        // RFC 4231 test case 2, with the data split between salt and path
+        let config = config(&[(b"Jefe", b"what do ya want")], 32);
+        assert!(verify_signature(&config, "W9zBRr9gdU5qBCQmCJV1x1oAPwidJzmDnexYuWTsOEM", " for nothing?").is_ok());
+    }
+
+    #[test]
+    fn rejects_changed_path_or_signature() {
+        let config = example_config(32);
+        assert!(is_unauthorized(verify_signature(&config, SIGNATURE, &PATH.replace("300", "301"))));
+        assert!(is_unauthorized(verify_signature(&config, &SIGNATURE.replace('9', "8"), PATH)));
+        assert!(is_unauthorized(verify_signature(&config, "insecure", PATH)));
+        assert!(is_unauthorized(verify_signature(&config, "not base64!", PATH)));
+    }
+
+    #[test]
+    fn accepts_any_configured_pair() {
+        let (key, salt) = (hex::decode(KEY).unwrap(), hex::decode(SALT).unwrap());
+        let config = config(&[(b"Jefe", b"what do ya want"), (&key, &salt)], 32);
+        assert!(verify_signature(&config, SIGNATURE, PATH).is_ok());
+        assert!(verify_signature(&config, "W9zBRr9gdU5qBCQmCJV1x1oAPwidJzmDnexYuWTsOEM", " for nothing?").is_ok());
+    }
+
+    #[test]
+    fn checks_truncated_signatures_at_the_configured_size() {
+        let config = config(&[(b"Jefe", b"what do ya want")], 8);
+        assert!(verify_signature(&config, "W9zBRr9gdU4", " for nothing?").is_ok());
+        // The full signature is the wrong length once signatures are truncated
+        assert!(is_unauthorized(verify_signature(&config, "W9zBRr9gdU5qBCQmCJV1x1oAPwidJzmDnexYuWTsOEM", " for nothing?")));
+        assert!(is_unauthorized(verify_signature(&example_config(32), "W9zBRr9gdU4", " for nothing?")));
+    }
+
+    #[test]
+    fn accepts_anything_without_keys() {
+        assert!(verify_signature(&Config::default(), "insecure", PATH).is_ok());
+    }
+
+    #[test]
+    fn parses_short_and_long_names() {
+        let req = parse_options("w:512,height:256,f:webp,q:80,bg:ff0000,r:8,lossless:1".split(',')).unwrap();
+        assert_eq!((req.width, req.height), (Some(512), Some(256)));
+        assert_eq!(req.format.as_deref(), Some("webp"));
+        assert_eq!(req.quality, Some(80));
+        assert_eq!(req.background.as_deref(), Some("ff0000"));
+        assert_eq!(req.radius, Some(8));
+        assert_eq!(req.lossless, Some(true));
+    }
+
+    #[test]
+    fn parses_imgproxy_resizing() {
+        let req = parse_options(["rs:force:300:0", "bg:255:128:0", "g:sm"].into_iter()).unwrap();
+        assert_eq!(req.stretch, Some(true));
+        assert_eq!((req.width, req.height), (Some(300), None));
+        assert_eq!(req.background.as_deref(), Some("ff8000"));
+
+        let req = parse_options(["s:64:32", "rt:fit"].into_iter()).unwrap();
+        assert_eq!((req.width, req.height), (Some(64), Some(32)));
+        assert_eq!(req.stretch, Some(false));
+
+        let req = parse_options(["resizing_type:force", "width:10"].into_iter()).unwrap();
+        assert_eq!(req.stretch, Some(true));
+        assert_eq!(req.width, Some(10));
+    }
+
+    #[test]
+    fn parses_cloudinary_components_alongside() {
+        let req = parse_options(["w_128", "h:64"].into_iter()).unwrap();
+        assert_eq!((req.width, req.height), (Some(128), Some(64)));
+    }
+
+    #[test]
+    fn rejects_malformed_options() {
+        assert!(is_invalid(parse_options(["w"].into_iter()), "w"));
+        assert!(is_invalid(parse_options(["w:wide"].into_iter()), "w"));
+        assert!(is_invalid(parse_options(["w:-1"].into_iter()), "w"));
+        assert!(is_invalid(parse_options(["q:high"].into_iter()), "q"));
+        assert!(is_invalid(parse_options(["bg:256:0:0"].into_iter()), "bg"));
+        assert!(is_invalid(parse_options(["maskable:yes"].into_iter()), "maskable"));
+        assert!(is_invalid(parse_options(["onerror:html"].into_iter()), "onerror"));
+        assert!(is_invalid(parse_options(["blur:5"].into_iter()), "blur"));
+    }
+
+    #[test]
+    fn decodes_base64url_sources() {
+        let mut req = SvgRequest::default();
+        decode_source(&mut req, "aHR0cHM6Ly9leGFtcGxlLmNvbS9hLnN2Zw").unwrap();
+        assert_eq!(req.url, "https://example.com/a.svg");
+        assert_eq!(req.format, None);
+
+        // Padded, split over segments, with an extension
+        let mut req = SvgRequest::default();
+        decode_source(&mut req, "aHR0cHM6Ly9leGFt/cGxlLmNvbS9hLnN2/Zw==.webp").unwrap();
+        assert_eq!(req.url, "https://example.com/a.svg");
+        assert_eq!(req.format.as_deref(), Some("webp"));
+    }
+
+    #[test]
+    fn rejects_invalid_sources() {
+        let mut req = SvgRequest::default();
+        assert!(is_invalid(decode_source(&mut req, "not base64!"), "url"));
+        // Valid base64 of bytes that aren't UTF-8
+        assert!(is_invalid(decode_source(&mut req, "_-8"), "url"));
+    }
+}
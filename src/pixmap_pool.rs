@@ -0,0 +1,93 @@
+use resvg::tiny_skia::{IntSize, Pixmap};
+use std::collections::HashMap;
+use std::sync::{Mutex, OnceLock};
+
+use crate::config::Config;
+use crate::metrics::metrics;
+
+static POOL: OnceLock<PixmapPool> = OnceLock::new();
+
+// Pixel buffers from finished renders, kept for reuse so every render doesn't
+// allocate (and the allocator doesn't have to return) megabytes of memory.
+// Buffers are bucketed by power-of-two capacity, a bucket serves any request
+// that fits in it.
+struct PixmapPool {
+    max_bytes: usize,
+    buffers: Mutex<Buffers>,
+}
+
+#[derive(Default)]
+struct Buffers {
+    by_capacity: HashMap<usize, Vec<Vec<u8>>>,
+    resident_bytes: usize,
+}
+
+pub fn configure(config: &Config) {
+    if config.pixmap_pool_max_bytes > 0 {
+        let _ = POOL.set(PixmapPool {
+            max_bytes: config.pixmap_pool_max_bytes,
+            buffers: Mutex::new(Buffers::default()),
+        });
+    }
+}
+
+// A transparent pixmap, backed by a pooled buffer when one is free
+pub fn get(width: u32, height: u32) -> Option<Pixmap> {
+    let size = IntSize::from_wh(width, height)?;
+    let len = (width as usize).checked_mul(height as usize)?.checked_mul(4)?;
+
+    let Some(pool) = POOL.get() else {
+        return Pixmap::new(width, height);
+    };
+
+    let reused = {
+        let mut buffers = pool.buffers.lock().unwrap();
+        let buffer = buffers.by_capacity
+            .get_mut(&len.next_power_of_two())
+            .and_then(Vec::pop);
+        if let Some(buffer) = &buffer {
+            buffers.resident_bytes -= buffer.capacity();
+            metrics().pixmap_pool_resident_bytes.set(buffers.resident_bytes as i64);
+        }
+        buffer
+    };
+
+    let mut buffer = match reused {
+        Some(buffer) => {
+            metrics().pixmap_pool_requests.with_label_values(&["hit"]).inc();
+            buffer
+        },
+        None => {
+            metrics().pixmap_pool_requests.with_label_values(&["miss"]).inc();
+            Vec::with_capacity(len.next_power_of_two())
+        },
+    };
+
+    // Zeroed pixels are transparent
+    buffer.clear();
+    buffer.resize(len, 0);
+    Pixmap::from_vec(buffer, size)
+}
+
+// Hands a pixmap that is no longer needed back to the pool
+pub fn release(pixmap: Pixmap) {
+    let Some(pool) = POOL.get() else {
+        return;
+    };
+
+    let buffer = pixmap.take();
+    // Only power-of-two capacities can be found again, anything else came from elsewhere
+    let capacity = buffer.capacity();
+    if !capacity.is_power_of_two() {
+        return;
+    }
+
+    let mut buffers = pool.buffers.lock().unwrap();
+    if buffers.resident_bytes + capacity > pool.max_bytes {
+        return;
+    }
+
+    buffers.resident_bytes += capacity;
+    buffers.by_capacity.entry(capacity).or_default().push(buffer);
+    metrics().pixmap_pool_resident_bytes.set(buffers.resident_bytes as i64);
+}
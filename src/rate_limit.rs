@@ -1,31 +1,38 @@
 use std::sync::Arc;
 use std::time::Duration;
 use crate::cache::RedisCache;
-
-const DEFAULT_RATE_LIMIT: i32 = 60;
-const DEFAULT_WINDOW_SECS: u64 = 60;
+use crate::config;
+use crate::metrics::metrics;
+use crate::request_context;
 
 #[derive(Clone)]
 pub struct RateLimiter {
     cache: Arc<RedisCache>,
-    max_requests: i32,
-    window: Duration,
 }
 
 impl RateLimiter {
     pub fn new(cache: Arc<RedisCache>) -> Self {
-        Self {
-            cache,
-            max_requests: DEFAULT_RATE_LIMIT,
-            window: Duration::from_secs(DEFAULT_WINDOW_SECS),
-        }
+        Self { cache }
     }
 
     pub async fn check_rate(&self) -> bool {
-        let key = "rate_limit";  // In production, use IP or API key based limiting
+        // Tenants are counted against their own limit
+        let key = match request_context::tenant() {
+            Some(tenant) => format!("rate_limit:tenant:{}", tenant),
+            None => "rate_limit".to_string(),
+        };
+        // Read on every check so a reloaded limit applies right away
+        let config = config::current();
+        if config.rate_limit == 0 {
+            return true;
+        }
         
-        match self.cache.increment_counter(key, self.window).await {
-            Ok(count) => count <= self.max_requests,
+        match self.cache.increment_counter(&key, Duration::from_secs(config.rate_limit_window_secs)).await {
+            Ok(count) if i64::from(count) <= i64::from(config.rate_limit) => true,
+            Ok(_) => {
+                metrics().rate_limit_rejections.inc();
+                false
+            },
             Err(_) => true  // On error, allow the request but log it
         }
     }
@@ -0,0 +1,104 @@
+use actix_web::HttpResponse;
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
+
+use crate::config::{self, Config};
+use crate::error::{ServiceError, ServiceResult};
+use crate::logging;
+use crate::request_context;
+
+// Set when the application embedding the service passed its own configuration
+static DISABLED: AtomicBool = AtomicBool::new(false);
+
+// Settings, as named in GET /admin/config, that are applied on startup only. Entries
+// ending in `_` cover every setting starting with them.
+const RESTART_REQUIRED: &[&str] = &[
+    "port", "bind_address", "listen_", "listeners", "tls_", "client_ca", "client_cert_paths", "admin_client_ca",
+    "http_", "grpc_", "shutdown_timeout_secs", "cors_", "redis_url", "run_mode", "job_queue", "render_stream",
+    "render_group", "stream_max_attempts", "kafka_", "nats_", "bus_", "render_workers", "render_threads",
+    "max_concurrent_renders", "render_queue_size", "load_shed_", "pixmap_pool_", "tree_cache_", "cpu_throttle_",
+    "fetch_", "forward_headers", "dns_cache", "circuit_breaker_", "redis_source_prefixes", "fallback_image_",
+    "s3_", "font_dir", "deterministic_rendering", "wasm_transform", "otel_", "sentry_dsn", "log_format",
+    "log_redact_urls", "access_log", "slow_request_ms", "audit_", "usage_", "health_canary", "warmup_",
+];
+
+pub fn disable() {
+    DISABLED.store(true, Ordering::SeqCst);
+}
+
+// Re-reads the environment, ENV_FILE and CONFIG_PATH. An invalid configuration is rejected
+// as a whole and the current one stays in effect. Returns the changed settings that
+// only take effect after a restart.
+pub fn reload() -> ServiceResult<Vec<String>> {
+    if DISABLED.load(Ordering::SeqCst) {
+        return Err(ServiceError::Conflict(
+            "The configuration was passed to RasterizerServiceBuilder::config and isn't reloaded from the environment".to_string()
+        ));
+    }
+    let config = Config::from_env()?;
+    let pending = restart_required(&config::current(), &config);
+
+    logging::set_filter(&config.log_filter);
+    request_context::configure_priority(&config);
+    config::set_current(config);
+
+    log::info!("Configuration reloaded");
+    if !pending.is_empty() {
+        log::warn!("Changed settings that need a restart to take effect: {}", pending.join(", "));
+    }
+    Ok(pending)
+}
+
+fn restart_required(current: &Config, reloaded: &Config) -> Vec<String> {
+    let (current, reloaded) = (current.redacted(), reloaded.redacted());
+    let Some(settings) = reloaded.as_object() else {
+        return Vec::new();
+    };
+    settings.iter()
+        .filter(|(name, _)| RESTART_REQUIRED.iter().any(|entry| if entry.ends_with('_') { name.starts_with(entry) } else { name == entry }))
+        .filter(|(name, value)| current.get(name.as_str()) != Some(*value))
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+// Reloads on every SIGHUP. Elsewhere only POST /admin/reload reloads.
+#[cfg(unix)]
+pub async fn watch_signals() {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => return log::error!("Failed to install SIGHUP handler: {}", e),
+    };
+
+    while hangup.recv().await.is_some() {
+        log::info!("SIGHUP received, reloading configuration");
+        if let Err(e) = reload() {
+            log::error!("Configuration reload failed, keeping the current configuration: {}", e);
+        }
+    }
+}
+
+pub async fn reload_handler() -> ServiceResult<HttpResponse> {
+    let pending = reload()?;
+    Ok(HttpResponse::Ok().json(json!({ "status": "reloaded", "restartRequired": pending })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_changed_startup_settings() {
+        let current = Config::default();
+        let reloaded = Config {
+            port: current.port + 1,
+            fetch_retries: current.fetch_retries + 1,
+            rate_limit: current.rate_limit + 1,
+            cache_ttl_secs: current.cache_ttl_secs + 1,
+            ..Config::default()
+        };
+        assert_eq!(restart_required(&current, &reloaded), ["fetch_retries", "port"]);
+        assert!(restart_required(&current, &Config::default()).is_empty());
+    }
+}
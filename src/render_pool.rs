@@ -0,0 +1,499 @@
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::collections::VecDeque;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use crate::config::Config;
+use crate::metrics::metrics;
+use crate::error::{ServiceResult, ServiceError};
+use crate::request_context;
+
+static POOL: OnceLock<rayon::ThreadPool> = OnceLock::new();
+static LIMITER: OnceLock<RenderLimiter> = OnceLock::new();
+
+// Caps how many renders run at once; callers beyond the cap wait in a bounded queue
+struct RenderLimiter {
+    slots: Mutex<Slots>,
+    capacity: usize,
+    queue_size: usize,
+    waiting: AtomicUsize,
+    waiting_priority: AtomicUsize,
+    // Moving average of how long renders waited for a slot, in microseconds
+    average_wait_us: AtomicU64,
+    shed_queue_depth: Option<usize>,
+    shed_wait: Option<Duration>,
+    retry_after_secs: u64,
+}
+
+// Render slots in use and the callers waiting for one, in two lanes. A freed slot
+// goes to the oldest priority waiter, then to the oldest normal waiter. The limit
+// starts at `capacity` and is lowered while the host's CPU is saturated.
+struct Slots {
+    in_use: usize,
+    limit: usize,
+    priority: VecDeque<oneshot::Sender<()>>,
+    normal: VecDeque<oneshot::Sender<()>>,
+}
+
+// Weight of the newest sample in the moving average
+const WAIT_AVERAGE_WEIGHT: f64 = 0.2;
+
+// Parsing, rendering and encoding are CPU bound and would stall every other
+// request on the same executor thread, so they run on a dedicated pool
+pub fn configure(config: &Config) {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(config.render_workers)
+        .thread_name(|i| format!("render-{}", i))
+        // rayon aborts the process on panics by default, this fails only the one render
+        .panic_handler(|_| log::error!("Render task panicked"))
+        .build()
+        .expect("Failed to create render thread pool");
+    log::info!("Render pool started with {} threads, at most {} concurrent renders and {} queued",
+        pool.current_num_threads(), config.max_concurrent_renders, config.render_queue_size);
+    let _ = POOL.set(pool);
+
+    metrics().render_concurrency_limit.set(config.max_concurrent_renders as i64);
+    let _ = LIMITER.set(RenderLimiter::new(config));
+}
+
+// Rejects new renders up front while the queue is backed up, so cache hits stay
+// fast and callers retry later instead of timing out in the queue. Shedding on
+// wait time only applies while something is queued, so it stops once the queue drains.
+// Priority requests are never shed, they skip the queue anyway.
+pub fn check_load() -> ServiceResult<()> {
+    let Some(limiter) = LIMITER.get() else {
+        return Ok(());
+    };
+    if request_context::is_priority_request() {
+        return Ok(());
+    }
+
+    let waiting = limiter.waiting.load(Ordering::SeqCst);
+    let average_wait = Duration::from_micros(limiter.average_wait_us.load(Ordering::Relaxed));
+
+    if limiter.sheds(waiting, average_wait) {
+        log::warn!("Shedding render: {} queued, average wait {:?}", waiting, average_wait);
+        metrics().render_rejections.with_label_values(&["shed"]).inc();
+        return Err(limiter.overloaded("Server is overloaded, try again later"));
+    }
+
+    Ok(())
+}
+
+// Snapshot of render pool saturation, for the health endpoint
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RenderLoad {
+    pub in_flight: usize,
+    pub max_concurrent: usize,
+    pub concurrency_limit: usize,
+    pub queued: usize,
+    pub queued_priority: usize,
+    pub queue_size: usize,
+    pub average_wait_ms: f64,
+}
+
+pub fn load() -> Option<RenderLoad> {
+    let limiter = LIMITER.get()?;
+    let average_wait_us = limiter.average_wait_us.load(Ordering::Relaxed);
+    let (in_flight, concurrency_limit) = {
+        let slots = limiter.slots.lock().unwrap();
+        (slots.in_use, slots.limit)
+    };
+
+    Some(RenderLoad {
+        in_flight,
+        max_concurrent: limiter.capacity,
+        concurrency_limit,
+        queued: limiter.waiting.load(Ordering::SeqCst),
+        queued_priority: limiter.waiting_priority.load(Ordering::SeqCst),
+        queue_size: limiter.queue_size,
+        average_wait_ms: (average_wait_us as f64 / 100.0).round() / 10.0,
+    })
+}
+
+// Whether a new render would be turned away right now, for the readiness probe
+pub fn saturated() -> bool {
+    let Some(limiter) = LIMITER.get() else {
+        return false;
+    };
+    let waiting = limiter.waiting.load(Ordering::SeqCst);
+    let average_wait = Duration::from_micros(limiter.average_wait_us.load(Ordering::Relaxed));
+    let queue_full = {
+        let slots = limiter.slots.lock().unwrap();
+        slots.in_use >= slots.limit && waiting >= limiter.queue_size
+    };
+
+    queue_full || limiter.sheds(waiting, average_wait)
+}
+
+impl RenderLimiter {
+    fn new(config: &Config) -> Self {
+        Self {
+            slots: Mutex::new(Slots {
+                in_use: 0,
+                limit: config.max_concurrent_renders,
+                priority: VecDeque::new(),
+                normal: VecDeque::new(),
+            }),
+            capacity: config.max_concurrent_renders,
+            queue_size: config.render_queue_size,
+            waiting: AtomicUsize::new(0),
+            waiting_priority: AtomicUsize::new(0),
+            average_wait_us: AtomicU64::new(0),
+            shed_queue_depth: (config.load_shed_queue_depth > 0).then_some(config.load_shed_queue_depth),
+            shed_wait: (config.load_shed_wait_ms > 0).then(|| Duration::from_millis(config.load_shed_wait_ms)),
+            retry_after_secs: config.load_shed_retry_after,
+        }
+    }
+
+    fn sheds(&self, waiting: usize, average_wait: Duration) -> bool {
+        let over_depth = self.shed_queue_depth.is_some_and(|depth| waiting >= depth);
+        let over_wait = waiting > 0 && self.shed_wait.is_some_and(|max| average_wait > max);
+        over_depth || over_wait
+    }
+
+    fn overloaded(&self, message: &str) -> ServiceError {
+        ServiceError::Overloaded(message.to_string(), self.retry_after_secs)
+    }
+
+    // Takes a free slot, or joins the back of the caller's lane and waits for one
+    async fn acquire(&self, priority: bool) -> ServiceResult<Slot<'_>> {
+        let (receiver, _queued) = {
+            let mut slots = self.slots.lock().unwrap();
+            // Waiters are dispatched whenever a slot frees up, so nobody is queued while one is free
+            if slots.in_use < slots.limit {
+                slots.in_use += 1;
+                return Ok(Slot(self));
+            }
+
+            // Reserve a queue slot, or fail fast when the queue is full
+            let queued = self.waiting.load(Ordering::SeqCst);
+            if queued >= self.queue_size {
+                log::warn!("Render queue full ({} waiting), rejecting render", queued);
+                metrics().render_rejections.with_label_values(&["queue_full"]).inc();
+                return Err(self.overloaded("Too many renders in progress, try again later"));
+            }
+
+            let (sender, receiver) = oneshot::channel();
+            if priority {
+                slots.priority.push_back(sender);
+            } else {
+                slots.normal.push_back(sender);
+            }
+            (receiver, Queued::new(self, priority))
+        };
+
+        let mut waiter = Waiter { limiter: self, receiver: Some(receiver) };
+        let received = waiter.receiver.as_mut().unwrap().await;
+        waiter.receiver = None;
+
+        // Senders stay queued until they're used, so this only fails if the limiter is gone
+        received.map_err(|_| ServiceError::SvgProcessingError("Render slot was lost".to_string()))?;
+        Ok(Slot(self))
+    }
+
+    // Frees a finished render's slot, handing it to the next waiter if there is room
+    fn release(&self) {
+        let mut slots = self.slots.lock().unwrap();
+        slots.in_use -= 1;
+        dispatch(&mut slots);
+    }
+
+    fn record_wait(&self, wait: Duration) {
+        let previous = self.average_wait_us.load(Ordering::Relaxed) as f64;
+        let average = previous + WAIT_AVERAGE_WEIGHT * (wait.as_micros() as f64 - previous);
+        self.average_wait_us.store(average as u64, Ordering::Relaxed);
+    }
+}
+
+// Hands free slots to waiters, priority lane first
+fn dispatch(slots: &mut Slots) {
+    while slots.in_use < slots.limit {
+        let Some(sender) = slots.priority.pop_front().or_else(|| slots.normal.pop_front()) else {
+            return;
+        };
+        // Fails when the waiter already gave up
+        if sender.send(()).is_ok() {
+            slots.in_use += 1;
+        }
+    }
+}
+
+pub fn max_concurrency() -> Option<usize> {
+    LIMITER.get().map(|limiter| limiter.capacity)
+}
+
+// Changes how many renders may run at once, between 1 and MAX_CONCURRENT_RENDERS.
+// Running renders are never interrupted, a lower limit applies as they finish.
+pub fn set_concurrency_limit(limit: usize) {
+    let Some(limiter) = LIMITER.get() else {
+        return;
+    };
+
+    let mut slots = limiter.slots.lock().unwrap();
+    slots.limit = limit.clamp(1, limiter.capacity.max(1));
+    metrics().render_concurrency_limit.set(slots.limit as i64);
+    dispatch(&mut slots);
+}
+
+pub fn default_workers() -> usize {
+    std::thread::available_parallelism().map(|n| n.get()).unwrap_or(4)
+}
+
+// Runs `f` on the render pool, keeping the request and trace context of the caller
+pub async fn run<F, T>(f: F) -> ServiceResult<T>
+where
+    F: FnOnce() -> ServiceResult<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let request = request_context::current();
+    let trace = opentelemetry::Context::current();
+    let task = move || {
+        let _guard = trace.attach();
+        request_context::sync_scope(request, f)
+    };
+
+    let (Some(pool), Some(limiter)) = (POOL.get(), LIMITER.get()) else {
+        // Not configured, e.g. in one-off tools: run inline
+        return task();
+    };
+
+    spawn(pool, limiter, task).await
+}
+
+// Waits for a slot and runs `task` on `pool`. The task holds the slot, so a render
+// whose caller stops waiting, e.g. because the client disconnected, keeps it until it
+// finishes instead of letting another render start next to it.
+async fn spawn<F, T>(pool: &rayon::ThreadPool, limiter: &'static RenderLimiter, task: F) -> ServiceResult<T>
+where
+    F: FnOnce() -> ServiceResult<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let queued_at = Instant::now();
+    let slot = limiter.acquire(request_context::is_priority_request()).await?;
+    let wait = queued_at.elapsed();
+    limiter.record_wait(wait);
+    metrics().render_queue_wait.observe(wait.as_secs_f64());
+
+    metrics().renders_in_flight.inc();
+    let in_flight = InFlight;
+
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+    pool.spawn(move || {
+        let _slot = slot;
+        let _in_flight = in_flight;
+        let _ = sender.send(task());
+    });
+
+    receiver.await
+        .map_err(|_| ServiceError::SvgProcessingError("Render task panicked".to_string()))?
+}
+
+// A render's slot, released when the render completes
+struct Slot<'a>(&'a RenderLimiter);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+// Counts a waiting caller in the queue depth until it gets a slot or gives up,
+// e.g. because the client disconnected
+struct Queued<'a> {
+    limiter: &'a RenderLimiter,
+    lane: &'static str,
+}
+
+impl<'a> Queued<'a> {
+    fn new(limiter: &'a RenderLimiter, priority: bool) -> Self {
+        limiter.waiting.fetch_add(1, Ordering::SeqCst);
+        if priority {
+            limiter.waiting_priority.fetch_add(1, Ordering::SeqCst);
+        }
+        let lane = if priority { "priority" } else { "normal" };
+        metrics().render_queue_depth.with_label_values(&[lane]).inc();
+        Self { limiter, lane }
+    }
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        self.limiter.waiting.fetch_sub(1, Ordering::SeqCst);
+        if self.lane == "priority" {
+            self.limiter.waiting_priority.fetch_sub(1, Ordering::SeqCst);
+        }
+        metrics().render_queue_depth.with_label_values(&[self.lane]).dec();
+    }
+}
+
+// A caller dropped right after being handed a slot would leak it, so pass it on
+struct Waiter<'a> {
+    limiter: &'a RenderLimiter,
+    receiver: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for Waiter<'_> {
+    fn drop(&mut self) {
+        if let Some(mut receiver) = self.receiver.take() {
+            receiver.close();
+            if receiver.try_recv().is_ok() {
+                self.limiter.release();
+            }
+        }
+    }
+}
+
+// Counts a render in the in-flight gauge until it finishes
+struct InFlight;
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        metrics().renders_in_flight.dec();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::poll;
+    use std::pin::pin;
+    use std::task::Poll;
+
+    fn limiter(max_concurrent_renders: usize, render_queue_size: usize) -> RenderLimiter {
+        RenderLimiter::new(&Config { max_concurrent_renders, render_queue_size, ..Config::default() })
+    }
+
+    fn in_use(limiter: &RenderLimiter) -> usize {
+        limiter.slots.lock().unwrap().in_use
+    }
+
+    #[tokio::test]
+    async fn releases_slots_when_renders_finish() {
+        let limiter = limiter(2, 1);
+        let first = limiter.acquire(false).await.unwrap();
+        let second = limiter.acquire(false).await.unwrap();
+        assert_eq!(in_use(&limiter), 2);
+        drop(first);
+        drop(second);
+        assert_eq!(in_use(&limiter), 0);
+    }
+
+    #[tokio::test]
+    async fn cancelled_waiters_leave_the_queue() {
+        let limiter = limiter(1, 1);
+        let slot = limiter.acquire(false).await.unwrap();
+        {
+            let mut waiter = pin!(limiter.acquire(false));
+            assert!(poll!(&mut waiter).is_pending());
+            assert_eq!(limiter.waiting.load(Ordering::SeqCst), 1);
+        }
+        assert_eq!(limiter.waiting.load(Ordering::SeqCst), 0);
+
+        // The freed slot isn't handed to the cancelled waiter
+        drop(slot);
+        assert_eq!(in_use(&limiter), 0);
+        let _slot = limiter.acquire(false).await.unwrap();
+        assert_eq!(in_use(&limiter), 1);
+    }
+
+    #[tokio::test]
+    async fn waiter_dropped_after_handoff_passes_the_slot_on() {
+        let limiter = limiter(1, 1);
+        let slot = limiter.acquire(false).await.unwrap();
+        {
+            let mut waiter = pin!(limiter.acquire(false));
+            assert!(poll!(&mut waiter).is_pending());
+            // Handed to the waiter, which is dropped before it sees the slot
+            drop(slot);
+            assert_eq!(in_use(&limiter), 1);
+        }
+        assert_eq!(in_use(&limiter), 0);
+        assert_eq!(limiter.waiting.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn rejects_renders_once_the_queue_is_full() {
+        let limiter = limiter(1, 1);
+        let slot = limiter.acquire(false).await.unwrap();
+        let mut waiter = pin!(limiter.acquire(false));
+        assert!(poll!(&mut waiter).is_pending());
+
+        assert!(matches!(limiter.acquire(false).await, Err(ServiceError::Overloaded(..))));
+        assert!(matches!(limiter.acquire(true).await, Err(ServiceError::Overloaded(..))));
+
+        drop(slot);
+        let Poll::Ready(Ok(_slot)) = poll!(&mut waiter) else {
+            panic!("waiter should get the freed slot");
+        };
+        assert_eq!(in_use(&limiter), 1);
+    }
+
+    #[tokio::test]
+    async fn priority_waiters_go_first() {
+        let limiter = limiter(1, 3);
+        let slot = limiter.acquire(false).await.unwrap();
+        let mut normal = pin!(limiter.acquire(false));
+        assert!(poll!(&mut normal).is_pending());
+        let mut priority = pin!(limiter.acquire(true));
+        assert!(poll!(&mut priority).is_pending());
+        assert_eq!(limiter.waiting_priority.load(Ordering::SeqCst), 1);
+
+        drop(slot);
+        let Poll::Ready(Ok(priority_slot)) = poll!(&mut priority) else {
+            panic!("priority waiter should get the freed slot");
+        };
+        assert!(poll!(&mut normal).is_pending());
+
+        drop(priority_slot);
+        let Poll::Ready(Ok(_slot)) = poll!(&mut normal) else {
+            panic!("waiter should get the freed slot");
+        };
+        assert_eq!(in_use(&limiter), 1);
+        assert_eq!(limiter.waiting.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn lower_limit_applies_as_renders_finish() {
+        let limiter = limiter(2, 1);
+        let first = limiter.acquire(false).await.unwrap();
+        let second = limiter.acquire(false).await.unwrap();
+        limiter.slots.lock().unwrap().limit = 1;
+
+        let mut waiter = pin!(limiter.acquire(false));
+        assert!(poll!(&mut waiter).is_pending());
+        drop(first);
+        assert!(poll!(&mut waiter).is_pending());
+        drop(second);
+        let Poll::Ready(Ok(_slot)) = poll!(&mut waiter) else {
+            panic!("waiter should get the freed slot");
+        };
+        assert_eq!(in_use(&limiter), 1);
+    }
+
+    #[tokio::test]
+    async fn cancelled_requests_keep_the_slot_until_the_render_finishes() {
+        let limiter: &'static RenderLimiter = Box::leak(Box::new(limiter(1, 1)));
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+        let (finish, finished) = std::sync::mpsc::channel::<()>();
+        {
+            let mut render = pin!(spawn(&pool, limiter, move || {
+                finished.recv().unwrap();
+                Ok(())
+            }));
+            assert!(poll!(&mut render).is_pending());
+        }
+        // The render is still running without anyone waiting for it
+        assert_eq!(in_use(limiter), 1);
+        let mut waiter = pin!(limiter.acquire(false));
+        assert!(poll!(&mut waiter).is_pending());
+
+        finish.send(()).unwrap();
+        let slot = tokio::time::timeout(Duration::from_secs(5), waiter).await
+            .expect("the slot should be released once the render finishes");
+        assert!(slot.is_ok());
+    }
+}
@@ -0,0 +1,355 @@
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::web;
+use arc_swap::ArcSwapOption;
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use crate::access_log::{self, AccessLogEntry};
+use crate::audit;
+use crate::cache::RedisCache;
+use crate::config::{Config, IpNetwork};
+use crate::telemetry;
+use crate::usage;
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+pub const API_KEY_HEADER: &str = "x-api-key";
+pub const PRIORITY_HEADER: &str = "x-priority";
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+// Operational endpoints aren't renders and would drown out audit and usage data
+const OPERATIONAL_PREFIXES: [&str; 5] = ["/admin", "/health", "/livez", "/readyz", "/metrics"];
+
+static SLOW_REQUEST_THRESHOLD: OnceLock<Option<Duration>> = OnceLock::new();
+// Replaced on configuration reload
+static PRIORITY: ArcSwapOption<PrioritySettings> = ArcSwapOption::const_empty();
+static FORWARD_HEADERS: OnceLock<Vec<String>> = OnceLock::new();
+
+struct PrioritySettings {
+    // Digests of the keys, compared the same way as the admin token
+    api_keys: Vec<Vec<u8>>,
+    networks: Vec<IpNetwork>,
+}
+
+tokio::task_local! {
+    static CONTEXT: Arc<RequestContext>;
+}
+
+// Per-request state shared by the middleware, the handlers and the log formatter
+pub struct RequestContext {
+    pub id: String,
+    fields: Mutex<RequestFields>,
+}
+
+#[derive(Default, Clone)]
+pub struct RequestFields {
+    pub method: String,
+    pub path: String,
+    pub route: Option<String>,
+    pub client_ip: Option<String>,
+    // Fingerprint of the caller's API key, never the key itself
+    pub client_id: Option<String>,
+    // Renders for this request skip ahead of anonymous traffic in the render queue
+    pub priority: bool,
+    pub status: Option<u16>,
+    pub latency: Option<Duration>,
+    pub cache: Option<&'static str>,
+    pub upstream_host: Option<String>,
+    // Incoming headers from FORWARD_HEADERS, sent along on source fetches. Never logged.
+    pub forwarded_headers: Vec<(String, String)>,
+    // The Accept header, for format=auto
+    pub accept: Option<String>,
+    // Decoded query parameters, for hooks that depend on the request
+    pub query_params: Vec<(String, String)>,
+    // Time spent per pipeline stage, summed when a stage runs more than once
+    pub timings: Vec<(&'static str, Duration)>,
+    // The tenant the request was identified as, and the configuration with its policies
+    pub tenant: Option<String>,
+    pub tenant_config: Option<Arc<Config>>,
+}
+
+impl RequestContext {
+    pub fn fields(&self) -> RequestFields {
+        self.fields.lock().unwrap().clone()
+    }
+}
+
+pub fn configure(config: &Config) {
+    let threshold = (config.slow_request_ms > 0).then(|| Duration::from_millis(config.slow_request_ms));
+    let _ = SLOW_REQUEST_THRESHOLD.set(threshold);
+
+    let _ = FORWARD_HEADERS.set(config.forward_headers.clone());
+
+    configure_priority(config);
+}
+
+pub fn configure_priority(config: &Config) {
+    PRIORITY.store(Some(Arc::new(PrioritySettings {
+        api_keys: config.priority_api_keys.iter().map(|key| Sha256::digest(key.as_bytes()).to_vec()).collect(),
+        networks: config.priority_networks.clone(),
+    })));
+}
+
+// A timed pipeline stage with its own trace span, recorded when dropped
+pub struct Stage {
+    name: &'static str,
+    start: Instant,
+    _span: opentelemetry::global::BoxedSpan,
+}
+
+pub fn stage(name: &'static str) -> Stage {
+    Stage { name, start: Instant::now(), _span: telemetry::span(name) }
+}
+
+impl Drop for Stage {
+    fn drop(&mut self) {
+        let Some(cx) = current() else {
+            return;
+        };
+        let elapsed = self.start.elapsed();
+
+        let mut fields = cx.fields.lock().unwrap();
+        match fields.timings.iter_mut().find(|(name, _)| *name == self.name) {
+            Some((_, total)) => *total += elapsed,
+            None => fields.timings.push((self.name, elapsed)),
+        }
+    }
+}
+
+// The context of the request being handled, if any
+pub fn current() -> Option<Arc<RequestContext>> {
+    CONTEXT.try_with(|cx| cx.clone()).ok()
+}
+
+// Context for work that doesn't arrive over HTTP, like queued jobs and gRPC calls
+pub fn detached(fields: RequestFields) -> Arc<RequestContext> {
+    Arc::new(RequestContext { id: uuid::Uuid::new_v4().to_string(), fields: Mutex::new(fields) })
+}
+
+// Runs `f` with the given context
+pub async fn scope<F: Future>(cx: Arc<RequestContext>, f: F) -> F::Output {
+    CONTEXT.scope(cx, f).await
+}
+
+// Runs `f` with the given context, for work handed off to other threads
+pub fn sync_scope<R>(cx: Option<Arc<RequestContext>>, f: impl FnOnce() -> R) -> R {
+    match cx {
+        Some(cx) => CONTEXT.sync_scope(cx, f),
+        None => f(),
+    }
+}
+
+pub fn current_id() -> Option<String> {
+    current().map(|cx| cx.id.clone())
+}
+
+pub fn forwarded_headers() -> Vec<(String, String)> {
+    current().map(|cx| cx.fields.lock().unwrap().forwarded_headers.clone()).unwrap_or_default()
+}
+
+pub fn accept() -> Option<String> {
+    current().and_then(|cx| cx.fields.lock().unwrap().accept.clone())
+}
+
+pub fn query_params() -> Vec<(String, String)> {
+    current().map(|cx| cx.fields.lock().unwrap().query_params.clone()).unwrap_or_default()
+}
+
+pub fn set_tenant(name: &str, config: Arc<Config>) {
+    if let Some(cx) = current() {
+        let mut fields = cx.fields.lock().unwrap();
+        fields.tenant = Some(name.to_string());
+        fields.tenant_config = Some(config);
+    }
+}
+
+pub fn tenant() -> Option<String> {
+    current().and_then(|cx| cx.fields.lock().unwrap().tenant.clone())
+}
+
+pub fn tenant_config() -> Option<Arc<Config>> {
+    current().and_then(|cx| cx.fields.lock().unwrap().tenant_config.clone())
+}
+
+// Suffix for cache keys of anything fetched with forwarded headers, so a result
+// fetched with one caller's credentials is never served to another caller
+pub fn forwarded_headers_key() -> String {
+    let headers = forwarded_headers();
+    if headers.is_empty() {
+        return String::new();
+    }
+
+    let mut hasher = Sha256::new();
+    for (name, value) in &headers {
+        hasher.update(name.as_bytes());
+        hasher.update(b":");
+        hasher.update(value.as_bytes());
+        hasher.update(b"\n");
+    }
+    format!(":fwd-{}", &hex::encode(hasher.finalize())[..16])
+}
+
+pub fn record_cache_lookup(hit: bool) {
+    if let Some(cx) = current() {
+        cx.fields.lock().unwrap().cache = Some(if hit { "hit" } else { "miss" });
+    }
+}
+
+pub fn record_upstream(url: &str) {
+    if let Some(cx) = current() {
+        let host = url::Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_string));
+        cx.fields.lock().unwrap().upstream_host = host;
+    }
+}
+
+// Middleware that takes the caller's `X-Request-Id` (or generates one), makes the
+// request context available while the request runs, echoes the id back and writes
+// the access log line
+pub fn handle_request<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let start = Instant::now();
+    let id = req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| is_valid(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok()).unwrap_or("-").to_string();
+    let (referer, user_agent) = (header("referer"), header("user-agent"));
+    let (uri, version) = (req.uri().to_string(), format!("{:?}", req.version()));
+    let query = req.query_string().to_string();
+    let cache = req.app_data::<web::Data<Arc<RedisCache>>>().map(|cache| cache.get_ref().clone());
+
+    let cx = Arc::new(RequestContext {
+        id: id.clone(),
+        fields: Mutex::new(RequestFields {
+            method: req.method().to_string(),
+            path: req.path().to_string(),
+            route: req.match_pattern(),
+            client_ip: req.connection_info().realip_remote_addr().map(str::to_string),
+            client_id: req.headers().get(API_KEY_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .map(api_key_fingerprint),
+            priority: is_priority(&req),
+            forwarded_headers: FORWARD_HEADERS.get()
+                .map(|names| names.iter()
+                    .filter_map(|name| Some((name.clone(), req.headers().get(name)?.to_str().ok()?.to_string())))
+                    .collect())
+                .unwrap_or_default(),
+            accept: req.headers().get(header::ACCEPT).and_then(|v| v.to_str().ok()).map(str::to_string),
+            query_params: url::form_urlencoded::parse(req.query_string().as_bytes()).into_owned().collect(),
+            ..RequestFields::default()
+        }),
+    });
+
+    let response = CONTEXT.sync_scope(cx.clone(), || srv.call(req));
+
+    CONTEXT.scope(cx.clone(), async move {
+        let mut response = response.await?;
+        if let Ok(value) = HeaderValue::from_str(&id) {
+            response.headers_mut().insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+        }
+
+        let latency = start.elapsed();
+        let fields = {
+            let mut fields = cx.fields.lock().unwrap();
+            fields.status = Some(response.status().as_u16());
+            fields.latency = Some(latency);
+            fields.clone()
+        };
+
+        let bytes = match response.response().body().size() {
+            BodySize::Sized(size) => Some(size),
+            _ => None,
+        };
+
+        if let Some(cache) = cache {
+            if audit::enabled() {
+                if let Some(entry) = audit::entry(&id, &fields, &query, bytes) {
+                    audit::record(cache.clone(), entry);
+                }
+            }
+            if usage::enabled() && fields.route.as_deref().is_some_and(|route| !is_operational_route(route)) {
+                usage::record(cache, fields.client_id.clone(), bytes.unwrap_or_default());
+            }
+        }
+
+        access_log::write(&AccessLogEntry {
+            request_id: &id,
+            client_ip: fields.client_ip.as_deref().unwrap_or("-"),
+            method: &fields.method,
+            uri: &uri,
+            version: &version,
+            status: response.status().as_u16(),
+            bytes,
+            referer: &referer,
+            user_agent: &user_agent,
+            latency,
+        });
+
+        if let Some(threshold) = SLOW_REQUEST_THRESHOLD.get().copied().flatten() {
+            if latency >= threshold {
+                let breakdown: Vec<String> = fields.timings.iter()
+                    .map(|(name, elapsed)| format!("{}={:.1}ms", name, elapsed.as_secs_f64() * 1000.0))
+                    .collect();
+                log::warn!(target: "slow_request", "Slow request {} {} took {:.1}ms ({})",
+                    fields.method, fields.path, latency.as_secs_f64() * 1000.0,
+                    if breakdown.is_empty() { "no pipeline stages".to_string() } else { breakdown.join(" ") });
+            }
+        }
+
+        Ok(response)
+    })
+}
+
+pub fn is_priority_request() -> bool {
+    current().is_some_and(|cx| cx.fields.lock().unwrap().priority)
+}
+
+// A known API key, or `X-Priority: high` from a trusted network. The network check
+// uses the socket address so a forwarded-for header can't claim to be trusted.
+fn is_priority(req: &ServiceRequest) -> bool {
+    let settings = PRIORITY.load();
+    let Some(settings) = settings.as_ref() else {
+        return false;
+    };
+    let header = |name: &str| req.headers().get(name).and_then(|v| v.to_str().ok());
+
+    if header(API_KEY_HEADER).is_some_and(is_priority_key) {
+        return true;
+    }
+
+    header(PRIORITY_HEADER).is_some_and(|value| value.eq_ignore_ascii_case("high"))
+        && req.peer_addr().is_some_and(|addr| settings.networks.iter().any(|network| network.contains(addr.ip())))
+}
+
+// Whether `key` is one of PRIORITY_API_KEYS, comparing digests like the admin token
+pub fn is_priority_key(key: &str) -> bool {
+    let digest = Sha256::digest(key.as_bytes());
+    PRIORITY.load().as_ref().is_some_and(|settings| settings.api_keys.iter().any(|known| known.as_slice() == digest.as_slice()))
+}
+
+pub fn is_operational_route(route: &str) -> bool {
+    OPERATIONAL_PREFIXES.iter().any(|prefix| route.starts_with(prefix))
+}
+
+pub fn api_key_fingerprint(key: &str) -> String {
+    let digest = hex::encode(Sha256::digest(key.as_bytes()));
+    format!("key:{}", &digest[..12])
+}
+
+// Incoming ids end up in logs and upstream requests, so only accept simple tokens
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | ':'))
+}
@@ -0,0 +1,248 @@
+use actix_web::body::BoxBody;
+use actix_web::dev::{Service, ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::{middleware, web, Scope};
+use std::sync::{Arc, Once};
+use std::time::Duration;
+use svg_rasterizer_core::MAX_SVG_SIZE;
+
+use crate::cache::RedisCache;
+use crate::config::{self, Config};
+use crate::error::ServiceResult;
+use crate::rate_limit::RateLimiter;
+use crate::storage::S3Storage;
+use crate::{admin, api_keys, audit, blurhash, circuit_breaker, content_signature, diff, favicon, handlers, health, host_limit};
+use crate::{idempotency, jobs, metrics, montage, openapi, optimize, palette, path_api, pixmap_pool, reload, render_pool};
+use crate::{request_context, signed_url, spritesheet, svg, templates, tenants, tree_cache, usage};
+
+static CONFIGURED: Once = Once::new();
+
+// Applies the settings the handlers read from process-wide state. Only the first
+// configuration counts, later ones come in through reloads.
+pub fn configure(config: &Config) {
+    CONFIGURED.call_once(|| {
+        config::set_current(config.clone());
+        request_context::configure(config);
+        audit::configure(config);
+        usage::configure(config);
+        render_pool::configure(config);
+        pixmap_pool::configure(config);
+        tree_cache::configure(config);
+        host_limit::configure(config);
+        circuit_breaker::configure(config);
+        svg::configure(config);
+    });
+}
+
+// The rasterizer's routes, for mounting in an existing actix application:
+//
+//     let service = RasterizerService::builder().build().await?;
+//     HttpServer::new(move || App::new()
+//         .service(service.scope("/images"))
+//         .service(service.admin_scope("/images/admin")))
+//
+// Listeners, TLS, CORS, access logs and the cache warm-up stay with the standalone service.
+#[derive(Clone)]
+pub struct RasterizerService {
+    cache: web::Data<Arc<RedisCache>>,
+    rate_limiter: web::Data<RateLimiter>,
+    client: web::Data<reqwest::Client>,
+    storage: web::Data<Option<S3Storage>>,
+}
+
+#[derive(Default)]
+pub struct RasterizerServiceBuilder {
+    config: Option<Config>,
+    client: Option<reqwest::Client>,
+    cache: Option<Arc<RedisCache>>,
+    standalone: bool,
+}
+
+impl RasterizerServiceBuilder {
+    // Settings to use instead of the environment and CONFIG_PATH
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    // Client for source fetches, e.g. with the host application's proxy settings
+    pub fn client(mut self, client: reqwest::Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    pub(crate) fn cache(mut self, cache: Arc<RedisCache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    // The standalone service reports ready after its warm-up, and reloads its
+    // configuration from the environment even though it passes one in
+    pub(crate) fn standalone(mut self) -> Self {
+        self.standalone = true;
+        self
+    }
+
+    // Loads the configuration when none was given and connects to Redis
+    pub async fn build(self) -> ServiceResult<RasterizerService> {
+        // A configuration from the host application isn't replaced by the environment's
+        if self.config.is_some() && !self.standalone {
+            reload::disable();
+        }
+        let config = match self.config {
+            Some(config) => config,
+            None => Config::from_env()?,
+        };
+        configure(&config);
+        #[cfg(feature = "wasm")]
+        crate::wasm_transform::register(&config)?;
+
+        let cache = match self.cache {
+            Some(cache) => cache,
+            None => {
+                let cache = Arc::new(RedisCache::new(&config.redis_url)?);
+                cache.initialize().await?;
+                cache
+            },
+        };
+        let client = match self.client {
+            Some(client) => client,
+            None => reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()?,
+        };
+
+        tenants::start(cache.clone()).await;
+        // Embedded services have no warm-up to wait for
+        if !self.standalone {
+            health::set_warmed_up();
+        }
+
+        let rate_limiter = RateLimiter::new(cache.clone());
+        log::info!("Rate limiter initialized");
+
+        Ok(RasterizerService {
+            cache: web::Data::new(cache),
+            rate_limiter: web::Data::new(rate_limiter),
+            client: web::Data::new(client),
+            storage: web::Data::new(S3Storage::from_config(&config)),
+        })
+    }
+}
+
+impl RasterizerService {
+    pub fn builder() -> RasterizerServiceBuilder {
+        RasterizerServiceBuilder::default()
+    }
+
+    pub(crate) fn cache(&self) -> &Arc<RedisCache> {
+        self.cache.get_ref()
+    }
+
+    // For middleware outside the scopes, which records audit and usage entries
+    pub(crate) fn cache_data(&self) -> web::Data<Arc<RedisCache>> {
+        self.cache.clone()
+    }
+
+    pub(crate) fn client(&self) -> &reqwest::Client {
+        self.client.get_ref()
+    }
+
+    #[cfg(feature = "grpc")]
+    pub(crate) fn rate_limiter(&self) -> &RateLimiter {
+        self.rate_limiter.get_ref()
+    }
+
+    // Rendering, analysis, job and health routes, at the same paths below `path` as
+    // in the standalone service. Set PUBLIC_BASE_URL to include `path` so links in
+    // JSON responses point at the mounted routes.
+    pub fn scope(&self, path: &str) -> Scope<impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<BoxBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >> {
+        web::scope(path)
+            // Make sure to clone the Data wrappers, not the inner values
+            .app_data(self.cache.clone())
+            .app_data(self.rate_limiter.clone())
+            .app_data(self.client.clone())
+            .app_data(self.storage.clone())
+            .wrap(middleware::from_fn(api_keys::handle))
+            .wrap(middleware::from_fn(content_signature::handle))
+            // Outermost, so everything else sees the tenant's configuration
+            .wrap(middleware::from_fn(tenants::handle))
+            .route("/health", web::get().to(health::readyz))
+            .route("/livez", web::get().to(health::livez))
+            .route("/readyz", web::get().to(health::readyz))
+            .route("/metrics", web::get().to(metrics::metrics_handler))
+            .route("/openapi.json", web::get().to(openapi::openapi_json))
+            .route("/content-signature-key", web::get().to(content_signature::public_key_handler))
+            .route("/v1/rasterize", web::get().to(handlers::rasterize_svg))
+            .route("/v1/rasterize/signed", web::get().to(signed_url::rasterize_signed))
+            .route("/sign", web::post().to(signed_url::sign_handler))
+            // The unversioned route, kept working for existing integrations
+            .service(web::resource("/rasterize-svg")
+                .wrap(middleware::DefaultHeaders::new()
+                    .add(("Deprecation", "true"))
+                    .add(("Link", "</v1/rasterize>; rel=\"successor-version\""))
+                    .add(("Warning", "299 - \"/rasterize-svg is deprecated, use /v1/rasterize\"")))
+                .route(web::get().to(handlers::rasterize_svg)))
+            // The encoded URL may be split across segments
+            .route("/r/{options}/{url:.+}", web::get().to(path_api::rasterize_path))
+            .route("/imgproxy/{signature}/{path:.+}", web::get().to(path_api::rasterize_imgproxy))
+            .route("/optimize", web::get().to(optimize::optimize_svg))
+            .route("/blurhash", web::get().to(blurhash::blurhash_handler))
+            .route("/colors", web::get().to(palette::dominant_colors_handler))
+            .route("/diff", web::get().to(diff::visual_diff))
+            .route("/favicon-package", web::get().to(favicon::favicon_package))
+            .service(web::resource("/spritesheet")
+                .wrap(middleware::from_fn(idempotency::handle))
+                .route(web::post().to(spritesheet::create_spritesheet)))
+            .route("/spritesheet/{id}/sheet.png", web::get().to(spritesheet::spritesheet_image))
+            .route("/spritesheet/{id}/sprites.json", web::get().to(spritesheet::spritesheet_json))
+            .route("/spritesheet/{id}/sprites.css", web::get().to(spritesheet::spritesheet_css))
+            .service(web::resource("/contact-sheet")
+                .wrap(middleware::from_fn(idempotency::handle))
+                .route(web::post().to(montage::create_contact_sheet)))
+            .service(web::resource("/templates/{id}")
+                .app_data(web::PayloadConfig::new(MAX_SVG_SIZE))
+                .route(web::put().to(templates::put_template)))
+            .route("/templates/{id}/render", web::get().to(templates::render_template))
+            .service(web::resource("/jobs")
+                .wrap(middleware::from_fn(idempotency::handle))
+                .route(web::post().to(jobs::create_job)))
+            .route("/jobs/{id}", web::get().to(jobs::job_status))
+            .route("/jobs/{id}/result", web::get().to(jobs::job_result))
+    }
+
+    // The admin API, authorized as a whole with ADMIN_TOKEN (or an admin client certificate)
+    pub fn admin_scope(&self, path: &str) -> Scope<impl ServiceFactory<
+        ServiceRequest,
+        Config = (),
+        Response = ServiceResponse<BoxBody>,
+        Error = actix_web::Error,
+        InitError = (),
+    >> {
+        web::scope(path)
+            .app_data(self.cache.clone())
+            .wrap_fn(|req, srv| {
+                let authorized = admin::authorize(req.request(), &config::current());
+                let response = authorized.map(|_| srv.call(req));
+                async move { response?.await }
+            })
+            .route("/upstreams", web::get().to(metrics::upstream_stats_handler))
+            .route("/reload", web::post().to(reload::reload_handler))
+            .route("/config", web::get().to(admin::config_handler))
+            .route("/purge", web::post().to(admin::purge_handler))
+            .route("/audit", web::get().to(audit::audit_query))
+            .route("/usage/export", web::get().to(usage::usage_export))
+            .route("/api-keys", web::post().to(api_keys::create_key))
+            .route("/api-keys", web::get().to(api_keys::list_keys))
+            .route("/api-keys/{id}", web::delete().to(api_keys::revoke_key))
+            .route("/api-keys/{id}/limits", web::put().to(api_keys::set_limits))
+            .route("/tenants", web::get().to(tenants::list_tenants))
+            .route("/tenants/{name}", web::put().to(tenants::put_tenant))
+            .route("/tenants/{name}", web::delete().to(tenants::delete_tenant))
+    }
+}
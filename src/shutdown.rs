@@ -0,0 +1,84 @@
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+static RUNTIME: OnceLock<Handle> = OnceLock::new();
+static BACKGROUND_TASKS: AtomicUsize = AtomicUsize::new(0);
+static DRAINING_SINCE: OnceLock<Instant> = OnceLock::new();
+
+// Keeps a count of running background tasks
+struct TaskGuard;
+
+// Must run on the main runtime, which outlives the HTTP workers
+pub fn init() {
+    let _ = RUNTIME.set(Handle::current());
+}
+
+// Runs work that outlives the request (job renders, audit and usage writes) on
+// the main runtime, so shutdown can wait for it instead of dropping it with the
+// worker that spawned it
+pub fn spawn<F>(task: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    BACKGROUND_TASKS.fetch_add(1, Ordering::SeqCst);
+    let guard = TaskGuard;
+    let task = async move {
+        let _guard = guard;
+        task.await
+    };
+
+    match RUNTIME.get() {
+        Some(runtime) => drop(runtime.spawn(task)),
+        None => drop(actix_web::rt::spawn(task)),
+    }
+}
+
+pub fn draining() -> bool {
+    DRAINING_SINCE.get().is_some()
+}
+
+// Resolves on SIGTERM or Ctrl-C and marks the service as draining
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler");
+        tokio::select! {
+            _ = terminate.recv() => log::info!("SIGTERM received"),
+            _ = tokio::signal::ctrl_c() => log::info!("SIGINT received"),
+        }
+    }
+
+    // Only Ctrl-C (or Ctrl-Break) exists elsewhere
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        log::info!("Ctrl-C received");
+    }
+
+    let _ = DRAINING_SINCE.set(Instant::now());
+}
+
+// Waits for background tasks until `timeout` after the shutdown signal,
+// returning how many were still running
+pub async fn drain_tasks(timeout: Duration) -> usize {
+    let deadline = DRAINING_SINCE.get().copied().unwrap_or_else(Instant::now) + timeout;
+    loop {
+        let running = BACKGROUND_TASKS.load(Ordering::SeqCst);
+        if running == 0 || Instant::now() >= deadline {
+            return running;
+        }
+        tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        BACKGROUND_TASKS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
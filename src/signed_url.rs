@@ -0,0 +1,214 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+use std::sync::Arc;
+
+use crate::admin;
+use crate::cache::RedisCache;
+use crate::config::{self, Config};
+use crate::error::{ServiceError, ServiceResult};
+use crate::handlers::{self, SvgRequest};
+use crate::params::{self, Query};
+use crate::rate_limit::RateLimiter;
+use crate::storage::S3Storage;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const SIGNATURE_PARAM: &str = "signature";
+const EXPIRES_PARAM: &str = "expires";
+const DEFAULT_EXPIRES_IN_SECS: u64 = 60 * 60;
+
+// Render links that carry no credentials, so internal apps can hand them to end
+// users. A link is the render query string with an expiry, signed with
+// SIGNED_URL_SECRET, and stops working once it expires.
+
+#[derive(Deserialize, Debug)]
+pub struct SignRequest {
+    /// Seconds until the link expires, at most SIGNED_URL_MAX_TTL_SECS
+    pub expires_in: Option<u64>,
+    /// The /v1/rasterize parameters, e.g. `{"url": "...", "width": 512}`
+    #[serde(flatten)]
+    pub params: serde_json::Map<String, serde_json::Value>,
+}
+
+// POST /sign: answers with a signed GET URL for the render described by the body.
+// Requires the admin token.
+pub async fn sign_handler(
+    http_req: HttpRequest,
+    req: web::Json<SignRequest>,
+) -> ServiceResult<HttpResponse> {
+    let config = config::current();
+    admin::authorize(&http_req, &config)?;
+    let secret = secret(&config)?;
+
+    let expires_in = req.expires_in.unwrap_or(DEFAULT_EXPIRES_IN_SECS);
+    if expires_in == 0 || expires_in > config.signed_url_max_ttl_secs {
+        return Err(ServiceError::InvalidParameter(
+            "expires_in".to_string(), format!("must be between 1 and {} seconds", config.signed_url_max_ttl_secs)));
+    }
+    let expires = chrono::Utc::now().timestamp() + expires_in as i64;
+
+    let mut query = url::form_urlencoded::Serializer::new(String::new());
+    for (name, value) in &req.params {
+        if name == SIGNATURE_PARAM || name == EXPIRES_PARAM {
+            return Err(ServiceError::InvalidParameter(name.clone(), "is set by the signature".to_string()));
+        }
+        let values = match value {
+            serde_json::Value::String(value) => vec![value.clone()],
+            serde_json::Value::Number(_) | serde_json::Value::Bool(_) => vec![value.to_string()],
+            // Repeated parameters, e.g. `"overlay": ["...", "..."]`
+            serde_json::Value::Array(values) if name == handlers::OVERLAY_PARAM => values.iter()
+                .map(|value| value.as_str().map(str::to_string))
+                .collect::<Option<Vec<_>>>()
+                .ok_or_else(|| ServiceError::InvalidParameter(name.clone(), "must be a list of strings".to_string()))?,
+            _ => return Err(ServiceError::InvalidParameter(name.clone(), "must be a string, number or boolean".to_string())),
+        };
+        for value in values {
+            query.append_pair(name, &value);
+        }
+    }
+    query.append_pair(EXPIRES_PARAM, &expires.to_string());
+    let query = query.finish();
+
+    // Fail now rather than when the end user opens the link
+    params::parse::<SvgRequest>(&query)?;
+
+    let url = format!("{}/v1/rasterize/signed?{}&{}={}",
+        config.public_base_url, query, SIGNATURE_PARAM, sign(secret, &query));
+    Ok(HttpResponse::Ok().json(json!({
+        "url": url,
+        "expiresAt": chrono::DateTime::from_timestamp(expires, 0).map(|at| at.to_rfc3339()),
+    })))
+}
+
+// GET /v1/rasterize/signed?...&expires=...&signature=...
+pub async fn rasterize_signed(
+    http_req: HttpRequest,
+    req: Query<SvgRequest>,
+    cache: web::Data<Arc<RedisCache>>,
+    rate_limiter: web::Data<RateLimiter>,
+    client: web::Data<reqwest::Client>,
+    storage: web::Data<Option<S3Storage>>,
+) -> ServiceResult<HttpResponse> {
+    verify(&config::current(), http_req.query_string())?;
+
+    let Query(mut req) = req;
+    req.overlay = params::repeated(http_req.query_string(), handlers::OVERLAY_PARAM);
+    handlers::apply_transformation(&mut req)?;
+    handlers::respond(&req, &cache, &rate_limiter, &client, &storage).await
+}
+
+fn secret(config: &Config) -> ServiceResult<&str> {
+    config.signed_url_secret.as_deref()
+        .ok_or_else(|| ServiceError::Unauthorized("Signed URLs are disabled, set SIGNED_URL_SECRET to enable them".to_string()))
+}
+
+fn sign(secret: &str, query: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(query.as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// The signature covers the query string as sent up to itself, which includes the expiry
+fn verify(config: &Config, query: &str) -> ServiceResult<()> {
+    let secret = secret(config)?;
+    let invalid = || ServiceError::Unauthorized("Invalid URL signature".to_string());
+
+    let (signed, signature) = query.rsplit_once(&format!("&{}=", SIGNATURE_PARAM)).ok_or_else(invalid)?;
+    let signature = hex::decode(signature).map_err(|_| invalid())?;
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(signed.as_bytes());
+    mac.verify_slice(&signature).map_err(|_| invalid())?;
+
+    let expires = url::form_urlencoded::parse(signed.as_bytes())
+        .find(|(name, _)| name == EXPIRES_PARAM)
+        .and_then(|(_, value)| value.parse::<i64>().ok())
+        .ok_or_else(invalid)?;
+    if expires < chrono::Utc::now().timestamp() {
+        return Err(ServiceError::Unauthorized("URL has expired".to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "secret";
+
+    fn config() -> Config {
+        Config { signed_url_secret: Some(SECRET.to_string()), ..Config::default() }
+    }
+
+    fn signed(query: &str) -> String {
+        format!("{}&{}={}", query, SIGNATURE_PARAM, sign(SECRET, query))
+    }
+
+    fn expires_in(secs: i64) -> i64 {
+        chrono::Utc::now().timestamp() + secs
+    }
+
+    fn is_unauthorized(result: ServiceResult<()>, message: &str) -> bool {
+        matches!(result, Err(ServiceError::Unauthorized(m)) if m == message)
+    }
+
+    #[test]
+    fn signs_with_hmac_sha256() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn accepts_signed_unexpired_links() {
+        let query = signed(&format!("url=https%3A%2F%2Fexample.com%2Fa.svg&width=512&expires={}", expires_in(60)));
+        assert!(verify(&config(), &query).is_ok());
+    }
+
+    #[test]
+    fn rejects_tampered_links() {
+        let expires = expires_in(60);
+        let query = signed(&format!("url=https%3A%2F%2Fexample.com%2Fa.svg&width=512&expires={}", expires));
+        assert!(is_unauthorized(verify(&config(), &query.replace("width=512", "width=4096")), "Invalid URL signature"));
+        // A later expiry is a tampered link as well
+        let tampered = query.replace(&format!("expires={}", expires), &format!("expires={}", expires + 3600));
+        assert!(is_unauthorized(verify(&config(), &tampered), "Invalid URL signature"));
+        // Parameters after the signature aren't signed
+        assert!(is_unauthorized(verify(&config(), &format!("{}&width=4096", query)), "Invalid URL signature"));
+    }
+
+    #[test]
+    fn rejects_malformed_signatures() {
+        let query = format!("url=a&expires={}", expires_in(60));
+        let signature = sign(SECRET, &query);
+        let truncated = format!("{}&{}={}", query, SIGNATURE_PARAM, &signature[..32]);
+        assert!(is_unauthorized(verify(&config(), &truncated), "Invalid URL signature"));
+        let not_hex = format!("{}&{}=zz{}", query, SIGNATURE_PARAM, &signature[2..]);
+        assert!(is_unauthorized(verify(&config(), &not_hex), "Invalid URL signature"));
+        assert!(is_unauthorized(verify(&config(), &query), "Invalid URL signature"));
+    }
+
+    #[test]
+    fn rejects_expired_links() {
+        let query = signed(&format!("url=a&expires={}", expires_in(-1)));
+        assert!(is_unauthorized(verify(&config(), &query), "URL has expired"));
+    }
+
+    #[test]
+    fn rejects_links_without_an_expiry() {
+        assert!(is_unauthorized(verify(&config(), &signed("url=a")), "Invalid URL signature"));
+        assert!(is_unauthorized(verify(&config(), &signed("url=a&expires=soon")), "Invalid URL signature"));
+    }
+
+    #[test]
+    fn rejects_everything_without_a_secret() {
+        let query = signed(&format!("url=a&expires={}", expires_in(60)));
+        assert!(matches!(verify(&Config::default(), &query), Err(ServiceError::Unauthorized(_))));
+    }
+}
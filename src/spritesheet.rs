@@ -0,0 +1,332 @@
+use actix_web::{web, HttpResponse};
+use resvg::tiny_skia::{Pixmap, PixmapPaint, Transform};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use crate::cache::RedisCache;
+use crate::config::{self, Config};
+use crate::error::{ServiceResult, ServiceError};
+use crate::rate_limit::RateLimiter;
+use crate::svg::SvgProcessor;
+
+const MAX_PADDING: u32 = 64;
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct SpritesheetRequest {
+    pub urls: Vec<String>,
+    pub cell_width: u32,
+    pub cell_height: Option<u32>,
+    pub columns: Option<u32>,
+    pub padding: Option<u32>,
+    pub layout: Option<String>,
+    // Response format only, so it is kept out of the sheet id
+    #[serde(skip_serializing)]
+    pub format: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Sprite {
+    pub url: String,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpritesheetMeta {
+    pub id: String,
+    pub width: u32,
+    pub height: u32,
+    pub sprites: Vec<Sprite>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CssQuery {
+    pub prefix: Option<String>,
+}
+
+fn sheet_key(id: &str) -> String {
+    format!("sprite:{}", id)
+}
+
+fn meta_key(id: &str) -> String {
+    format!("sprite:{}:meta", id)
+}
+
+fn image_url(config: &Config, id: &str) -> String {
+    format!("{}/spritesheet/{}/sheet.png", config.public_base_url, id)
+}
+
+async fn load_meta(cache: &RedisCache, id: &str) -> ServiceResult<SpritesheetMeta> {
+    let data = cache.get(&meta_key(id)).await?
+        .ok_or_else(|| ServiceError::NotFound(format!("Spritesheet {} not found", id)))?;
+    serde_json::from_slice(&data)
+        .map_err(|e| ServiceError::CacheError(format!("Failed to deserialize spritesheet {}: {}", id, e)))
+}
+
+pub async fn create_spritesheet(
+    req: web::Json<SpritesheetRequest>,
+    cache: web::Data<Arc<RedisCache>>,
+    rate_limiter: web::Data<RateLimiter>,
+    client: web::Data<reqwest::Client>,
+) -> ServiceResult<HttpResponse> {
+    let config = config::current();
+    log::info!("Processing spritesheet request for {} URLs", req.urls.len());
+
+    if !rate_limiter.check_rate().await {
+        log::warn!("Rate limit exceeded for spritesheet request");
+        return Err(ServiceError::RateLimitExceeded);
+    }
+
+    if req.urls.is_empty() {
+        return Err(ServiceError::ValidationError("urls must not be empty".to_string()));
+    }
+
+    if req.urls.len() > config.max_sprites {
+        return Err(ServiceError::ValidationError(
+            format!("Too many URLs: {} (max {})", req.urls.len(), config.max_sprites)
+        ));
+    }
+
+    let json_response = match req.format.as_deref() {
+        None | Some("png") => false,
+        Some("json") => true,
+        Some(other) => return Err(ServiceError::ValidationError(
+            format!("Unsupported format: {}", other)
+        )),
+    };
+
+    let body = serde_json::to_vec(&*req)
+        .map_err(|e| ServiceError::ValidationError(e.to_string()))?;
+    let id = hex::encode(Sha256::digest(&body));
+
+    let cached = match cache.get(&sheet_key(&id)).await? {
+        Some(png_data) => load_meta(&cache, &id).await.ok().map(|meta| (png_data, meta)),
+        None => None,
+    };
+
+    let (png_data, meta) = match cached {
+        Some(cached) => {
+            log::debug!("Cache hit for spritesheet {}", id);
+            cached
+        },
+        None => {
+            let start = std::time::Instant::now();
+            let (sheet, sprites) = render_spritesheet(&req, &config, &client).await?;
+            let png_data = SvgProcessor::new(&client).encode_png(&sheet)?;
+            log::info!("Spritesheet rendered in {:?}, size: {} bytes", start.elapsed(), png_data.len());
+
+            let meta = SpritesheetMeta {
+                id: id.clone(),
+                width: sheet.width(),
+                height: sheet.height(),
+                sprites,
+            };
+            let meta_data = serde_json::to_vec(&meta)
+                .map_err(|e| ServiceError::CacheError(format!("Failed to serialize spritesheet: {}", e)))?;
+
+            let ttl = config.cache_ttl();
+            cache.set(&sheet_key(&id), &png_data, ttl).await?;
+            cache.set(&meta_key(&id), &meta_data, ttl).await?;
+            (png_data, meta)
+        }
+    };
+
+    if !json_response {
+        return Ok(HttpResponse::Ok()
+            .content_type("image/png")
+            .body(png_data));
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "id": meta.id,
+        "width": meta.width,
+        "height": meta.height,
+        "size": png_data.len(),
+        "imageUrl": image_url(&config, &meta.id),
+        "cssUrl": format!("{}/spritesheet/{}/sprites.css", config.public_base_url, meta.id),
+        "sprites": meta.sprites,
+    })))
+}
+
+pub async fn spritesheet_image(
+    id: web::Path<String>,
+    cache: web::Data<Arc<RedisCache>>,
+) -> ServiceResult<HttpResponse> {
+    let png_data = cache.get(&sheet_key(&id)).await?
+        .ok_or_else(|| ServiceError::NotFound(format!("Spritesheet {} not found", id)))?;
+
+    Ok(HttpResponse::Ok()
+        .content_type("image/png")
+        .body(png_data))
+}
+
+pub async fn spritesheet_json(
+    id: web::Path<String>,
+    cache: web::Data<Arc<RedisCache>>,
+) -> ServiceResult<HttpResponse> {
+    let meta = load_meta(&cache, &id).await?;
+    Ok(HttpResponse::Ok().json(meta))
+}
+
+pub async fn spritesheet_css(
+    id: web::Path<String>,
+    query: web::Query<CssQuery>,
+    cache: web::Data<Arc<RedisCache>>,
+) -> ServiceResult<HttpResponse> {
+    let config = config::current();
+    let meta = load_meta(&cache, &id).await?;
+    let prefix = query.prefix.as_deref().unwrap_or("sprite");
+    if prefix.is_empty() || !prefix.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(ServiceError::ValidationError("Invalid CSS prefix".to_string()));
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/css")
+        .body(sprite_css(&meta, prefix, &image_url(&config, &meta.id))))
+}
+
+// One shared class with the sheet, plus one class per sprite named after its file
+fn sprite_css(meta: &SpritesheetMeta, prefix: &str, image_url: &str) -> String {
+    let mut css = format!(
+        ".{} {{ display: inline-block; background-image: url(\"{}\"); background-repeat: no-repeat; }}\n",
+        prefix, image_url
+    );
+    let mut used = Vec::with_capacity(meta.sprites.len());
+
+    for sprite in &meta.sprites {
+        let mut name = class_name(&sprite.url);
+        if used.contains(&name) {
+            name = format!("{}-{}", name, used.len());
+        }
+
+        css.push_str(&format!(
+            ".{}-{} {{ width: {}px; height: {}px; background-position: -{}px -{}px; }}\n",
+            prefix, name, sprite.width, sprite.height, sprite.x, sprite.y
+        ));
+        used.push(name);
+    }
+
+    css
+}
+
+fn class_name(url: &str) -> String {
+    let file = url.split(['?', '#']).next().unwrap_or(url)
+        .rsplit('/').next().unwrap_or(url);
+    let stem = file.strip_suffix(".svg").unwrap_or(file);
+
+    let name: String = stem.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c.to_ascii_lowercase() } else { '-' })
+        .collect();
+    let name = name.trim_matches('-');
+
+    if name.is_empty() { "image".to_string() } else { name.to_string() }
+}
+
+pub async fn render_spritesheet(
+    req: &SpritesheetRequest,
+    config: &Config,
+    client: &reqwest::Client,
+) -> ServiceResult<(Pixmap, Vec<Sprite>)> {
+    let (cell_width, cell_height) = config.validate_dimensions(
+        Some(req.cell_width),
+        Some(req.cell_height.unwrap_or(req.cell_width)),
+    );
+    let padding = req.padding.unwrap_or(0);
+    if padding > MAX_PADDING {
+        return Err(ServiceError::ValidationError(
+            format!("padding too large: {} (max {})", padding, MAX_PADDING)
+        ));
+    }
+
+    let columns = req.columns
+        .unwrap_or_else(|| (req.urls.len() as f64).sqrt().ceil() as u32)
+        .clamp(1, req.urls.len() as u32);
+    let packed = match req.layout.as_deref() {
+        None | Some("grid") => false,
+        Some("packed") => true,
+        Some(other) => return Err(ServiceError::ValidationError(
+            format!("Unsupported layout: {}", other)
+        )),
+    };
+
+    let processor = SvgProcessor::new(client);
+    let trees = processor.fetch_all(&req.urls).await?;
+
+    let sizes: Vec<(u32, u32)> = trees.iter()
+        .map(|rtree| if packed {
+            processor.fit_size(rtree, cell_width, cell_height)
+        } else {
+            (cell_width, cell_height)
+        })
+        .collect();
+
+    let (positions, sheet_width, sheet_height) = if packed {
+        layout_packed(&sizes, columns * cell_width + (columns - 1) * padding, padding)
+    } else {
+        layout_grid(&sizes, cell_width, cell_height, columns, padding)
+    };
+
+    if sheet_width > config.max_width || sheet_height > config.max_height {
+        return Err(ServiceError::ValidationError(format!(
+            "Spritesheet too large: {}x{} (max {}x{})",
+            sheet_width, sheet_height, config.max_width, config.max_height
+        )));
+    }
+
+    let mut sheet = Pixmap::new(sheet_width, sheet_height)
+        .ok_or_else(|| ServiceError::SvgProcessingError("Failed to create pixel buffer".into()))?;
+    let mut sprites = Vec::with_capacity(trees.len());
+
+    for (i, rtree) in trees.iter().enumerate() {
+        let (width, height) = sizes[i];
+        let (x, y) = positions[i];
+        let sprite = processor.render_pixmap(rtree, width, height)?;
+        sheet.draw_pixmap(x as i32, y as i32, sprite.as_ref(), &PixmapPaint::default(), Transform::identity(), None);
+
+        sprites.push(Sprite { url: req.urls[i].clone(), x, y, width, height });
+    }
+
+    Ok((sheet, sprites))
+}
+
+fn layout_grid(sizes: &[(u32, u32)], cell_width: u32, cell_height: u32, columns: u32, padding: u32) -> (Vec<(u32, u32)>, u32, u32) {
+    let rows = (sizes.len() as u32).div_ceil(columns);
+    let positions = (0..sizes.len() as u32)
+        .map(|i| ((i % columns) * (cell_width + padding), (i / columns) * (cell_height + padding)))
+        .collect();
+
+    (
+        positions,
+        columns * cell_width + (columns - 1) * padding,
+        rows * cell_height + (rows - 1) * padding,
+    )
+}
+
+// Shelf packing: tallest sprites first, filling rows up to the target width
+fn layout_packed(sizes: &[(u32, u32)], target_width: u32, padding: u32) -> (Vec<(u32, u32)>, u32, u32) {
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by(|a, b| sizes[*b].1.cmp(&sizes[*a].1));
+
+    let mut positions = vec![(0, 0); sizes.len()];
+    let (mut x, mut y, mut shelf_height, mut sheet_width) = (0, 0, 0, 0);
+
+    for i in order {
+        let (width, height) = sizes[i];
+        if x > 0 && x + width > target_width {
+            x = 0;
+            y += shelf_height + padding;
+            shelf_height = 0;
+        }
+
+        positions[i] = (x, y);
+        sheet_width = sheet_width.max(x + width);
+        shelf_height = shelf_height.max(height);
+        x += width + padding;
+    }
+
+    (positions, sheet_width, y + shelf_height)
+}
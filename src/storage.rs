@@ -0,0 +1,286 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::error::{ServiceResult, ServiceError};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Minimal S3 client (path or virtual-host style PUT with SigV4), enough to
+// publish rendered images to S3 or any S3-compatible object store
+#[derive(Clone, Debug)]
+pub struct S3Storage {
+    bucket: String,
+    prefix: String,
+    region: String,
+    endpoint: Option<String>,
+    access_key_id: String,
+    secret_access_key: String,
+    public_url: Option<String>,
+}
+
+impl S3Storage {
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let bucket = config.s3_bucket.clone()?;
+
+        Some(Self {
+            bucket,
+            prefix: config.s3_prefix.clone(),
+            region: config.s3_region.clone(),
+            endpoint: config.s3_endpoint.clone(),
+            access_key_id: config.s3_access_key_id.clone().unwrap_or_default(),
+            secret_access_key: config.s3_secret_access_key.clone().unwrap_or_default(),
+            public_url: config.s3_public_url.clone(),
+        })
+    }
+
+    // Deterministic object key so repeated renders of the same variant overwrite each other
+    pub fn object_key(&self, cache_key: &str, extension: &str) -> String {
+        let digest = hex::encode(Sha256::digest(cache_key.as_bytes()));
+        format!("{}{}.{}", self.prefix, digest, extension)
+    }
+
+    fn host_and_path(&self, key: &str) -> (String, String, String) {
+        match &self.endpoint {
+            Some(endpoint) => {
+                let endpoint = endpoint.trim_end_matches('/');
+                let (scheme, host) = endpoint.split_once("://").unwrap_or(("https", endpoint));
+                (scheme.to_string(), host.to_string(), format!("/{}/{}", self.bucket, key))
+            },
+            None => (
+                "https".to_string(),
+                format!("{}.s3.{}.amazonaws.com", self.bucket, self.region),
+                format!("/{}", key),
+            ),
+        }
+    }
+
+    pub fn object_url(&self, key: &str) -> String {
+        match &self.public_url {
+            Some(public_url) => format!("{}/{}", public_url.trim_end_matches('/'), key),
+            None => {
+                let (scheme, host, path) = self.host_and_path(key);
+                format!("{}://{}{}", scheme, host, uri_encode_path(&path))
+            }
+        }
+    }
+
+    pub async fn put(&self, client: &reqwest::Client, key: &str, body: Vec<u8>, content_type: &str) -> ServiceResult<()> {
+        let (scheme, host, path) = self.host_and_path(key);
+        let canonical_uri = uri_encode_path(&path);
+
+        let amz_date = chrono::Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = hex::encode(Sha256::digest(&body));
+        let authorization = self.authorization("s3", &SignedRequest {
+            method: "PUT",
+            path: &path,
+            headers: &[
+                ("content-type", content_type),
+                ("host", &host),
+                ("x-amz-content-sha256", &payload_hash),
+                ("x-amz-date", &amz_date),
+            ],
+            payload_hash: &payload_hash,
+        }, &amz_date);
+
+        let response = client
+            .put(format!("{}://{}{}", scheme, host, canonical_uri))
+            .header("content-type", content_type)
+            .header("x-amz-content-sha256", payload_hash)
+            .header("x-amz-date", amz_date)
+            .header("authorization", authorization)
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| ServiceError::StorageError(format!("Failed to upload {}: {}", key, e)))?;
+
+        if !response.status().is_success() {
+            return Err(ServiceError::StorageError(
+                format!("Failed to upload {}: HTTP {}", key, response.status())
+            ));
+        }
+
+        Ok(())
+    }
+
+    // SigV4 Authorization header for `request` sent at `amz_date`, e.g. `20150830T123600Z`
+    fn authorization(&self, service: &str, request: &SignedRequest, amz_date: &str) -> String {
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region, service);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date, scope, hex::encode(Sha256::digest(request.canonical().as_bytes()))
+        );
+        let signing_key = signing_key(&self.secret_access_key, date, &self.region, service);
+        let signature = hex::encode(hmac(&signing_key, string_to_sign.as_bytes()));
+
+        format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key_id, scope, request.signed_headers(), signature
+        )
+    }
+}
+
+// What SigV4 signs of a request without a query string. Headers are lowercase,
+// sorted by name, and include host and x-amz-date.
+struct SignedRequest<'a> {
+    method: &'a str,
+    path: &'a str,
+    headers: &'a [(&'a str, &'a str)],
+    payload_hash: &'a str,
+}
+
+impl SignedRequest<'_> {
+    fn canonical(&self) -> String {
+        let headers = self.headers.iter().map(|(name, value)| format!("{}:{}\n", name, value)).collect::<String>();
+        format!(
+            "{}\n{}\n\n{}\n{}\n{}",
+            self.method, uri_encode_path(self.path), headers, self.signed_headers(), self.payload_hash
+        )
+    }
+
+    fn signed_headers(&self) -> String {
+        self.headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";")
+    }
+}
+
+fn signing_key(secret_access_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let mut key = hmac(format!("AWS4{}", secret_access_key).as_bytes(), date.as_bytes());
+    for part in [region, service, "aws4_request"] {
+        key = hmac(&key, part.as_bytes());
+    }
+    key
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+// SigV4 URI encoding: everything except unreserved characters and '/'
+fn uri_encode_path(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMPTY_PAYLOAD_HASH: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+    fn storage(secret_access_key: &str) -> S3Storage {
+        S3Storage {
+            bucket: "examplebucket".to_string(),
+            prefix: String::new(),
+            region: "us-east-1".to_string(),
+            endpoint: None,
+            access_key_id: "AKIDEXAMPLE".to_string(),
+            secret_access_key: secret_access_key.to_string(),
+            public_url: None,
+        }
+    }
+
+    // Signs a request from the AWS SigV4 test suite, which uses the "service" service
+    fn suite_authorization(method: &str, path: &str) -> String {
+        let request = SignedRequest {
+            method,
+            path,
+            headers: &[("host", "example.amazonaws.com"), ("x-amz-date", "20150830T123600Z")],
+            payload_hash: EMPTY_PAYLOAD_HASH,
+        };
+        storage("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY").authorization("service", &request, "20150830T123600Z")
+    }
+
+    fn suite_header(signature: &str) -> String {
+        format!(
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+             SignedHeaders=host;x-amz-date, Signature={}",
+            signature
+        )
+    }
+
+    #[test]
+    fn signing_key_matches_aws_example() {
+        let key = signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20150830", "us-east-1", "iam");
+        assert_eq!(hex::encode(key), "c4afb1cc5771d871763a393e44b703571b55cc28424d1a5e86da6ed3c154a4b9");
+    }
+
+    #[test]
+    fn signs_aws_test_suite_requests() {
+        let cases = [
+            ("get-vanilla", "GET", "/", "5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31"),
+            ("post-vanilla", "POST", "/", "5da7c1a2acd57cee7505fc6676e4e544621c30862966e37dddb68e92efbe5d6b"),
+            ("get-space-unnormalized", "GET", "/example space/", "652487583200325589f1fba4c7e578f72c47cb61beeca81406b39ddec1366741"),
+            ("get-utf8", "GET", "/\u{1234}", "8318018e0b0f223aa2bbf98705b62bb787dc9c0e678f255a891fd03141be5d85"),
+            (
+                "get-unreserved",
+                "GET",
+                "/-._~0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz",
+                "07ef7494c76fa4850883e2b006601f940f8a34d404d0cfa977f52a65bbf5f24f",
+            ),
+        ];
+        for (name, method, path, signature) in cases {
+            assert_eq!(suite_authorization(method, path), suite_header(signature), "{}", name);
+        }
+    }
+
+    #[test]
+    fn signs_s3_get_object_example() {
+        let request = SignedRequest {
+            method: "GET",
+            path: "/test.txt",
+            headers: &[
+                ("host", "examplebucket.s3.amazonaws.com"),
+                ("range", "bytes=0-9"),
+                ("x-amz-content-sha256", EMPTY_PAYLOAD_HASH),
+                ("x-amz-date", "20130524T000000Z"),
+            ],
+            payload_hash: EMPTY_PAYLOAD_HASH,
+        };
+        let authorization = storage("wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY").authorization("s3", &request, "20130524T000000Z");
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20130524/us-east-1/s3/aws4_request, \
+             SignedHeaders=host;range;x-amz-content-sha256;x-amz-date, \
+             Signature=f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41"
+        );
+    }
+
+    #[test]
+    fn changed_request_changes_signature() {
+        let signed = suite_authorization("GET", "/");
+        assert_ne!(suite_authorization("GET", "/other"), signed);
+        assert_ne!(suite_authorization("DELETE", "/"), signed);
+    }
+
+    #[test]
+    fn encodes_reserved_characters_in_paths() {
+        assert_eq!(uri_encode_path("/a b/c+d/\u{e9}.png"), "/a%20b/c%2Bd/%C3%A9.png");
+        assert_eq!(uri_encode_path("/-._~/AZaz09"), "/-._~/AZaz09");
+    }
+
+    #[test]
+    fn builds_virtual_host_and_endpoint_urls() {
+        let mut storage = storage("secret");
+        storage.prefix = "renders/".to_string();
+        assert_eq!(storage.object_url("a b.png"), "https://examplebucket.s3.us-east-1.amazonaws.com/a%20b.png");
+
+        storage.endpoint = Some("http://localhost:9000/".to_string());
+        assert_eq!(storage.object_url("a.png"), "http://localhost:9000/examplebucket/a.png");
+
+        storage.public_url = Some("https://cdn.example.com/".to_string());
+        assert_eq!(storage.object_url("a.png"), "https://cdn.example.com/a.png");
+
+        let key = storage.object_key("cache-key", "png");
+        assert!(key.starts_with("renders/") && key.ends_with(".png"));
+        assert_eq!(key, storage.object_key("cache-key", "png"));
+    }
+}
@@ -1,13 +1,371 @@
-use resvg::usvg::{self, TreeParsing, Options};
-use resvg::tiny_skia::{Pixmap, Transform};
+use resvg::usvg::{self, fontdb, TreeTextToPath};
+use resvg::tiny_skia::{Color, Pixmap, Transform};
+use reqwest::header::{HeaderName, HeaderValue};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, OnceLock};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use crate::cache::RedisCache;
+use crate::circuit_breaker;
+use crate::config::{self, Config, IpVersion, RetryOn};
+use crate::error_reporting;
+use crate::hooks;
+use crate::host_limit;
+use crate::metrics::metrics;
+use crate::pixmap_pool;
+use crate::render_pool;
+use crate::request_context;
+use crate::telemetry;
+use crate::tree_cache;
 use crate::error::{ServiceResult, ServiceError};
-use bytes::Bytes;
-use futures::StreamExt;
+use rayon::prelude::*;
+use svg_rasterizer_core::filters::ColorAdjustments;
+use svg_rasterizer_core::{render, Animation, EncodeOptions, ImageFormat, Rasterizer, Surface, MAX_SVG_SIZE};
 
-// Constants for size limits
-const MAX_SVG_SIZE: usize = 1024 * 1024; // 1MB
-const MAX_RESPONSE_SIZE: usize = 5 * 1024 * 1024; // 5MB safety limit
+pub use svg_rasterizer_core::{Overlay, Position, Tiling};
 
+// Smaller outputs render faster than the tiles can be set up and composited
+const TILED_RENDER_MIN_PIXELS: u64 = 1024 * 1024;
+// For SVGs whose animations never end on their own, like a <set> without a duration
+const DEFAULT_ANIMATION_DURATION_SECS: f32 = 1.0;
+
+static FONT_DATABASE: OnceLock<Arc<fontdb::Database>> = OnceLock::new();
+static RASTERIZER: OnceLock<Rasterizer> = OnceLock::new();
+static DETERMINISTIC: AtomicBool = AtomicBool::new(false);
+static RENDER_THREADS: AtomicUsize = AtomicUsize::new(1);
+static FETCH_HEADERS: OnceLock<HashMap<String, Vec<(String, String)>>> = OnceLock::new();
+static FORWARD_HEADERS_HOSTS: OnceLock<Vec<String>> = OnceLock::new();
+static RETRIES: OnceLock<RetrySettings> = OnceLock::new();
+static IP_VERSION: OnceLock<IpVersion> = OnceLock::new();
+static REDIS_SOURCES: OnceLock<RedisSources> = OnceLock::new();
+
+// Sources given as `redis://key`, read from the service's own Redis
+const REDIS_SOURCE_SCHEME: &str = "redis://";
+// What allowed source domains must list for redis:// sources to be allowed
+const REDIS_SOURCE_HOST: &str = "redis";
+
+struct RedisSources {
+    cache: RedisCache,
+    prefixes: Vec<String>,
+}
+
+struct RetrySettings {
+    retries: u32,
+    backoff: Duration,
+    max_backoff: Duration,
+    jitter: bool,
+    retry_on: Vec<RetryOn>,
+}
+
+// A failed fetch attempt, with the failure class when it may succeed on a retry
+struct FetchError {
+    error: ServiceError,
+    retry: Option<RetryOn>,
+}
+
+impl From<ServiceError> for FetchError {
+    fn from(error: ServiceError) -> Self {
+        Self { error, retry: None }
+    }
+}
+
+impl From<svg_rasterizer_core::Error> for FetchError {
+    fn from(error: svg_rasterizer_core::Error) -> Self {
+        match error {
+            svg_rasterizer_core::Error::Request(e) => Self::request(e),
+            e => ServiceError::from(e).into(),
+        }
+    }
+}
+
+impl FetchError {
+    fn request(error: reqwest::Error) -> Self {
+        let retry = if error.is_timeout() {
+            Some(RetryOn::Timeout)
+        } else if error.is_connect() {
+            Some(RetryOn::Connect)
+        } else {
+            None
+        };
+        Self { error: ServiceError::RequestError(error), retry }
+    }
+}
+
+// Applies process-wide rendering settings, must run before the first render
+pub fn configure(config: &Config) {
+    DETERMINISTIC.store(config.deterministic_rendering, Ordering::Relaxed);
+    RENDER_THREADS.store(config.render_threads, Ordering::Relaxed);
+    let _ = FETCH_HEADERS.set(config.fetch_headers.clone());
+    let _ = FORWARD_HEADERS_HOSTS.set(config.forward_headers_hosts.clone());
+    let _ = RETRIES.set(RetrySettings {
+        retries: config.fetch_retries,
+        backoff: Duration::from_millis(config.fetch_retry_backoff_ms),
+        max_backoff: Duration::from_millis(config.fetch_retry_max_backoff_ms),
+        jitter: config.fetch_retry_jitter,
+        retry_on: config.fetch_retry_on.clone(),
+    });
+    let _ = IP_VERSION.set(config.fetch_ip_version);
+    if !config.redis_source_prefixes.is_empty() {
+        match RedisCache::new(&config.redis_url) {
+            Ok(cache) => {
+                let _ = REDIS_SOURCES.set(RedisSources { cache, prefixes: config.redis_source_prefixes.clone() });
+            },
+            Err(e) => log::error!("Redis sources are unavailable: {}", e),
+        }
+    }
+
+    if let Some(font_dir) = &config.font_dir {
+        let _ = FONT_DATABASE.set(Arc::new(svg_rasterizer_core::load_fonts(Some(font_dir))));
+    }
+}
+
+// Hosts given as an IP address skip the resolver, which is where FETCH_IP_VERSION
+// keeps other fetches to one address family
+fn check_ip_version(url: &str) -> ServiceResult<()> {
+    let ip_version = IP_VERSION.get().copied().unwrap_or(IpVersion::Any);
+    let ip = match url::Url::parse(url).ok().and_then(|url| url.host().map(|host| host.to_owned())) {
+        Some(url::Host::Ipv4(ip)) => IpAddr::V4(ip),
+        Some(url::Host::Ipv6(ip)) => IpAddr::V6(ip),
+        _ => return Ok(()),
+    };
+
+    if !ip_version.allows(ip) {
+        let (family, allowed) = if ip.is_ipv4() { ("IPv4", "IPv6") } else { ("IPv6", "IPv4") };
+        return Err(ServiceError::ValidationError(format!("Source address {} is {}, fetches are limited to {}", ip, family, allowed)));
+    }
+    Ok(())
+}
+
+// ALLOWED_SOURCE_DOMAINS, or the domains of the request's tenant
+fn check_allowed_domain(url: &str) -> ServiceResult<()> {
+    allowed_domain(url, &config::current().allowed_source_domains)
+}
+
+fn allowed_domain(url: &str, allowed_source_domains: &[String]) -> ServiceResult<()> {
+    if allowed_source_domains.is_empty() {
+        return Ok(());
+    }
+    // Redis keys aren't hosts, and usually aren't valid URLs either
+    let host = if url.starts_with(REDIS_SOURCE_SCHEME) {
+        REDIS_SOURCE_HOST.to_string()
+    } else {
+        url::Url::parse(url).ok().and_then(|url| url.host_str().map(str::to_lowercase)).unwrap_or_default()
+    };
+    if allowed_source_domains.iter().any(|pattern| host_matches(pattern, &host)) {
+        Ok(())
+    } else {
+        Err(ServiceError::ValidationError(format!("Fetching from {} is not allowed", host)))
+    }
+}
+
+pub fn deterministic_rendering() -> bool {
+    DETERMINISTIC.load(Ordering::Relaxed)
+}
+
+// Fonts from FONT_DIR, or the system fonts, loaded once on first use
+pub fn font_database() -> &'static Arc<fontdb::Database> {
+    FONT_DATABASE.get_or_init(|| Arc::new(svg_rasterizer_core::load_fonts(None)))
+}
+
+// The core rasterizer every render and encode goes through, with the service's
+// fonts and determinism. Fetching stays with SvgProcessor, which adds retries,
+// hooks and limits.
+fn rasterizer() -> &'static Rasterizer {
+    RASTERIZER.get_or_init(|| Rasterizer::builder()
+        .fonts(font_database().clone())
+        .deterministic(deterministic_rendering())
+        .max_svg_size(MAX_SVG_SIZE)
+        .build())
+}
+
+// Loads the fonts ahead of the first render, which would otherwise pay for it
+pub fn warm_up() {
+    let _ = font_database();
+}
+
+// Parses "#rrggbb", "#rrggbbaa" (with or without '#') or "transparent"
+pub fn parse_color(value: &str) -> ServiceResult<Color> {
+    let invalid = || ServiceError::ValidationError(format!("Invalid color: {}", value));
+
+    if value.eq_ignore_ascii_case("transparent") {
+        return Ok(Color::TRANSPARENT);
+    }
+
+    let hex = value.trim_start_matches('#');
+    if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+    let alpha = if hex.len() == 8 { channel(6)? } else { 255 };
+    Ok(Color::from_rgba8(channel(0)?, channel(2)?, channel(4)?, alpha))
+}
+
+// Everything besides the source URL that changes the rendered output. The rendering
+// and encoding options are handed to the core pipeline as core_options().
+#[derive(Clone, Debug, PartialEq)]
+pub struct RenderOptions {
+    pub width: u32,
+    pub height: u32,
+    pub background: Option<Color>,
+    pub maskable: bool,
+    pub corner_radius: Option<u32>,
+    pub deterministic: bool,
+    pub lqip: bool,
+    // Maps the viewBox to the whole canvas, ignoring the SVG's aspect ratio
+    pub stretch: bool,
+    // Unsharp mask strength, 0-1, applied before the color adjustments
+    pub sharpen: Option<f32>,
+    // Gamma, brightness and contrast, applied before hooks
+    pub adjustments: Option<ColorAdjustments>,
+    // Opaque color the finished image is composited onto, after hooks
+    pub flatten: Option<Color>,
+    // Other SVGs drawn over the render in order, after hooks
+    pub overlays: Vec<Overlay>,
+    // The render repeated in a grid, after overlays
+    pub tile: Option<Tiling>,
+    pub format: ImageFormat,
+    // Encoder quality for JPEG, WebP and AVIF, JPEG_QUALITY, WEBP_QUALITY or AVIF_QUALITY when None
+    pub quality: Option<u8>,
+    // Lossless WebP output, WEBP_LOSSLESS unless the request says
+    pub lossless: bool,
+    pub animation: Option<AnimationOptions>,
+    // Seconds into the SVG's animations to render, or to start an animation at
+    pub time: Option<f32>,
+}
+
+// Frame sampling for animate=true
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AnimationOptions {
+    pub fps: u32,
+    // Seconds, None for the length of the SVG's own animations
+    pub duration: Option<f32>,
+}
+
+impl RenderOptions {
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            background: None,
+            maskable: false,
+            corner_radius: None,
+            deterministic: deterministic_rendering(),
+            lqip: false,
+            stretch: false,
+            sharpen: None,
+            adjustments: None,
+            flatten: None,
+            overlays: Vec::new(),
+            tile: None,
+            format: ImageFormat::Png,
+            quality: None,
+            lossless: config::current().webp_lossless,
+            animation: None,
+            time: None,
+        }
+    }
+
+    // Size of the image as delivered, the tiled canvas for tile=XxY. Handlers check
+    // it stays within MAX_DIMENSION.
+    pub fn output_size(&self) -> (u32, u32) {
+        match self.tile {
+            Some(tiling) => {
+                let (width, height) = tiling.canvas_size(self.width, self.height);
+                (width as u32, height as u32)
+            },
+            None => (self.width, self.height),
+        }
+    }
+
+    pub fn core_options(&self) -> svg_rasterizer_core::RenderOptions {
+        svg_rasterizer_core::RenderOptions {
+            width: self.width,
+            height: self.height,
+            background: self.background,
+            maskable: self.maskable,
+            corner_radius: self.corner_radius,
+            lqip: self.lqip,
+            stretch: self.stretch,
+            sharpen: self.sharpen,
+            adjustments: self.adjustments,
+            flatten: self.flatten,
+            overlays: self.overlays.clone(),
+            tile: self.tile,
+            format: self.format,
+            encode: encode_options(self),
+        }
+    }
+
+    // Cache key suffix for non-default options, empty for a plain render
+    pub fn variant_key(&self) -> String {
+        let mut key = String::new();
+
+        if let Some(color) = self.background {
+            let color = color.to_color_u8();
+            key.push_str(&format!(":bg{:02x}{:02x}{:02x}{:02x}", color.red(), color.green(), color.blue(), color.alpha()));
+        }
+        if self.maskable {
+            key.push_str(":maskable");
+        }
+        if let Some(radius) = self.corner_radius {
+            key.push_str(&format!(":r{}", radius));
+        }
+        if self.deterministic {
+            key.push_str(":det");
+        }
+        if self.lqip {
+            key.push_str(":lqip");
+        }
+        if self.stretch {
+            key.push_str(":stretch");
+        }
+        if let Some(amount) = self.sharpen {
+            key.push_str(&format!(":sharpen{}", amount));
+        }
+        if let Some(adjustments) = self.adjustments {
+            key.push_str(&format!(":g{}b{}c{}", adjustments.gamma, adjustments.brightness, adjustments.contrast));
+        }
+        for overlay in &self.overlays {
+            key.push_str(&format!(":overlay[{}|{}|{}|{}]", overlay.url, overlay.position.name(), overlay.scale, overlay.opacity));
+        }
+        if let Some(tiling) = self.tile {
+            key.push_str(&format!(":tile{}x{}s{}", tiling.columns, tiling.rows, tiling.spacing));
+        }
+        if let Some(matte) = self.flatten {
+            let matte = matte.to_color_u8();
+            key.push_str(&format!(":flat{:02x}{:02x}{:02x}", matte.red(), matte.green(), matte.blue()));
+        }
+        if self.format != ImageFormat::Png {
+            key.push_str(&format!(":{}", self.format.extension()));
+        }
+        let webp = self.format == ImageFormat::Webp;
+        if webp && self.lossless {
+            key.push_str(":lossless");
+        }
+        // The effective quality, so changing a configured default doesn't serve renders
+        // made at the old one. Lossless output comes out the same at any quality.
+        if matches!(self.format, ImageFormat::Jpeg | ImageFormat::Avif) || (webp && !self.lossless) {
+            if let Some(quality) = encode_options(self).quality {
+                key.push_str(&format!(":q{}", quality));
+            }
+        }
+        if let Some(time) = self.time {
+            key.push_str(&format!(":t{}ms", (time * 1000.0).round()));
+        }
+        if let Some(animation) = self.animation {
+            key.push_str(&format!(":anim{}fps", animation.fps));
+            if let Some(duration) = animation.duration {
+                key.push_str(&format!("{}ms", (duration * 1000.0).round()));
+            }
+        }
+
+        key
+    }
+}
+
+#[derive(Clone)]
 pub struct SvgProcessor {
     client: reqwest::Client,
 }
@@ -19,158 +377,557 @@ impl SvgProcessor {
         }
     }
 
-    pub async fn process(&self, url: &str, width: u32, height: u32) -> ServiceResult<Vec<u8>> {
-        let svg_data = self.fetch_svg(url).await?;
+    pub async fn process(&self, url: &str, options: &RenderOptions) -> ServiceResult<Vec<u8>> {
+        let svg_data = self.fetch(url).await?;
+        self.process_svg(svg_data, options).await
+    }
+
+    // Renders and encodes SVG that didn't come from a fetch, e.g. a stored template
+    pub async fn process_svg(&self, svg_data: String, options: &RenderOptions) -> ServiceResult<Vec<u8>> {
+        let overlays = futures::future::try_join_all(options.overlays.iter().map(|overlay| self.fetch(&overlay.url))).await?;
+        let processor = self.clone();
+        let options = options.clone();
+        render_pool::run(move || processor.convert(&svg_data, &overlays, &options)).await
+    }
+
+    // Fetches and checks an SVG, with the URL rewrites and SVG transforms of any hooks
+    pub async fn fetch(&self, url: &str) -> ServiceResult<String> {
+        let rewritten = hooks::pre_fetch(url)?;
+        let url = rewritten.as_deref().unwrap_or(url);
+        let svg_data = self.fetch_source(url).await?;
+        if !hooks::has_svg_transforms() {
+            return Ok(svg_data);
+        }
+        // Transforms may be as expensive as a render, like WASM_TRANSFORM modules
+        let url = url.to_string();
+        render_pool::run(move || hooks::svg_transform(&url, svg_data)).await
+    }
+
+    async fn fetch_source(&self, url: &str) -> ServiceResult<String> {
+        check_allowed_domain(url)?;
+        if let Some(key) = url.strip_prefix(REDIS_SOURCE_SCHEME) {
+            let Some(sources) = REDIS_SOURCES.get() else {
+                return Err(ServiceError::ValidationError("redis:// sources are disabled, set REDIS_SOURCE_PREFIXES to enable them".to_string()));
+            };
+            return fetch_redis(sources, key).await;
+        }
+
+        request_context::record_upstream(url);
+        check_ip_version(url)?;
+        circuit_breaker::check(url)?;
+        let _permit = host_limit::acquire(url).await?;
+        let span = request_context::stage("fetch");
+        let mut attempt = 0;
+        let result = loop {
+            // The circuit may have opened while waiting to retry
+            if attempt > 0 {
+                if let Err(e) = circuit_breaker::check(url) {
+                    break Err(e);
+                }
+            }
+            let start = Instant::now();
+            let result = self.fetch_svg(url).await;
+            let error = result.as_ref().err().map(|e| e.error.to_string());
+            metrics().observe_upstream_fetch(url, start.elapsed(), error.as_deref());
+            circuit_breaker::record(url, result.as_ref().is_err_and(|e| e.retry.is_some()));
+
+            match (result, RETRIES.get()) {
+                (Err(FetchError { error, retry: Some(reason) }), Some(settings)) if settings.retry_on.contains(&reason) => {
+                    if attempt >= settings.retries {
+                        if settings.retries > 0 {
+                            metrics().upstream_retries_exhausted.inc();
+                        }
+                        break Err(error);
+                    }
+                    attempt += 1;
+                    let delay = settings.delay(attempt);
+                    metrics().upstream_retries.with_label_values(&[reason.as_str()]).inc();
+                    log::warn!("Fetching {} failed ({}), retry {} of {} in {}ms",
+                        url, error, attempt, settings.retries, delay.as_millis());
+                    tokio::time::sleep(delay).await;
+                },
+                (result, _) => break result.map_err(|e| e.error),
+            }
+        };
+        drop(span);
+
+        let svg_data = result?;
         log::debug!("Fetched SVG data (size: {} bytes)", svg_data.len());
         
         // Check SVG size before processing
-        if svg_data.len() > MAX_SVG_SIZE {
-            return Err(ServiceError::ValidationError(
-                format!("SVG file too large: {} bytes (max {})", svg_data.len(), MAX_SVG_SIZE)
-            ));
-        }
-        
-        self.convert_to_png(&svg_data, width, height)
+        svg_rasterizer_core::check_size(svg_data.len(), MAX_SVG_SIZE)?;
+
+        Ok(svg_data)
     }
 
-    async fn fetch_svg(&self, url: &str) -> ServiceResult<String> {
-        // First, do a HEAD request to check content-length
-        let head_resp = self.client
-            .head(url)
-            .send()
-            .await
-            .map_err(ServiceError::RequestError)?;
-
-        // Check content-length if available
-        if let Some(length) = head_resp.headers().get("content-length") {
-            let size = length.to_str()
-                .unwrap_or("0")
-                .parse::<usize>()
-                .unwrap_or(0);
-
-            if size > MAX_SVG_SIZE {
-                return Err(ServiceError::ValidationError(
-                    format!("SVG file too large: {} bytes (max {})", size, MAX_SVG_SIZE)
-                ));
+    // Correlation headers for the request being handled, forwarded caller headers
+    // and the configured headers for the source host, which take precedence.
+    // Caller headers only go to the FETCH_HEADERS and FORWARD_HEADERS_HOSTS hosts.
+    fn upstream_headers(&self, url: &str) -> reqwest::header::HeaderMap {
+        let mut headers = telemetry::trace_headers();
+        if let Some(value) = request_context::current_id().and_then(|id| HeaderValue::from_str(&id).ok()) {
+            headers.insert(request_context::REQUEST_ID_HEADER, value);
+        }
+
+        let host = url::Url::parse(url).ok().and_then(|u| u.host_str().map(str::to_lowercase)).unwrap_or_default();
+        let fetch_headers = FETCH_HEADERS.get();
+        let configured = fetch_headers
+            .into_iter()
+            .flatten()
+            .filter(|(pattern, _)| host_matches(pattern, &host))
+            .flat_map(|(_, headers)| headers.iter().cloned());
+        let forwarded = if forwards_to(&host, fetch_headers, FORWARD_HEADERS_HOSTS.get().map_or(&[], Vec::as_slice)) {
+            request_context::forwarded_headers()
+        } else {
+            Vec::new()
+        };
+
+        for (name, value) in forwarded.into_iter().chain(configured) {
+            if let (Ok(name), Ok(value)) = (HeaderName::from_bytes(name.as_bytes()), HeaderValue::from_str(&value)) {
+                headers.insert(name, value);
             }
         }
+        headers
+    }
+
+    async fn fetch_svg(&self, url: &str) -> Result<String, FetchError> {
+        let _timer = metrics().upstream_fetch_duration.start_timer();
 
-        // Now fetch the actual content with streaming
         let response = self.client
             .get(url)
+            .headers(self.upstream_headers(url))
             .send()
             .await
-            .map_err(ServiceError::RequestError)?;
-            
+            .map_err(|e| {
+                metrics().observe_upstream_status(None);
+                FetchError::request(e)
+            })?;
+        metrics().observe_upstream_status(Some(response.status()));
+
         if !response.status().is_success() {
-            return Err(ServiceError::SvgProcessingError(
-                format!("Failed to fetch SVG: HTTP {}", response.status())
-            ));
+            let retry = if response.status().is_server_error() {
+                Some(RetryOn::ServerError)
+            } else if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                Some(RetryOn::TooManyRequests)
+            } else {
+                None
+            };
+            return Err(FetchError {
+                error: ServiceError::SvgProcessingError(format!("Failed to fetch SVG: HTTP {}", response.status())),
+                retry,
+            });
         }
         
-        let content_type = response.headers()
-            .get("content-type")
-            .and_then(|v| v.to_str().ok())
-            .unwrap_or("");
-            
-        log::debug!("Response content-type: {}", content_type);
-
-        // Stream the response with size limit
-        let mut total_size = 0;
-        let mut chunks = Vec::new();
-
-        let mut stream = response.bytes_stream();
-
-        while let Some(chunk) = stream.next().await {
-            let chunk = chunk.map_err(|e| ServiceError::RequestError(e))?;
-            total_size += chunk.len();
-
-            // Check running total against limit
-            if total_size > MAX_RESPONSE_SIZE {
-                return Err(ServiceError::ValidationError(
-                    format!("Response too large: exceeded {} bytes", MAX_RESPONSE_SIZE)
-                ));
-            }
+        let text = svg_rasterizer_core::read_svg(response, MAX_SVG_SIZE, |size| metrics().upstream_bytes.inc_by(size as u64)).await?;
+        Ok(text)
+    }
 
-            chunks.push(chunk);
+    // `overlays` are the sources of options.overlays
+    fn convert(&self, svg_data: &str, overlays: &[String], options: &RenderOptions) -> ServiceResult<Vec<u8>> {
+        if let Some(animation) = options.animation {
+            return self.convert_animated(svg_data, overlays, options, animation);
         }
 
-        // Combine chunks and convert to string
-        let bytes: Bytes = chunks.into_iter().flatten().collect();
-        let text = String::from_utf8(bytes.to_vec())
-            .map_err(|e| ServiceError::SvgProcessingError(format!("Invalid UTF-8 content: {}", e)))?;
-        
-        // Basic SVG validation
-        if !text.contains("<svg") {
-            return Err(ServiceError::ValidationError(
-                "Response does not contain SVG content".to_string()
-            ));
+        log::debug!("Parsing SVG with dimensions {}x{}", options.width, options.height);
+        let start = Instant::now();
+        let snapshot = match options.time {
+            Some(time) => snapshot(svg_data, time)?,
+            None => None,
+        };
+        let svg_data = snapshot.as_deref().unwrap_or(svg_data);
+        let rtree = {
+            let _span = request_context::stage("parse");
+            tree_cache::get_or_parse(svg_data, |svg_data| self.parse(svg_data))?
+        };
+        let pixmap = {
+            let _span = request_context::stage("render");
+            self.render_with_overlays(svg_data, &rtree, overlays, options)?
+        };
+
+        let _span = request_context::stage("encode");
+        let image_data = self.encode(&pixmap, options)?;
+        let format = if options.lqip && options.format == ImageFormat::Png { "lqip" } else { options.format.extension() };
+
+        metrics().observe_render(pixmap.width(), pixmap.height(), format, start.elapsed());
+        pixmap_pool::release(pixmap);
+        Ok(image_data)
+    }
+
+    // Samples the SVG's animations into frames and encodes them as an animation.
+    // SVGs without animations come out as a still image.
+    fn convert_animated(&self, svg_data: &str, overlays: &[String], options: &RenderOptions, animation: AnimationOptions) -> ServiceResult<Vec<u8>> {
+        let start = Instant::now();
+        let timeline = {
+            let _span = request_context::stage("parse");
+            Animation::parse(svg_data)?
+        };
+        if !timeline.is_animated() {
+            log::debug!("SVG has no animations, rendering a still image");
+            return self.convert(svg_data, overlays, &RenderOptions { animation: None, time: None, ..options.clone() });
         }
 
-        // Additional SVG validation
-        if text.contains("<script") || text.contains("javascript:") {
-            return Err(ServiceError::ValidationError(
-                "SVG contains potentially unsafe content".to_string()
-            ));
+        let config = config::current();
+        let duration = animation.duration
+            .or_else(|| timeline.duration())
+            .unwrap_or(DEFAULT_ANIMATION_DURATION_SECS)
+            .min(config.animation_max_duration_secs as f32);
+        let frames = ((duration * animation.fps as f32).ceil() as u32).max(1);
+        let (width, height) = options.output_size();
+        let pixels = frames as u64 * width as u64 * height as u64;
+        if pixels > config.animation_max_pixels {
+            return Err(ServiceError::ValidationError(format!(
+                "{} frames at {}x{} exceed ANIMATION_MAX_PIXELS {}, lower fps, duration or size",
+                frames, width, height, config.animation_max_pixels)));
         }
-        
-        Ok(text)
-    }
+        log::debug!("Rendering {} frames over {}s", frames, duration);
 
-    fn convert_to_png(&self, svg_data: &str, width: u32, height: u32) -> ServiceResult<Vec<u8>> {
-        // Create options
-        let opt = Options::default();
+        // Each frame is a separate SVG with its own tree, so they render in parallel
+        let pixmaps = {
+            let _span = request_context::stage("render");
+            (0..frames)
+                .into_par_iter()
+                .map(|frame| {
+                    let frame_svg = timeline.frame(options.time.unwrap_or(0.0) + frame as f32 / animation.fps as f32);
+                    let rtree = self.parse(&frame_svg)?;
+                    self.render_with_overlays(&frame_svg, &rtree, overlays, options)
+                })
+                .collect::<ServiceResult<Vec<_>>>()?
+        };
 
-        log::debug!("Parsing SVG with dimensions {}x{}", width, height);
-        
-        // Parse the SVG string into a tree
-        let rtree = usvg::Tree::from_str(svg_data, &opt)
+        let _span = request_context::stage("encode");
+        let image_data = rasterizer().encode_animation(&pixmaps, 1000 / animation.fps, &options.core_options())
+            .map_err(render_error)?;
+
+        metrics().observe_render(width, height, "animated", start.elapsed());
+        for pixmap in pixmaps {
+            pixmap_pool::release(pixmap);
+        }
+        Ok(image_data)
+    }
+
+    pub fn parse(&self, svg_data: &str) -> ServiceResult<usvg::Tree> {
+        rasterizer().parse(svg_data)
             .map_err(|e| {
-                log::error!("Failed to parse SVG: {}", e);
-                ServiceError::SvgProcessingError(format!("Failed to parse SVG: {}", e))
-            })?;
+                log::error!("{}", e);
+                e.into()
+            })
+    }
+
+    // Fetches the SVG and renders it to fit within the given bounds, for image analysis
+    pub async fn fetch_pixmap(&self, url: &str, max_width: u32, max_height: u32) -> ServiceResult<Pixmap> {
+        let svg_data = self.fetch(url).await?;
+        let rtree = self.parse(&svg_data)?;
+        let (width, height) = self.fit_size(&rtree, max_width, max_height);
+        self.render_pixmap(&rtree, width, height)
+    }
+
+    // Parses SVG whose text should be rendered with the system fonts
+    pub fn parse_with_text(&self, svg_data: &str) -> ServiceResult<usvg::Tree> {
+        let mut rtree = self.parse(svg_data)?;
+        rtree.convert_text(font_database());
+        Ok(rtree)
+    }
+
+    // Fetches and parses several SVGs concurrently, failing on the first bad one
+    pub async fn fetch_all(&self, urls: &[String]) -> ServiceResult<Vec<usvg::Tree>> {
+        let fetched = futures::future::join_all(urls.iter().map(|url| self.fetch(url))).await;
+
+        let mut trees = Vec::with_capacity(fetched.len());
+        for (url, svg_data) in urls.iter().zip(fetched) {
+            let svg_data = svg_data.map_err(|e| 
+                ServiceError::SvgProcessingError(format!("{}: {}", url, e)))?;
+            let rtree = self.parse(&svg_data).map_err(|e| 
+                ServiceError::SvgProcessingError(format!("{}: {}", url, e)))?;
+            trees.push(rtree);
+        }
+
+        Ok(trees)
+    }
 
+    // Largest size that fits within the bounds while keeping the SVG's aspect ratio
+    pub fn fit_size(&self, rtree: &usvg::Tree, max_width: u32, max_height: u32) -> (u32, u32) {
+        render::fit_size(rtree, max_width, max_height)
+    }
+
+    pub fn render_with_options(&self, svg_data: &str, rtree: &usvg::Tree, options: &RenderOptions) -> ServiceResult<Pixmap> {
+        self.render_with_overlays(svg_data, rtree, &[], options)
+    }
+
+    // Runs the core pipeline on pooled buffers, with the post-render hooks. Overlays
+    // without a source in `overlays` are left out.
+    pub fn render_with_overlays(&self, svg_data: &str, rtree: &usvg::Tree, overlays: &[String], options: &RenderOptions) -> ServiceResult<Pixmap> {
+        let overlays = overlays.iter()
+            .map(|overlay| tree_cache::get_or_parse(overlay, |svg_data| self.parse(svg_data)))
+            .collect::<ServiceResult<Vec<_>>>()?;
+        let surface = Pooled { processor: self, svg_data: Some(svg_data), options: Some(options) };
+        rasterizer().render_on(&surface, rtree, &overlays, &options.core_options())
+    }
+
+    // Maskable PWA icons must keep their content inside a centered circle with a
+    // radius of 40% of the icon size, on an opaque background
+    pub fn render_maskable(&self, rtree: &usvg::Tree, size: u32, background: Color) -> ServiceResult<Pixmap> {
+        let mut options = svg_rasterizer_core::RenderOptions::new(size, size);
+        options.maskable = true;
+        options.background = Some(background);
+        let surface = Pooled { processor: self, svg_data: None, options: None };
+        rasterizer().render_on(&surface, rtree, &[], &options)
+    }
+
+    // Encodes a render made with `options` in its format
+    pub fn encode(&self, pixmap: &Pixmap, options: &RenderOptions) -> ServiceResult<Vec<u8>> {
+        rasterizer().encode(pixmap, &options.core_options()).map_err(render_error)
+    }
+
+    pub fn render(&self, rtree: &usvg::Tree, width: u32, height: u32) -> ServiceResult<Vec<u8>> {
+        let pixmap = self.render_pixmap(rtree, width, height)?;
+        let png_data = self.encode_png(&pixmap);
+        pixmap_pool::release(pixmap);
+        png_data
+    }
+
+    pub fn render_pixmap(&self, rtree: &usvg::Tree, width: u32, height: u32) -> ServiceResult<Pixmap> {
+        self.render_pixmap_with(rtree, width, height, render::fit_transform(rtree, width, height))
+    }
+
+    fn render_pixmap_with(&self, rtree: &usvg::Tree, width: u32, height: u32, transform: Transform) -> ServiceResult<Pixmap> {
         // Get the size of the SVG
         let view_box = rtree.view_box;
         let svg_width = view_box.rect.width();
         let svg_height = view_box.rect.height();
         log::debug!("Original SVG size: {}x{}", svg_width, svg_height);
 
-        // Create a new pixel map with the specified dimensions
-        let mut pixmap = Pixmap::new(width, height)
-            .ok_or_else(|| ServiceError::SvgProcessingError("Failed to create pixel buffer".into()))?;
+        // Create a new, transparent pixel map with the specified dimensions
+        let mut pixmap = self.pixel_buffer(width, height)?;
 
-        // Clear the pixmap with a transparent background
-        pixmap.fill(tiny_skia::Color::TRANSPARENT);
+        log::debug!("Rendering SVG to pixmap");
+        render::draw_with(rtree, &mut pixmap, transform);
 
-        // Create rendering object
-        let tree = resvg::Tree::from_usvg(&rtree);
+        Ok(pixmap)
+    }
 
-        log::debug!("Rendering SVG to pixmap");
-        
-        // Calculate scale to fit while maintaining aspect ratio
-        let scale_x = width as f32 / svg_width;
-        let scale_y = height as f32 / svg_height;
-        let scale = scale_x.min(scale_y);
-
-        // Calculate centering offset
-        let translate_x = (width as f32 - svg_width * scale) / 2.0;
-        let translate_y = (height as f32 - svg_height * scale) / 2.0;
-
-        // Create transform that scales and centers the image
-        let transform = Transform::from_scale(scale, scale)
-            .pre_translate(translate_x / scale, translate_y / scale);
-
-        // Render with the calculated transform
-        tree.render(transform, &mut pixmap.as_mut());
-
-        // Encode as PNG
-        log::debug!("Encoding to PNG");
-        let png_data = pixmap.encode_png()
-            .map_err(|e| ServiceError::SvgProcessingError(e.to_string()))?;
-            
-        log::debug!("PNG encoded successfully, size: {} bytes", png_data.len());
-        
-        Ok(png_data)
+    // Renders large outputs as horizontal strips on RENDER_THREADS threads and
+    // stitches them together. Filters read neighbouring pixels that a strip
+    // doesn't have, so SVGs using them are rendered in one piece. Anti-aliasing
+    // can differ slightly from a single-piece render, so deterministic mode doesn't tile.
+    pub fn render_pixmap_tiled(&self, svg_data: &str, rtree: &usvg::Tree, width: u32, height: u32, transform: Transform) -> ServiceResult<Pixmap> {
+        let threads = RENDER_THREADS.load(Ordering::Relaxed).min(height as usize) as u32;
+        if threads <= 1
+            || deterministic_rendering()
+            || (width as u64 * height as u64) < TILED_RENDER_MIN_PIXELS
+            || render::has_filters(rtree)
+        {
+            return self.render_pixmap_with(rtree, width, height, transform);
+        }
+
+        log::debug!("Rendering {}x{} in {} tiles", width, height, threads);
+
+        // Trees can't be shared between threads, each tile thread gets its own
+        let tiles = tile_rows(height, threads)
+            .into_par_iter()
+            .map(|(y, rows)| {
+                let rtree = tree_cache::get_or_parse(svg_data, |svg_data| self.parse(svg_data))?;
+                let mut tile = self.pixel_buffer(width, rows)?;
+                resvg::Tree::from_usvg(&rtree).render(transform.post_translate(0.0, -(y as f32)), &mut tile.as_mut());
+                Ok((y, tile))
+            })
+            .collect::<ServiceResult<Vec<_>>>()?;
+
+        let mut pixmap = self.pixel_buffer(width, height)?;
+        let row_bytes = width as usize * 4;
+        for (y, tile) in tiles {
+            let start = y as usize * row_bytes;
+            pixmap.data_mut()[start..start + tile.data().len()].copy_from_slice(tile.data());
+            pixmap_pool::release(tile);
+        }
+
+        Ok(pixmap)
+    }
+
+    pub fn encode_png(&self, pixmap: &Pixmap) -> ServiceResult<Vec<u8>> {
+        svg_rasterizer_core::encode_png(pixmap, deterministic_rendering()).map_err(render_error)
+    }
+
+    fn pixel_buffer(&self, width: u32, height: u32) -> ServiceResult<Pixmap> {
+        pixmap_pool::get(width, height)
+            .ok_or_else(|| error_reporting::report(ServiceError::SvgProcessingError("Failed to create pixel buffer".into())))
+    }
+}
+
+// The core pipeline's buffers come from the pixmap pool, large renders are split
+// over RENDER_THREADS, and the post-render hooks run for requests
+struct Pooled<'a> {
+    processor: &'a SvgProcessor,
+    // Source of the tree being rendered, which tile threads parse their own copy of
+    svg_data: Option<&'a str>,
+    options: Option<&'a RenderOptions>,
+}
+
+impl Surface for Pooled<'_> {
+    type Error = ServiceError;
+
+    fn pixmap(&self, width: u32, height: u32) -> ServiceResult<Pixmap> {
+        self.processor.pixel_buffer(width, height)
+    }
+
+    fn release(&self, pixmap: Pixmap) {
+        pixmap_pool::release(pixmap);
+    }
+
+    fn draw(&self, rtree: &usvg::Tree, width: u32, height: u32, transform: Transform) -> ServiceResult<Pixmap> {
+        self.processor.render_pixmap_with(rtree, width, height, transform)
+    }
+
+    fn draw_main(&self, rtree: &usvg::Tree, width: u32, height: u32, transform: Transform) -> ServiceResult<Pixmap> {
+        match self.svg_data {
+            Some(svg_data) => self.processor.render_pixmap_tiled(svg_data, rtree, width, height, transform),
+            None => self.draw(rtree, width, height, transform),
+        }
+    }
+
+    fn post_render(&self, pixmap: &mut Pixmap) -> ServiceResult<()> {
+        match self.options {
+            Some(options) => hooks::post_render(pixmap, options),
+            None => Ok(()),
+        }
+    }
+}
+
+// The request's quality, or the configured default for its format
+fn encode_options(options: &RenderOptions) -> EncodeOptions {
+    let config = config::current();
+    let default = match options.format {
+        ImageFormat::Jpeg => config.jpeg_quality,
+        ImageFormat::Avif => config.avif_quality,
+        _ => config.webp_quality,
+    };
+    EncodeOptions { quality: Some(options.quality.unwrap_or(default)), lossless: options.lossless }
+}
+
+// The SVG as it looks `time` seconds into its animations, None when it has none
+fn snapshot(svg_data: &str, time: f32) -> ServiceResult<Option<String>> {
+    let _span = request_context::stage("parse");
+    let timeline = Animation::parse(svg_data)?;
+    Ok(timeline.is_animated().then(|| timeline.frame(time)))
+}
+
+// An SVG another service wrote to Redis, for keys under one of REDIS_SOURCE_PREFIXES
+async fn fetch_redis(sources: &RedisSources, key: &str) -> ServiceResult<String> {
+    if !sources.prefixes.iter().any(|prefix| key.starts_with(prefix.as_str())) {
+        return Err(ServiceError::ValidationError(format!("Redis key {} is not under an allowed prefix", key)));
+    }
+
+    let span = request_context::stage("fetch");
+    let svg_data = sources.cache.read(key).await?
+        .ok_or_else(|| ServiceError::NotFound(format!("Redis key {} not found", key)))?;
+    drop(span);
+
+    svg_rasterizer_core::check_size(svg_data.len(), MAX_SVG_SIZE)?;
+    let svg_data = String::from_utf8(svg_data)
+        .map_err(|_| ServiceError::ValidationError(format!("Redis key {} is not UTF-8", key)))?;
+    svg_rasterizer_core::check_content(&svg_data)?;
+    Ok(svg_data)
+}
+
+impl RetrySettings {
+    // Exponential backoff from the base delay, with full jitter when enabled
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self.backoff
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(self.max_backoff);
+        if self.jitter {
+            delay.mul_f64(rand::random::<f64>())
+        } else {
+            delay
+        }
+    }
+}
+
+// The first row and height of each strip when splitting `height` rows into `threads`
+// strips. Rounding up can leave fewer strips than threads, e.g. 3 for 5 rows on 4.
+fn tile_rows(height: u32, threads: u32) -> Vec<(u32, u32)> {
+    let tile_height = height.div_ceil(threads);
+    (0..height)
+        .step_by(tile_height as usize)
+        .map(|y| (y, tile_height.min(height - y)))
+        .collect()
+}
+
+// Whether caller headers may be forwarded to `host`
+fn forwards_to(host: &str, fetch_headers: Option<&HashMap<String, Vec<(String, String)>>>, forward_hosts: &[String]) -> bool {
+    fetch_headers.into_iter().flat_map(HashMap::keys).chain(forward_hosts)
+        .any(|pattern| host_matches(pattern, host))
+}
+
+// "cdn.example.com" matches only that host, "*.example.com" any subdomain of example.com
+pub fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.ends_with('.') && sub.len() > 1),
+        None => pattern == host,
+    }
+}
+
+// Encoder failures point at resource limits or bugs rather than at the SVG
+pub fn render_error(error: svg_rasterizer_core::Error) -> ServiceError {
+    error_reporting::report(error.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_rows_into_strips() {
+        assert_eq!(tile_rows(10, 4), [(0, 3), (3, 3), (6, 3), (9, 1)]);
+        assert_eq!(tile_rows(5, 4), [(0, 2), (2, 2), (4, 1)]);
+        assert_eq!(tile_rows(4, 4), [(0, 1), (1, 1), (2, 1), (3, 1)]);
+    }
+
+    #[test]
+    fn matches_hosts_and_subdomains() {
+        assert!(host_matches("assets.example.com", "assets.example.com"));
+        assert!(host_matches("*.example.com", "cdn.assets.example.com"));
+        assert!(!host_matches("*.example.com", "example.com"));
+        assert!(!host_matches("*.example.com", "evilexample.com"));
+    }
+
+    #[test]
+    fn forwards_caller_headers_only_to_listed_hosts() {
+        let fetch_headers = HashMap::from([("assets.example.com".to_string(), Vec::new())]);
+        let forward_hosts = ["*.internal.example.com".to_string()];
+
+        assert!(forwards_to("assets.example.com", Some(&fetch_headers), &forward_hosts));
+        assert!(forwards_to("svg.internal.example.com", Some(&fetch_headers), &forward_hosts));
+        assert!(!forwards_to("overlays.example.net", Some(&fetch_headers), &forward_hosts));
+        assert!(!forwards_to("assets.example.com", None, &[]));
+    }
+
+    #[test]
+    fn limits_sources_to_allowed_domains() {
+        let allowed = ["*.example.com".to_string()];
+        assert!(allowed_domain("https://assets.example.com/a.svg", &[]).is_ok());
+        assert!(allowed_domain("https://assets.example.com/a.svg", &allowed).is_ok());
+        assert!(allowed_domain("https://example.net/a.svg", &allowed).is_err());
+    }
+
+    #[test]
+    fn allows_redis_sources_only_when_listed() {
+        let url = "redis://svg:incoming:badge-42";
+        assert!(allowed_domain(url, &[]).is_ok());
+        assert!(allowed_domain(url, &["*.example.com".to_string()]).is_err());
+        assert!(allowed_domain(url, &["*.example.com".to_string(), REDIS_SOURCE_HOST.to_string()]).is_ok());
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn reads_redis_sources_only_under_allowed_prefixes() {
+        let sources = RedisSources {
+            cache: RedisCache::new("redis://127.0.0.1:1").unwrap(),
+            prefixes: vec!["svg:incoming:".to_string()],
+        };
+
+        let result = fetch_redis(&sources, "session:abc").await;
+        assert!(matches!(result, Err(ServiceError::ValidationError(_))), "{:?}", result.err());
+        // Allowed keys get as far as Redis, which isn't reachable here
+        let result = fetch_redis(&sources, "svg:incoming:badge-42").await;
+        assert!(matches!(result, Err(ServiceError::CacheError(_))), "{:?}", result.err());
+    }
+}
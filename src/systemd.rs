@@ -0,0 +1,61 @@
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::fd::{FromRawFd, IntoRawFd};
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+
+// systemd passes sockets starting at this descriptor
+#[cfg(unix)]
+const LISTEN_FDS_START: i32 = 3;
+
+// A socket bound by systemd (socket activation), named by FileDescriptorName=
+pub struct Activated {
+    pub name: Option<String>,
+    pub socket: Socket,
+}
+
+pub enum Socket {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+// Takes the sockets systemd passed through LISTEN_FDS, if they are meant for
+// this process. The variables are cleared so child processes don't pick them up.
+#[cfg(unix)]
+pub fn activated_sockets() -> Vec<Activated> {
+    let for_us = std::env::var("LISTEN_PID").ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid == std::process::id());
+    let count = std::env::var("LISTEN_FDS").ok()
+        .and_then(|count| count.parse::<i32>().ok())
+        .unwrap_or(0);
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    if !for_us || count <= 0 {
+        return Vec::new();
+    }
+
+    let mut names = names.split(':').map(|name| Some(name.to_string()).filter(|name| !name.is_empty() && name != "unknown"));
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|fd| {
+            // Safety: systemd hands these descriptors to this process, nothing else owns them
+            let tcp = unsafe { TcpListener::from_raw_fd(fd) };
+            // Only inet sockets have an address a TcpListener can read
+            let socket = match tcp.local_addr() {
+                Ok(_) => Socket::Tcp(tcp),
+                Err(_) => Socket::Unix(unsafe { UnixListener::from_raw_fd(tcp.into_raw_fd()) }),
+            };
+            Activated { name: names.next().flatten(), socket }
+        })
+        .collect()
+}
+
+// There is no systemd to pass sockets elsewhere
+#[cfg(not(unix))]
+pub fn activated_sockets() -> Vec<Activated> {
+    Vec::new()
+}
@@ -0,0 +1,133 @@
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderMap;
+use opentelemetry::global::{self, BoxedSpan};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry::trace::{FutureExt, SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{Context, KeyValue};
+use std::future::Future;
+
+use crate::config::Config;
+
+const TRACER_NAME: &str = "svg-rasterizer";
+
+// Spans are only exported with the `otel` feature and an OTLP endpoint configured,
+// otherwise the global tracer is a no-op and all spans below cost next to nothing.
+pub fn init(config: &Config) {
+    let Some(endpoint) = &config.otel_endpoint else {
+        return;
+    };
+
+    #[cfg(feature = "otel")]
+    {
+        use opentelemetry_otlp::WithExportConfig;
+
+        global::set_text_map_propagator(opentelemetry_sdk::propagation::TraceContextPropagator::new());
+
+        let result = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().http().with_endpoint(endpoint))
+            .with_trace_config(opentelemetry_sdk::trace::config().with_resource(
+                opentelemetry_sdk::Resource::new(vec![KeyValue::new("service.name", config.otel_service_name.clone())]),
+            ))
+            .install_batch(opentelemetry_sdk::runtime::Tokio);
+
+        match result {
+            Ok(_) => log::info!("Exporting traces to {}", endpoint),
+            Err(e) => log::error!("Failed to set up trace export: {}", e),
+        }
+    }
+
+    #[cfg(not(feature = "otel"))]
+    log::warn!("OTEL_EXPORTER_OTLP_ENDPOINT is set to {} but tracing support is not compiled in (feature `otel`)", endpoint);
+}
+
+// Flushes spans that are still buffered in the batch exporter
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+// Starts a child span of the current request span, ended when dropped
+pub fn span(name: &'static str) -> BoxedSpan {
+    global::tracer(TRACER_NAME).start(name)
+}
+
+// W3C trace context headers for outgoing requests
+pub fn trace_headers() -> reqwest::header::HeaderMap {
+    let mut headers = reqwest::header::HeaderMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Context::current(), &mut HeaderInjector(&mut headers))
+    });
+    headers
+}
+
+// Middleware wrapping every request in a server span, continuing the caller's trace
+// when the request carries a `traceparent` header
+pub fn trace_request<S, B>(
+    req: ServiceRequest,
+    srv: &S,
+) -> impl Future<Output = Result<ServiceResponse<B>, actix_web::Error>>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error>,
+{
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(req.headers())));
+    let route = req.match_pattern().unwrap_or_else(|| "unmatched".to_string());
+
+    let tracer = global::tracer(TRACER_NAME);
+    let span = tracer
+        .span_builder(format!("{} {}", req.method(), route))
+        .with_kind(SpanKind::Server)
+        .with_attributes(vec![
+            KeyValue::new("http.method", req.method().to_string()),
+            KeyValue::new("http.route", route),
+        ])
+        .start_with_context(&tracer, &parent);
+    let cx = parent.with_span(span);
+
+    let response = {
+        let _guard = cx.clone().attach();
+        srv.call(req)
+    };
+
+    async move {
+        let response = response.with_context(cx.clone()).await;
+
+        let span = cx.span();
+        match &response {
+            Ok(res) => {
+                span.set_attribute(KeyValue::new("http.status_code", res.status().as_u16() as i64));
+                if res.status().is_server_error() {
+                    span.set_status(Status::error(res.status().to_string()));
+                }
+            },
+            Err(e) => span.set_status(Status::error(e.to_string())),
+        }
+        span.end();
+
+        response
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut reqwest::header::HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            reqwest::header::HeaderName::from_bytes(key.as_bytes()),
+            reqwest::header::HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
@@ -0,0 +1,312 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::admin;
+use crate::cache::RedisCache;
+use crate::config;
+use crate::error::{ServiceError, ServiceResult};
+use crate::handlers;
+use crate::params::Query;
+use crate::rate_limit::RateLimiter;
+use crate::render_pool;
+use crate::svg::{parse_color, RenderOptions, SvgProcessor};
+use svg_rasterizer_core::MAX_SVG_SIZE;
+
+const SVG_NS: &str = "http://www.w3.org/2000/svg";
+const XLINK_NS: &str = "http://www.w3.org/1999/xlink";
+const XML_NS: &str = "http://www.w3.org/XML/1998/namespace";
+// Elements that can run code or pull in HTML, dropped with their content
+const BLOCKED_ELEMENTS: &[&str] = &["script", "foreignObject"];
+const MAX_TEMPLATE_ID_LENGTH: usize = 64;
+// Longer values would only be useful for smuggling markup-sized content into templates
+const MAX_VARIABLE_LENGTH: usize = 1024;
+const VARIABLE_PREFIX: &str = "var.";
+
+// Stored SVGs with `{{name}}` placeholders, rendered by id with the placeholders filled
+// from `var.name` query parameters, e.g. for generating Open Graph images.
+
+#[derive(Deserialize, Debug)]
+pub struct TemplateRenderRequest {
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub preset: Option<String>,
+    pub background: Option<String>,
+    pub format: Option<String>,
+}
+
+fn template_key(id: &str) -> String {
+    format!("template:{}", id)
+}
+
+fn check_id(id: &str) -> ServiceResult<()> {
+    let valid = !id.is_empty()
+        && id.len() <= MAX_TEMPLATE_ID_LENGTH
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err(ServiceError::ValidationError(format!(
+            "Template ids are 1-{} letters, digits, '-' or '_'", MAX_TEMPLATE_ID_LENGTH)))
+    }
+}
+
+// PUT /templates/{id}: stores the SVG in the body, replacing any template with that
+// id. Requires the admin token.
+pub async fn put_template(
+    req: HttpRequest,
+    id: web::Path<String>,
+    body: web::Bytes,
+    cache: web::Data<Arc<RedisCache>>,
+    client: web::Data<reqwest::Client>,
+) -> ServiceResult<HttpResponse> {
+    let config = config::current();
+    admin::authorize(&req, &config)?;
+    check_id(&id)?;
+
+    svg_rasterizer_core::check_size(body.len(), MAX_SVG_SIZE)?;
+    let svg_data = std::str::from_utf8(&body)
+        .map_err(|_| ServiceError::ValidationError("Template must be UTF-8".to_string()))?;
+    svg_rasterizer_core::check_content(svg_data)?;
+    let template = sanitize(svg_data)?;
+
+    // Must still be an SVG the renderer accepts once the placeholders are filled
+    let variables = variables(&template);
+    SvgProcessor::new(&client).parse(&substitute(&template, &HashMap::new()))?;
+
+    cache.set_persistent(&template_key(&id), template.as_bytes()).await?;
+    log::info!("Stored template {} ({} bytes, variables: {:?})", id, template.len(), variables);
+
+    Ok(HttpResponse::Ok().json(json!({
+        "success": true,
+        "id": *id,
+        "size": template.len(),
+        "variables": variables,
+        "renderUrl": format!("{}/templates/{}/render", config.public_base_url, id),
+    })))
+}
+
+// GET /templates/{id}/render?var.title=...&width=...
+pub async fn render_template(
+    req: HttpRequest,
+    id: web::Path<String>,
+    query: Query<TemplateRenderRequest>,
+    cache: web::Data<Arc<RedisCache>>,
+    rate_limiter: web::Data<RateLimiter>,
+    client: web::Data<reqwest::Client>,
+) -> ServiceResult<HttpResponse> {
+    let config = config::current();
+    if !rate_limiter.check_rate().await {
+        log::warn!("Rate limit exceeded for template render");
+        return Err(ServiceError::RateLimitExceeded);
+    }
+    check_id(&id)?;
+
+    let mut values = HashMap::new();
+    for (name, value) in url::form_urlencoded::parse(req.query_string().as_bytes()) {
+        if let Some(name) = name.strip_prefix(VARIABLE_PREFIX) {
+            if value.len() > MAX_VARIABLE_LENGTH {
+                return Err(ServiceError::InvalidParameter(
+                    format!("{}{}", VARIABLE_PREFIX, name), format!("must be at most {} bytes", MAX_VARIABLE_LENGTH)));
+            }
+            values.insert(name.to_string(), value.into_owned());
+        }
+    }
+
+    let template = cache.get(&template_key(&id)).await?
+        .ok_or_else(|| ServiceError::NotFound(format!("Template {} not found", id)))?;
+    let template = String::from_utf8(template)
+        .map_err(|_| ServiceError::CacheError(format!("Template {} is not UTF-8", id)))?;
+    let svg_data = substitute(&template, &values);
+    // Values are limited one by one, but a template can use each of them many times
+    svg_rasterizer_core::check_size(svg_data.len(), MAX_SVG_SIZE)?;
+
+    let (width, height) = config.resolve_size(query.width, query.height, query.preset.as_deref())?;
+    let mut options = RenderOptions::new(width, height);
+    options.background = query.background.as_deref().map(parse_color).transpose()?;
+    options.format = handlers::output_format(query.format.as_deref())?;
+
+    // Keyed on the filled-in SVG, so replacing the template never serves stale renders
+    let cache_key = format!("template:render:{}:{}x{}{}",
+        hex::encode(Sha256::digest(svg_data.as_bytes())), width, height, options.variant_key());
+    let image_data = match cache.get(&cache_key).await? {
+        Some(image_data) => image_data,
+        None => {
+            render_pool::check_load()?;
+            let image_data = SvgProcessor::new(&client).process_svg(svg_data, &options).await?;
+            cache.set(&cache_key, &image_data, config.cache_ttl()).await?;
+            image_data
+        },
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(options.format.content_type())
+        .body(image_data))
+}
+
+// Placeholder names in order of first use
+fn variables(template: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start + 2..].find("}}") else { break };
+        let name = rest[start + 2..start + 2 + end].trim();
+        if is_variable_name(name) && !names.iter().any(|known| known == name) {
+            names.push(name.to_string());
+        }
+        rest = &rest[start + 2 + end + 2..];
+    }
+    names
+}
+
+fn is_variable_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.')
+}
+
+// Fills every placeholder with its XML-escaped value, missing values with nothing
+fn substitute(template: &str, values: &HashMap<String, String>) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        match after.find("}}").map(|end| (after[..end].trim(), end)) {
+            Some((name, end)) if is_variable_name(name) => {
+                output.push_str(&escape(values.get(name).map(String::as_str).unwrap_or_default()));
+                rest = &after[end + 2..];
+            },
+            _ => {
+                output.push_str("{{");
+                rest = after;
+            },
+        }
+    }
+    output.push_str(rest);
+    output
+}
+
+fn escape(value: &str) -> String {
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+// Rewrites the SVG keeping only SVG elements without script or foreign content, no
+// event handler attributes and only local or data: references. Comments, processing
+// instructions and editor metadata in other namespaces are dropped.
+fn sanitize(svg_data: &str) -> ServiceResult<String> {
+    let document = roxmltree::Document::parse(svg_data)
+        .map_err(|e| ServiceError::ValidationError(format!("Invalid template: {}", e)))?;
+    let root = document.root_element();
+    if root.tag_name().name() != "svg" || root.tag_name().namespace() != Some(SVG_NS) {
+        return Err(ServiceError::ValidationError("Template root must be an <svg> element".to_string()));
+    }
+
+    let mut output = String::with_capacity(svg_data.len());
+    write_element(root, &mut output, true);
+    Ok(output)
+}
+
+fn write_element(node: roxmltree::Node, output: &mut String, is_root: bool) {
+    let name = node.tag_name().name();
+    output.push('<');
+    output.push_str(name);
+    if is_root {
+        output.push_str(&format!(" xmlns=\"{}\" xmlns:xlink=\"{}\"", SVG_NS, XLINK_NS));
+    }
+
+    for attribute in node.attributes() {
+        let prefix = match attribute.namespace() {
+            None => "",
+            Some(XLINK_NS) => "xlink:",
+            Some(XML_NS) => "xml:",
+            Some(_) => continue,
+        };
+        let local = attribute.name();
+        if local.to_ascii_lowercase().starts_with("on") {
+            continue;
+        }
+        let value = attribute.value();
+        if local == "href" && !(value.starts_with('#') || value.starts_with("data:image/")) {
+            continue;
+        }
+        output.push_str(&format!(" {}{}=\"{}\"", prefix, local, escape(value)));
+    }
+    output.push('>');
+
+    for child in node.children() {
+        if child.is_text() {
+            output.push_str(&escape(child.text().unwrap_or_default()));
+        } else if child.is_element()
+            && child.tag_name().namespace() == Some(SVG_NS)
+            && !BLOCKED_ELEMENTS.contains(&child.tag_name().name())
+        {
+            write_element(child, output, false);
+        }
+    }
+
+    output.push_str(&format!("</{}>", name));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitized(body: &str) -> String {
+        sanitize(&format!(r#"<svg xmlns="http://www.w3.org/2000/svg" xmlns:xlink="http://www.w3.org/1999/xlink">{}</svg>"#, body)).unwrap()
+    }
+
+    #[test]
+    fn drops_scripts_and_foreign_content() {
+        let output = sanitized(r#"<script>alert(1)</script><foreignObject><div xmlns="http://www.w3.org/1999/xhtml">x</div></foreignObject><rect width="1"/>"#);
+        assert!(!output.contains("script") && !output.contains("alert"), "{}", output);
+        assert!(!output.contains("foreignObject") && !output.contains("div"), "{}", output);
+        assert!(output.contains(r#"<rect width="1"></rect>"#), "{}", output);
+    }
+
+    #[test]
+    fn drops_event_handlers() {
+        let output = sanitized(r#"<rect onclick="alert(1)" ONLOAD="alert(2)" onmouseover="x" width="1"/>"#);
+        assert!(!output.to_ascii_lowercase().contains("alert") && !output.contains("mouseover"), "{}", output);
+        assert!(output.contains(r#"width="1""#));
+    }
+
+    #[test]
+    fn keeps_only_local_and_data_references() {
+        let output = sanitized(concat!(
+            r##"<use href="#local"/><use xlink:href="https://evil.example/x.svg#a"/>"##,
+            r#"<image href="data:image/png;base64,AAAA"/><a href="javascript:alert(1)"><text>t</text></a>"#,
+        ));
+        assert!(output.contains(r##"href="#local""##), "{}", output);
+        assert!(output.contains(r#"href="data:image/png;base64,AAAA""#), "{}", output);
+        assert!(!output.contains("evil.example") && !output.contains("javascript"), "{}", output);
+    }
+
+    #[test]
+    fn rejects_non_svg_roots() {
+        assert!(sanitize("<html/>").is_err());
+        assert!(sanitize("not xml").is_err());
+    }
+
+    #[test]
+    fn escapes_substituted_values() {
+        let values = HashMap::from([("title".to_string(), r#"<script>"&'</script>"#.to_string())]);
+        let output = substitute(r#"<text data-title="{{ title }}">{{title}}</text>"#, &values);
+        assert_eq!(output, concat!(
+            r#"<text data-title="&lt;script&gt;&quot;&amp;&apos;&lt;/script&gt;">"#,
+            "&lt;script&gt;&quot;&amp;&apos;&lt;/script&gt;</text>",
+        ));
+    }
+
+    #[test]
+    fn leaves_missing_values_empty_and_non_placeholders_alone() {
+        let output = substitute("{{missing}}|{{not a name}}|{{unclosed", &HashMap::new());
+        assert_eq!(output, "|{{not a name}}|{{unclosed");
+        assert_eq!(variables("{{b}} {{a}} {{b}} {{not a name}}"), ["b", "a"]);
+    }
+}
@@ -0,0 +1,244 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::http::header;
+use actix_web::{web, HttpResponse};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::cache::RedisCache;
+use crate::config::{self, Config, IpNetwork, TenantProfile};
+use crate::error::{ServiceError, ServiceResult};
+use crate::request_context::{self, API_KEY_HEADER};
+use crate::svg::host_matches;
+
+// How often other instances pick up tenants changed through the admin API
+const REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+const REDACTED: &str = "[redacted]";
+
+// Tenant profiles from TENANTS and from Redis, stored under `tenant:{name}` by the
+// admin API. Requests are matched to a tenant by API key, or else by host, and
+// handled with its policies applied to the configuration.
+
+static STORED: RwLock<Vec<(String, TenantProfile)>> = RwLock::new(Vec::new());
+static REFRESHING: AtomicBool = AtomicBool::new(false);
+
+fn tenant_key(name: &str) -> String {
+    format!("tenant:{}", name)
+}
+
+// Loads the stored tenants now and then every REFRESH_INTERVAL
+pub async fn start(cache: Arc<RedisCache>) {
+    if REFRESHING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    if let Err(e) = refresh(&cache).await {
+        log::error!("Failed to load tenants from Redis: {}", e);
+    }
+
+    actix_web::rt::spawn(async move {
+        let mut ticker = tokio::time::interval(REFRESH_INTERVAL);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Err(e) = refresh(&cache).await {
+                log::warn!("Failed to refresh tenants, keeping the loaded ones: {}", e);
+            }
+        }
+    });
+}
+
+async fn refresh(cache: &RedisCache) -> ServiceResult<()> {
+    let mut tenants = Vec::new();
+    for key in cache.keys_matching(&tenant_key("*")).await? {
+        let Some(data) = cache.read(&key).await? else {
+            continue;
+        };
+        match serde_json::from_slice(&data) {
+            Ok(profile) => tenants.push((key[tenant_key("").len()..].to_string(), profile)),
+            Err(e) => log::warn!("Ignoring invalid tenant {}: {}", key, e),
+        }
+    }
+    *STORED.write().unwrap() = tenants;
+    Ok(())
+}
+
+// The tenant a request's key identifies, or else the one serving its host. Tenants
+// from the configuration take precedence over stored ones of the same name.
+fn resolve(config: &Config, api_key: Option<&str>, host: &str) -> Option<(String, TenantProfile)> {
+    let stored = STORED.read().unwrap();
+    let tenants = config.tenants.iter()
+        .chain(stored.iter().filter(|(name, _)| !config.tenants.contains_key(name)).map(|(name, profile)| (name, profile)))
+        .collect::<Vec<_>>();
+
+    if let Some(api_key) = api_key {
+        let digest = Sha256::digest(api_key.as_bytes());
+        let hash = hex::encode(digest);
+        let by_key = tenants.iter().find(|(_, profile)| {
+            profile.api_keys.iter().any(|key| Sha256::digest(key.as_bytes()) == digest)
+                || profile.api_key_hashes.iter().any(|known| known.eq_ignore_ascii_case(&hash))
+        });
+        if let Some((name, profile)) = by_key {
+            return Some(((*name).clone(), (*profile).clone()));
+        }
+    }
+    tenants.iter()
+        .find(|(_, profile)| profile.hosts.iter().any(|pattern| host_matches(&pattern.to_lowercase(), host)))
+        .map(|(name, profile)| ((*name).clone(), (*profile).clone()))
+}
+
+// The configuration of the tenant called `name` with its policies applied, for work
+// that runs after the tenant's request, like queued jobs
+pub fn config_for(config: &Config, name: &str) -> Option<Arc<Config>> {
+    let stored = STORED.read().unwrap();
+    config.tenants.get(name)
+        .or_else(|| stored.iter().find(|(stored_name, _)| stored_name == name).map(|(_, profile)| profile))
+        .map(|profile| Arc::new(profile.apply(config)))
+}
+
+// Middleware for the public routes, making the tenant's configuration the one
+// config::current() returns for the rest of the request
+pub async fn handle(req: ServiceRequest, next: Next<impl MessageBody + 'static>) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let config = config::current();
+    let api_key = req.headers().get(API_KEY_HEADER).and_then(|v| v.to_str().ok());
+    identify(&config, api_key, &request_host(&req, &config.trusted_proxies));
+    next.call(req).await.map(ServiceResponse::map_into_boxed_body)
+}
+
+// Makes the tenant the key or host (with or without a port) identify the one of the
+// current request
+pub fn identify(config: &Config, api_key: Option<&str>, host: &str) {
+    let host = host.to_lowercase();
+    let host = host.rsplit_once(':').filter(|(_, port)| port.chars().all(|c| c.is_ascii_digit())).map_or(host.as_str(), |(host, _)| host);
+
+    if let Some((name, profile)) = resolve(config, api_key, host) {
+        request_context::set_tenant(&name, Arc::new(profile.apply(config)));
+    }
+}
+
+// The Host header, or the :authority of an HTTP/2 request. Forwarded and
+// X-Forwarded-Host are only believed from TRUSTED_PROXIES, anyone else could use them
+// to pass as another tenant.
+fn request_host(req: &ServiceRequest, trusted_proxies: &[IpNetwork]) -> String {
+    let from_proxy = req.peer_addr().is_some_and(|addr| trusted_proxies.iter().any(|network| network.contains(addr.ip())));
+    if from_proxy {
+        return req.connection_info().host().to_string();
+    }
+    req.headers().get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri().authority().map(|authority| authority.as_str()))
+        .unwrap_or_default()
+        .to_string()
+}
+
+// GET /admin/tenants: every tenant with where it's defined, keys redacted
+pub async fn list_tenants() -> ServiceResult<HttpResponse> {
+    let config = config::current();
+    let redact = |profile: &TenantProfile, source: &str| {
+        let mut value = serde_json::to_value(profile).unwrap_or_default();
+        value["api_keys"] = profile.api_keys.iter().map(|_| REDACTED).collect::<Vec<_>>().into();
+        value["source"] = source.into();
+        value
+    };
+
+    let mut tenants = serde_json::Map::new();
+    for (name, profile) in STORED.read().unwrap().iter() {
+        tenants.insert(name.clone(), redact(profile, "redis"));
+    }
+    for (name, profile) in &config.tenants {
+        tenants.insert(name.clone(), redact(profile, "config"));
+    }
+    Ok(HttpResponse::Ok().json(json!({ "tenants": tenants })))
+}
+
+// PUT /admin/tenants/{name}: creates or replaces a stored tenant. Its API keys are
+// stored as hashes.
+pub async fn put_tenant(
+    name: web::Path<String>,
+    profile: web::Json<TenantProfile>,
+    cache: web::Data<Arc<RedisCache>>,
+) -> ServiceResult<HttpResponse> {
+    if config::current().tenants.contains_key(name.as_str()) {
+        return Err(ServiceError::Conflict(format!("Tenant {} is defined in the configuration", name)));
+    }
+    let mut profile = profile.into_inner();
+    let problems = profile.problems(&name);
+    if !problems.is_empty() {
+        return Err(ServiceError::ValidationError(problems.join("; ")));
+    }
+
+    let hashes = profile.api_keys.drain(..).map(|key| hex::encode(Sha256::digest(key.as_bytes())));
+    profile.api_key_hashes.extend(hashes.collect::<Vec<_>>());
+    profile.api_key_hashes.sort();
+    profile.api_key_hashes.dedup();
+
+    let data = serde_json::to_vec(&profile)
+        .map_err(|e| ServiceError::CacheError(format!("Failed to serialize tenant: {}", e)))?;
+    cache.set_persistent(&tenant_key(&name), &data).await?;
+    refresh(&cache).await?;
+    log::info!("Stored tenant {}", name);
+
+    Ok(HttpResponse::Ok().json(profile))
+}
+
+// DELETE /admin/tenants/{name}
+pub async fn delete_tenant(
+    name: web::Path<String>,
+    cache: web::Data<Arc<RedisCache>>,
+) -> ServiceResult<HttpResponse> {
+    if cache.read(&tenant_key(&name)).await?.is_none() {
+        return Err(ServiceError::NotFound(format!("Tenant {} not found", name)));
+    }
+    cache.delete(&tenant_key(&name)).await?;
+    refresh(&cache).await?;
+    log::info!("Deleted tenant {}", name);
+
+    Ok(HttpResponse::Ok().json(json!({ "deleted": *name })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request_context::RequestFields;
+
+    fn config() -> Config {
+        let mut config = Config::default();
+        config.tenants.insert("acme".to_string(), TenantProfile {
+            hosts: vec!["*.acme.example".to_string()],
+            allowed_domains: vec!["assets.acme.example".to_string()],
+            ..TenantProfile::default()
+        });
+        config
+    }
+
+    #[test]
+    fn looks_up_tenants_by_name() {
+        let tenant_config = config_for(&config(), "acme").unwrap();
+        assert_eq!(tenant_config.allowed_source_domains, ["assets.acme.example"]);
+        assert!(config_for(&config(), "gone").is_none());
+    }
+
+    #[tokio::test]
+    async fn applies_a_tenants_policies_outside_its_request() {
+        let tenant_config = config_for(&config(), "acme").unwrap();
+        request_context::scope(request_context::detached(RequestFields::default()), async {
+            request_context::set_tenant("acme", tenant_config);
+            assert_eq!(request_context::tenant().as_deref(), Some("acme"));
+            assert_eq!(config::current().allowed_source_domains, ["assets.acme.example"]);
+        }).await;
+    }
+
+    #[tokio::test]
+    async fn identifies_tenants_by_host_with_or_without_port() {
+        for host in ["www.acme.example", "WWW.ACME.EXAMPLE:8080"] {
+            let tenant = request_context::scope(request_context::detached(RequestFields::default()), async {
+                identify(&config(), None, host);
+                request_context::tenant()
+            }).await;
+            assert_eq!(tenant.as_deref(), Some("acme"), "{}", host);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use actix_tls::accept::rustls_0_21::TlsStream;
+use actix_web::dev::{Extensions, ServiceRequest};
+use actix_web::rt::net::TcpStream;
+use rustls::server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use std::any::Any;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::config::{self, Config};
+use crate::error::{ServiceResult, ServiceError};
+
+// How often the certificate and key files are checked for changes
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// Marks connections whose client presented a certificate the verifier accepted
+struct ClientCertificate;
+
+// Hands out the current certificate, which the reload thread replaces when
+// the files on disk change, e.g. after a certbot or cert-manager renewal
+struct ReloadingResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for ReloadingResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+// TLS settings for the listener, None when TLS_CERT_PATH and TLS_KEY_PATH aren't set
+pub fn server_config(config: &Config) -> ServiceResult<Option<ServerConfig>> {
+    let (Some(cert_path), Some(key_path)) = (config.tls_cert_path.clone(), config.tls_key_path.clone()) else {
+        return Ok(None);
+    };
+
+    let resolver = Arc::new(ReloadingResolver {
+        current: RwLock::new(Arc::new(load(&cert_path, &key_path)?)),
+    });
+    log::info!("TLS enabled with certificate {}", cert_path);
+
+    let reloading = resolver.clone();
+    let spawned = std::thread::Builder::new()
+        .name("tls-reload".to_string())
+        .spawn(move || {
+            let mut loaded = modified(&cert_path, &key_path);
+            loop {
+                std::thread::sleep(RELOAD_CHECK_INTERVAL);
+                let current = modified(&cert_path, &key_path);
+                if current == loaded {
+                    continue;
+                }
+
+                match load(&cert_path, &key_path) {
+                    Ok(key) => {
+                        *reloading.current.write().unwrap() = Arc::new(key);
+                        loaded = current;
+                        log::info!("Reloaded TLS certificate {}", cert_path);
+                    },
+                    // Usually caught halfway through a renewal, try again on the next check
+                    Err(e) => log::warn!("Failed to reload TLS certificate, keeping the current one: {}", e),
+                }
+            }
+        });
+    if let Err(e) = spawned {
+        log::error!("Failed to start TLS certificate reloading: {}", e);
+    }
+
+    // actix-web adds h2 and http/1.1 to the ALPN protocols when binding
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let server_config = match &config.client_ca {
+        None => builder.with_no_client_auth().with_cert_resolver(resolver),
+        // Only the paths in CLIENT_CERT_PATHS need one, the rest stay open to clients without
+        Some(ca_path) if !config.client_cert_paths.is_empty() => {
+            log::info!("Verifying client certificates from {} for {:?}", ca_path, config.client_cert_paths);
+            let verifier = AllowAnyAnonymousOrAuthenticatedClient::new(load_roots(ca_path, "CLIENT_CA")?);
+            builder.with_client_cert_verifier(verifier.boxed()).with_cert_resolver(resolver)
+        },
+        Some(ca_path) => {
+            log::info!("https listeners require client certificates from {}", ca_path);
+            let verifier = AllowAnyAuthenticatedClient::new(load_roots(ca_path, "CLIENT_CA")?);
+            builder.with_client_cert_verifier(verifier.boxed()).with_cert_resolver(resolver)
+        },
+    };
+    Ok(Some(server_config))
+}
+
+// Records on each TLS connection whether the client authenticated with a certificate
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() else {
+        return;
+    };
+    if stream.get_ref().1.peer_certificates().is_some_and(|certs| !certs.is_empty()) {
+        data.insert(ClientCertificate);
+    }
+}
+
+// Whether the request may go through: paths in CLIENT_CERT_PATHS only answer on
+// connections with a verified client certificate, whichever listener they came in on
+pub fn client_allowed(req: &ServiceRequest) -> bool {
+    let config = config::current();
+    let path = req.path();
+    !config.client_cert_paths.iter().any(|prefix| path.starts_with(prefix.as_str()))
+        || req.conn_data::<ClientCertificate>().is_some()
+}
+
+// TLS settings for admin listeners with ADMIN_CLIENT_CA: the server certificate of
+// `public`, and only clients presenting a certificate signed by the CA get through
+pub fn admin_server_config(config: &Config, public: &ServerConfig) -> ServiceResult<Option<ServerConfig>> {
+    let Some(ca_path) = &config.admin_client_ca else {
+        return Ok(None);
+    };
+    let roots = load_roots(ca_path, "ADMIN_CLIENT_CA")?;
+    log::info!("Admin listeners require client certificates from {}", ca_path);
+
+    let server_config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        .with_cert_resolver(public.cert_resolver.clone());
+    Ok(Some(server_config))
+}
+
+// The CA bundle at `ca_path`, named by its setting in errors
+fn load_roots(ca_path: &str, setting: &str) -> ServiceResult<RootCertStore> {
+    let invalid = |detail: String| ServiceError::ValidationError(format!("Invalid {}: {}", setting, detail));
+
+    let mut reader = BufReader::new(File::open(ca_path).map_err(|e| invalid(format!("{}: {}", ca_path, e)))?);
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut reader).map_err(|e| invalid(format!("{}: {}", ca_path, e)))? {
+        roots.add(&Certificate(cert)).map_err(|e| invalid(e.to_string()))?;
+    }
+    if roots.is_empty() {
+        return Err(invalid(format!("no certificates in {}", ca_path)));
+    }
+    Ok(roots)
+}
+
+fn load(cert_path: &str, key_path: &str) -> ServiceResult<CertifiedKey> {
+    let invalid = |detail: String| ServiceError::ValidationError(format!("Invalid TLS certificate or key: {}", detail));
+
+    let mut cert_reader = BufReader::new(File::open(cert_path).map_err(|e| invalid(format!("{}: {}", cert_path, e)))?);
+    let certs: Vec<Certificate> = rustls_pemfile::certs(&mut cert_reader)
+        .map_err(|e| invalid(format!("{}: {}", cert_path, e)))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    if certs.is_empty() {
+        return Err(invalid(format!("no certificates in {}", cert_path)));
+    }
+
+    let mut key_reader = BufReader::new(File::open(key_path).map_err(|e| invalid(format!("{}: {}", key_path, e)))?);
+    let key = rustls_pemfile::read_all(&mut key_reader)
+        .map_err(|e| invalid(format!("{}: {}", key_path, e)))?
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key) | rustls_pemfile::Item::RSAKey(key) | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| invalid(format!("no private key in {}", key_path)))?;
+
+    let signing_key = rustls::sign::any_supported_type(&key).map_err(|e| invalid(e.to_string()))?;
+    Ok(CertifiedKey::new(certs, signing_key))
+}
+
+fn modified(cert_path: &str, key_path: &str) -> (Option<SystemTime>, Option<SystemTime>) {
+    let mtime = |path: &str| std::fs::metadata(path).and_then(|m| m.modified()).ok();
+    (mtime(cert_path), mtime(key_path))
+}
@@ -0,0 +1,124 @@
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+use crate::config::Config;
+use crate::error::ServiceResult;
+use crate::metrics::metrics;
+
+// A parsed tree is several times larger than its source, this is only an
+// estimate for the memory bound
+const TREE_SIZE_FACTOR: usize = 4;
+
+static MAX_BYTES_PER_THREAD: OnceLock<usize> = OnceLock::new();
+
+// usvg trees are reference counted and can't move between threads, so every
+// render thread keeps its own cache with an equal share of the memory budget
+thread_local! {
+    static CACHE: RefCell<TreeCache> = RefCell::new(TreeCache::default());
+}
+
+#[derive(Default)]
+struct TreeCache {
+    entries: HashMap<[u8; 32], Entry>,
+    bytes: usize,
+    tick: u64,
+}
+
+struct Entry {
+    tree: usvg::Tree,
+    bytes: usize,
+    last_used: u64,
+}
+
+pub fn configure(config: &Config) {
+    let per_thread = config.tree_cache_max_bytes / config.render_workers.max(1);
+    let _ = MAX_BYTES_PER_THREAD.set(per_thread);
+}
+
+// Returns the tree for this exact source, parsing it only on a miss. Callers get
+// their own copy, so changes to it never reach the cached tree.
+pub fn get_or_parse<F>(svg_data: &str, parse: F) -> ServiceResult<usvg::Tree>
+where
+    F: FnOnce(&str) -> ServiceResult<usvg::Tree>,
+{
+    let max_bytes = MAX_BYTES_PER_THREAD.get().copied().unwrap_or(0);
+    let bytes = svg_data.len() * TREE_SIZE_FACTOR;
+    if bytes > max_bytes {
+        return parse(svg_data);
+    }
+
+    let key: [u8; 32] = Sha256::digest(svg_data.as_bytes()).into();
+
+    let cached = CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        cache.tick += 1;
+        let tick = cache.tick;
+        cache.entries.get_mut(&key).map(|entry| {
+            entry.last_used = tick;
+            deep_copy(&entry.tree)
+        })
+    });
+    if let Some(tree) = cached {
+        metrics().tree_cache_lookups.with_label_values(&["hit"]).inc();
+        return Ok(tree);
+    }
+    metrics().tree_cache_lookups.with_label_values(&["miss"]).inc();
+
+    let tree = parse(svg_data)?;
+
+    CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+
+        // Evict least recently used trees until the new one fits
+        while cache.bytes + bytes > max_bytes {
+            let Some(oldest) = cache.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(key, _)| *key) else {
+                break;
+            };
+            if let Some(entry) = cache.entries.remove(&oldest) {
+                cache.bytes -= entry.bytes;
+                metrics().tree_cache_bytes.sub(entry.bytes as i64);
+            }
+        }
+
+        let last_used = cache.tick;
+        cache.bytes += bytes;
+        cache.entries.insert(key, Entry { tree: deep_copy(&tree), bytes, last_used });
+        metrics().tree_cache_bytes.add(bytes as i64);
+    });
+
+    Ok(tree)
+}
+
+// Cloning a usvg::Tree only clones the Rc of its root, so nodes changed through
+// one clone change in every other
+fn deep_copy(tree: &usvg::Tree) -> usvg::Tree {
+    usvg::Tree { size: tree.size, view_box: tree.view_box, root: tree.root.make_deep_copy() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use usvg::TreeParsing;
+
+    const SVG: &str = r#"<svg xmlns="http://www.w3.org/2000/svg" width="10" height="10"><rect id="a" width="10" height="10"/></svg>"#;
+
+    fn parse(svg_data: &str) -> ServiceResult<usvg::Tree> {
+        Ok(usvg::Tree::from_str(svg_data, &usvg::Options::default()).unwrap())
+    }
+
+    #[test]
+    fn changes_to_returned_trees_stay_out_of_the_cache() {
+        let _ = MAX_BYTES_PER_THREAD.set(1024 * 1024);
+
+        let tree = get_or_parse(SVG, parse).unwrap();
+        tree.root.first_child().unwrap().detach();
+        assert!(!tree.root.has_children());
+
+        let cached = get_or_parse(SVG, |_| panic!("should be cached")).unwrap();
+        assert!(cached.root.has_children());
+        cached.root.first_child().unwrap().detach();
+        assert!(get_or_parse(SVG, |_| panic!("should be cached")).unwrap().root.has_children());
+    }
+}
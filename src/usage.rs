@@ -0,0 +1,130 @@
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+use crate::cache::RedisCache;
+use crate::config::{self, Config};
+use crate::error::{ServiceResult, ServiceError};
+use crate::shutdown;
+
+const ANONYMOUS_CLIENT: &str = "anonymous";
+const DEFAULT_EXPORT_DAYS: i64 = 30;
+const MAX_EXPORT_DAYS: i64 = 366;
+
+static RETENTION: OnceLock<Option<Duration>> = OnceLock::new();
+
+#[derive(Deserialize, Debug)]
+pub struct UsageExportRequest {
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub format: Option<String>,
+}
+
+pub fn configure(config: &Config) {
+    let retention = config.usage_tracking.then(|| Duration::from_secs(config.usage_retention_days * 24 * 60 * 60));
+    let _ = RETENTION.set(retention);
+}
+
+pub fn enabled() -> bool {
+    matches!(RETENTION.get(), Some(Some(_)))
+}
+
+fn usage_key(date: chrono::NaiveDate) -> String {
+    format!("usage:{}", date.format("%Y-%m-%d"))
+}
+
+// Adds a served request to today's per-client totals, in the background
+pub fn record(cache: Arc<RedisCache>, client: Option<String>, bytes: u64) {
+    let Some(Some(retention)) = RETENTION.get().copied() else {
+        return;
+    };
+    let client = client.unwrap_or_else(|| ANONYMOUS_CLIENT.to_string());
+    let key = usage_key(chrono::Utc::now().date_naive());
+
+    shutdown::spawn(async move {
+        let increments = [(format!("{}:requests", client), 1), (format!("{}:bytes", client), bytes as i64)];
+        if let Err(e) = cache.hash_increment(&key, &increments, retention).await {
+            log::error!("Failed to record usage for {}: {}", client, e);
+        }
+    });
+}
+
+// Per day and client request counts and bytes served, as CSV or JSON
+pub async fn usage_export(
+    req: web::Query<UsageExportRequest>,
+    cache: web::Data<Arc<RedisCache>>,
+) -> ServiceResult<HttpResponse> {
+    let config = config::current();
+    if !config.usage_tracking {
+        return Err(ServiceError::NotFound("Usage tracking is disabled, set USAGE_TRACKING=true to enable it".to_string()));
+    }
+
+    let to = match &req.to {
+        Some(to) => parse_date(to)?,
+        None => chrono::Utc::now().date_naive(),
+    };
+    let from = match &req.from {
+        Some(from) => parse_date(from)?,
+        None => to - chrono::Duration::days(DEFAULT_EXPORT_DAYS - 1),
+    };
+    if from > to {
+        return Err(ServiceError::ValidationError("from must not be after to".to_string()));
+    }
+    if (to - from).num_days() >= MAX_EXPORT_DAYS {
+        return Err(ServiceError::ValidationError(format!("Export range is limited to {} days", MAX_EXPORT_DAYS)));
+    }
+
+    // date -> client -> (requests, bytes)
+    let mut rows: BTreeMap<chrono::NaiveDate, BTreeMap<String, (i64, i64)>> = BTreeMap::new();
+    for date in from.iter_days().take_while(|date| *date <= to) {
+        for (field, value) in cache.hash_get_all(&usage_key(date)).await? {
+            let Some((client, metric)) = field.rsplit_once(':') else {
+                continue;
+            };
+            let totals = rows.entry(date).or_default().entry(client.to_string()).or_default();
+            match metric {
+                "requests" => totals.0 += value,
+                "bytes" => totals.1 += value,
+                _ => {},
+            }
+        }
+    }
+
+    match req.format.as_deref().unwrap_or("json") {
+        "csv" => {
+            let mut csv = String::from("date,client,requests,bytes\n");
+            for (date, clients) in &rows {
+                for (client, (requests, bytes)) in clients {
+                    csv.push_str(&format!("{},{},{},{}\n", date, client, requests, bytes));
+                }
+            }
+            Ok(HttpResponse::Ok()
+                .content_type("text/csv")
+                .insert_header(("Content-Disposition", format!("attachment; filename=\"usage-{}-{}.csv\"", from, to)))
+                .body(csv))
+        },
+        "json" => {
+            let usage: Vec<_> = rows.iter()
+                .flat_map(|(date, clients)| clients.iter().map(move |(client, (requests, bytes))| serde_json::json!({
+                    "date": date.to_string(),
+                    "client": client,
+                    "requests": requests,
+                    "bytes": bytes,
+                })))
+                .collect();
+            Ok(HttpResponse::Ok().json(serde_json::json!({
+                "from": from.to_string(),
+                "to": to.to_string(),
+                "usage": usage,
+            })))
+        },
+        other => Err(ServiceError::ValidationError(format!("Unsupported format: {} (expected csv or json)", other))),
+    }
+}
+
+fn parse_date(value: &str) -> ServiceResult<chrono::NaiveDate> {
+    chrono::NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| ServiceError::ValidationError(format!("Invalid date: {} (expected YYYY-MM-DD)", value)))
+}
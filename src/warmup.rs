@@ -0,0 +1,125 @@
+use futures::stream::{self, StreamExt};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::cache::RedisCache;
+use crate::config::{self, Config};
+use crate::error::{ServiceResult, ServiceError};
+use crate::handlers::render_cached;
+use crate::svg::RenderOptions;
+
+// One asset and size to pre-render, sized like a render request
+#[derive(Deserialize, Debug)]
+struct WarmupEntry {
+    url: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    preset: Option<String>,
+}
+
+// Reads WARMUP_MANIFEST, a JSON array of entries, from a file or an http(s) URL
+async fn load_manifest(manifest: &str, client: &reqwest::Client) -> ServiceResult<Vec<WarmupEntry>> {
+    let data = if manifest.starts_with("http://") || manifest.starts_with("https://") {
+        let response = client.get(manifest).send().await?.error_for_status()?;
+        response.bytes().await?.to_vec()
+    } else {
+        tokio::fs::read(manifest)
+            .await
+            .map_err(|e| ServiceError::ValidationError(format!("Failed to read warm-up manifest {}: {}", manifest, e)))?
+    };
+
+    serde_json::from_slice(&data)
+        .map_err(|e| ServiceError::ValidationError(format!("Invalid warm-up manifest {}: {}", manifest, e)))
+}
+
+// Renders every manifest entry into the cache. Entries that are still cached are
+// left alone, failures are logged and skipped so one bad asset doesn't hold up the rest.
+// A warm-up running past WARMUP_TIMEOUT is abandoned, so a slow manifest or source
+// can't keep the service from becoming ready.
+pub async fn run(cache: &RedisCache, client: &reqwest::Client) {
+    let config = config::current();
+    let Some(manifest) = &config.warmup_manifest else {
+        return;
+    };
+
+    let timeout = Duration::from_secs(config.warmup_timeout_secs);
+    if tokio::time::timeout(timeout, warm(manifest, &config, cache, client)).await.is_err() {
+        log::warn!("Cache warm-up from {} abandoned after {:?}", manifest, timeout);
+    }
+}
+
+async fn warm(manifest: &str, config: &Config, cache: &RedisCache, client: &reqwest::Client) {
+
+    let entries = match load_manifest(manifest, client).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::error!("Skipping cache warm-up: {}", e);
+            return;
+        },
+    };
+
+    log::info!("Warming the cache with {} entries from {}", entries.len(), manifest);
+    let start = Instant::now();
+    let rendered = stream::iter(&entries)
+        .map(|entry| render_entry(entry, config, cache, client))
+        .buffer_unordered(config.warmup_concurrency)
+        .filter(|result| futures::future::ready(*result))
+        .count()
+        .await;
+    log::info!("Cache warm-up finished in {:?}, {} of {} entries rendered", start.elapsed(), rendered, entries.len());
+}
+
+async fn render_entry(entry: &WarmupEntry, config: &Config, cache: &RedisCache, client: &reqwest::Client) -> bool {
+    let rendered = match config.resolve_size(entry.width, entry.height, entry.preset.as_deref()) {
+        Ok((width, height)) => render_cached(&entry.url, &RenderOptions::new(width, height), cache, client).await,
+        Err(e) => Err(e),
+    };
+
+    match rendered {
+        Ok(_) => true,
+        Err(e) => {
+            log::warn!("Failed to warm {:?}: {}", entry, e);
+            false
+        },
+    }
+}
+
+// Warms the cache again every WARMUP_INTERVAL, re-reading the manifest each time,
+// so entries that expired in the meantime are rendered before someone asks for them
+pub fn schedule(cache: Arc<RedisCache>, client: reqwest::Client) {
+    let interval = config::current().warmup_interval_secs;
+    if interval == 0 || config::current().warmup_manifest.is_none() {
+        return;
+    }
+
+    actix_web::rt::spawn(async move {
+        let mut ticker = tokio::time::interval(Duration::from_secs(interval));
+        // The first tick completes immediately, right after the startup run
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            run(&cache, &client).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_manifest_files() {
+        let path = std::env::temp_dir().join(format!("warmup-{}.json", uuid::Uuid::new_v4()));
+        std::fs::write(&path, r#"[{"url": "https://example.com/logo.svg", "preset": "thumb"}]"#).unwrap();
+        let client = reqwest::Client::new();
+
+        let entries = load_manifest(path.to_str().unwrap(), &client).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].url, "https://example.com/logo.svg");
+        assert_eq!(entries[0].preset.as_deref(), Some("thumb"));
+
+        assert!(load_manifest(path.to_str().unwrap(), &client).await.is_err());
+    }
+}
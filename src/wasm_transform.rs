@@ -0,0 +1,129 @@
+use serde_json::json;
+use std::sync::OnceLock;
+use svg_rasterizer_core::MAX_SVG_SIZE;
+use wasmi::{Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::config::Config;
+use crate::error::{ServiceError, ServiceResult};
+use crate::hooks::{self, SvgTransformHook};
+
+// SVG transforms written in any language that compiles to WebAssembly. The module
+// exports its `memory`, `alloc(len: i32) -> i32` and
+// `transform(svg_ptr: i32, svg_len: i32, params_ptr: i32, params_len: i32) -> i64`.
+// The parameters are JSON, `{"url": ..., "params": {<query parameters>}}`, and the
+// result is the transformed SVG's pointer in the high and length in the low 32 bits,
+// or a negative value to reject the SVG. Each call gets a fresh instance with no
+// imports, limited to WASM_TRANSFORM_FUEL and WASM_TRANSFORM_MAX_MEMORY.
+pub struct WasmTransform {
+    engine: Engine,
+    module: Module,
+    fuel: u64,
+    max_memory: usize,
+}
+
+struct State {
+    limits: StoreLimits,
+}
+
+impl WasmTransform {
+    pub fn load(path: &str, config: &Config) -> Result<Self, String> {
+        let wasm = std::fs::read(path)
+            .map_err(|e| format!("Failed to read WASM_TRANSFORM {}: {}", path, e))?;
+
+        let mut engine_config = wasmi::Config::default();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config);
+        let module = Module::new(&engine, &wasm)
+            .map_err(|e| format!("Invalid WASM_TRANSFORM {}: {}", path, e))?;
+
+        let transform = Self {
+            engine,
+            module,
+            fuel: config.wasm_transform_fuel,
+            max_memory: config.wasm_transform_max_memory,
+        };
+        // Fail at startup rather than on the first request when the exports are missing
+        transform.instantiate()
+            .map_err(|e| format!("Invalid WASM_TRANSFORM {}: {}", path, e))?;
+        Ok(transform)
+    }
+
+    fn instantiate(&self) -> Result<(Store<State>, wasmi::Instance), String> {
+        let mut store = Store::new(&self.engine, State {
+            limits: StoreLimitsBuilder::new()
+                .memory_size(self.max_memory)
+                .instances(1)
+                .build(),
+        });
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(self.fuel).map_err(|e| e.to_string())?;
+
+        let instance = Linker::<State>::new(&self.engine)
+            .instantiate(&mut store, &self.module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(|e| e.to_string())?;
+        if instance.get_memory(&store, "memory").is_none() {
+            return Err("module doesn't export its memory".to_string());
+        }
+        instance.get_typed_func::<i32, i32>(&store, "alloc").map_err(|e| format!("alloc: {}", e))?;
+        instance.get_typed_func::<(i32, i32, i32, i32), i64>(&store, "transform").map_err(|e| format!("transform: {}", e))?;
+        Ok((store, instance))
+    }
+
+    fn run(&self, svg: &str, params: &str) -> Result<String, String> {
+        let (mut store, instance) = self.instantiate()?;
+        let memory = instance.get_memory(&store, "memory").ok_or("module doesn't export its memory")?;
+        let alloc = instance.get_typed_func::<i32, i32>(&store, "alloc").map_err(|e| e.to_string())?;
+        let transform = instance.get_typed_func::<(i32, i32, i32, i32), i64>(&store, "transform").map_err(|e| e.to_string())?;
+
+        let mut write = |data: &[u8]| -> Result<(i32, i32), String> {
+            let len = i32::try_from(data.len()).map_err(|_| "input too large".to_string())?;
+            let ptr = alloc.call(&mut store, len).map_err(|e| e.to_string())?;
+            memory.write(&mut store, ptr as u32 as usize, data).map_err(|e| e.to_string())?;
+            Ok((ptr, len))
+        };
+        let (svg_ptr, svg_len) = write(svg.as_bytes())?;
+        let (params_ptr, params_len) = write(params.as_bytes())?;
+
+        let result = transform.call(&mut store, (svg_ptr, svg_len, params_ptr, params_len)).map_err(|e| e.to_string())?;
+        if result < 0 {
+            return Err("the module rejected the SVG".to_string());
+        }
+        let (ptr, len) = ((result >> 32) as u32 as usize, result as u32 as usize);
+        // Before allocating, the length comes from the module
+        svg_rasterizer_core::check_size(len, MAX_SVG_SIZE).map_err(|e| e.to_string())?;
+        let mut output = vec![0; len];
+        memory.read(&store, ptr, &mut output).map_err(|e| e.to_string())?;
+        String::from_utf8(output).map_err(|_| "output is not UTF-8".to_string())
+    }
+}
+
+impl SvgTransformHook for WasmTransform {
+    fn transform(&self, url: &str, svg: String) -> ServiceResult<String> {
+        let params = json!({
+            "url": url,
+            "params": hooks::query_params().into_iter().collect::<std::collections::HashMap<_, _>>(),
+        });
+        let transformed = self.run(&svg, &params.to_string())
+            .map_err(|e| ServiceError::SvgProcessingError(format!("WASM transform failed: {}", e)))?;
+        // The module's output is checked like any fetched SVG
+        svg_rasterizer_core::check_content(&transformed)?;
+        Ok(transformed)
+    }
+}
+
+static REGISTERED: OnceLock<Result<(), String>> = OnceLock::new();
+
+// Registers WASM_TRANSFORM as an SVG transform hook. Like the rest of the startup
+// configuration only the first call counts; the module isn't reloaded.
+pub fn register(config: &Config) -> ServiceResult<()> {
+    REGISTERED.get_or_init(|| {
+        let Some(path) = &config.wasm_transform else {
+            return Ok(());
+        };
+        let transform = WasmTransform::load(path, config)?;
+        log::info!("Loaded WASM transform {}", path);
+        hooks::register_svg_transform(transform);
+        Ok(())
+    }).clone().map_err(ServiceError::ValidationError)
+}
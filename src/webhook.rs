@@ -0,0 +1,156 @@
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use serde_json::json;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::dns;
+use crate::error::{ServiceResult, ServiceError};
+use crate::jobs::Job;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Callbacks go to the submitter's own server, so hosts that resolve to loopback,
+// private or link-local addresses (like cloud metadata endpoints) are rejected
+pub async fn validate_callback_url(callback_url: &str) -> ServiceResult<()> {
+    callback_address(callback_url).await.map(|_| ())
+}
+
+// The domain of the callback URL and the checked address it's delivered to, None for
+// an IP address host
+async fn callback_address(callback_url: &str) -> ServiceResult<Option<(String, SocketAddr)>> {
+    let invalid = |message: String| ServiceError::ValidationError(format!("Invalid callback_url: {}", message));
+    let parsed = url::Url::parse(callback_url).map_err(|e| invalid(e.to_string()))?;
+
+    match parsed.scheme() {
+        "http" | "https" => {},
+        scheme => return Err(ServiceError::ValidationError(
+            format!("Unsupported callback_url scheme: {}", scheme)
+        )),
+    }
+
+    let port = parsed.port_or_known_default().unwrap_or(80);
+    let (domain, addrs) = match parsed.host() {
+        Some(url::Host::Ipv4(ip)) => (None, vec![SocketAddr::new(ip.into(), port)]),
+        Some(url::Host::Ipv6(ip)) => (None, vec![SocketAddr::new(ip.into(), port)]),
+        Some(url::Host::Domain(domain)) => {
+            let addrs = tokio::net::lookup_host((domain, port)).await
+                .map_err(|e| invalid(format!("can't resolve {}: {}", domain, e)))?
+                .collect::<Vec<_>>();
+            (Some(domain.to_string()), addrs)
+        },
+        None => return Err(invalid("no host".to_string())),
+    };
+
+    // Every address must be public, or the connection could pick the internal one
+    if let Some(addr) = addrs.iter().find(|addr| !dns::is_public(addr.ip())) {
+        return Err(invalid(format!("{} is not a public address", addr.ip())));
+    }
+    let addr = *addrs.first().ok_or_else(|| invalid("host has no addresses".to_string()))?;
+    Ok(domain.map(|domain| (domain, addr)))
+}
+
+// Signature over "{timestamp}.{body}" so receivers can reject replayed payloads
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+        .expect("HMAC accepts keys of any size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+// Delivered with a client of its own, so it connects to the address checked here even if
+// DNS answers differently by now, and never follows a redirect to an internal host
+pub async fn send_job_callback(
+    config: &Config,
+    callback_url: &str,
+    job: &Job,
+) -> ServiceResult<()> {
+    let body = serde_json::to_vec(&json!({
+        "jobId": job.id,
+        "status": job.status,
+        "error": job.error,
+        "resultUrl": format!("{}/jobs/{}/result", config.public_base_url, job.id),
+    })).map_err(|e| ServiceError::SvgProcessingError(format!("Failed to serialize callback: {}", e)))?;
+
+    let mut client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(CALLBACK_TIMEOUT);
+    if let Some((domain, addr)) = callback_address(callback_url).await? {
+        client = client.resolve(&domain, addr);
+    }
+    let client = client.build()
+        .map_err(|e| ServiceError::SvgProcessingError(format!("Failed to create callback client: {}", e)))?;
+
+    let timestamp = chrono::Utc::now().timestamp();
+    let mut request = client
+        .post(callback_url)
+        .header("content-type", "application/json")
+        .header("x-webhook-timestamp", timestamp.to_string());
+
+    if let Some(secret) = &config.webhook_secret {
+        request = request.header("x-webhook-signature", format!("sha256={}", sign(secret, timestamp, &body)));
+    }
+
+    let response = request.body(body).send().await?;
+
+    if !response.status().is_success() {
+        return Err(ServiceError::SvgProcessingError(
+            format!("Callback returned HTTP {}", response.status())
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = br#"{"jobId":"abc","status":"completed"}"#;
+
+    #[test]
+    fn signs_timestamp_and_body() {
+        assert_eq!(sign("whsec_test", 1_700_000_000, BODY), "2433ad8af6226f9c3968081cd3d62bc1d918981ebf1acb43c528df99b0c62d28");
+    }
+
+    #[test]
+    fn signature_changes_with_timestamp_secret_and_body() {
+        let signature = sign("whsec_test", 1_700_000_000, BODY);
+        assert_eq!(sign("whsec_test", 1_700_000_001, BODY), "3aaf91f38a01056de4abbdb7a92b13df98ffcadb2e7e1677dfe1cd858fec60b0");
+        assert_ne!(sign("whsec_other", 1_700_000_000, BODY), signature);
+        assert_ne!(sign("whsec_test", 1_700_000_000, br#"{"jobId":"abd","status":"completed"}"#), signature);
+    }
+
+    #[tokio::test]
+    async fn accepts_public_http_callbacks() {
+        assert!(validate_callback_url("https://93.184.216.34/hook").await.is_ok());
+        assert!(validate_callback_url("http://[2606:4700::1111]:8080/hook").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_internal_callbacks() {
+        for url in [
+            "http://127.0.0.1/hook",
+            "http://localhost:8080/hook",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://10.0.0.5/hook",
+            "http://192.168.1.1/hook",
+            "http://[::1]/hook",
+            "http://[::ffff:127.0.0.1]/hook",
+            "http://0.0.0.0/hook",
+        ] {
+            assert!(matches!(validate_callback_url(url).await, Err(ServiceError::ValidationError(_))), "{}", url);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_other_schemes_and_malformed_urls() {
+        assert!(matches!(validate_callback_url("ftp://93.184.216.34/hook").await, Err(ServiceError::ValidationError(_))));
+        assert!(matches!(validate_callback_url("not a url").await, Err(ServiceError::ValidationError(_))));
+    }
+}
@@ -0,0 +1,185 @@
+use actix_web::web;
+use redis::streams::StreamId;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::cache::RedisCache;
+use crate::config::Config;
+use crate::error::{ServiceResult, ServiceError};
+use crate::svg::RenderOptions;
+use crate::handlers::render_cached;
+use crate::jobs;
+use crate::tenants;
+
+const READ_BLOCK: Duration = Duration::from_secs(5);
+const READ_COUNT: usize = 10;
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+// Delay before retrying a failing call, doubling with every failure up to
+// MAX_RETRY_DELAY until reset by a success
+pub(crate) struct Backoff {
+    delay: Duration,
+}
+
+impl Backoff {
+    pub(crate) fn new() -> Self {
+        Self { delay: RETRY_DELAY }
+    }
+
+    pub(crate) async fn wait(&mut self) {
+        tokio::time::sleep(self.next()).await;
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.delay = RETRY_DELAY;
+    }
+
+    fn next(&mut self) -> Duration {
+        let delay = self.delay;
+        self.delay = (delay * 2).min(MAX_RETRY_DELAY);
+        delay
+    }
+}
+
+// Consumes render messages from the render stream instead of serving HTTP.
+// Messages either reference a job (`job_id`) created through `POST /jobs`, or
+// carry `url` plus optional `width`/`height`/`preset` to pre-render into the cache.
+pub async fn run(
+    config: web::Data<Config>,
+    cache: Arc<RedisCache>,
+    client: reqwest::Client,
+) -> std::io::Result<()> {
+    // Jobs run with the policies of the tenant that created them
+    tenants::start(cache.clone()).await;
+
+    cache.stream_create_group(&config.render_stream, &config.render_group)
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))?;
+
+    let consumer = std::env::var("HOSTNAME")
+        .unwrap_or_else(|_| uuid::Uuid::new_v4().to_string());
+    log::info!("Worker {} consuming {} as group {}", consumer, config.render_stream, config.render_group);
+
+    // Start with messages delivered to this consumer before a restart, then new ones
+    let mut draining_pending = true;
+    let mut read_backoff = Backoff::new();
+    let mut requeue_backoff = Backoff::new();
+
+    loop {
+        let id = if draining_pending { "0" } else { ">" };
+        let messages = match cache.stream_read_group(
+            &config.render_stream,
+            &config.render_group,
+            &consumer,
+            id,
+            READ_BLOCK,
+            READ_COUNT,
+        ).await {
+            Ok(messages) => {
+                read_backoff.reset();
+                messages
+            },
+            Err(e) => {
+                log::error!("Failed to read render stream: {}", e);
+                read_backoff.wait().await;
+                continue;
+            }
+        };
+
+        if draining_pending && messages.is_empty() {
+            draining_pending = false;
+            continue;
+        }
+
+        for message in messages {
+            handle_message(&message, &config, &cache, &client, &mut requeue_backoff).await;
+        }
+    }
+}
+
+async fn handle_message(
+    message: &StreamId,
+    config: &web::Data<Config>,
+    cache: &Arc<RedisCache>,
+    client: &reqwest::Client,
+    requeue_backoff: &mut Backoff,
+) {
+    let attempts: u32 = message.get("attempts").unwrap_or(0) + 1;
+    log::debug!("Processing stream message {} (attempt {})", message.id, attempts);
+
+    if let Err(e) = process_message(message, config, cache, client).await {
+        log::warn!("Stream message {} failed on attempt {}: {}", message.id, attempts, e);
+
+        let mut fields = message_fields(message);
+        fields.retain(|(name, _)| *name != "attempts" && *name != "error");
+        fields.push(("attempts", attempts.to_string()));
+
+        let stream = if attempts < config.stream_max_attempts {
+            config.render_stream.clone()
+        } else {
+            fields.push(("error", e.to_string()));
+            format!("{}:dead", config.render_stream)
+        };
+
+        if let Err(e) = cache.stream_add(&stream, &fields).await {
+            // Leave the message pending so it is retried after a restart, and
+            // give Redis time to recover before the next one
+            log::error!("Failed to requeue stream message {}: {}", message.id, e);
+            requeue_backoff.wait().await;
+            return;
+        }
+        requeue_backoff.reset();
+    }
+
+    if let Err(e) = cache.stream_ack(&config.render_stream, &config.render_group, &message.id).await {
+        log::error!("Failed to ack stream message {}: {}", message.id, e);
+    }
+}
+
+async fn process_message(message: &StreamId, config: &web::Data<Config>, cache: &Arc<RedisCache>, client: &reqwest::Client) -> ServiceResult<()> {
+    if let Some(job_id) = message.get::<String>("job_id") {
+        // Failures are recorded on the job itself and reported through its callback
+        let job = jobs::load_job(cache, &job_id).await?;
+        jobs::run_job(job, cache.clone(), client.clone()).await;
+        return Ok(());
+    }
+
+    let url: String = message.get("url")
+        .ok_or_else(|| ServiceError::ValidationError("Stream message has no url or job_id".to_string()))?;
+    let (width, height) = config.resolve_size(
+        message.get("width"),
+        message.get("height"),
+        message.get::<String>("preset").as_deref(),
+    )?;
+
+    render_cached(&url, &RenderOptions::new(width, height), cache, client).await?;
+    Ok(())
+}
+
+fn message_fields(message: &StreamId) -> Vec<(&str, String)> {
+    ["job_id", "url", "width", "height", "preset", "attempts", "error"]
+        .into_iter()
+        .filter_map(|name| message.get::<String>(name).map(|value| (name, value)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_retry_delays_up_to_the_limit() {
+        let mut backoff = Backoff::new();
+        assert_eq!(backoff.next(), RETRY_DELAY);
+        assert_eq!(backoff.next(), RETRY_DELAY * 2);
+        assert_eq!(backoff.next(), RETRY_DELAY * 4);
+        for _ in 0..10 {
+            backoff.next();
+        }
+        assert_eq!(backoff.next(), MAX_RETRY_DELAY);
+
+        backoff.reset();
+        assert_eq!(backoff.next(), RETRY_DELAY);
+    }
+}